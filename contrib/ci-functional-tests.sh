@@ -2,6 +2,9 @@ set -xe
 
 # Build the revaultd binary
 cargo build --release
+# The functional tests use the debug one, with ZMQ notifications
+sudo apt update && sudo apt install libzmq3-dev
+cargo build --features zmq
 
 # Download the bitcoind binary
 BITCOIND_VERSION="0.21.0"
@@ -18,6 +18,9 @@ use uds_windows::UnixStream;
 fn show_usage() {
     eprintln!("Usage:");
     eprintln!(" revault-cli [--conf conf_path] <command> [<param 1> <param 2> ...]");
+    eprintln!(
+        " revault-cli [--conf conf_path] exportvaults [csv|json] [<blockheight>] [--output path]"
+    );
     process::exit(1);
 }
 
@@ -66,6 +69,47 @@ fn rpc_request(method: String, params: Vec<String>) -> Json {
     Json::Object(object)
 }
 
+// Remove the `--output path` parameters of `exportvaults`, if any
+fn export_output(params: &mut Vec<String>) -> Option<PathBuf> {
+    let index = params.iter().position(|param| param == "--output")?;
+    if index + 1 >= params.len() {
+        eprintln!("No path given for '--output'.");
+        show_usage();
+    }
+    let path = PathBuf::from(params.remove(index + 1));
+    params.remove(index);
+
+    Some(path)
+}
+
+// Write the exported vaults themselves rather than the JSONRPC response, as CSV or as JSON.
+fn write_export(response: &Json, output: Option<PathBuf>) {
+    let result = match response.get("result") {
+        Some(result) => result,
+        None => {
+            eprintln!("{}", response);
+            process::exit(1);
+        }
+    };
+    let content = match result.get("csv").and_then(|csv| csv.as_str()) {
+        Some(csv) => csv.to_string(),
+        None => format!(
+            "{}\n",
+            serde_json::to_string_pretty(result).expect("Serializing a JSON value")
+        ),
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(&path, content).unwrap_or_else(|e| {
+                eprintln!("Writing to {:?}: '{}'", path, e);
+                process::exit(1);
+            });
+        }
+        None => print!("{}", content),
+    }
+}
+
 fn socket_file(conf_file: Option<PathBuf>) -> PathBuf {
     let config = Config::from_file(conf_file).unwrap_or_else(|e| {
         eprintln!("Error getting config: {}", e);
@@ -105,7 +149,13 @@ fn trimmed(mut vec: Vec<u8>, bytes_read: usize) -> Vec<u8> {
 
 fn main() {
     let args = env::args().collect();
-    let (conf_file, method, params) = parse_args(args);
+    let (conf_file, method, mut params) = parse_args(args);
+    let is_export = method == "exportvaults";
+    let export_output = if is_export {
+        export_output(&mut params)
+    } else {
+        None
+    };
     let request = rpc_request(method, params);
     let socket_file = socket_file(conf_file);
     let mut raw_response = vec![0; 256];
@@ -147,5 +197,9 @@ fn main() {
         }
     }
 
-    print!("{}", response);
+    if is_export {
+        write_export(&response, export_output);
+    } else {
+        print!("{}", response);
+    }
 }
@@ -1,9 +1,20 @@
-use std::{net::SocketAddr, path::PathBuf, vec::Vec};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, vec::Vec};
 
 use revault_net::noise::PublicKey as NoisePubKey;
 use revault_tx::{
-    bitcoin::{hashes::hex::FromHex, util::bip32, Network, PublicKey},
-    scripts::EmergencyAddress,
+    bitcoin::{
+        hashes::{hex::FromHex, sha256, Hash},
+        util::bip32::{self, DerivationPath},
+        Network, PublicKey,
+    },
+    miniscript::{
+        descriptor::{DescriptorPublicKey, DescriptorSinglePub, DescriptorXKey},
+        Descriptor, Terminal,
+    },
+    scripts::{
+        cpfp_descriptor, deposit_descriptor, unvault_descriptor, CpfpDescriptor, DepositDescriptor,
+        EmergencyAddress, UnvaultDescriptor,
+    },
 };
 
 use serde::{de, Deserialize};
@@ -55,6 +66,31 @@ impl<'de> Deserialize<'de> for NoisePubkeyHex {
     }
 }
 
+fn deserialize_fromstr<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: FromStr,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    let string = String::deserialize(deserializer)?;
+    T::from_str(&string)
+        .map_err(|e| de::Error::custom(format!("Error parsing '{}': '{}'", string, e)))
+}
+
+fn deserialize_fromstr_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: de::Deserializer<'de>,
+    T: FromStr,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|string| {
+            T::from_str(&string)
+                .map_err(|e| de::Error::custom(format!("Error parsing '{}': '{}'", string, e)))
+        })
+        .transpose()
+}
+
 /// Everything we need to know for talking to bitcoind serenely
 #[derive(Debug, Clone, Deserialize)]
 pub struct BitcoindConfig {
@@ -64,6 +100,12 @@ pub struct BitcoindConfig {
     pub cookie_path: PathBuf,
     /// The IP:port bitcoind's RPC is listening on
     pub addr: SocketAddr,
+    /// How often we poll bitcoind, in seconds (default: 30, 3 on regtest)
+    pub poll_seconds: Option<u64>,
+    /// bitcoind's `zmqpubhashblock` endpoint, to be notified of new blocks
+    pub zmq_hashblock: Option<String>,
+    /// bitcoind's `zmqpubrawtx` endpoint, to be notified of new transactions
+    pub zmq_rawtx: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -92,6 +134,271 @@ pub struct CosignerConfig {
 pub struct ManagerConfig {
     pub xpub: bip32::ExtendedPubKey,
     pub cosigners: Vec<CosignerConfig>,
+    /// The sha256 of the Emergency address' scriptPubKey. We don't know the Emergency address
+    /// as a manager but need it to compute the deployment fingerprint.
+    #[serde(default, deserialize_with = "deserialize_fromstr_opt")]
+    pub emergency_address_hash: Option<sha256::Hash>,
+}
+
+/// The Revault scripts we are operating with. Either given as the participants' keys, from which
+/// we create the descriptors, or directly as the descriptors resulting from the key ceremony.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ScriptsConfig {
+    Keys {
+        /// The stakeholders' xpubs
+        stakeholders_xpubs: Vec<bip32::ExtendedPubKey>,
+        /// The cosigners' static public keys (must be as many as stakeholders'
+        /// xpubs)
+        cosigners_keys: Vec<PublicKey>,
+        /// The managers' xpubs
+        managers_xpubs: Vec<bip32::ExtendedPubKey>,
+        /// How many of the managers must sign a Spend (default: all of them)
+        managers_threshold: Option<usize>,
+        /// The unvault output scripts relative timelock
+        unvault_csv: u32,
+    },
+    Descriptors {
+        /// The descriptor of the deposit outputs, with xpubs as `[fingerprint/path]xpub/*`
+        #[serde(deserialize_with = "deserialize_fromstr")]
+        deposit_descriptor: Descriptor<DescriptorPublicKey>,
+        /// The descriptor of the unvault outputs. The unvault CSV and the managers threshold are
+        /// read from it.
+        #[serde(deserialize_with = "deserialize_fromstr")]
+        unvault_descriptor: Descriptor<DescriptorPublicKey>,
+        /// The descriptor of the CPFP outputs
+        #[serde(deserialize_with = "deserialize_fromstr")]
+        cpfp_descriptor: Descriptor<DescriptorPublicKey>,
+    },
+}
+
+/// The Revault descriptors, along with the participants' keys and the CSV they were created with
+#[derive(Debug, Clone)]
+pub struct RevaultDescriptors {
+    pub deposit_descriptor: DepositDescriptor<DescriptorPublicKey>,
+    pub unvault_descriptor: UnvaultDescriptor<DescriptorPublicKey>,
+    pub cpfp_descriptor: CpfpDescriptor<DescriptorPublicKey>,
+    pub stakeholders_keys: Vec<DescriptorPublicKey>,
+    pub managers_keys: Vec<DescriptorPublicKey>,
+    pub cosigners_keys: Vec<DescriptorPublicKey>,
+    pub managers_threshold: usize,
+    pub unvault_csv: u32,
+}
+
+impl RevaultDescriptors {
+    /// Whether this xpub is one of the stakeholders'
+    pub fn is_stakeholder_xpub(&self, xpub: &bip32::ExtendedPubKey) -> bool {
+        self.stakeholders_keys.iter().any(|k| key_is_xpub(k, xpub))
+    }
+
+    /// Whether this xpub is one of the managers'
+    pub fn is_manager_xpub(&self, xpub: &bip32::ExtendedPubKey) -> bool {
+        self.managers_keys.iter().any(|k| key_is_xpub(k, xpub))
+    }
+}
+
+fn key_is_xpub(key: &DescriptorPublicKey, xpub: &bip32::ExtendedPubKey) -> bool {
+    match key {
+        DescriptorPublicKey::XPub(xkey) => &xkey.xkey == xpub,
+        DescriptorPublicKey::SinglePub(_) => false,
+    }
+}
+
+fn descriptorxpub_from_xpub(xpubs: &[bip32::ExtendedPubKey]) -> Vec<DescriptorPublicKey> {
+    xpubs
+        .iter()
+        .map(|xpub| {
+            DescriptorPublicKey::XPub(DescriptorXKey {
+                origin: None,
+                xkey: *xpub,
+                derivation_path: DerivationPath::from(vec![]),
+                is_wildcard: true,
+            })
+        })
+        .collect()
+}
+
+// All the keys in this descriptor, in order of appearance
+fn descriptor_keys(
+    name: &str,
+    descriptor: &Descriptor<DescriptorPublicKey>,
+) -> Result<Vec<DescriptorPublicKey>, ConfigError> {
+    let keys: Vec<DescriptorPublicKey> = match descriptor {
+        Descriptor::Wsh(ms) => ms.iter_pk().collect(),
+        _ => {
+            return Err(ConfigError(format!(
+                r#""{}" must be a P2WSH descriptor"#,
+                name
+            )))
+        }
+    };
+
+    // We derive our own keys by a single unhardened step from the xpub, so we can't accept
+    // anything else than 'xpub/*'.
+    for key in keys.iter() {
+        if let DescriptorPublicKey::XPub(xkey) = key {
+            if !xkey.is_wildcard || !xkey.derivation_path.as_ref().is_empty() {
+                return Err(ConfigError(format!(
+                    r#"Invalid key '{}' in "{}": xpubs must be of the form '[fingerprint/path]xpub/*'"#,
+                    key, name
+                )));
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+impl ScriptsConfig {
+    /// Get the Revault descriptors out of the configured scripts. If we were given the
+    /// descriptors directly, this checks they are actual Revault descriptors by re-creating them
+    /// from the keys they contain.
+    pub fn revault_descriptors(&self) -> Result<RevaultDescriptors, ConfigError> {
+        match self {
+            Self::Keys {
+                stakeholders_xpubs,
+                cosigners_keys,
+                managers_xpubs,
+                managers_threshold,
+                unvault_csv,
+            } => {
+                let stakeholders_keys = descriptorxpub_from_xpub(stakeholders_xpubs);
+                let managers_keys = descriptorxpub_from_xpub(managers_xpubs);
+                let cosigners_keys: Vec<DescriptorPublicKey> = cosigners_keys
+                    .iter()
+                    .map(|key| {
+                        DescriptorPublicKey::SinglePub(DescriptorSinglePub {
+                            origin: None,
+                            key: *key,
+                        })
+                    })
+                    .collect();
+
+                let managers_threshold = managers_threshold.unwrap_or(managers_keys.len());
+
+                create_descriptors(
+                    stakeholders_keys,
+                    managers_keys,
+                    cosigners_keys,
+                    managers_threshold,
+                    *unvault_csv,
+                )
+            }
+            Self::Descriptors {
+                deposit_descriptor,
+                unvault_descriptor,
+                cpfp_descriptor,
+            } => {
+                let stakeholders_keys = descriptor_keys("deposit_descriptor", deposit_descriptor)?;
+                let managers_keys = descriptor_keys("cpfp_descriptor", cpfp_descriptor)?;
+                // The Unvault descriptor contains the stakeholders, managers, and cosigners keys.
+                let cosigners_keys: Vec<DescriptorPublicKey> =
+                    descriptor_keys("unvault_descriptor", unvault_descriptor)?
+                        .into_iter()
+                        .filter(|k| !stakeholders_keys.contains(k) && !managers_keys.contains(k))
+                        .collect();
+                let unvault_csv = match unvault_descriptor {
+                    Descriptor::Wsh(ms) => ms.iter().find_map(|node| match node.node {
+                        Terminal::Older(csv) => Some(csv),
+                        _ => None,
+                    }),
+                    _ => None,
+                }
+                .ok_or_else(|| ConfigError(r#"No CSV in "unvault_descriptor""#.to_string()))?;
+
+                // Find out the managers threshold by trying them all, defaulting to all managers
+                // (in which case we'll error below if it's not a Revault descriptor).
+                let unvault_desc_str = unvault_descriptor.to_string();
+                let managers_threshold = (1..=managers_keys.len())
+                    .find(|threshold| {
+                        create_descriptors(
+                            stakeholders_keys.clone(),
+                            managers_keys.clone(),
+                            cosigners_keys.clone(),
+                            *threshold,
+                            unvault_csv,
+                        )
+                        .map(|descs| descs.unvault_descriptor.0.to_string() == unvault_desc_str)
+                        .unwrap_or(false)
+                    })
+                    .unwrap_or_else(|| managers_keys.len());
+
+                let descriptors = create_descriptors(
+                    stakeholders_keys,
+                    managers_keys,
+                    cosigners_keys,
+                    managers_threshold,
+                    unvault_csv,
+                )?;
+                for (name, given, expected) in &[
+                    (
+                        "deposit_descriptor",
+                        deposit_descriptor,
+                        &descriptors.deposit_descriptor.0,
+                    ),
+                    (
+                        "unvault_descriptor",
+                        unvault_descriptor,
+                        &descriptors.unvault_descriptor.0,
+                    ),
+                    (
+                        "cpfp_descriptor",
+                        cpfp_descriptor,
+                        &descriptors.cpfp_descriptor.0,
+                    ),
+                ] {
+                    if given.to_string() != expected.to_string() {
+                        return Err(ConfigError(format!(
+                            r#""{}" is not a Revault descriptor. Expected '{}' from its keys but got '{}'"#,
+                            name, expected, given
+                        )));
+                    }
+                }
+
+                Ok(descriptors)
+            }
+        }
+    }
+}
+
+fn create_descriptors(
+    stakeholders_keys: Vec<DescriptorPublicKey>,
+    managers_keys: Vec<DescriptorPublicKey>,
+    cosigners_keys: Vec<DescriptorPublicKey>,
+    managers_threshold: usize,
+    unvault_csv: u32,
+) -> Result<RevaultDescriptors, ConfigError> {
+    if managers_threshold < 1 || managers_threshold > managers_keys.len() {
+        return Err(ConfigError(format!(
+            r#""managers_threshold" must be between 1 and the number of managers ({}), got {}"#,
+            managers_keys.len(),
+            managers_threshold
+        )));
+    }
+
+    let deposit_descriptor = deposit_descriptor(stakeholders_keys.clone())
+        .map_err(|e| ConfigError(format!("Creating deposit descriptor: {}", e)))?;
+    let unvault_descriptor = unvault_descriptor(
+        stakeholders_keys.clone(),
+        managers_keys.clone(),
+        managers_threshold,
+        cosigners_keys.clone(),
+        unvault_csv,
+    )
+    .map_err(|e| ConfigError(format!("Creating unvault descriptor: {}", e)))?;
+    let cpfp_descriptor = cpfp_descriptor(managers_keys.clone())
+        .map_err(|e| ConfigError(format!("Creating CPFP descriptor: {}", e)))?;
+
+    Ok(RevaultDescriptors {
+        deposit_descriptor,
+        unvault_descriptor,
+        cpfp_descriptor,
+        stakeholders_keys,
+        managers_keys,
+        cosigners_keys,
+        managers_threshold,
+        unvault_csv,
+    })
 }
 
 /// Static informations we require to operate
@@ -103,21 +410,19 @@ pub struct Config {
     pub stakeholder_config: Option<StakeholderConfig>,
     /// Some() if we are a manager
     pub manager_config: Option<ManagerConfig>,
-    /// The stakeholders' xpubs
-    pub stakeholders_xpubs: Vec<bip32::ExtendedPubKey>,
-    /// The cosigners' static public keys (must be as many as stakeholders'
-    /// xpubs)
-    pub cosigners_keys: Vec<PublicKey>,
-    /// The managers' xpubs
-    pub managers_xpubs: Vec<bip32::ExtendedPubKey>,
-    /// The unvault output scripts relative timelock
-    pub unvault_csv: u32,
+    /// The participants' keys, or directly the descriptors
+    #[serde(flatten)]
+    pub scripts_config: ScriptsConfig,
     /// The host of the sync server (may be an IP or a hidden service)
     pub coordinator_host: String,
     /// The Noise static public key of the sync server
     pub coordinator_noise_key: NoisePubkeyHex,
     /// The poll intervals for signature fetching (default: 1min)
     pub coordinator_poll_seconds: Option<u64>,
+    /// How many confirmations a deposit needs before we consider it (default: 6)
+    pub min_conf: Option<u32>,
+    /// How many deposit addresses we watch past the last used one (default: 100)
+    pub gap_limit: Option<u32>,
     /// An optional custom data directory
     pub data_dir: Option<PathBuf>,
     /// Whether to daemonize the process
@@ -127,6 +432,15 @@ pub struct Config {
     // TODO: sync server address
 }
 
+/// The maximum gap limit, as we import as many addresses to bitcoind's watchonly wallet
+pub const MAX_GAP_LIMIT: u32 = 10_000;
+
+/// The hash participants share in place of the Emergency address, the sha256 of its
+/// scriptPubKey.
+pub fn emergency_address_hash(emer_address: &EmergencyAddress) -> sha256::Hash {
+    sha256::Hash::hash(emer_address.address().script_pubkey().as_bytes())
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct ConfigError(pub String);
 
@@ -168,7 +482,9 @@ pub fn config_folder_path() -> Result<PathBuf, ConfigError> {
     ))
 }
 
-fn config_file_path() -> Result<PathBuf, ConfigError> {
+/// Get the absolute path to the default configuration file, `revault.toml` in the configuration
+/// folder.
+pub fn config_file_path() -> Result<PathBuf, ConfigError> {
     config_folder_path().map(|mut path| {
         path.push("revault.toml");
         path
@@ -184,12 +500,34 @@ impl Config {
     pub fn from_file(custom_path: Option<PathBuf>) -> Result<Config, ConfigError> {
         let config_file = custom_path.unwrap_or(config_file_path()?);
 
-        let config = std::fs::read(&config_file)
-            .map_err(|e| ConfigError(format!("Reading configuration file: {}", e)))
-            .and_then(|file_content| {
-                toml::from_slice::<Config>(&file_content)
-                    .map_err(|e| ConfigError(format!("Parsing configuration file: {}", e)))
-            })?;
+        let file_content = std::fs::read(&config_file)
+            .map_err(|e| ConfigError(format!("Reading configuration file: {}", e)))?;
+
+        Config::from_toml(&file_content)
+    }
+
+    /// Get our static configuration out of the content of a configuration file, performing the
+    /// same sanity checks as [Config::from_file].
+    pub fn from_toml(toml_content: &[u8]) -> Result<Config, ConfigError> {
+        let config = toml::from_slice::<Config>(toml_content)
+            .map_err(|e| ConfigError(format!("Parsing configuration file: {}", e)))?;
+
+        if config.bitcoind_config.poll_seconds == Some(0) {
+            return Err(ConfigError(
+                r#""poll_seconds" must be at least 1"#.to_string(),
+            ));
+        }
+        if config.min_conf == Some(0) {
+            return Err(ConfigError(r#""min_conf" must be at least 1"#.to_string()));
+        }
+        if let Some(gap_limit) = config.gap_limit {
+            if gap_limit == 0 || gap_limit > MAX_GAP_LIMIT {
+                return Err(ConfigError(format!(
+                    r#""gap_limit" must be between 1 and {}, got {}"#,
+                    MAX_GAP_LIMIT, gap_limit
+                )));
+            }
+        }
 
         if config.stakeholder_config.is_none() && config.manager_config.is_none() {
             return Err(ConfigError(format!(
@@ -197,20 +535,33 @@ impl Config {
             )));
         }
 
-        if config.stakeholders_xpubs.len() != config.cosigners_keys.len() {
+        if let ScriptsConfig::Keys {
+            ref stakeholders_xpubs,
+            ref cosigners_keys,
+            ..
+        } = config.scripts_config
+        {
+            if stakeholders_xpubs.len() != cosigners_keys.len() {
+                return Err(ConfigError(format!(
+                    r#"Not as much "stakeholders_xpubs" ({}) as "cosigners_keys" ({})"#,
+                    stakeholders_xpubs.len(),
+                    cosigners_keys.len()
+                )));
+            }
+        }
+
+        // This checks the descriptors are Revault ones if they were given directly
+        let descriptors = config.scripts_config.revault_descriptors()?;
+        if descriptors.stakeholders_keys.len() != descriptors.cosigners_keys.len() {
             return Err(ConfigError(format!(
-                r#"Not as much "stakeholders_xpubs" ({}) as "cosigners_keys" ({})"#,
-                config.stakeholders_xpubs.len(),
-                config.cosigners_keys.len()
+                "Not as much stakeholders ({}) as cosigners ({})",
+                descriptors.stakeholders_keys.len(),
+                descriptors.cosigners_keys.len()
             )));
         }
 
         if let Some(ref stk_config) = config.stakeholder_config {
-            if !config
-                .stakeholders_xpubs
-                .iter()
-                .any(|x| x == &stk_config.xpub)
-            {
+            if !descriptors.is_stakeholder_xpub(&stk_config.xpub) {
                 return Err(ConfigError(format!(
                     r#"Our "stakeholder_config" xpub is not part of the given stakeholders' xpubs: {}"#,
                     stk_config.xpub
@@ -228,12 +579,25 @@ impl Config {
         }
 
         if let Some(ref man_config) = config.manager_config {
-            if !config.managers_xpubs.iter().any(|x| x == &man_config.xpub) {
+            if !descriptors.is_manager_xpub(&man_config.xpub) {
                 return Err(ConfigError(format!(
                     r#"Our "manager_config" xpub is not part of the given managers' xpubs: {}"#,
                     man_config.xpub
                 )));
             }
+
+            if let (Some(ref stk_config), Some(emer_hash)) = (
+                &config.stakeholder_config,
+                man_config.emergency_address_hash,
+            ) {
+                let expected_hash = emergency_address_hash(&stk_config.emergency_address);
+                if emer_hash != expected_hash {
+                    return Err(ConfigError(format!(
+                        r#"Our "emergency_address_hash" ({}) is not the hash of our "emergency_address" ({})"#,
+                        emer_hash, expected_hash
+                    )));
+                }
+            }
         }
 
         Ok(config)
@@ -242,7 +606,7 @@ impl Config {
 
 #[cfg(test)]
 mod tests {
-    use super::{config_file_path, Config};
+    use super::{config_file_path, Config, ScriptsConfig, MAX_GAP_LIMIT};
 
     // Test the format of the configuration file
     #[test]
@@ -285,7 +649,10 @@ mod tests {
             watchtowers = [ { host = "127.0.0.1:1", noise_key = "46084f8a7da40ef7ffc38efa5af8a33a742b90f920885d17c533bb2a0b680cb3" } ]
             emergency_address = "bc1qwqdg6squsna38e46795at95yu9atm8azzmyvckulcc7kytlcckxswvvzej"
         "#;
-        toml::from_str::<Config>(toml_str).expect("Deserializing stakeholder toml_str");
+        let config =
+            toml::from_str::<Config>(toml_str).expect("Deserializing stakeholder toml_str");
+        assert!(config.bitcoind_config.zmq_hashblock.is_none());
+        assert!(config.bitcoind_config.zmq_rawtx.is_none());
 
         // A valid manager config
         let toml_str = r#"
@@ -318,13 +685,23 @@ mod tests {
             network = "bitcoin"
             cookie_path = "/home/user/.bitcoin/.cookie"
             addr = "127.0.0.1:8332"
+            zmq_hashblock = "tcp://127.0.0.1:28332"
+            zmq_rawtx = "tcp://127.0.0.1:28332"
 
             # We are one of the above managers
             [manager_config]
             xpub = "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4"
             cosigners = [ { host = "127.0.0.1:1", noise_key = "087629614d227ff2b9ed5f2ce2eb7cd527d2d18f866b24009647251fce58de38" } ]
         "#;
-        toml::from_str::<Config>(toml_str).expect("Deserializing manager toml_str");
+        let config = toml::from_str::<Config>(toml_str).expect("Deserializing manager toml_str");
+        assert_eq!(
+            config.bitcoind_config.zmq_hashblock,
+            Some("tcp://127.0.0.1:28332".to_string())
+        );
+        assert_eq!(
+            config.bitcoind_config.zmq_rawtx,
+            Some("tcp://127.0.0.1:28332".to_string())
+        );
 
         // A valid sakeholder-manager config
         let toml_str = r#"
@@ -383,6 +760,259 @@ mod tests {
         config_res.expect_err("Deserializing an invalid toml_str");
     }
 
+    // The configuration without the scripts
+    const TOML_CONFIG_BASE: &str = r#"
+            daemon = false
+            coordinator_host = "127.0.0.1:1"
+            coordinator_noise_key = "d91563973102454a7830137e92d0548bc83b4ea2799f1df04622ca1307381402"
+
+            [bitcoind_config]
+            network = "bitcoin"
+            cookie_path = "/home/user/.bitcoin/.cookie"
+            addr = "127.0.0.1:8332"
+
+            [stakeholder_config]
+            xpub = "xpub6AP3nZhB34Zoan3KCL9bAdnwNHdzMbskLudpbchwTfkHwnNDXYf1769gzozjgzDNUF7iwa5nCdhE5byrcx5PDKFCUDByeuqiHa382EKhcay"
+            watchtowers = [ { host = "127.0.0.1:1", noise_key = "46084f8a7da40ef7ffc38efa5af8a33a742b90f920885d17c533bb2a0b680cb3" } ]
+            emergency_address = "bc1qwqdg6squsna38e46795at95yu9atm8azzmyvckulcc7kytlcckxswvvzej"
+    "#;
+
+    fn descriptors_config(deposit: &str, unvault: &str, cpfp: &str) -> Config {
+        let toml_str = format!(
+            "deposit_descriptor = \"{}\"\nunvault_descriptor = \"{}\"\ncpfp_descriptor = \"{}\"\n{}",
+            deposit, unvault, cpfp, TOML_CONFIG_BASE
+        );
+        toml::from_str::<Config>(&toml_str).expect("Deserializing descriptors config")
+    }
+
+    // Strip the checksum, if any, so that we can tweak the descriptor string
+    fn desc_str(desc: &impl std::fmt::Display) -> String {
+        desc.to_string().split('#').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn revault_descriptors() {
+        let keys_toml = format!(
+            r#"
+            stakeholders_xpubs = [
+                    "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA",
+                    "xpub6AP3nZhB34Zoan3KCL9bAdnwNHdzMbskLudpbchwTfkHwnNDXYf1769gzozjgzDNUF7iwa5nCdhE5byrcx5PDKFCUDByeuqiHa382EKhcay"
+            ]
+            cosigners_keys = [
+                    "02644cf9e2b78feb0a751e50502f530a4cbd0bbda3020779605391e71654dd66c2",
+                    "03ced55d1208bd8c6b42b11e29baa577711cae831b3a1296607c5e5d3ed365f49c"
+            ]
+            managers_xpubs = [
+                    "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4"
+            ]
+            unvault_csv = 42
+            {}"#,
+            TOML_CONFIG_BASE
+        );
+        let config = toml::from_str::<Config>(&keys_toml).expect("Deserializing keys config");
+        assert!(matches!(config.scripts_config, ScriptsConfig::Keys { .. }));
+        let from_keys = config.scripts_config.revault_descriptors().unwrap();
+        assert!(from_keys.is_stakeholder_xpub(&config.stakeholder_config.as_ref().unwrap().xpub));
+        assert!(!from_keys.is_manager_xpub(&config.stakeholder_config.unwrap().xpub));
+
+        let (deposit, unvault, cpfp) = (
+            desc_str(&from_keys.deposit_descriptor.0),
+            desc_str(&from_keys.unvault_descriptor.0),
+            desc_str(&from_keys.cpfp_descriptor.0),
+        );
+
+        // We can give the descriptors directly, and get the same ones along with the CSV
+        let config = descriptors_config(&deposit, &unvault, &cpfp);
+        assert!(matches!(
+            config.scripts_config,
+            ScriptsConfig::Descriptors { .. }
+        ));
+        let from_descs = config.scripts_config.revault_descriptors().unwrap();
+        assert_eq!(
+            from_descs.deposit_descriptor.0.to_string(),
+            from_keys.deposit_descriptor.0.to_string()
+        );
+        assert_eq!(
+            from_descs.unvault_descriptor.0.to_string(),
+            from_keys.unvault_descriptor.0.to_string()
+        );
+        assert_eq!(
+            from_descs.cpfp_descriptor.0.to_string(),
+            from_keys.cpfp_descriptor.0.to_string()
+        );
+        assert_eq!(from_descs.cosigners_keys, from_keys.cosigners_keys);
+        assert_eq!(from_descs.unvault_csv, 42);
+
+        // The keys may have an origin
+        let with_origin = |desc: &str| {
+            desc.replace(
+                "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA",
+                "[aabbccdd/48'/0'/0'/2']xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA",
+            )
+        };
+        descriptors_config(&with_origin(&deposit), &with_origin(&unvault), &cpfp)
+            .scripts_config
+            .revault_descriptors()
+            .unwrap();
+        // But they must be the same across descriptors
+        descriptors_config(&with_origin(&deposit), &unvault, &cpfp)
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+
+        // We only accept xpub/*
+        let non_wildcard = |desc: &str| {
+            desc.replace(
+                "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA/*",
+                "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA/0/*",
+            )
+        };
+        descriptors_config(&non_wildcard(&deposit), &non_wildcard(&unvault), &cpfp)
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+
+        // The descriptors must be Revault descriptors
+        descriptors_config(&cpfp, &unvault, &cpfp)
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+        descriptors_config(&deposit, &deposit, &cpfp)
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+        let tweaked_csv = unvault.replace("older(42)", "older(43)");
+        assert_ne!(tweaked_csv, unvault);
+        assert_eq!(
+            descriptors_config(&deposit, &tweaked_csv, &cpfp)
+                .scripts_config
+                .revault_descriptors()
+                .unwrap()
+                .unvault_csv,
+            43
+        );
+    }
+
+    #[test]
+    fn managers_threshold() {
+        let keys_config = |threshold: &str| {
+            let toml_str = format!(
+                r#"
+                stakeholders_xpubs = [
+                        "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA",
+                        "xpub6AP3nZhB34Zoan3KCL9bAdnwNHdzMbskLudpbchwTfkHwnNDXYf1769gzozjgzDNUF7iwa5nCdhE5byrcx5PDKFCUDByeuqiHa382EKhcay"
+                ]
+                cosigners_keys = [
+                        "02644cf9e2b78feb0a751e50502f530a4cbd0bbda3020779605391e71654dd66c2",
+                        "03ced55d1208bd8c6b42b11e29baa577711cae831b3a1296607c5e5d3ed365f49c"
+                ]
+                managers_xpubs = [
+                        "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4",
+                        "xpub6AMXQWzNN9GSrWk5SeKdEUK6Ntha87BBtprp95EGSsLiMkUedYcHh53P3J1frsnMqRSssARq6EdRnAJmizJMaBqxCrA3MVGjV7d9wNQAEtm",
+                        "xpub6AL6oiHLkP5bDMry27vH7uethb1g8iTysk5MZJvNe1yBv5fedvqqgiaPS2riWCiu4o3H8xinEVdQ5zz8pZKH1RtjTbdQyxHsMMCBrp2PP8S"
+                ]
+                {}
+                unvault_csv = 42
+                {}"#,
+                threshold, TOML_CONFIG_BASE
+            );
+            toml::from_str::<Config>(&toml_str).expect("Deserializing keys config")
+        };
+
+        // Defaults to all the managers
+        let descs = keys_config("")
+            .scripts_config
+            .revault_descriptors()
+            .unwrap();
+        assert_eq!(descs.managers_threshold, 3);
+
+        let descs = keys_config("managers_threshold = 2")
+            .scripts_config
+            .revault_descriptors()
+            .unwrap();
+        assert_eq!(descs.managers_threshold, 2);
+        assert_ne!(
+            descs.unvault_descriptor.0.to_string(),
+            keys_config("")
+                .scripts_config
+                .revault_descriptors()
+                .unwrap()
+                .unvault_descriptor
+                .0
+                .to_string()
+        );
+
+        // The threshold is read back from the Unvault descriptor
+        let from_descs = descriptors_config(
+            &desc_str(&descs.deposit_descriptor.0),
+            &desc_str(&descs.unvault_descriptor.0),
+            &desc_str(&descs.cpfp_descriptor.0),
+        )
+        .scripts_config
+        .revault_descriptors()
+        .unwrap();
+        assert_eq!(from_descs.managers_threshold, 2);
+        assert_eq!(
+            from_descs.unvault_descriptor.0.to_string(),
+            descs.unvault_descriptor.0.to_string()
+        );
+
+        keys_config("managers_threshold = 0")
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+        keys_config("managers_threshold = 4")
+            .scripts_config
+            .revault_descriptors()
+            .unwrap_err();
+    }
+
+    #[test]
+    fn operational_parameters() {
+        let config = |params: &str, poll: &str| {
+            let toml_str = format!(
+                r#"
+                stakeholders_xpubs = [
+                        "xpub6BHATNyFVsBD8MRygTsv2q9WFTJzEB3o6CgJK7sjopcB286bmWFkNYm6kK5fzVe2gk4mJrSK5isFSFommNDST3RYJWSzrAe9V4bEzboHqnA",
+                        "xpub6AP3nZhB34Zoan3KCL9bAdnwNHdzMbskLudpbchwTfkHwnNDXYf1769gzozjgzDNUF7iwa5nCdhE5byrcx5PDKFCUDByeuqiHa382EKhcay"
+                ]
+                cosigners_keys = [
+                        "02644cf9e2b78feb0a751e50502f530a4cbd0bbda3020779605391e71654dd66c2",
+                        "03ced55d1208bd8c6b42b11e29baa577711cae831b3a1296607c5e5d3ed365f49c"
+                ]
+                managers_xpubs = [
+                        "xpub6AtVcKWPpZ9t3Aa3VvzWid1dzJFeXPfNntPbkGsYjNrp7uhXpzSL5QVMCmaHqUzbVUGENEwbBbzF9E8emTxQeP3AzbMjfzvwSDkwUrxg2G4"
+                ]
+                unvault_csv = 42
+                {}
+                {}"#,
+                params,
+                TOML_CONFIG_BASE.replace(
+                    "addr = \"127.0.0.1:8332\"",
+                    &format!("addr = \"127.0.0.1:8332\"\n{}", poll)
+                )
+            );
+            Config::from_toml(toml_str.as_bytes())
+        };
+
+        // They are all optional
+        let conf = config("", "").expect("No parameters");
+        assert_eq!(conf.min_conf, None);
+        assert_eq!(conf.gap_limit, None);
+        assert_eq!(conf.bitcoind_config.poll_seconds, None);
+
+        let conf = config("min_conf = 12\ngap_limit = 200", "poll_seconds = 10").unwrap();
+        assert_eq!(conf.min_conf, Some(12));
+        assert_eq!(conf.gap_limit, Some(200));
+        assert_eq!(conf.bitcoind_config.poll_seconds, Some(10));
+
+        config("min_conf = 0", "").unwrap_err();
+        config("gap_limit = 0", "").unwrap_err();
+        config(&format!("gap_limit = {}", MAX_GAP_LIMIT + 1), "").unwrap_err();
+        config(&format!("gap_limit = {}", MAX_GAP_LIMIT), "").unwrap();
+        config("", "poll_seconds = 0").unwrap_err();
+    }
+
     #[test]
     fn config_directory() {
         let filepath = config_file_path().expect("Getting config file path");
@@ -0,0 +1,159 @@
+//! The emergency kit: the fully signed Emergency and Unvault-Emergency transactions of our vaults,
+//! to be kept offline by a stakeholder. It's self-contained, so that `revault-emergency` can
+//! broadcast them even if revaultd or its database are gone.
+//!
+//! The kit is a JSON file, with a checksum of its content to detect corruption.
+
+use revault_tx::bitcoin::hashes::{sha256, Hash};
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The version of the kit format we write, and the only one we read.
+pub const KIT_VERSION: u32 = 1;
+
+/// A vault, and its transactions we could broadcast.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KitVault {
+    pub deposit_outpoint: String,
+    pub amount: u64,
+    pub derivation_index: u32,
+    /// The status of the vault when the kit was created
+    pub status: String,
+    /// The finalized Emergency transaction, as hex
+    pub emergency_tx: Option<String>,
+    /// The finalized Unvault-Emergency transaction, as hex
+    pub unvault_emergency_tx: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmergencyKit {
+    pub version: u32,
+    pub network: String,
+    /// Timestamp of the kit creation
+    pub created_at: u64,
+    pub vaults: Vec<KitVault>,
+}
+
+// What we actually write
+#[derive(Serialize, Deserialize)]
+struct ChecksummedKit {
+    #[serde(flatten)]
+    kit: EmergencyKit,
+    // sha256 of the JSON serialization of the kit, as hex
+    checksum: String,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct KitError(pub String);
+
+impl std::fmt::Display for KitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Emergency kit error: {}", self.0)
+    }
+}
+
+impl std::error::Error for KitError {}
+
+impl EmergencyKit {
+    fn checksum(&self) -> sha256::Hash {
+        sha256::Hash::hash(&serde_json::to_vec(self).expect("Serializing a kit"))
+    }
+
+    /// The content of the kit file
+    pub fn to_file_content(&self) -> String {
+        let kit = ChecksummedKit {
+            kit: self.clone(),
+            checksum: self.checksum().to_string(),
+        };
+        serde_json::to_string_pretty(&kit).expect("Serializing a kit")
+    }
+
+    /// Parse the content of a kit file, checking it wasn't corrupted.
+    pub fn from_file_content(content: &[u8]) -> Result<EmergencyKit, KitError> {
+        let ChecksummedKit { kit, checksum } =
+            serde_json::from_slice(content).map_err(|e| KitError(format!("Parsing kit: {}", e)))?;
+        if kit.version != KIT_VERSION {
+            return Err(KitError(format!(
+                "Unknown kit version '{}', expected '{}'",
+                kit.version, KIT_VERSION
+            )));
+        }
+        if kit.checksum().to_string() != checksum {
+            return Err(KitError(format!(
+                "Checksum mismatch: the kit says '{}' but its content hashes to '{}'",
+                checksum,
+                kit.checksum()
+            )));
+        }
+
+        Ok(kit)
+    }
+
+    /// Write the kit to a new file only we can read.
+    pub fn write_to(&self, path: &Path) -> Result<(), io::Error> {
+        let mut options = fs::OpenOptions::new();
+        options = options.write(true).create_new(true).clone();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options = options.mode(0o600).clone();
+        }
+
+        options
+            .open(path)?
+            .write_all(self.to_file_content().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmergencyKit, KitVault, KIT_VERSION};
+
+    fn dummy_kit() -> EmergencyKit {
+        EmergencyKit {
+            version: KIT_VERSION,
+            network: "regtest".to_string(),
+            created_at: 1_617_000_000,
+            vaults: vec![KitVault {
+                deposit_outpoint:
+                    "0e6a8b6a29dfbbf4b6e1e4b3ecb1a3e9e0a9c49e5fc6e2f4b1a6ef1f1d5e7c6d:1".to_string(),
+                amount: 100_000_000,
+                derivation_index: 3,
+                status: "active".to_string(),
+                emergency_tx: Some("0200000001".to_string()),
+                unvault_emergency_tx: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn kit_roundtrip() {
+        let kit = dummy_kit();
+        let content = kit.to_file_content();
+        assert_eq!(
+            EmergencyKit::from_file_content(content.as_bytes()).unwrap(),
+            kit
+        );
+    }
+
+    #[test]
+    fn kit_corruption() {
+        let content = dummy_kit().to_file_content();
+
+        let tampered = content.replace("100000000", "100000001");
+        assert_ne!(tampered, content);
+        EmergencyKit::from_file_content(tampered.as_bytes()).unwrap_err();
+
+        let tampered = content.replace("\"version\": 1", "\"version\": 2");
+        assert_ne!(tampered, content);
+        EmergencyKit::from_file_content(tampered.as_bytes()).unwrap_err();
+
+        EmergencyKit::from_file_content(b"not a kit").unwrap_err();
+    }
+}
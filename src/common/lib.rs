@@ -1,4 +1,5 @@
 pub mod config;
+pub mod emergency_kit;
 pub mod utils;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
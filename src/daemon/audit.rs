@@ -0,0 +1,178 @@
+//! Tamper detection for the presigned transactions we store. We are about to sign or broadcast
+//! them, so we make sure they are still the ones we derive from the vault's deposit outpoint,
+//! amount and derivation index rather than trusting whatever is in the database.
+
+use crate::{
+    database::{
+        interface::db_presigned_transactions,
+        schema::{DbVault, RevaultTx, TransactionType},
+        DatabaseError,
+    },
+    revaultd::RevaultD,
+    threadmessages::PresignedMismatch,
+};
+use common::assume_some;
+use revault_tx::{
+    bitcoin::{util::bip32::ChildNumber, OutPoint, Txid},
+    transactions::{
+        transaction_chain, transaction_chain_manager, CancelTransaction, EmergencyTransaction,
+        RevaultTransaction, UnvaultEmergencyTransaction, UnvaultTransaction,
+    },
+    txins::DepositTxIn,
+    txouts::DepositTxOut,
+};
+
+#[derive(Debug)]
+pub struct AuditError(String);
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Presigned transactions audit error: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl From<DatabaseError> for AuditError {
+    fn from(e: DatabaseError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<revault_tx::Error> for AuditError {
+    fn from(e: revault_tx::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+/// Derive the presigned transactions of the vault at this deposit outpoint. All of them if we
+/// are a stakeholder, only the Unvault and the Cancel if we are a manager.
+pub fn derive_presigned_transactions(
+    revaultd: &RevaultD,
+    outpoint: &OutPoint,
+    amount: u64,
+    derivation_index: ChildNumber,
+) -> Result<
+    (
+        UnvaultTransaction,
+        CancelTransaction,
+        Option<EmergencyTransaction>,
+        Option<UnvaultEmergencyTransaction>,
+    ),
+    revault_tx::Error,
+> {
+    // We use the same derivation index for all descriptors.
+    let deposit_descriptor = revaultd.deposit_descriptor.derive(derivation_index);
+    let unvault_descriptor = revaultd.unvault_descriptor.derive(derivation_index);
+    let cpfp_descriptor = revaultd.cpfp_descriptor.derive(derivation_index);
+
+    let deposit_txin = DepositTxIn::new(
+        *outpoint,
+        DepositTxOut::new(amount, &deposit_descriptor, revaultd.xpub_ctx()),
+    );
+    if revaultd.is_stakeholder() {
+        let emer_address = assume_some!(revaultd.emergency_address.clone(), "We are a stakeholder");
+        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = transaction_chain(
+            deposit_txin,
+            &deposit_descriptor,
+            &unvault_descriptor,
+            &cpfp_descriptor,
+            emer_address,
+            revaultd.xpub_ctx(),
+            revaultd.lock_time,
+            revaultd.unvault_csv,
+        )?;
+        Ok((unvault_tx, cancel_tx, Some(emer_tx), Some(unemer_tx)))
+    } else {
+        let (unvault_tx, cancel_tx) = transaction_chain_manager(
+            deposit_txin,
+            &deposit_descriptor,
+            &unvault_descriptor,
+            &cpfp_descriptor,
+            revaultd.xpub_ctx(),
+            revaultd.lock_time,
+            revaultd.unvault_csv,
+        )?;
+        Ok((unvault_tx, cancel_tx, None, None))
+    }
+}
+
+fn unsigned_txid(tx: &impl RevaultTransaction) -> Txid {
+    tx.inner_tx().global.unsigned_tx.txid()
+}
+
+/// Compare the presigned transactions stored for this (confirmed) vault to the ones we derive
+/// from it. Returns the mismatches, an empty list means the stored ones can be trusted.
+pub fn audit_vault(
+    revaultd: &RevaultD,
+    db_vault: &DbVault,
+) -> Result<Vec<PresignedMismatch>, AuditError> {
+    let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = derive_presigned_transactions(
+        revaultd,
+        &db_vault.deposit_outpoint,
+        db_vault.amount.as_sat(),
+        db_vault.derivation_index,
+    )?;
+    let mut expected = vec![
+        (TransactionType::Unvault, unsigned_txid(&unvault_tx)),
+        (TransactionType::Cancel, unsigned_txid(&cancel_tx)),
+    ];
+    if let (Some(emer_tx), Some(unemer_tx)) = (emer_tx, unemer_tx) {
+        expected.push((TransactionType::Emergency, unsigned_txid(&emer_tx)));
+        expected.push((TransactionType::UnvaultEmergency, unsigned_txid(&unemer_tx)));
+    }
+
+    let stored: Vec<(TransactionType, Txid)> =
+        db_presigned_transactions(&revaultd.db, db_vault.id)?
+            .into_iter()
+            .map(|db_tx| {
+                let txid = match db_tx.psbt {
+                    RevaultTx::Unvault(ref tx) => unsigned_txid(tx),
+                    RevaultTx::Cancel(ref tx) => unsigned_txid(tx),
+                    RevaultTx::Emergency(ref tx) => unsigned_txid(tx),
+                    RevaultTx::UnvaultEmergency(ref tx) => unsigned_txid(tx),
+                };
+                (db_tx.tx_type, txid)
+            })
+            .collect();
+
+    let mut mismatches = Vec::new();
+    for (tx_type, expected_txid) in expected.iter() {
+        let stored_txids: Vec<Txid> = stored
+            .iter()
+            .filter(|(stored_type, _)| stored_type == tx_type)
+            .map(|(_, txid)| *txid)
+            .collect();
+        if stored_txids.is_empty() {
+            mismatches.push(PresignedMismatch {
+                tx_type: *tx_type,
+                expected_txid: Some(*expected_txid),
+                stored_txid: None,
+            });
+        }
+        for stored_txid in stored_txids {
+            if stored_txid != *expected_txid {
+                mismatches.push(PresignedMismatch {
+                    tx_type: *tx_type,
+                    expected_txid: Some(*expected_txid),
+                    stored_txid: Some(stored_txid),
+                });
+            }
+        }
+    }
+    // We must not have stored anything we wouldn't derive
+    for (tx_type, stored_txid) in stored.iter() {
+        if !expected
+            .iter()
+            .any(|(expected_type, _)| expected_type == tx_type)
+        {
+            mismatches.push(PresignedMismatch {
+                tx_type: *tx_type,
+                expected_txid: None,
+                stored_txid: Some(*stored_txid),
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
@@ -0,0 +1,332 @@
+//! Encrypted backups of everything we can't recover from the chain: the database (and its signed
+//! revocation transactions!), the configuration and our Noise key.
+//!
+//! A backup file is made of a magic, then the salt we derived the encryption key from the user's
+//! passphrase with, the nonce, and the encrypted (JSON) content.
+
+use crate::{
+    database::{
+        interface::{db_network, db_version, db_wallet, DbConnection},
+        DatabaseError, DB_VERSION,
+    },
+    revaultd::{canonical_descriptor, create_datadir, RevaultD},
+};
+use common::config::{config_file_path, config_folder_path, Config, RevaultDescriptors};
+use revault_net::sodiumoxide::crypto::{pwhash::argon2id13, secretbox};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const BACKUP_MAGIC: &[u8] = b"revaultd-backup-v1\n";
+
+#[derive(Debug)]
+pub struct BackupError(String);
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Backup error: {}", self.0)
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+// What we encrypt. Binary data is base64-encoded.
+#[derive(Serialize, Deserialize)]
+struct BackupContent {
+    config: String,
+    noise_secret: String,
+    database: String,
+}
+
+// Create a file only we can read, refusing to overwrite an existing one.
+fn write_new_file(path: &Path, content: &[u8]) -> Result<(), io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options = options.write(true).create_new(true).clone();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options = options.mode(0o600).clone();
+    }
+
+    options.open(path)?.write_all(content)
+}
+
+fn derive_key(passphrase: &str, salt: &argon2id13::Salt) -> Result<secretbox::Key, BackupError> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    argon2id13::derive_key(
+        &mut key.0,
+        passphrase.as_bytes(),
+        salt,
+        argon2id13::OPSLIMIT_INTERACTIVE,
+        argon2id13::MEMLIMIT_INTERACTIVE,
+    )
+    .map_err(|_| BackupError("Deriving the encryption key from the passphrase".to_string()))?;
+
+    Ok(key)
+}
+
+fn encrypt(content: &BackupContent, passphrase: &str) -> Result<Vec<u8>, BackupError> {
+    let plaintext = serde_json::to_vec(content)
+        .map_err(|e| BackupError(format!("Serializing backup: {}", e)))?;
+    let salt = argon2id13::gen_salt();
+    let nonce = secretbox::gen_nonce();
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut backup = BACKUP_MAGIC.to_vec();
+    backup.extend_from_slice(&salt.0);
+    backup.extend_from_slice(&nonce.0);
+    backup.extend_from_slice(&secretbox::seal(&plaintext, &nonce, &key));
+    Ok(backup)
+}
+
+fn decrypt(backup: &[u8], passphrase: &str) -> Result<BackupContent, BackupError> {
+    if !backup.starts_with(BACKUP_MAGIC) {
+        return Err(BackupError("Not a revaultd backup file".to_string()));
+    }
+    let backup = &backup[BACKUP_MAGIC.len()..];
+    if backup.len() < argon2id13::SALTBYTES + secretbox::NONCEBYTES {
+        return Err(BackupError("Truncated backup file".to_string()));
+    }
+    let (salt, backup) = backup.split_at(argon2id13::SALTBYTES);
+    let (nonce, ciphertext) = backup.split_at(secretbox::NONCEBYTES);
+    let salt = argon2id13::Salt::from_slice(salt).expect("We checked the length");
+    let nonce = secretbox::Nonce::from_slice(nonce).expect("We checked the length");
+
+    let key = derive_key(passphrase, &salt)?;
+    let plaintext = secretbox::open(ciphertext, &nonce, &key).map_err(|_| {
+        BackupError("Could not decrypt backup: wrong passphrase or corrupted file".to_string())
+    })?;
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| BackupError(format!("Invalid backup content: {}", e)))
+}
+
+/// Write an encrypted backup of our database, configuration file and Noise key to `path`. The
+/// database is copied using SQLite's online backup, so it's consistent even if we are writing to
+/// it at the same time.
+pub fn create_backup(
+    revaultd: &RevaultD,
+    path: &Path,
+    passphrase: &str,
+) -> Result<(), BackupError> {
+    if passphrase.is_empty() {
+        return Err(BackupError("The passphrase must not be empty".to_string()));
+    }
+    if path.exists() {
+        return Err(BackupError(format!(
+            "'{}' already exists",
+            path.to_string_lossy()
+        )));
+    }
+
+    let config_file = revaultd
+        .config_file
+        .as_ref()
+        .ok_or_else(|| BackupError("Unknown configuration file path".to_string()))?;
+    let config = fs::read_to_string(config_file).map_err(|e| {
+        BackupError(format!(
+            "Reading configuration file '{}': {}",
+            config_file.to_string_lossy(),
+            e
+        ))
+    })?;
+
+    let mut db_copy = revaultd.db_file().into_os_string();
+    db_copy.push(".backup");
+    let db_copy = PathBuf::from(db_copy);
+    let database = revaultd.db.backup(&db_copy).and_then(|_| {
+        fs::read(&db_copy).map_err(|e| DatabaseError(format!("Reading copy: {}", e)))
+    });
+    fs::remove_file(&db_copy).unwrap_or_else(|e| {
+        log::error!(
+            "Removing database copy at '{}': {}",
+            db_copy.to_string_lossy(),
+            e
+        )
+    });
+    let database = database.map_err(|e| BackupError(e.to_string()))?;
+
+    let content = BackupContent {
+        config,
+        noise_secret: base64::encode(revaultd.noise_secret.as_ref()),
+        database: base64::encode(database),
+    };
+    write_new_file(path, &encrypt(&content, passphrase)?).map_err(|e| {
+        BackupError(format!(
+            "Writing backup file '{}': {}",
+            path.to_string_lossy(),
+            e
+        ))
+    })?;
+    log::info!("Wrote backup to '{}'", path.to_string_lossy());
+
+    Ok(())
+}
+
+// Would the two configurations track the same coins?
+fn check_same_deployment(ours: &Config, backup: &Config) -> Result<(), BackupError> {
+    if ours.bitcoind_config.network != backup.bitcoind_config.network {
+        return Err(BackupError(format!(
+            "Network mismatch: the backup is for '{}' but the configuration is for '{}'",
+            backup.bitcoind_config.network, ours.bitcoind_config.network
+        )));
+    }
+
+    let (ours, backup) = (
+        ours.scripts_config
+            .revault_descriptors()
+            .map_err(|e| BackupError(e.to_string()))?,
+        backup
+            .scripts_config
+            .revault_descriptors()
+            .map_err(|e| BackupError(e.to_string()))?,
+    );
+    // The keys' origins are only there if the descriptors were configured directly
+    let canonical = |descriptors: &RevaultDescriptors| {
+        [
+            canonical_descriptor(&descriptors.deposit_descriptor.0.to_string()),
+            canonical_descriptor(&descriptors.unvault_descriptor.0.to_string()),
+            canonical_descriptor(&descriptors.cpfp_descriptor.0.to_string()),
+        ]
+    };
+    if canonical(&ours) != canonical(&backup) {
+        return Err(BackupError(
+            "Descriptors mismatch: the backup is for another deployment".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Is this database one we can use with this configuration?
+fn check_restored_db(db: &DbConnection, revaultd: &RevaultD) -> Result<(), DatabaseError> {
+    let version = db_version(db)?;
+    if version > DB_VERSION {
+        return Err(DatabaseError(format!(
+            "Unexpected database version: got '{}', expected '{}'",
+            version, DB_VERSION
+        )));
+    }
+
+    let network = db_network(db)?;
+    if network != revaultd.bitcoind_config.network {
+        return Err(DatabaseError(format!(
+            "Database is on '{}' but config says '{}'",
+            network, revaultd.bitcoind_config.network
+        )));
+    }
+
+    let wallet = db_wallet(db)?;
+    if canonical_descriptor(&wallet.deposit_descriptor)
+        != canonical_descriptor(&revaultd.deposit_descriptor.0.to_string())
+        || canonical_descriptor(&wallet.unvault_descriptor)
+            != canonical_descriptor(&revaultd.unvault_descriptor.0.to_string())
+    {
+        return Err(DatabaseError(
+            "Descriptors mismatch between the database and the config".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Not echoed if read from a terminal
+fn read_passphrase() -> Result<String, BackupError> {
+    rpassword::prompt_password_stdout("Backup passphrase: ")
+        .map_err(|e| BackupError(format!("Reading the passphrase: {}", e)))
+}
+
+/// Install the backup at `backup_file` for the configuration at `conf_file` (the default one if
+/// None). If there is no configuration file yet, the backed up one is installed, otherwise it
+/// must be for the same deployment. We never overwrite an existing database.
+pub fn restore(
+    backup_file: &Path,
+    conf_file: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let backup = fs::read(backup_file).map_err(|e| {
+        BackupError(format!(
+            "Reading backup file '{}': {}",
+            backup_file.to_string_lossy(),
+            e
+        ))
+    })?;
+    let content = decrypt(&backup, &read_passphrase()?)?;
+    let backup_config = Config::from_toml(content.config.as_bytes())?;
+    let noise_secret = base64::decode(&content.noise_secret)
+        .map_err(|e| BackupError(format!("Invalid Noise key in backup: {}", e)))?;
+    let database = base64::decode(&content.database)
+        .map_err(|e| BackupError(format!("Invalid database in backup: {}", e)))?;
+
+    let conf_file = match conf_file {
+        Some(path) => path,
+        None => config_file_path()?,
+    };
+    let config = if conf_file.exists() {
+        let config = Config::from_file(Some(conf_file.clone()))?;
+        check_same_deployment(&config, &backup_config)?;
+        config
+    } else {
+        if let Some(parent) = conf_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_new_file(&conf_file, content.config.as_bytes()).map_err(|e| {
+            BackupError(format!(
+                "Writing configuration file '{}': {}",
+                conf_file.to_string_lossy(),
+                e
+            ))
+        })?;
+        println!(
+            "Wrote configuration file at '{}'.",
+            conf_file.to_string_lossy()
+        );
+        backup_config
+    };
+
+    // Install the Noise key before creating our state, or a fresh one would be generated.
+    let mut data_dir = match config.data_dir {
+        Some(ref data_dir) => data_dir.clone(),
+        None => config_folder_path()?,
+    };
+    data_dir.push(config.bitcoind_config.network.to_string());
+    create_datadir(&data_dir)?;
+    let noise_file = data_dir.join("noise_secret");
+    if noise_file.exists() {
+        if fs::read(&noise_file)? != noise_secret {
+            return Err(Box::from(BackupError(format!(
+                "A different Noise key already exists at '{}'",
+                noise_file.to_string_lossy()
+            ))));
+        }
+    } else {
+        write_new_file(&noise_file, &noise_secret)?;
+    }
+
+    let revaultd = RevaultD::from_config(config)?;
+    let db_file = revaultd.db_file();
+    if db_file.exists() {
+        return Err(Box::from(BackupError(format!(
+            "Refusing to overwrite the database at '{}'",
+            db_file.to_string_lossy()
+        ))));
+    }
+    // Only install it once we checked it
+    let mut restored_file = db_file.clone().into_os_string();
+    restored_file.push(".restore");
+    let restored_file = PathBuf::from(restored_file);
+    write_new_file(&restored_file, &database)?;
+    if let Err(e) = check_restored_db(&DbConnection::new(restored_file.clone()), &revaultd) {
+        fs::remove_file(&restored_file)?;
+        return Err(Box::from(BackupError(format!(
+            "Invalid database in backup: {}",
+            e
+        ))));
+    }
+    fs::rename(&restored_file, &db_file)?;
+
+    println!("Restored database at '{}'.", db_file.to_string_lossy());
+    Ok(())
+}
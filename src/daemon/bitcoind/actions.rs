@@ -1,36 +1,44 @@
 use crate::{
+    audit::derive_presigned_transactions,
     bitcoind::{
+        consistency::check_consistency,
         interface::{BitcoinD, DepositInfo, SyncInfo},
-        BitcoindError, MIN_CONF,
+        notifications::{start_notifications_listener, Notification, NOTIFICATIONS_QUEUE_SIZE},
+        spends::{SpendKind, VaultSpend, VaultSpends},
+        BitcoindError,
     },
     database::{
         actions::{
-            db_confirm_deposit, db_insert_new_unconfirmed_vault, db_unconfirm_deposit_dbtx,
-            db_unvault_deposit, db_update_deposit_index, db_update_tip, db_update_tip_dbtx,
+            db_confirm_deposit, db_insert_new_unconfirmed_vault, db_store_onchain_tx,
+            db_unconfirm_deposit_dbtx, db_update_deposit_index, db_update_spends_last_block,
+            db_update_tip, db_update_tip_dbtx, db_update_vault_status, derive_addresses,
         },
-        interface::{db_deposits, db_exec, db_tip, db_vaults_dbtx, db_wallet},
+        interface::{db_deposits, db_exec, db_tip, db_vault_by_deposit, db_vaults_dbtx, db_wallet},
+        schema::OnchainTransactionType,
     },
-    revaultd::{RevaultD, VaultStatus},
-    threadmessages::{BitcoindMessageOut, WalletTransaction},
+    revaultd::{RevaultD, VaultStatus, HARDENED_INDEX},
+    statemachine::check_transition,
+    threadmessages::{BitcoindMessageOut, Inconsistency, WalletTransaction},
 };
 use common::{assume_ok, assume_some, config::BitcoindConfig};
 use revault_tx::{
-    bitcoin::{Amount, Network, OutPoint, TxOut, Txid},
+    bitcoin::{
+        consensus::encode, hashes::hex::FromHex, util::bip32::ChildNumber, Amount, Network,
+        OutPoint, Transaction, TxOut, Txid,
+    },
     transactions::{
-        transaction_chain, transaction_chain_manager, CancelTransaction, EmergencyTransaction,
-        UnvaultEmergencyTransaction, UnvaultTransaction,
+        CancelTransaction, EmergencyTransaction, UnvaultEmergencyTransaction, UnvaultTransaction,
     },
-    txins::DepositTxIn,
-    txouts::DepositTxOut,
 };
 
 use std::{
     collections::HashMap,
+    ops::Range,
     path::PathBuf,
     process,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::Receiver,
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError},
         Arc, RwLock,
     },
     thread,
@@ -152,7 +160,7 @@ fn bitcoind_sync_status(
 
 // This creates the actual wallet file, and imports the descriptors
 fn maybe_create_wallet(revaultd: &mut RevaultD, bitcoind: &BitcoinD) -> Result<(), BitcoindError> {
-    let wallet = db_wallet(&revaultd.db_file())?;
+    let wallet = db_wallet(&revaultd.db)?;
     let bitcoind_wallet_path = revaultd
         .watchonly_wallet_file()
         .expect("Wallet id is set at startup in setup_db()");
@@ -168,33 +176,77 @@ fn maybe_create_wallet(revaultd: &mut RevaultD, bitcoind: &BitcoinD) -> Result<(
     if !PathBuf::from(bitcoind_wallet_path.clone()).exists() {
         bitcoind.createwallet_startup(bitcoind_wallet_path)?;
         log::info!("Importing descriptors to bitcoind watchonly wallet.");
+        import_descriptors(revaultd, bitcoind, wallet.timestamp, fresh_wallet)?;
+    }
+
+    Ok(())
+}
+
+// Import the deposit and unvault addresses up to the gap limit to bitcoind's watchonly wallet.
+fn import_descriptors(
+    revaultd: &mut RevaultD,
+    bitcoind: &BitcoinD,
+    timestamp: u32,
+    fresh_wallet: bool,
+) -> Result<(), BitcoindError> {
+    // In theory, we could just import the vault (deposit) descriptor expressed using xpubs, give a
+    // range to bitcoind as the gap limit, and be fine.
+    // Unfortunately we cannot just import descriptors as is, since bitcoind does not support
+    // Miniscript ones yet. Worse, we actually need to derive them to pass them to bitcoind since
+    // the vault one (which we are interested about) won't be expressed with a `multi()` statement (
+    // currently supported by bitcoind) if there are more than 15 stakeholders.
+    // Therefore, we derive [max index] `addr()` descriptors to import into bitcoind, and handle
+    // the derivation index mess ourselves :'(
+    let mut addresses = revaultd.all_deposit_addresses();
+    for i in 0..addresses.len() {
+        addresses[i] = bitcoind.addr_descriptor(&addresses[i])?;
+    }
+    log::trace!("Importing deposit descriptors '{:?}'", &addresses);
+    bitcoind.startup_import_deposit_descriptors(addresses, timestamp, fresh_wallet)?;
+
+    // As a consequence, we don't have enough information to opportunistically import a
+    // descriptor at the reception of a deposit anymore. Thus we need to blindly import *both*
+    // deposit and unvault descriptors..
+    // FIXME: maybe we actually have, with the derivation_index_map ?
+    let mut addresses = revaultd.all_unvault_addresses();
+    for i in 0..addresses.len() {
+        addresses[i] = bitcoind.addr_descriptor(&addresses[i])?;
+    }
+    log::trace!("Importing unvault descriptors '{:?}'", &addresses);
+    bitcoind.startup_import_unvault_descriptors(addresses, timestamp, fresh_wallet)?;
+
+    Ok(())
+}
 
-        // Now, import descriptors.
-        // In theory, we could just import the vault (deposit) descriptor expressed using xpubs, give a
-        // range to bitcoind as the gap limit, and be fine.
-        // Unfortunately we cannot just import descriptors as is, since bitcoind does not support
-        // Miniscript ones yet. Worse, we actually need to derive them to pass them to bitcoind since
-        // the vault one (which we are interested about) won't be expressed with a `multi()` statement (
-        // currently supported by bitcoind) if there are more than 15 stakeholders.
-        // Therefore, we derive [max index] `addr()` descriptors to import into bitcoind, and handle
-        // the derivation index mess ourselves :'(
-        let mut addresses = revaultd.all_deposit_addresses();
-        for i in 0..addresses.len() {
-            addresses[i] = bitcoind.addr_descriptor(&addresses[i])?;
-        }
-        log::trace!("Importing deposit descriptors '{:?}'", &addresses);
-        bitcoind.startup_import_deposit_descriptors(addresses, wallet.timestamp, fresh_wallet)?;
-
-        // As a consequence, we don't have enough information to opportunistically import a
-        // descriptor at the reception of a deposit anymore. Thus we need to blindly import *both*
-        // deposit and unvault descriptors..
-        // FIXME: maybe we actually have, with the derivation_index_map ?
-        let mut addresses = revaultd.all_unvault_addresses();
-        for i in 0..addresses.len() {
-            addresses[i] = bitcoind.addr_descriptor(&addresses[i])?;
-        }
-        log::trace!("Importing unvault descriptors '{:?}'", &addresses);
-        bitcoind.startup_import_unvault_descriptors(addresses, wallet.timestamp, fresh_wallet)?;
+// The gap limit may have been increased since the watchonly wallet was created, in which case
+// it doesn't watch the last addresses yet. Import them, rescanning since the wallet creation as
+// they may have been used by another participant already. The import would rescan for as long as
+// the whole chain since then, which our requests would time out waiting for: we import them
+// without a rescan and rescan by height, which tolerates it.
+fn maybe_extend_gap_limit(
+    revaultd: &mut RevaultD,
+    bitcoind: &BitcoinD,
+) -> Result<(), BitcoindError> {
+    // The watched range was checked not to reach hardened indexes when loading it.
+    let raw_index: u32 = revaultd.current_unused_index.into();
+    let last_address =
+        revaultd.vault_address(ChildNumber::from(raw_index + revaultd.gap_limit - 1));
+    if bitcoind.is_watched(&last_address.to_string())? {
+        return Ok(());
+    }
+
+    log::info!(
+        "Importing the addresses up to the new gap limit ({}) to bitcoind watchonly wallet. \
+         Rescan may take some time.",
+        revaultd.gap_limit
+    );
+    let wallet = db_wallet(&revaultd.db)?;
+    import_descriptors(revaultd, bitcoind, wallet.timestamp, true)?;
+    // No block since the wallet creation, nothing to rescan.
+    if let Some(height) =
+        bitcoind.first_block_since(wallet.timestamp.saturating_sub(TIMESTAMP_WINDOW))?
+    {
+        bitcoind.rescan_blockchain(height)?;
     }
 
     Ok(())
@@ -248,6 +300,7 @@ fn comprehensive_rescan(
     db_tx: &rusqlite::Transaction,
     bitcoind: &BitcoinD,
     deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    min_conf: u32,
 ) -> Result<(), BitcoindError> {
     log::info!("Starting rescan of all vaults in db..");
     let mut vaults = db_vaults_dbtx(&db_tx)?;
@@ -274,23 +327,25 @@ fn comprehensive_rescan(
         }
 
         // bitcoind's wallet will always keep track of our transaction, even in case of reorg.
-        let (_, blockheight, _) = bitcoind.get_wallet_transaction(&vault.deposit_outpoint.txid)?;
+        let blockheight = bitcoind
+            .get_wallet_transaction(&vault.deposit_outpoint.txid)?
+            .blockheight;
         if let Some(height) = blockheight {
             // Edge case: what if our tip is actually not up to date anymore
             if height > tip.height {
-                return comprehensive_rescan(db_tx, bitcoind, deposits_cache);
+                return comprehensive_rescan(db_tx, bitcoind, deposits_cache, min_conf);
             }
 
             let deposit_conf = tip.height.checked_sub(height).expect("Checked above") + 1;
-            if deposit_conf < MIN_CONF as u32 {
+            if deposit_conf < min_conf {
                 log::warn!(
                     "Vault deposit '{}' ended up with '{}' confirmations (<{}), \
                      marking as unconfirmed",
                     vault.deposit_outpoint,
                     deposit_conf,
-                    MIN_CONF,
+                    min_conf,
                 );
-                db_unconfirm_deposit_dbtx(db_tx, vault.id)?;
+                db_unconfirm_deposit_dbtx(db_tx, vault.id, &vault.deposit_outpoint.txid)?;
                 assume_some!(
                     deposits_cache.get_mut(&vault.deposit_outpoint),
                     "Db vault not in cache?"
@@ -305,7 +360,7 @@ fn comprehensive_rescan(
                 "Vault deposit '{}' still has '{}' confirmations (>={}), not doing anything",
                 vault.deposit_outpoint,
                 deposit_conf,
-                MIN_CONF
+                min_conf
             );
         } else {
             log::warn!(
@@ -313,7 +368,7 @@ fn comprehensive_rescan(
                  unconfirmed",
                 vault.deposit_outpoint
             );
-            db_unconfirm_deposit_dbtx(db_tx, vault.id)?;
+            db_unconfirm_deposit_dbtx(db_tx, vault.id, &vault.deposit_outpoint.txid)?;
             assume_some!(
                 deposits_cache.get_mut(&vault.deposit_outpoint),
                 "Db vault not in cache?"
@@ -341,7 +396,7 @@ fn update_tip(
     bitcoind: &BitcoinD,
     deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
 ) -> Result<(), BitcoindError> {
-    let current_tip = db_tip(&revaultd.read().unwrap().db_file())?;
+    let current_tip = db_tip(&revaultd.read().unwrap().db)?;
     let tip = bitcoind.get_tip()?;
 
     // Nothing changed, shortcut.
@@ -354,7 +409,7 @@ fn update_tip(
         let bit_curr_hash = bitcoind.getblockhash(current_tip.height)?;
         if bit_curr_hash == current_tip.hash || current_tip.height == 0 {
             // We moved forward, everything is fine.
-            db_update_tip(&revaultd.read().unwrap().db_file(), &tip)?;
+            db_update_tip(&revaultd.read().unwrap().db, &tip)?;
             return Ok(());
         }
     }
@@ -364,8 +419,9 @@ fn update_tip(
         &current_tip,
         &tip
     );
-    db_exec(&revaultd.read().unwrap().db_file(), |db_tx| {
-        comprehensive_rescan(db_tx, bitcoind, deposits_cache).unwrap_or_else(|e| {
+    let min_conf = revaultd.read().unwrap().min_conf;
+    db_exec(&revaultd.read().unwrap().db, |db_tx| {
+        comprehensive_rescan(db_tx, bitcoind, deposits_cache, min_conf).unwrap_or_else(|e| {
             log::error!("Error while rescaning vaults: '{}'", e);
             std::process::exit(1);
         });
@@ -376,6 +432,48 @@ fn update_tip(
     Ok(())
 }
 
+pub fn decode_wallet_tx(
+    txid: &Txid,
+    wallet_tx: &WalletTransaction,
+) -> Result<Transaction, BitcoindError> {
+    let bytes = Vec::<u8>::from_hex(&wallet_tx.hex).map_err(|e| {
+        BitcoindError::Custom(format!("Invalid transaction hex for '{}': {}", txid, e))
+    })?;
+    encode::deserialize(&bytes)
+        .map_err(|e| BitcoindError::Custom(format!("Invalid transaction for '{}': {}", txid, e)))
+}
+
+// Keep a copy of this transaction of the vault at this deposit outpoint, so that we don't depend
+// on bitcoind's wallet to serve it afterward. If we already have it, update its confirmation.
+pub fn store_onchain_tx(
+    revaultd: &RevaultD,
+    deposit_outpoint: &OutPoint,
+    tx_type: OnchainTransactionType,
+    txid: &Txid,
+    wallet_tx: &WalletTransaction,
+) -> Result<(), BitcoindError> {
+    let tx = decode_wallet_tx(txid, wallet_tx)?;
+    let vault_id = db_vault_by_deposit(&revaultd.db, deposit_outpoint)?
+        .ok_or_else(|| {
+            BitcoindError::Custom(format!(
+                "Storing a transaction for an unknown vault at '{}'",
+                deposit_outpoint
+            ))
+        })?
+        .id;
+    db_store_onchain_tx(
+        &revaultd.db,
+        vault_id,
+        tx_type,
+        &tx,
+        wallet_tx.blockheight,
+        wallet_tx.blocktime,
+        wallet_tx.received_time,
+    )?;
+
+    Ok(())
+}
+
 // Get fresh to-be-presigned transactions for this deposit utxo
 fn presigned_transactions(
     revaultd: &RevaultD,
@@ -390,55 +488,26 @@ fn presigned_transactions(
     ),
     BitcoindError,
 > {
-    // We use the same derivation index for all descriptors.
     let derivation_index = *revaultd
         .derivation_index_map
         .get(&utxo.txo.script_pubkey)
         .ok_or_else(|| {
             BitcoindError::Custom(format!("Unknown derivation index for: {:#?}", &utxo))
         })?;
-    let deposit_descriptor = revaultd.deposit_descriptor.derive(derivation_index);
-    let unvault_descriptor = revaultd.unvault_descriptor.derive(derivation_index);
-    let cpfp_descriptor = revaultd.cpfp_descriptor.derive(derivation_index);
-
-    // Reconstruct the deposit UTXO and derive all pre-signed transactions out of it
-    // if we are a stakeholder, and only the Unvault and the Cancel if we are a manager.
-    let deposit_txin = DepositTxIn::new(
-        *outpoint,
-        DepositTxOut::new(utxo.txo.value, &deposit_descriptor, revaultd.xpub_ctx()),
-    );
-    if revaultd.is_stakeholder() {
-        let emer_address = assume_some!(revaultd.emergency_address.clone(), "We are a stakeholder");
-        let (unvault_tx, cancel_tx, emer_tx, unemer_tx) = transaction_chain(
-            deposit_txin,
-            &deposit_descriptor,
-            &unvault_descriptor,
-            &cpfp_descriptor,
-            emer_address,
-            revaultd.xpub_ctx(),
-            revaultd.lock_time,
-            revaultd.unvault_csv,
-        )?;
-        Ok((unvault_tx, cancel_tx, Some(emer_tx), Some(unemer_tx)))
-    } else {
-        let (unvault_tx, cancel_tx) = transaction_chain_manager(
-            deposit_txin,
-            &deposit_descriptor,
-            &unvault_descriptor,
-            &cpfp_descriptor,
-            revaultd.xpub_ctx(),
-            revaultd.lock_time,
-            revaultd.unvault_csv,
-        )?;
-        Ok((unvault_tx, cancel_tx, None, None))
-    }
+
+    Ok(derive_presigned_transactions(
+        revaultd,
+        outpoint,
+        utxo.txo.value,
+        derivation_index,
+    )?)
 }
 
 // Fill up the deposit UTXOs cache from db vaults
 fn populate_deposit_cache(
     revaultd: &RevaultD,
 ) -> Result<HashMap<OutPoint, DepositInfo>, BitcoindError> {
-    let db_vaults = db_deposits(&revaultd.db_file())?;
+    let db_vaults = db_deposits(&revaultd.db)?;
     let mut cache = HashMap::with_capacity(db_vaults.len());
 
     for db_vault in db_vaults.into_iter() {
@@ -462,14 +531,113 @@ fn populate_deposit_cache(
     Ok(cache)
 }
 
+// Whether the vault at this deposit outpoint may go to this status. If the state machine refuses
+// it, we misunderstood what happened onchain: we log it and leave this vault alone rather than
+// taking the poller down.
+fn can_transition(
+    revaultd: &RevaultD,
+    outpoint: &OutPoint,
+    new_status: VaultStatus,
+) -> Result<bool, BitcoindError> {
+    let db_vault = db_vault_by_deposit(&revaultd.db, outpoint)?
+        .ok_or_else(|| BitcoindError::Custom(format!("Unknown vault at '{}'", outpoint)))?;
+    if let Err(e) = check_transition(db_vault.status, new_status) {
+        log::error!("Not updating the vault at '{}': {}", outpoint, e);
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+// The vault at this deposit outpoint was spent by this transaction: move it to this status if the
+// state machine agrees, and keep a copy of the transaction. Returns whether we did.
+fn mark_vault_spent(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    deposit_outpoint: &OutPoint,
+    txid: &Txid,
+    new_status: VaultStatus,
+    tx_type: OnchainTransactionType,
+) -> Result<bool, BitcoindError> {
+    if !can_transition(revaultd, deposit_outpoint, new_status)? {
+        return Ok(false);
+    }
+
+    // Note that it *might* have actually been confirmed during the last 30s, but it's not a big
+    // deal to have it marked as unconfirmed for the next 30s..
+    db_update_vault_status(&revaultd.db, deposit_outpoint, new_status, txid)?;
+    store_onchain_tx(
+        revaultd,
+        deposit_outpoint,
+        tx_type,
+        txid,
+        &bitcoind.get_wallet_transaction(txid)?,
+    )?;
+    if let Some(utxo) = deposits_cache.get_mut(deposit_outpoint) {
+        utxo.status = new_status;
+    }
+
+    Ok(true)
+}
+
+// The Unvault output of this vault was spent by a Spend transaction. We keep a copy of it in any
+// case, but we don't track the 'unvaulted' and 'spendable' statuses yet, and a Spend transaction
+// is only valid once the Unvault is 'spendable': we only mark the vault as 'spending' if the
+// state machine allows it.
+fn mark_vault_spending(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    spend: &VaultSpend,
+) -> Result<(), BitcoindError> {
+    store_onchain_tx(
+        revaultd,
+        &spend.deposit_outpoint,
+        OnchainTransactionType::Spend,
+        &spend.txid,
+        &bitcoind.get_wallet_transaction(&spend.txid)?,
+    )?;
+
+    let db_vault =
+        db_vault_by_deposit(&revaultd.db, &spend.deposit_outpoint)?.ok_or_else(|| {
+            BitcoindError::Custom(format!("Unknown vault at '{}'", spend.deposit_outpoint))
+        })?;
+    if check_transition(db_vault.status, VaultStatus::Spending).is_ok() {
+        db_update_vault_status(
+            &revaultd.db,
+            &spend.deposit_outpoint,
+            VaultStatus::Spending,
+            &spend.txid,
+        )?;
+        log::debug!(
+            "The vault at '{}' is being spent by the Spend transaction '{}'",
+            &spend.deposit_outpoint,
+            &spend.txid
+        );
+    } else {
+        log::info!(
+            "The Unvault output of the vault at '{}' ('{}') was spent by the Spend transaction \
+             '{}'",
+            &spend.deposit_outpoint,
+            db_vault.status,
+            &spend.txid
+        );
+    }
+
+    Ok(())
+}
+
 // This syncs with bitcoind our incoming deposits, and those that were spent.
 fn update_deposits(
     revaultd: &mut Arc<RwLock<RevaultD>>,
     bitcoind: &BitcoinD,
     deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    spends: &mut VaultSpends,
+    mut queued_imports: Option<&mut Vec<QueuedImport>>,
 ) -> Result<(), BitcoindError> {
     // Sync deposit of vaults we know have an unspent deposit.
-    let (new_deposits, conf_deposits, spent_deposits) = bitcoind.sync_deposits(&deposits_cache)?;
+    let (new_deposits, conf_deposits, spent_deposits) =
+        bitcoind.sync_deposits(&deposits_cache, revaultd.read().unwrap().min_conf)?;
 
     for (outpoint, utxo) in new_deposits.into_iter() {
         let derivation_index = *revaultd
@@ -481,11 +649,11 @@ fn update_deposits(
                 BitcoindError::Custom(format!("Unknown derivation index for: {:#?}", &utxo))
             })?;
 
-        // Note that the deposit *might* have already MIN_CONF confirmations, that's fine. We'll
+        // Note that the deposit *might* have already enough confirmations, that's fine. We'll
         // confim it during the next poll.
         let amount = Amount::from_sat(utxo.txo.value);
         db_insert_new_unconfirmed_vault(
-            &revaultd.read().unwrap().db_file(),
+            &revaultd.read().unwrap().db,
             revaultd
                 .read()
                 .unwrap()
@@ -496,6 +664,13 @@ fn update_deposits(
             &amount,
             derivation_index,
         )?;
+        store_onchain_tx(
+            &revaultd.read().unwrap(),
+            &outpoint,
+            OnchainTransactionType::Deposit,
+            &outpoint.txid,
+            &bitcoind.get_wallet_transaction(&outpoint.txid)?,
+        )?;
         log::debug!(
             "Got a new unconfirmed deposit at {} for {} ({})",
             &outpoint,
@@ -505,46 +680,35 @@ fn update_deposits(
         deposits_cache.insert(outpoint, utxo);
 
         // Mind the gap! https://www.youtube.com/watch?v=UOPyGKDQuRk
-        // FIXME: of course, that's rudimentary
-        let current_first_index = revaultd.read().unwrap().current_unused_index;
-        if derivation_index >= current_first_index {
-            let new_index = revaultd
-                .read()
-                .unwrap()
-                .current_unused_index
-                .increment()
-                .map_err(|e| {
-                    // FIXME: we should probably go back to 0 at this point.
-                    BitcoindError::Custom(format!("Deriving next index: {}", e))
-                })?;
-            db_update_deposit_index(&revaultd.read().unwrap().db_file(), new_index)?;
-            revaultd.write().unwrap().current_unused_index = new_index;
-            let next_addr = bitcoind
-                .addr_descriptor(&revaultd.read().unwrap().last_deposit_address().to_string())?;
-            bitcoind.import_fresh_deposit_descriptor(next_addr)?;
-            let next_addr = bitcoind
-                .addr_descriptor(&revaultd.read().unwrap().last_unvault_address().to_string())?;
-            bitcoind.import_fresh_unvault_descriptor(next_addr)?;
-
-            log::debug!(
-                "Incremented deposit derivation index from {}",
-                current_first_index
+        // The vault is in, don't stop the poller over it: the addresses may be imported again with
+        // the next deposit, or with 'rescanindexes'.
+        if let Err(e) = mark_index_used(
+            revaultd,
+            bitcoind,
+            derivation_index,
+            queued_imports.as_deref_mut(),
+        ) {
+            log::error!(
+                "Error importing the addresses past derivation index {}: '{}'",
+                derivation_index,
+                e
             );
         }
     }
 
     for (outpoint, utxo) in conf_deposits.into_iter() {
-        let blockheight = bitcoind
-            .get_wallet_transaction(&outpoint.txid)?
-            .1
-            .ok_or_else(|| {
-                BitcoindError::Custom("Deposit transaction isn't confirmed!".to_string())
-            })?;
+        if !can_transition(&revaultd.read().unwrap(), &outpoint, VaultStatus::Funded)? {
+            continue;
+        }
+        let deposit_tx = bitcoind.get_wallet_transaction(&outpoint.txid)?;
+        let blockheight = deposit_tx.blockheight.ok_or_else(|| {
+            BitcoindError::Custom("Deposit transaction isn't confirmed!".to_string())
+        })?;
         // emer_tx and unemer_tx are None for managers
         let (unvault_tx, cancel_tx, emer_tx, unemer_tx) =
             presigned_transactions(&revaultd.read().unwrap(), &outpoint, &utxo)?;
         db_confirm_deposit(
-            &revaultd.read().unwrap().db_file(),
+            &revaultd.read().unwrap().db,
             &outpoint,
             blockheight,
             &unvault_tx,
@@ -552,6 +716,13 @@ fn update_deposits(
             emer_tx.as_ref(),
             unemer_tx.as_ref(),
         )?;
+        store_onchain_tx(
+            &revaultd.read().unwrap(),
+            &outpoint,
+            OnchainTransactionType::Deposit,
+            &outpoint.txid,
+            &deposit_tx,
+        )?;
         deposits_cache
             .get_mut(&outpoint)
             .ok_or_else(|| BitcoindError::Custom("An unknown vault got confirmed?".to_string()))?
@@ -560,37 +731,68 @@ fn update_deposits(
         log::debug!("Vault at {} is now confirmed", &outpoint);
     }
 
+    // Find out what spent the deposits, and the Unvault outputs.
+    spends.update(&revaultd.read().unwrap(), bitcoind)?;
+    let new_spends = spends.take_new_spends();
+
     for (outpoint, utxo) in spent_deposits.into_iter() {
-        let deriv_index = *revaultd.read().unwrap()
-            .derivation_index_map
-            .get(&utxo.txo.script_pubkey)
-            .ok_or_else(|| BitcoindError::Custom(
-                    format!("A deposit was spent for which we don't know the corresponding xpub derivation. Outpoint: '{}'\nUtxo: '{:#?}'",
+        // We already applied the spend of its deposit, and track the vault from there.
+        if utxo.status as u32 > VaultStatus::Active as u32 {
+            continue;
+        }
+
+        match spends.deposit_spend(&outpoint) {
+            Some(VaultSpend {
+                kind: SpendKind::Unvault,
+                txid: unvault_txid,
+                ..
+            }) => {
+                if mark_vault_spent(
+                    &revaultd.read().unwrap(),
+                    bitcoind,
+                    deposits_cache,
+                    &outpoint,
+                    unvault_txid,
+                    VaultStatus::Unvaulting,
+                    OnchainTransactionType::Unvault,
+                )? {
+                    log::debug!(
+                        "The deposit utxo created via '{}' was unvaulted via '{}'",
                         &outpoint,
-                        &utxo)))?;
-        let unvault_addr = revaultd
-            .read()
-            .unwrap()
-            .unvault_address(deriv_index)
-            .to_string();
-
-        if let Some(unvault_outpoint) = bitcoind.unvault_from_vault(&outpoint, unvault_addr)? {
-            // Note that it *might* have actually been confirmed during the last 30s, but it's not
-            // a big deal to have it marked as unconfirmed for the next 30s..
-            db_unvault_deposit(&revaultd.read().unwrap().db_file(), &outpoint)?;
-            deposits_cache
-                .get_mut(&outpoint)
-                .expect("We just checked it")
-                .status = VaultStatus::Unvaulting;
-            log::debug!(
-                "The deposit utxo created via '{}' was unvaulted via '{}'",
+                        unvault_txid
+                    );
+                }
+            }
+            Some(VaultSpend {
+                kind: SpendKind::Emergency,
+                txid: emer_txid,
+                ..
+            }) => {
+                if mark_vault_spent(
+                    &revaultd.read().unwrap(),
+                    bitcoind,
+                    deposits_cache,
+                    &outpoint,
+                    emer_txid,
+                    VaultStatus::EmergencyVaulting,
+                    OnchainTransactionType::Emergency,
+                )? {
+                    log::warn!(
+                        "The deposit utxo created via '{}' was spent by the Emergency \
+                         transaction '{}'",
+                        &outpoint,
+                        emer_txid
+                    );
+                }
+            }
+            // There is no status for a vault whose deposit was spent by anything else.
+            Some(spend) => log::warn!(
+                "The deposit utxo created via '{}' was spent by a {} transaction '{}'",
                 &outpoint,
-                &unvault_outpoint
-            );
-        } else if false {
-            // TODO: handle bypass and emergency
-        } else {
-            match utxo.status {
+                spend.kind,
+                &spend.txid
+            ),
+            None => match utxo.status {
                 // Fine.
                 VaultStatus::Unconfirmed => log::debug!(
                     "The unconfirmed deposit utxo created via '{}' just vanished",
@@ -601,36 +803,720 @@ fn update_deposits(
                     "The deposit utxo created via '{}' just vanished. Maybe a reorg is ongoing?",
                     &outpoint
                 ),
-                // Impossible.
-                _ => unreachable!(),
+                // The transaction spending it was reorged out, or left the mempool. We'll see
+                // it again if it gets mined.
+                _ => log::warn!(
+                    "The deposit utxo created via '{}' is '{}' but we don't know about the \
+                     transaction spending it anymore",
+                    &outpoint,
+                    utxo.status
+                ),
+            },
+        }
+    }
+
+    // Now that the Unvaults are applied, the spends of their outputs.
+    for spend in new_spends.iter() {
+        let (new_status, tx_type) = match spend.kind {
+            SpendKind::Cancel => (VaultStatus::Canceling, OnchainTransactionType::Cancel),
+            SpendKind::UnvaultEmergency => (
+                VaultStatus::UnvaultEmergencyVaulting,
+                OnchainTransactionType::UnvaultEmergency,
+            ),
+            SpendKind::Spend => {
+                mark_vault_spending(&revaultd.read().unwrap(), bitcoind, spend)?;
+                continue;
             }
+            // The deposit spends were applied above.
+            SpendKind::Unvault | SpendKind::Emergency | SpendKind::Bypass => continue,
+        };
+        if mark_vault_spent(
+            &revaultd.read().unwrap(),
+            bitcoind,
+            deposits_cache,
+            &spend.deposit_outpoint,
+            &spend.txid,
+            new_status,
+            tx_type,
+        )? {
+            log::debug!(
+                "The Unvault output of the vault at '{}' was spent by the {} transaction '{}'",
+                &spend.deposit_outpoint,
+                spend.kind,
+                &spend.txid
+            );
         }
     }
 
     // TODO: keep track of unconfirmed unvaults and eventually mark them as confirmed
 
+    // We went through the spends up to there, don't do it again after a restart.
+    if let Some(last_block) = spends.last_block() {
+        db_update_spends_last_block(&revaultd.read().unwrap().db, last_block)?;
+    }
+
+    Ok(())
+}
+
+// Import the deposit and Unvault addresses for this range of derivation indexes, without a
+// rescan.
+fn import_index_range(
+    revaultd: &mut RevaultD,
+    bitcoind: &BitcoinD,
+    range: Range<u32>,
+) -> Result<(), BitcoindError> {
+    let descriptors = index_range_descriptors(revaultd, bitcoind, range)?;
+    import_range_descriptors(bitcoind, descriptors)
+}
+
+// The deposit and Unvault descriptors to import for this range of derivation indexes, deriving
+// (and storing) the addresses we don't know yet.
+fn index_range_descriptors(
+    revaultd: &mut RevaultD,
+    bitcoind: &BitcoinD,
+    range: Range<u32>,
+) -> Result<(Vec<String>, Vec<String>), BitcoindError> {
+    if range.end > HARDENED_INDEX {
+        return Err(BitcoindError::Custom(format!(
+            "Can't import the addresses at derivation index {}, it's hardened",
+            range.end - 1
+        )));
+    }
+
+    derive_addresses(revaultd, range.clone())?;
+    let (mut deposit_descs, mut unvault_descs) = (Vec::new(), Vec::new());
+    for index in range.map(ChildNumber::from) {
+        deposit_descs.push(bitcoind.addr_descriptor(&revaultd.vault_address(index).to_string())?);
+        unvault_descs.push(bitcoind.addr_descriptor(&revaultd.unvault_address(index).to_string())?);
+    }
+
+    Ok((deposit_descs, unvault_descs))
+}
+
+// Import these deposit and Unvault descriptors, without a rescan.
+fn import_range_descriptors(
+    bitcoind: &BitcoinD,
+    (deposit_descs, unvault_descs): (Vec<String>, Vec<String>),
+) -> Result<(), BitcoindError> {
+    if deposit_descs.is_empty() {
+        return Ok(());
+    }
+
+    bitcoind.startup_import_deposit_descriptors(deposit_descs, 0, true)?;
+    bitcoind.startup_import_unvault_descriptors(unvault_descs, 0, true)
+}
+
+// A range of derivation indexes whose addresses we couldn't import as a rescan was ongoing, and
+// our tip height at the time.
+type QueuedImport = (Range<u32>, u32);
+
+// A deposit was made at this derivation index. Make sure we still watch `gap_limit` unused
+// addresses past it: deposits may not come in order, and may skip some indexes.
+// bitcoind refuses imports while rescanning: if `queued_imports` is given, the addresses are
+// only derived, and queued to be imported once the rescan is over.
+fn mark_index_used(
+    revaultd: &Arc<RwLock<RevaultD>>,
+    bitcoind: &BitcoinD,
+    used_index: ChildNumber,
+    queued_imports: Option<&mut Vec<QueuedImport>>,
+) -> Result<(), BitcoindError> {
+    let mut revaultd = revaultd.write().unwrap();
+    let first_unused: u32 = revaultd.current_unused_index.into();
+    let used_index: u32 = used_index.into();
+    if used_index < first_unused {
+        return Ok(());
+    }
+    if used_index > first_unused {
+        log::info!(
+            "Got a deposit at derivation index {} while the first unused one was {}",
+            used_index,
+            first_unused
+        );
+    }
+
+    let new_unused = used_index + 1;
+    let (watched_end, new_watched_end) = match (
+        revaultd.watched_indexes_end(first_unused),
+        revaultd.watched_indexes_end(new_unused),
+    ) {
+        (Some(watched_end), Some(new_watched_end)) => (watched_end, new_watched_end),
+        _ => {
+            return Err(BitcoindError::Custom(format!(
+                "Ran out of deposit addresses: can't watch {} addresses past derivation index \
+                 {} without deriving hardened indexes",
+                revaultd.gap_limit, used_index
+            )))
+        }
+    };
+    let range = watched_end..new_watched_end;
+    match queued_imports {
+        Some(queued_imports) => {
+            derive_addresses(&mut revaultd, range.clone())?;
+            log::debug!(
+                "Importing the addresses at derivation indexes {} to {} once the rescan is over",
+                range.start,
+                range.end - 1
+            );
+            queued_imports.push((range, db_tip(&revaultd.db)?.height));
+        }
+        None => import_index_range(&mut revaultd, bitcoind, range)?,
+    }
+
+    let new_index = ChildNumber::from(new_unused);
+    db_update_deposit_index(&revaultd.db, new_index)?;
+    revaultd.current_unused_index = new_index;
+    log::debug!(
+        "Incremented deposit derivation index from {} to {}",
+        first_unused,
+        new_unused
+    );
+
+    Ok(())
+}
+
+// Re-import the addresses for this range of derivation indexes, and return the height to rescan
+// the chain from for them: the first block since our wallet was created, if any. The import itself
+// doesn't rescan, as it would take as long as the rescan and our requests would time out.
+fn rescan_index_range(
+    revaultd: &Arc<RwLock<RevaultD>>,
+    bitcoind: &BitcoinD,
+    range: Range<u32>,
+) -> Result<Option<u32>, BitcoindError> {
+    log::info!(
+        "Importing the addresses at derivation indexes {} to {} to rescan the chain for them.",
+        range.start,
+        range.end - 1
+    );
+    let wallet = {
+        let mut revaultd = revaultd.write().unwrap();
+        import_index_range(&mut revaultd, bitcoind, range)?;
+        db_wallet(&revaultd.db)?
+    };
+    bitcoind.first_block_since(wallet.timestamp.saturating_sub(TIMESTAMP_WINDOW))
+}
+
+// Below it, the start of a rescan is a block height. Above it, a UNIX timestamp. As for nLockTime.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+// Block times may be off by up to 2 hours, bitcoind rescans this much earlier than a timestamp.
+const TIMESTAMP_WINDOW: u32 = 2 * 60 * 60;
+
+// The height to rescan the chain from, given a height or a timestamp.
+fn rescan_start_height(bitcoind: &BitcoinD, start: u32) -> Result<u32, BitcoindError> {
+    if start < LOCKTIME_THRESHOLD {
+        let tip = bitcoind.get_tip()?;
+        if start > tip.height {
+            return Err(BitcoindError::Custom(format!(
+                "Start height '{}' is above our tip '{}'",
+                start, tip.height
+            )));
+        }
+        return Ok(start);
+    }
+
+    bitcoind
+        .first_block_since(start.saturating_sub(TIMESTAMP_WINDOW))?
+        .ok_or_else(|| BitcoindError::Custom(format!("No block after timestamp '{}' yet", start)))
+}
+
+// Rescan the chain since this height in a thread of its own, as it may take hours. It gets its
+// own connection to bitcoind so that it doesn't hold up our other requests. The result is sent
+// on the returned channel once done.
+fn start_chain_rescan(
+    revaultd: &RevaultD,
+    height: u32,
+) -> Result<Receiver<Result<(), BitcoindError>>, BitcoindError> {
+    let bitcoind = BitcoinD::new(
+        &revaultd.bitcoind_config,
+        revaultd
+            .watchonly_wallet_file()
+            .expect("Wallet id is set at startup in setup_db()"),
+    )?;
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        // The poller is gone if we are shutting down, and bitcoind will finish the rescan anyways.
+        let _ = done_tx.send(bitcoind.rescan_blockchain(height));
+    });
+
+    Ok(done_rx)
+}
+
+// Import the addresses we queued during the rescan that just ended, and rescan the chain for them
+// since we queued them in the background. Returns this rescan's start height and where to get its
+// result from, if there was anything to import.
+fn import_queued_ranges(
+    revaultd: &Arc<RwLock<RevaultD>>,
+    bitcoind: &BitcoinD,
+    queued_imports: Vec<QueuedImport>,
+) -> Result<Option<(u32, Receiver<Result<(), BitcoindError>>)>, BitcoindError> {
+    let height = match queued_imports.iter().map(|(_, height)| *height).min() {
+        Some(height) => height,
+        None => return Ok(None),
+    };
+    {
+        let mut revaultd = revaultd.write().unwrap();
+        for (range, _) in queued_imports {
+            import_index_range(&mut revaultd, bitcoind, range)?;
+        }
+    }
+
+    let done_rx = start_chain_rescan(&revaultd.read().unwrap(), height)?;
+    Ok(Some((height, done_rx)))
+}
+
+// Re-create the vault for this recovered deposit, with its unsigned presigned transactions if it
+// is confirmed. The signature fetcher will get their signatures back from the Coordinator.
+fn recover_vault(
+    revaultd: &Arc<RwLock<RevaultD>>,
+    outpoint: OutPoint,
+    txo: TxOut,
+    deposit_tx: &WalletTransaction,
+    tip_height: u32,
+) -> Result<(), BitcoindError> {
+    let revaultd = revaultd.read().unwrap();
+    let derivation_index = *revaultd
+        .derivation_index_map
+        .get(&txo.script_pubkey)
+        .expect("We only recover deposits to known addresses");
+    let amount = Amount::from_sat(txo.value);
+    db_insert_new_unconfirmed_vault(
+        &revaultd.db,
+        revaultd
+            .wallet_id
+            .expect("Wallet id is set at startup in setup_db()"),
+        &VaultStatus::Unconfirmed,
+        &outpoint,
+        &amount,
+        derivation_index,
+    )?;
+    store_onchain_tx(
+        &revaultd,
+        &outpoint,
+        OnchainTransactionType::Deposit,
+        &outpoint.txid,
+        deposit_tx,
+    )?;
+    log::debug!("Recovered deposit at {} ({})", &outpoint, &amount);
+
+    // Otherwise the poller will confirm it as usual.
+    let blockheight = match deposit_tx.blockheight {
+        Some(height) if tip_height + 1 >= height + revaultd.min_conf => height,
+        _ => return Ok(()),
+    };
+    let utxo = DepositInfo {
+        txo,
+        status: VaultStatus::Unconfirmed,
+    };
+    let (unvault_tx, cancel_tx, emer_tx, unemer_tx) =
+        presigned_transactions(&revaultd, &outpoint, &utxo)?;
+    db_confirm_deposit(
+        &revaultd.db,
+        &outpoint,
+        blockheight,
+        &unvault_tx,
+        &cancel_tx,
+        emer_tx.as_ref(),
+        unemer_tx.as_ref(),
+    )?;
+
+    Ok(())
+}
+
+// Keep a copy of this transaction spending the recovered vault at this deposit outpoint, and
+// tell whether it is confirmed.
+fn store_recovered_spend(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    deposit_outpoint: &OutPoint,
+    tx_type: OnchainTransactionType,
+    txid: &Txid,
+) -> Result<bool, BitcoindError> {
+    let wallet_tx = bitcoind.get_wallet_transaction(txid)?;
+    store_onchain_tx(revaultd, deposit_outpoint, tx_type, txid, &wallet_tx)?;
+    Ok(wallet_tx.blockheight.is_some())
+}
+
+// Walk the confirmed vault at this recovered deposit through the statuses the transactions
+// spending it took it to. A transaction only takes it past the in-between status ('unvaulting',
+// 'canceling', ..) once confirmed.
+fn recover_vault_spends(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    spends: &VaultSpends,
+    deposit_outpoint: &OutPoint,
+) -> Result<(), BitcoindError> {
+    let deposit_spend = match spends.deposit_spend(deposit_outpoint) {
+        Some(spend) => *spend,
+        None => return Ok(()),
+    };
+
+    // The statuses it went through, and the transaction which took it there
+    let mut path: Vec<(VaultStatus, Txid)> = Vec::new();
+    match deposit_spend.kind {
+        SpendKind::Unvault => {
+            let unvault_txid = deposit_spend.txid;
+            let unvault_confirmed = store_recovered_spend(
+                revaultd,
+                bitcoind,
+                deposit_outpoint,
+                OnchainTransactionType::Unvault,
+                &unvault_txid,
+            )?;
+            path.push((VaultStatus::Unvaulting, unvault_txid));
+            if unvault_confirmed {
+                path.push((VaultStatus::Unvaulted, unvault_txid));
+            }
+
+            if let Some(spend) = spends.unvault_spend(deposit_outpoint) {
+                let (tx_type, pending, done) = match spend.kind {
+                    SpendKind::Cancel => (
+                        OnchainTransactionType::Cancel,
+                        VaultStatus::Canceling,
+                        VaultStatus::Canceled,
+                    ),
+                    SpendKind::UnvaultEmergency => (
+                        OnchainTransactionType::UnvaultEmergency,
+                        VaultStatus::UnvaultEmergencyVaulting,
+                        VaultStatus::UnvaultEmergencyVaulted,
+                    ),
+                    _ => (
+                        OnchainTransactionType::Spend,
+                        VaultStatus::Spending,
+                        VaultStatus::Spent,
+                    ),
+                };
+                let confirmed = store_recovered_spend(
+                    revaultd,
+                    bitcoind,
+                    deposit_outpoint,
+                    tx_type,
+                    &spend.txid,
+                )?;
+                // A Spend could only be broadcast once the Unvault's CSV expired
+                if pending == VaultStatus::Spending {
+                    path.push((VaultStatus::Spendable, unvault_txid));
+                }
+                path.push((pending, spend.txid));
+                if confirmed {
+                    path.push((done, spend.txid));
+                }
+            }
+        }
+        SpendKind::Emergency => {
+            let confirmed = store_recovered_spend(
+                revaultd,
+                bitcoind,
+                deposit_outpoint,
+                OnchainTransactionType::Emergency,
+                &deposit_spend.txid,
+            )?;
+            path.push((VaultStatus::EmergencyVaulting, deposit_spend.txid));
+            if confirmed {
+                path.push((VaultStatus::EmergencyVaulted, deposit_spend.txid));
+            }
+        }
+        _ => log::warn!(
+            "Recovered deposit at {} was spent by a {} transaction '{}'",
+            deposit_outpoint,
+            deposit_spend.kind,
+            &deposit_spend.txid
+        ),
+    }
+
+    for (status, txid) in path {
+        if !can_transition(revaultd, deposit_outpoint, status)? {
+            break;
+        }
+        db_update_vault_status(&revaultd.db, deposit_outpoint, status, &txid)?;
+        log::debug!(
+            "Recovered vault at {} is now '{}' via '{}'",
+            deposit_outpoint,
+            status,
+            &txid
+        );
+    }
+
+    Ok(())
+}
+
+// Rebuild our database from the chain. We rescan it for our deposit addresses, importing more of
+// them until the last `gap` ones are unused, and then re-create a vault for each deposit we found.
+fn recover_vaults(
+    revaultd: &Arc<RwLock<RevaultD>>,
+    bitcoind: &BitcoinD,
+    gap: u32,
+    start: Option<u32>,
+) -> Result<(), BitcoindError> {
+    let start_height = match start {
+        Some(start) => rescan_start_height(bitcoind, start)?,
+        None => {
+            log::warn!(
+                "No '--recovery-start' given, we'll rescan the chain since the genesis block. \
+                 This may take a long time."
+            );
+            0
+        }
+    };
+    log::info!(
+        "Recovering our vaults from the chain since block {}, with a gap of {} derivation \
+         indexes.",
+        start_height,
+        gap
+    );
+
+    // The wallet creation imported the first ones, import the whole window before rescanning.
+    let mut imported = {
+        let revaultd = revaultd.read().unwrap();
+        u32::from(revaultd.current_unused_index) + revaultd.gap_limit
+    };
+    if gap > imported {
+        import_index_range(&mut revaultd.write().unwrap(), bitcoind, imported..gap)?;
+        imported = gap;
+    }
+    // We only need to rescan again if we found deposits less than 'gap' indexes before the end
+    // of the window.
+    let (deposits, last_used) = loop {
+        log::info!(
+            "Rescanning the chain for the first {} derivation indexes.",
+            imported
+        );
+        bitcoind.rescan_blockchain(start_height)?;
+        let deposits = bitcoind.received_deposits()?;
+        let last_used = {
+            let revaultd = revaultd.read().unwrap();
+            deposits
+                .keys()
+                .filter_map(|spk| revaultd.derivation_index_map.get(spk))
+                .map(|index| u32::from(*index))
+                .max()
+        };
+
+        let wanted = last_used.map(|index| index + 1).unwrap_or(0) + gap;
+        if wanted <= imported {
+            break (deposits, last_used);
+        }
+        import_index_range(&mut revaultd.write().unwrap(), bitcoind, imported..wanted)?;
+        imported = wanted;
+    };
+
+    // Never hand out an address we found to be used
+    if let Some(last_used) = last_used {
+        let mut revaultd = revaultd.write().unwrap();
+        let next_index = ChildNumber::from(last_used + 1);
+        db_update_deposit_index(&revaultd.db, next_index)?;
+        revaultd.current_unused_index = next_index;
+        let last_watched = last_used + 1 + revaultd.gap_limit;
+        if last_watched > imported {
+            import_index_range(&mut revaultd, bitcoind, imported..last_watched)?;
+        }
+    }
+
+    let tip = bitcoind.get_tip()?;
+    db_update_tip(&revaultd.read().unwrap().db, &tip)?;
+
+    // Re-create the vaults in the order they were created on chain
+    let mut txs = Vec::new();
+    for txid in deposits.into_iter().flat_map(|(_, txids)| txids) {
+        if txs.iter().any(|(id, _, _)| *id == txid) {
+            continue;
+        }
+        let wallet_tx = bitcoind.get_wallet_transaction(&txid)?;
+        let tx = decode_wallet_tx(&txid, &wallet_tx)?;
+        txs.push((txid, wallet_tx, tx));
+    }
+    txs.sort_by_key(|(_, wallet_tx, _)| wallet_tx.blockheight.unwrap_or(u32::MAX));
+
+    let mut recovered = Vec::new();
+    for (txid, wallet_tx, tx) in txs {
+        for (vout, txo) in tx.output.into_iter().enumerate() {
+            let outpoint = OutPoint::new(txid, vout as u32);
+            if !revaultd
+                .read()
+                .unwrap()
+                .derivation_index_map
+                .contains_key(&txo.script_pubkey)
+                || db_vault_by_deposit(&revaultd.read().unwrap().db, &outpoint)?.is_some()
+            {
+                continue;
+            }
+            recover_vault(revaultd, outpoint, txo, &wallet_tx, tip.height)?;
+            recovered.push(outpoint);
+        }
+    }
+
+    // Now that we know about all of them, find out what happened to the confirmed ones.
+    let mut spends = VaultSpends::new();
+    spends.update(&revaultd.read().unwrap(), bitcoind)?;
+    for outpoint in recovered.iter() {
+        let revaultd = revaultd.read().unwrap();
+        let confirmed = db_vault_by_deposit(&revaultd.db, outpoint)?
+            .map(|db_vault| db_vault.status == VaultStatus::Funded)
+            .unwrap_or(false);
+        if confirmed {
+            recover_vault_spends(&revaultd, bitcoind, &spends, outpoint)?;
+        }
+    }
+
+    log::info!(
+        "Recovery done: found {} vault(s), next derivation index is {}.",
+        recovered.len(),
+        revaultd.read().unwrap().current_unused_index
+    );
     Ok(())
 }
 
+// Consistency check requests: whether to repair, and where to send the inconsistencies found.
+type ConsistencyRequest = (bool, SyncSender<Result<Vec<Inconsistency>, String>>);
+// What to rescan the chain for
+enum RescanTarget {
+    // The addresses at this range of derivation indexes, since the wallet creation
+    Indexes(Range<u32>),
+    // All the transactions of our wallet, since this height or timestamp
+    Chain(u32),
+}
+// Rescan requests: what to rescan for, and where to send the result.
+type RescanRequest = (RescanTarget, SyncSender<Result<(), String>>);
+
 fn poller_main(
     mut revaultd: Arc<RwLock<RevaultD>>,
     bitcoind: Arc<RwLock<BitcoinD>>,
     sync_progress: Arc<RwLock<f64>>,
     shutdown: Arc<AtomicBool>,
+    consistency_rx: Receiver<ConsistencyRequest>,
+    rescan_rx: Receiver<RescanRequest>,
+    notif_rx: Receiver<Notification>,
 ) -> Result<(), BitcoindError> {
     let mut last_poll = None;
     let mut sync_waittime = None;
+    let mut startup_check_done = false;
     // We use a cache for maintaining our deposits' state up-to-date by polling `listunspent`
     let mut deposits_cache = populate_deposit_cache(&revaultd.read().unwrap())?;
-    // When bitcoind is synced, we poll each 30s. On regtest we speed it up for testing.
-    let poll_interval = match revaultd.read().unwrap().bitcoind_config.network {
-        Network::Regtest => Duration::from_secs(3),
-        _ => Duration::from_secs(30),
-    };
+    // The transactions spending our vaults, found in the wallet transactions as they come
+    let mut spends = VaultSpends::from_db(&revaultd.read().unwrap())?;
+    let poll_interval = revaultd.read().unwrap().bitcoind_poll_interval;
+    // The start height of the chain rescan running in the background, and where to get its
+    // result from once done
+    let mut ongoing_rescan: Option<(u32, Receiver<Result<(), BitcoindError>>)> = None;
+    // The addresses to import once it's over
+    let mut queued_imports: Vec<QueuedImport> = Vec::new();
 
     while !shutdown.load(Ordering::Relaxed) {
         let now = Instant::now();
 
+        // We are the only ones to modify the deposits cache, so the check is done here.
+        if let Ok((repair, resp_tx)) = consistency_rx.try_recv() {
+            let res = if (*sync_progress.read().unwrap() as u32) < 1 {
+                Err("bitcoind is still synchronizing".to_string())
+            } else {
+                check_consistency(
+                    &revaultd.read().unwrap(),
+                    &bitcoind.read().unwrap(),
+                    &mut deposits_cache,
+                    &mut spends,
+                    repair,
+                )
+                .map_err(|e| e.to_string())
+            };
+            resp_tx.send(res).map_err(|e| {
+                BitcoindError::Custom(format!(
+                    "Sending consistency check result to main thread: {}",
+                    e
+                ))
+            })?;
+        }
+
+        // The chain rescan is done in the background, see if it's over.
+        let mut rescanned = false;
+        if let Some((height, done_rx)) = &ongoing_rescan {
+            let height = *height;
+            match done_rx.try_recv() {
+                Err(TryRecvError::Empty) => {}
+                Ok(Ok(())) => {
+                    log::info!("Rescan since block {} done.", height);
+                    rescanned = true;
+                    ongoing_rescan = None;
+                }
+                Ok(Err(e)) => {
+                    log::error!("Error while rescanning the chain: '{}'", e);
+                    ongoing_rescan = None;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("The chain rescan thread exited without a result");
+                    ongoing_rescan = None;
+                }
+            }
+        }
+        if ongoing_rescan.is_none() && !queued_imports.is_empty() {
+            match import_queued_ranges(
+                &revaultd,
+                &bitcoind.read().unwrap(),
+                std::mem::take(&mut queued_imports),
+            ) {
+                Ok(rescan) => {
+                    if let Some((height, _)) = &rescan {
+                        log::info!(
+                            "Rescanning the chain since block {} for the addresses imported \
+                             during the last rescan.",
+                            height
+                        );
+                    }
+                    ongoing_rescan = rescan;
+                }
+                Err(e) => log::error!(
+                    "Error importing the addresses queued during the rescan: '{}'. Use \
+                     'rescanindexes' to import them.",
+                    e
+                ),
+            }
+        }
+
+        // Same for the deposits we may find when rescanning.
+        if let Ok((target, resp_tx)) = rescan_rx.try_recv() {
+            let send_result = |res: Result<(), String>| {
+                resp_tx.send(res).map_err(|e| {
+                    BitcoindError::Custom(format!("Sending rescan result to main thread: {}", e))
+                })
+            };
+            if (*sync_progress.read().unwrap() as u32) < 1 {
+                send_result(Err("bitcoind is still synchronizing".to_string()))?;
+            } else if ongoing_rescan.is_some() {
+                send_result(Err("A rescan of the chain is already ongoing".to_string()))?;
+            } else {
+                let bitcoind = bitcoind.read().unwrap();
+                let start_height = match target {
+                    RescanTarget::Indexes(range) => rescan_index_range(&revaultd, &bitcoind, range),
+                    RescanTarget::Chain(start) => rescan_start_height(&bitcoind, start).map(Some),
+                };
+                // This may take hours, don't leave the caller (nor the poller) hanging. Its
+                // progress is reported by 'getinfo'.
+                let rescan = start_height.and_then(|start_height| match start_height {
+                    Some(height) => start_chain_rescan(&revaultd.read().unwrap(), height)
+                        .map(|done_rx| Some((height, done_rx))),
+                    None => Ok(None),
+                });
+                match rescan {
+                    Ok(Some((height, done_rx))) => {
+                        send_result(Ok(()))?;
+                        log::info!("Rescanning the chain since block {}.", height);
+                        ongoing_rescan = Some((height, done_rx));
+                    }
+                    // No block since the wallet creation, the import was enough.
+                    Ok(None) => {
+                        send_result(Ok(()))?;
+                        rescanned = true;
+                    }
+                    Err(e) => send_result(Err(e.to_string()))?,
+                }
+            }
+        }
+
+        if rescanned {
+            // Pick up the deposits we found right away, and their spends which are in blocks
+            // we already went through.
+            last_poll = None;
+            spends = VaultSpends::from_db(&revaultd.read().unwrap())?;
+            spends.rewind();
+        }
+
         if (*sync_progress.read().unwrap() as u32) < 1 {
             // While waiting for bitcoind to be synced, guesstimate how much time of block
             // connection we have left to not harass it with `getblockchaininfo`.
@@ -651,6 +1537,7 @@ fn poller_main(
 
             // Ok. Sync, done. Now just be sure the watchonly wallet is properly loaded, and
             // to create it if it's first run.
+            let mut recovery = None;
             if *sync_progress.read().unwrap() as u32 >= 1 {
                 let mut revaultd = revaultd.write().unwrap();
                 let bitcoind = bitcoind.read().unwrap();
@@ -660,18 +1547,53 @@ fn poller_main(
                 maybe_load_wallet(&revaultd, &bitcoind).map_err(|e| {
                     BitcoindError::Custom(format!("Error while loading wallet: {}", e.to_string()))
                 })?;
+                maybe_extend_gap_limit(&mut revaultd, &bitcoind).map_err(|e| {
+                    BitcoindError::Custom(format!(
+                        "Error while importing addresses up to the gap limit: {}",
+                        e.to_string()
+                    ))
+                })?;
 
                 log::info!("bitcoind now synced.");
+                recovery = revaultd
+                    .recovery_gap
+                    .take()
+                    .map(|gap| (gap, revaultd.recovery_start.take()));
             }
 
+            // We were started to rebuild our database, now is the time.
+            if let Some((gap, start)) = recovery {
+                recover_vaults(&revaultd, &bitcoind.read().unwrap(), gap, start)?;
+                deposits_cache = populate_deposit_cache(&revaultd.read().unwrap())?;
+                spends = VaultSpends::from_db(&revaultd.read().unwrap())?;
+            }
+
+            // We'll poll once synced anyways, don't pile them up during the initial sync.
+            while notif_rx.try_recv().is_ok() {}
+
             last_poll = Some(now);
             continue;
         }
 
         if let Some(last_poll) = last_poll {
             if now.duration_since(last_poll) < poll_interval {
-                thread::sleep(Duration::from_millis(500));
-                continue;
+                // Don't wait for the next poll if bitcoind notified us of something of interest.
+                match notif_rx.recv_timeout(Duration::from_millis(500)) {
+                    Ok(notif) => {
+                        if !is_relevant(&revaultd.read().unwrap(), &deposits_cache, &notif) {
+                            continue;
+                        }
+                        log::debug!("Polling bitcoind right away after a ZMQ notification");
+                        // This poll covers the pending ones too.
+                        while notif_rx.try_recv().is_ok() {}
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    // No (more) ZMQ notifications, just poll.
+                    Err(RecvTimeoutError::Disconnected) => {
+                        thread::sleep(Duration::from_millis(500));
+                        continue;
+                    }
+                }
             }
         }
 
@@ -685,20 +1607,66 @@ fn poller_main(
             &mut revaultd,
             &bitcoind.read().unwrap(),
             &mut deposits_cache,
+            &mut spends,
+            if ongoing_rescan.is_some() {
+                Some(&mut queued_imports)
+            } else {
+                None
+            },
         )?;
+
+        // Once we caught up with what happened while we were off, make sure our database agrees
+        // with the wallet.
+        if !startup_check_done {
+            let inconsistencies = check_consistency(
+                &revaultd.read().unwrap(),
+                &bitcoind.read().unwrap(),
+                &mut deposits_cache,
+                &mut spends,
+                false,
+            )?;
+            if inconsistencies.is_empty() {
+                log::info!("Database is consistent with bitcoind's watchonly wallet.");
+            } else {
+                log::warn!(
+                    "Found {} inconsistent vault(s). Use the 'checkconsistency' command to \
+                     repair them.",
+                    inconsistencies.len()
+                );
+            }
+            startup_check_done = true;
+        }
     }
 
     Ok(())
 }
 
+// Whether this notification may change the state of our vaults: a new block, a new deposit or
+// the spend of one.
+fn is_relevant(
+    revaultd: &RevaultD,
+    deposits_cache: &HashMap<OutPoint, DepositInfo>,
+    notif: &Notification,
+) -> bool {
+    match notif {
+        Notification::Block => true,
+        Notification::Transaction(tx) => {
+            tx.output.iter().any(|txo| {
+                revaultd
+                    .derivation_index_map
+                    .contains_key(&txo.script_pubkey)
+            }) || tx
+                .input
+                .iter()
+                .any(|txin| deposits_cache.contains_key(&txin.previous_output))
+        }
+    }
+}
+
 fn wallet_transaction(bitcoind: &BitcoinD, txid: Txid) -> Option<WalletTransaction> {
     let res = bitcoind.get_wallet_transaction(&txid);
-    if let Ok((hex, blockheight, received_time)) = res {
-        Some(WalletTransaction {
-            hex,
-            blockheight,
-            received_time,
-        })
+    if let Ok(wallet_tx) = res {
+        Some(wallet_tx)
     } else {
         log::trace!(
             "Got '{:?}' from bitcoind when requesting wallet transaction '{}'",
@@ -709,6 +1677,16 @@ fn wallet_transaction(bitcoind: &BitcoinD, txid: Txid) -> Option<WalletTransacti
     }
 }
 
+fn rescan_progress(bitcoind: &BitcoinD) -> Option<f64> {
+    bitcoind.rescan_progress().unwrap_or_else(|e| {
+        log::trace!(
+            "Got '{}' from bitcoind when requesting the rescan progress",
+            e
+        );
+        None
+    })
+}
+
 /// The bitcoind event loop.
 /// Listens for bitcoind requests (wallet / chain) and poll bitcoind every 30 seconds,
 /// updating our state accordingly.
@@ -724,13 +1702,36 @@ pub fn bitcoind_main_loop(
     // Used to shutdown the poller thread
     let shutdown = Arc::new(AtomicBool::new(false));
 
+    // Used to forward the consistency check requests to the poller thread
+    let (consistency_tx, consistency_rx) = mpsc::channel();
+    // Same for the rescan requests
+    let (rescan_tx, rescan_rx) = mpsc::channel();
+
+    // If bitcoind publishes ZMQ notifications, they'll wake up the poller thread.
+    let (notif_tx, notif_rx) = mpsc::sync_channel(NOTIFICATIONS_QUEUE_SIZE);
+    let notifications_thread = start_notifications_listener(
+        &revaultd.read().unwrap().bitcoind_config,
+        notif_tx,
+        shutdown.clone(),
+    )?;
+
     // We use a thread to 1) wait for bitcoind to be synced 2) poll listunspent
     let poller_thread = std::thread::spawn({
         let _revaultd = revaultd.clone();
         let _bitcoind = bitcoind.clone();
         let _sync_progress = sync_progress.clone();
         let _shutdown = shutdown.clone();
-        move || poller_main(_revaultd, _bitcoind, _sync_progress, _shutdown)
+        move || {
+            poller_main(
+                _revaultd,
+                _bitcoind,
+                _sync_progress,
+                _shutdown,
+                consistency_rx,
+                rescan_rx,
+                notif_rx,
+            )
+        }
     });
 
     for msg in rx {
@@ -742,6 +1743,15 @@ pub fn bitcoind_main_loop(
                     assume_ok!(poller_thread.join(), "Joining bitcoind poller thread"),
                     "Error in bitcoind poller thread"
                 );
+                if let Some(notifications_thread) = notifications_thread {
+                    // We were polling anyways, don't crash for it.
+                    if let Err(e) = assume_ok!(
+                        notifications_thread.join(),
+                        "Joining ZMQ notifications thread"
+                    ) {
+                        log::error!("Error in ZMQ notifications thread: {}", e);
+                    }
+                }
                 return Ok(());
             }
             BitcoindMessageOut::SyncProgress(resp_tx) => {
@@ -752,6 +1762,17 @@ pub fn bitcoind_main_loop(
                     ))
                 })?;
             }
+            BitcoindMessageOut::RescanProgress(resp_tx) => {
+                // The watchonly wallet is only loaded once synced.
+                let progress = if (*sync_progress.read().unwrap() as u32) < 1 {
+                    None
+                } else {
+                    rescan_progress(&bitcoind.read().unwrap())
+                };
+                resp_tx.send(progress).map_err(|e| {
+                    BitcoindError::Custom(format!("Sending rescan progress to main thread: {}", e))
+                })?;
+            }
             BitcoindMessageOut::WalletTransaction(txid, resp_tx) => {
                 log::trace!("Received 'wallettransaction' from main thread");
                 resp_tx
@@ -763,6 +1784,37 @@ pub fn bitcoind_main_loop(
                         ))
                     })?;
             }
+            BitcoindMessageOut::CheckConsistency(repair, resp_tx) => {
+                log::trace!("Received 'checkconsistency' from main thread");
+                consistency_tx.send((repair, resp_tx)).map_err(|e| {
+                    BitcoindError::Custom(format!(
+                        "Sending consistency check request to poller thread: {}",
+                        e
+                    ))
+                })?;
+            }
+            BitcoindMessageOut::RescanIndexes(range, resp_tx) => {
+                log::trace!("Received 'rescanindexes' from main thread");
+                rescan_tx
+                    .send((RescanTarget::Indexes(range), resp_tx))
+                    .map_err(|e| {
+                        BitcoindError::Custom(format!(
+                            "Sending rescan request to poller thread: {}",
+                            e
+                        ))
+                    })?;
+            }
+            BitcoindMessageOut::Rescan(start, resp_tx) => {
+                log::trace!("Received 'rescan' from main thread");
+                rescan_tx
+                    .send((RescanTarget::Chain(start), resp_tx))
+                    .map_err(|e| {
+                        BitcoindError::Custom(format!(
+                            "Sending rescan request to poller thread: {}",
+                            e
+                        ))
+                    })?;
+            }
         }
     }
 
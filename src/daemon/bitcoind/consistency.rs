@@ -0,0 +1,270 @@
+//! Cross-check our database against bitcoind's watchonly wallet. They MUST be in sync, but a
+//! crash at the wrong time, a bug or a manual intervention on either of them could break it.
+
+use crate::{
+    audit::audit_vault,
+    bitcoind::{
+        actions::{decode_wallet_tx, store_onchain_tx},
+        interface::{BitcoinD, DepositInfo},
+        spends::{SpendKind, VaultSpend, VaultSpends},
+        BitcoindError,
+    },
+    database::{
+        actions::{db_unconfirm_deposit_dbtx, db_unvault_deposit, db_update_vault_blockheight},
+        interface::{db_exec, db_vaults},
+        schema::{DbVault, OnchainTransactionType},
+    },
+    revaultd::{RevaultD, VaultStatus},
+    threadmessages::{Inconsistency, InconsistencyKind},
+};
+use revault_tx::bitcoin::{OutPoint, TxOut};
+
+use std::collections::HashMap;
+
+use jsonrpc::error::Error;
+
+// bitcoind's RPC_INVALID_ADDRESS_OR_KEY, what 'gettransaction' returns for a transaction that is
+// not in the wallet.
+const NOT_IN_WALLET: i32 = -5;
+
+// Forget about the confirmation of this deposit, and let the poller confirm it again. This
+// re-creates its presigned transactions, and the signature fetcher will get their signatures.
+fn unconfirm_deposit(
+    revaultd: &RevaultD,
+    deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    db_vault: &DbVault,
+    txo: TxOut,
+) -> Result<(), BitcoindError> {
+    db_exec(&revaultd.db, |db_tx| {
+        db_unconfirm_deposit_dbtx(db_tx, db_vault.id, &db_vault.deposit_outpoint.txid)
+    })?;
+    deposits_cache.insert(
+        db_vault.deposit_outpoint,
+        DepositInfo {
+            txo,
+            status: VaultStatus::Unconfirmed,
+        },
+    );
+
+    Ok(())
+}
+
+// Check a single vault, returning the first inconsistency we found.
+fn check_vault(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    spends: &VaultSpends,
+    db_vault: &DbVault,
+    tip_height: u32,
+    repair: bool,
+) -> Result<Option<Inconsistency>, BitcoindError> {
+    let outpoint = db_vault.deposit_outpoint;
+    let inconsistency = |kind, description, repaired| {
+        Some(Inconsistency {
+            deposit_outpoint: outpoint,
+            kind,
+            description,
+            repaired,
+        })
+    };
+
+    // The deposit must be in the wallet, paying what we think to where we think.
+    let wallet_tx = match bitcoind.get_wallet_transaction(&outpoint.txid) {
+        Ok(wallet_tx) => wallet_tx,
+        Err(BitcoindError::Server(Error::Rpc(ref e))) if e.code == NOT_IN_WALLET => {
+            return Ok(inconsistency(
+                InconsistencyKind::Deposit,
+                "The deposit transaction is not in the watchonly wallet".to_string(),
+                false,
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+    let txo = TxOut {
+        value: db_vault.amount.as_sat(),
+        script_pubkey: revaultd
+            .vault_address(db_vault.derivation_index)
+            .script_pubkey(),
+    };
+    let deposit_tx = decode_wallet_tx(&outpoint.txid, &wallet_tx)?;
+    if deposit_tx.output.get(outpoint.vout as usize) != Some(&txo) {
+        return Ok(inconsistency(
+            InconsistencyKind::Deposit,
+            format!(
+                "The deposit output is not of {} to the vault address at derivation index {}",
+                db_vault.amount,
+                u32::from(db_vault.derivation_index)
+            ),
+            false,
+        ));
+    }
+
+    // An unconfirmed vault whose deposit got enough confirmations is fine, the poller will
+    // confirm it. The opposite isn't.
+    let confirmed_height = wallet_tx
+        .blockheight
+        .filter(|height| tip_height + 1 >= height + revaultd.min_conf);
+    if db_vault.status != VaultStatus::Unconfirmed {
+        match confirmed_height {
+            None => {
+                if repair {
+                    unconfirm_deposit(revaultd, deposits_cache, db_vault, txo)?;
+                }
+                return Ok(inconsistency(
+                    InconsistencyKind::Confirmation,
+                    format!(
+                        "The vault is '{}' but its deposit has less than {} confirmations",
+                        db_vault.status, revaultd.min_conf
+                    ),
+                    repair,
+                ));
+            }
+            Some(height) if height != db_vault.blockheight => {
+                if repair {
+                    db_update_vault_blockheight(&revaultd.db, db_vault.id, height)?;
+                    store_onchain_tx(
+                        revaultd,
+                        &outpoint,
+                        OnchainTransactionType::Deposit,
+                        &outpoint.txid,
+                        &wallet_tx,
+                    )?;
+                }
+                return Ok(inconsistency(
+                    InconsistencyKind::Confirmation,
+                    format!(
+                        "The deposit is confirmed at height {} but we recorded height {}",
+                        height, db_vault.blockheight
+                    ),
+                    repair,
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    // Any status past 'active' means the deposit was spent.
+    let is_unspent = bitcoind.is_unspent(&outpoint)?;
+    let db_unspent = db_vault.status as u32 <= VaultStatus::Active as u32;
+    if db_unspent && !is_unspent {
+        let spend = spends.deposit_spend(&outpoint);
+        let description = match spend {
+            Some(spend) => format!(
+                "The vault is '{}' but its deposit was spent by the {} transaction '{}'",
+                db_vault.status, spend.kind, spend.txid
+            ),
+            None => format!(
+                "The vault is '{}' but its deposit was spent",
+                db_vault.status
+            ),
+        };
+        // We can only tell about an Unvault, and only for a confirmed vault.
+        let repaired = match spend {
+            Some(VaultSpend {
+                kind: SpendKind::Unvault,
+                txid: unvault_txid,
+                ..
+            }) if repair && db_vault.status != VaultStatus::Unconfirmed => {
+                db_unvault_deposit(&revaultd.db, &outpoint, unvault_txid)?;
+                store_onchain_tx(
+                    revaultd,
+                    &outpoint,
+                    OnchainTransactionType::Unvault,
+                    unvault_txid,
+                    &bitcoind.get_wallet_transaction(unvault_txid)?,
+                )?;
+                if let Some(utxo) = deposits_cache.get_mut(&outpoint) {
+                    utxo.status = VaultStatus::Unvaulting;
+                }
+                true
+            }
+            _ => false,
+        };
+        return Ok(inconsistency(
+            InconsistencyKind::Spend,
+            description,
+            repaired,
+        ));
+    }
+    if !db_unspent && is_unspent {
+        return Ok(inconsistency(
+            InconsistencyKind::Spend,
+            format!(
+                "The vault is '{}' but its deposit is unspent",
+                db_vault.status
+            ),
+            false,
+        ));
+    }
+
+    // The presigned transactions only exist once the deposit is confirmed.
+    if db_vault.status != VaultStatus::Unconfirmed {
+        let mismatched =
+            audit_vault(revaultd, db_vault).map_err(|e| BitcoindError::Custom(e.to_string()))?;
+        if !mismatched.is_empty() {
+            // Re-creating them is only safe as long as none was used.
+            let repaired = repair && db_unspent;
+            if repaired {
+                unconfirm_deposit(revaultd, deposits_cache, db_vault, txo)?;
+            }
+            return Ok(inconsistency(
+                InconsistencyKind::PresignedTransactions,
+                format!(
+                    "The stored {} transaction(s) differ from the ones derived from the deposit",
+                    mismatched
+                        .iter()
+                        .map(|mismatch| mismatch.tx_type.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+                repaired,
+            ));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check every vault in our database against bitcoind's watchonly wallet: its deposit must be
+/// there, confirmed and spent as we think, and its presigned transactions must be the ones we
+/// derive from it. In repair mode, we fix what can safely be (the deposits cache along with it).
+pub fn check_consistency(
+    revaultd: &RevaultD,
+    bitcoind: &BitcoinD,
+    deposits_cache: &mut HashMap<OutPoint, DepositInfo>,
+    spends: &mut VaultSpends,
+    repair: bool,
+) -> Result<Vec<Inconsistency>, BitcoindError> {
+    let tip = bitcoind.get_tip()?;
+    // Only the spends we applied survive a restart, go through all the wallet transactions.
+    spends.rewind();
+    spends.update(revaultd, bitcoind)?;
+    let mut inconsistencies = Vec::new();
+
+    for db_vault in db_vaults(&revaultd.db)? {
+        if let Some(inconsistency) = check_vault(
+            revaultd,
+            bitcoind,
+            deposits_cache,
+            spends,
+            &db_vault,
+            tip.height,
+            repair,
+        )? {
+            log::warn!(
+                "Inconsistency between the database and bitcoind's wallet for vault '{}': {}{}",
+                inconsistency.deposit_outpoint,
+                inconsistency.description,
+                if inconsistency.repaired {
+                    " (repaired)"
+                } else {
+                    ""
+                }
+            );
+            inconsistencies.push(inconsistency);
+        }
+    }
+
+    Ok(inconsistencies)
+}
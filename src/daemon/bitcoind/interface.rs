@@ -1,15 +1,20 @@
 use crate::{
-    bitcoind::{BitcoindError, MIN_CONF},
+    bitcoind::BitcoindError,
     revaultd::{BlockchainTip, VaultStatus},
+    threadmessages::WalletTransaction,
 };
 use common::config::BitcoindConfig;
-use revault_tx::bitcoin::{Address, Amount, BlockHash, OutPoint, TxOut, Txid};
+use revault_tx::bitcoin::{Address, Amount, BlockHash, OutPoint, Script, TxOut, Txid};
 
-use std::{collections::HashMap, fs, str::FromStr, time::Duration};
+use std::{collections::HashMap, fs, str::FromStr, thread, time::Duration};
 
-use jsonrpc::{arg, client::Client, simple_http::SimpleHttpTransport};
+use jsonrpc::{arg, client::Client, error::Error, simple_http::SimpleHttpTransport};
 use serde_json::Value as Json;
 
+// bitcoind's RPC_INVALID_ADDRESS_OR_KEY, what 'getmempoolentry' returns for a transaction that is
+// not in the mempool.
+const NOT_IN_MEMPOOL: i32 = -5;
+
 pub struct BitcoinD {
     node_client: Client,
     watchonly_client: Client,
@@ -253,6 +258,15 @@ impl BitcoinD {
             .to_string())
     }
 
+    /// Whether this address is watched by our watchonly wallet
+    pub fn is_watched(&self, address: &str) -> Result<bool, BitcoindError> {
+        let info =
+            self.make_watchonly_request("getaddressinfo", &params!(Json::String(address.into())))?;
+        let is_true = |key: &str| info.get(key).and_then(|v| v.as_bool()) == Some(true);
+
+        Ok(is_true("ismine") || is_true("iswatchonly"))
+    }
+
     fn bulk_import_descriptors(
         &self,
         descriptors: Vec<String>,
@@ -322,38 +336,6 @@ impl BitcoinD {
         )
     }
 
-    fn import_fresh_descriptor(
-        &self,
-        descriptor: String,
-        label: String,
-    ) -> Result<(), BitcoindError> {
-        let mut desc_map = serde_json::Map::with_capacity(3);
-        desc_map.insert("desc".to_string(), Json::String(descriptor));
-        desc_map.insert("timestamp".to_string(), Json::String("now".to_string()));
-        desc_map.insert("label".to_string(), Json::String(label));
-
-        let res = self.make_watchonly_request(
-            "importdescriptors",
-            &params!(Json::Array(vec![Json::Object(desc_map,)])),
-        )?;
-        if res.get(0).map(|x| x.get("success")) == Some(Some(&Json::Bool(true))) {
-            return Ok(());
-        }
-
-        Err(BitcoindError::Custom(format!(
-            "In import_fresh descriptor, error returned from 'importdescriptor': {:?}",
-            res.get("error")
-        )))
-    }
-
-    pub fn import_fresh_deposit_descriptor(&self, descriptor: String) -> Result<(), BitcoindError> {
-        self.import_fresh_descriptor(descriptor, self.deposit_utxos_label())
-    }
-
-    pub fn import_fresh_unvault_descriptor(&self, descriptor: String) -> Result<(), BitcoindError> {
-        self.import_fresh_descriptor(descriptor, self.unvault_utxos_label())
-    }
-
     // A routine to get the txid,vout pair out of a listunspent entry
     fn outpoint_from_utxo(&self, utxo: &Json) -> Result<OutPoint, BitcoindError> {
         let txid = utxo
@@ -399,6 +381,7 @@ impl BitcoinD {
     pub fn sync_deposits(
         &self,
         existing_utxos: &HashMap<OutPoint, DepositInfo>,
+        min_conf: u32,
     ) -> Result<
         (
             HashMap<OutPoint, DepositInfo>, // new
@@ -449,7 +432,7 @@ impl BitcoinD {
             //  - remove() will return None if it was not present in the map, ie new deposit
             if let Some(utxo) = spent_deposits.remove(&outpoint) {
                 // It may be present but still unconfirmed, though.
-                if utxo.status == VaultStatus::Unconfirmed && confirmations >= MIN_CONF {
+                if utxo.status == VaultStatus::Unconfirmed && confirmations >= min_conf as u64 {
                     confirmed_deposits.insert(outpoint, utxo);
                 }
                 continue;
@@ -515,12 +498,9 @@ impl BitcoinD {
         Ok((new_deposits, confirmed_deposits, spent_deposits))
     }
 
-    /// Get the raw transaction as hex, the blockheight it was included in if
-    /// it's confirmed, as well as the reception time.
-    pub fn get_wallet_transaction(
-        &self,
-        txid: &Txid,
-    ) -> Result<(String, Option<u32>, u32), BitcoindError> {
+    /// Get the raw transaction as hex, the height and time of the block it was
+    /// included in if it's confirmed, as well as the reception time.
+    pub fn get_wallet_transaction(&self, txid: &Txid) -> Result<WalletTransaction, BitcoindError> {
         let res = self
             .make_watchonly_request("gettransaction", &params!(Json::String(txid.to_string())))?;
         let tx_hex = res
@@ -537,6 +517,7 @@ impl BitcoinD {
             })?
             .to_string();
         let blockheight = res.get("blockheight").map(|bh| bh.as_u64().unwrap() as u32);
+        let blocktime = res.get("blocktime").map(|bt| bt.as_u64().unwrap() as u32);
         let received = res
             .get("timereceived")
             .ok_or_else(|| {
@@ -553,77 +534,273 @@ impl BitcoinD {
                 ))
             })? as u32;
 
-        Ok((tx_hex, blockheight, received))
+        Ok(WalletTransaction {
+            hex: tx_hex,
+            blockheight,
+            blocktime,
+            received_time: received,
+        })
     }
 
-    // This assumes wallet transactions, will error otherwise !
-    fn previous_outpoints(&self, outpoint: &OutPoint) -> Result<Vec<OutPoint>, BitcoindError> {
-        Ok(self
-            .make_watchonly_request(
-                "gettransaction",
-                &params!(
-                    Json::String(outpoint.txid.to_string()),
-                    Json::Bool(true), // include_watchonly
-                    Json::Bool(true), // verbose
-                ),
-            )?
-            .get("decoded")
-            .ok_or_else(|| {
-                BitcoindError::Custom(
-                    "API break: 'gettransaction' has no 'hex' in verbose mode?".to_string(),
+    /// Rescan the chain from this height for the transactions of our watchonly wallet. This may
+    /// take longer than our requests timeout, in which case we wait for bitcoind to be done.
+    pub fn rescan_blockchain(&self, start_height: u32) -> Result<(), BitcoindError> {
+        match self.make_watchonly_request("rescanblockchain", &params!(start_height)) {
+            Ok(_) => return Ok(()),
+            Err(BitcoindError::Server(Error::Transport(e))) => {
+                log::debug!(
+                    "Rescan request interrupted ('{}'), polling its progress.",
+                    e
                 )
-            })?
-            .get("vin")
-            .ok_or_else(|| {
-                BitcoindError::Custom("API break: 'gettransaction' has no 'vin' ?".to_string())
-            })?
-            .as_array()
+            }
+            Err(e) => return Err(e),
+        }
+
+        loop {
+            thread::sleep(Duration::from_secs(5));
+            match self.rescan_progress()? {
+                Some(progress) => log::info!("Rescanning the chain: {:.2}%", progress * 100.0),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// The progress of the ongoing rescan of our watchonly wallet, if any.
+    pub fn rescan_progress(&self) -> Result<Option<f64>, BitcoindError> {
+        // 'scanning' is false once done, or an object describing the progress
+        match self
+            .make_watchonly_request("getwalletinfo", &[])?
+            .get("scanning")
+        {
+            Some(Json::Bool(false)) | None => Ok(None),
+            Some(scanning) => scanning
+                .get("progress")
+                .and_then(|p| p.as_f64())
+                .map(Some)
+                .ok_or_else(|| {
+                    BitcoindError::Custom(format!(
+                        "API break: invalid 'scanning' in 'getwalletinfo': '{}'",
+                        scanning
+                    ))
+                }),
+        }
+    }
+
+    fn median_time_past(&self, height: u32) -> Result<u32, BitcoindError> {
+        let hash = self.getblockhash(height)?;
+        self.make_node_request("getblockheader", &params!(Json::String(hash.to_string())))?
+            .get("mediantime")
+            .and_then(|t| t.as_u64())
+            .map(|t| t as u32)
             .ok_or_else(|| {
                 BitcoindError::Custom(
-                    "API break: 'gettransaction' 'vin' isn't an array?".to_string(),
+                    "API break: no valid 'mediantime' in 'getblockheader'".to_string(),
                 )
-            })?
-            .iter()
-            .filter_map(|txin| {
-                Some(OutPoint {
-                    txid: Txid::from_str(txin.get("txid")?.as_str()?).ok()?,
-                    vout: txin.get("vout")?.as_u64()? as u32,
-                })
             })
-            .collect())
     }
 
-    /// There is no good way to get the "spending transaction" from an utxo in bitcoind.
-    /// So here we workaround it leveraging the fact we know the unvault address. So we list
-    /// the unvault address transactions and check if one spent this outpoint to this address.
-    pub fn unvault_from_vault(
-        &self,
-        vault_outpoint: &OutPoint,
-        unvault_address: String,
-    ) -> Result<Option<OutPoint>, BitcoindError> {
+    /// The height of the first block whose median time past is at or after this timestamp,
+    /// None if there is none yet. The median time past never decreases, unlike the block time.
+    pub fn first_block_since(&self, timestamp: u32) -> Result<Option<u32>, BitcoindError> {
+        let tip = self.get_tip()?;
+        if self.median_time_past(tip.height)? < timestamp {
+            return Ok(None);
+        }
+
+        let (mut low, mut high) = (0, tip.height);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.median_time_past(mid)? < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(Some(low))
+    }
+
+    /// All the transactions that ever paid to one of our deposit addresses, spent or not, by
+    /// deposit scriptPubKey.
+    pub fn received_deposits(&self) -> Result<HashMap<Script, Vec<Txid>>, BitcoindError> {
         let res = self.make_watchonly_request(
-            "listunspent",
+            "listreceivedbyaddress",
             &params!(
-                Json::Number(serde_json::Number::from(0)),       // minconf
-                Json::Number(serde_json::Number::from(9999999)), // maxconf (default)
-                Json::Array(vec![Json::String(unvault_address)]),
+                Json::Number(serde_json::Number::from(0)), // minconf
+                Json::Bool(false),                         // include_empty
+                Json::Bool(true),                          // include_watchonly
             ),
         )?;
-        let utxos = res.as_array().ok_or_else(|| {
-            BitcoindError::Custom("API break: 'listunspent' didn't return an array".to_string())
+        let entries = res.as_array().ok_or_else(|| {
+            BitcoindError::Custom(
+                "API break: 'listreceivedbyaddress' didn't return an array".to_string(),
+            )
         })?;
 
-        for utxo in utxos {
-            let outpoint = self.outpoint_from_utxo(&utxo)?;
-            for prev_outpoint in self.previous_outpoints(&outpoint)? {
-                if &prev_outpoint == vault_outpoint {
-                    return Ok(Some(outpoint));
-                }
+        let deposit_label = Json::String(self.deposit_utxos_label());
+        let mut received = HashMap::with_capacity(entries.len());
+        for entry in entries {
+            if entry.get("label") != Some(&deposit_label) {
+                continue;
             }
+            let address = entry
+                .get("address")
+                .and_then(|a| a.as_str())
+                .ok_or_else(|| {
+                    BitcoindError::Custom(
+                        "API break: 'listreceivedbyaddress' entry without a valid 'address'"
+                            .to_string(),
+                    )
+                })?;
+            let script_pubkey = Address::from_str(address)
+                .map_err(|e| {
+                    BitcoindError::Custom(format!(
+                        "Could not parse 'address' from 'listreceivedbyaddress' entry: {}",
+                        e
+                    ))
+                })?
+                .script_pubkey();
+            let txids = entry
+                .get("txids")
+                .and_then(|t| t.as_array())
+                .ok_or_else(|| {
+                    BitcoindError::Custom(
+                        "API break: 'listreceivedbyaddress' entry without a valid 'txids'"
+                            .to_string(),
+                    )
+                })?
+                .iter()
+                .map(|txid| {
+                    txid.as_str()
+                        .and_then(|txid| Txid::from_str(txid).ok())
+                        .ok_or_else(|| {
+                            BitcoindError::Custom(format!(
+                                "API break: invalid txid '{}' in 'listreceivedbyaddress'",
+                                txid
+                            ))
+                        })
+                })
+                .collect::<Result<Vec<Txid>, BitcoindError>>()?;
+            received.insert(script_pubkey, txids);
         }
 
-        Ok(None)
+        Ok(received)
     }
+
+    /// Is this output unspent, including by the transactions in the mempool?
+    pub fn is_unspent(&self, outpoint: &OutPoint) -> Result<bool, BitcoindError> {
+        let res = self.make_node_request(
+            "gettxout",
+            &params!(
+                Json::String(outpoint.txid.to_string()),
+                Json::Number(serde_json::Number::from(outpoint.vout)),
+                Json::Bool(true), // include_mempool
+            ),
+        )?;
+
+        Ok(!res.is_null())
+    }
+
+    /// Is this transaction in bitcoind's mempool?
+    pub fn is_in_mempool(&self, txid: &Txid) -> Result<bool, BitcoindError> {
+        match self.make_node_request("getmempoolentry", &params!(Json::String(txid.to_string()))) {
+            Ok(_) => Ok(true),
+            Err(BitcoindError::Server(Error::Rpc(ref e))) if e.code == NOT_IN_MEMPOOL => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The transactions of our watchonly wallet since this block, all of them if None.
+    pub fn list_since_block(
+        &self,
+        block: Option<&BlockHash>,
+    ) -> Result<WalletChanges, BitcoindError> {
+        let res = self.make_watchonly_request(
+            "listsinceblock",
+            &params!(
+                block
+                    .map(|hash| Json::String(hash.to_string()))
+                    .unwrap_or(Json::Null),
+                Json::Number(serde_json::Number::from(1)), // target_confirmations
+                Json::Bool(true),                          // include_watchonly
+                Json::Bool(true),                          // include_removed
+            ),
+        )?;
+
+        let txids = |key: &str| -> Result<Vec<(Txid, i64)>, BitcoindError> {
+            res.get(key)
+                .and_then(|entries| entries.as_array())
+                .ok_or_else(|| {
+                    BitcoindError::Custom(format!(
+                        "API break: 'listsinceblock' has no '{}' array",
+                        key
+                    ))
+                })?
+                .iter()
+                .map(|entry| {
+                    let txid = entry
+                        .get("txid")
+                        .and_then(|txid| txid.as_str())
+                        .and_then(|txid| Txid::from_str(txid).ok());
+                    let confirmations = entry.get("confirmations").and_then(|c| c.as_i64());
+                    match (txid, confirmations) {
+                        (Some(txid), Some(confirmations)) => Ok((txid, confirmations)),
+                        _ => Err(BitcoindError::Custom(format!(
+                            "API break: invalid 'listsinceblock' entry: '{}'",
+                            entry
+                        ))),
+                    }
+                })
+                .collect()
+        };
+
+        // There is an entry per output (or input) of ours, and the conflicted transactions
+        // have a negative number of confirmations.
+        let mut changes = WalletChanges {
+            txids: Vec::new(),
+            removed: Vec::new(),
+            last_block: BlockHash::from_str(
+                res.get("lastblock")
+                    .and_then(|hash| hash.as_str())
+                    .ok_or_else(|| {
+                        BitcoindError::Custom(
+                            "API break: 'listsinceblock' has no 'lastblock'".to_string(),
+                        )
+                    })?,
+            )
+            .map_err(|e| {
+                BitcoindError::Custom(format!("Invalid 'lastblock' in 'listsinceblock': {}", e))
+            })?,
+        };
+        for (txid, confirmations) in txids("transactions")? {
+            let list = if confirmations < 0 {
+                &mut changes.removed
+            } else {
+                &mut changes.txids
+            };
+            if !list.contains(&txid) {
+                list.push(txid);
+            }
+        }
+        for (txid, _) in txids("removed")? {
+            if !changes.removed.contains(&txid) {
+                changes.removed.push(txid);
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+/// What happened to our watchonly wallet since a given block
+#[derive(Debug)]
+pub struct WalletChanges {
+    /// The transactions confirmed after this block or unconfirmed
+    pub txids: Vec<Txid>,
+    /// The transactions that were reorged out, or conflicted
+    pub removed: Vec<Txid>,
+    /// Our tip, where to start from next time
+    pub last_block: BlockHash,
 }
 
 pub struct SyncInfo {
@@ -6,10 +6,10 @@ use jsonrpc::{
 };
 
 pub mod actions;
+pub mod consistency;
 pub mod interface;
-
-/// Minimum number of confirmations before treating a deposit as confirmed
-const MIN_CONF: u64 = 6;
+pub mod notifications;
+pub mod spends;
 
 /// An error happened in the bitcoind-manager thread
 #[derive(Debug)]
@@ -59,3 +59,10 @@ impl From<revault_tx::Error> for BitcoindError {
         Self::Custom(format!("revault_tx error in bitcoind thread: {}", e))
     }
 }
+
+#[cfg(feature = "zmq")]
+impl From<zmq::Error> for BitcoindError {
+    fn from(e: zmq::Error) -> Self {
+        Self::Custom(format!("ZMQ error: {}", e))
+    }
+}
@@ -0,0 +1,133 @@
+//! Listen to bitcoind's ZMQ notifications, so that the poller reacts to a new block or to a
+//! transaction right away instead of at its next poll. The poller keeps polling anyway, we
+//! could miss a notification (ZMQ doesn't guarantee delivery, and we drop them while the poller
+//! is busy). Needs the `zmq` feature.
+
+use crate::bitcoind::BitcoindError;
+use common::config::BitcoindConfig;
+#[cfg(feature = "zmq")]
+use revault_tx::bitcoin::consensus::encode;
+use revault_tx::bitcoin::Transaction;
+
+#[cfg(feature = "zmq")]
+use std::sync::{atomic::Ordering, mpsc::TrySendError};
+use std::{
+    sync::{atomic::AtomicBool, mpsc::SyncSender, Arc},
+    thread,
+};
+
+/// How many notifications may wait for the poller. Past that, it's busy and will poll afterwards
+/// anyway: we drop them.
+pub const NOTIFICATIONS_QUEUE_SIZE: usize = 100;
+
+// How often we check whether we were asked to shut down, in milliseconds
+#[cfg(feature = "zmq")]
+const RECV_TIMEOUT_MS: i32 = 500;
+
+#[derive(Debug)]
+#[cfg_attr(not(feature = "zmq"), allow(dead_code))]
+pub enum Notification {
+    /// A new block was connected
+    Block,
+    /// A transaction entered the mempool or was confirmed
+    Transaction(Transaction),
+}
+
+#[cfg(feature = "zmq")]
+fn notifications_loop(
+    socket: zmq::Socket,
+    notif_tx: SyncSender<Notification>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), BitcoindError> {
+    while !shutdown.load(Ordering::Relaxed) {
+        // A notification is made of the topic, the body and a sequence number.
+        let parts = match socket.recv_multipart(0) {
+            Ok(parts) => parts,
+            Err(zmq::Error::EAGAIN) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let notif = match (parts.get(0).map(|t| t.as_slice()), parts.get(1)) {
+            (Some(b"hashblock"), Some(_)) => Notification::Block,
+            (Some(b"rawtx"), Some(raw_tx)) => match encode::deserialize(raw_tx) {
+                Ok(tx) => Notification::Transaction(tx),
+                Err(e) => {
+                    log::error!("Invalid transaction in ZMQ notification: {}", e);
+                    continue;
+                }
+            },
+            _ => {
+                log::debug!("Ignoring unexpected ZMQ message: {:?}", parts);
+                continue;
+            }
+        };
+
+        match notif_tx.try_send(notif) {
+            Ok(()) => {}
+            Err(TrySendError::Full(notif)) => {
+                log::trace!("Poller busy, dropping ZMQ notification {:?}", notif)
+            }
+            // The poller is gone, so are we.
+            Err(TrySendError::Disconnected(_)) => return Ok(()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribe to the ZMQ notifications configured for bitcoind, if any, and forward them to the
+/// poller in a new thread.
+#[cfg(feature = "zmq")]
+pub fn start_notifications_listener(
+    bitcoind_config: &BitcoindConfig,
+    notif_tx: SyncSender<Notification>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<Option<thread::JoinHandle<Result<(), BitcoindError>>>, BitcoindError> {
+    let subscriptions: Vec<(&[u8], &String)> = vec![
+        (&b"hashblock"[..], bitcoind_config.zmq_hashblock.as_ref()),
+        (&b"rawtx"[..], bitcoind_config.zmq_rawtx.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(topic, endpoint)| endpoint.map(|endpoint| (topic, endpoint)))
+    .collect();
+    if subscriptions.is_empty() {
+        return Ok(None);
+    }
+
+    let socket = zmq::Context::new().socket(zmq::SUB)?;
+    socket.set_rcvtimeo(RECV_TIMEOUT_MS)?;
+    // bitcoind may publish both on the same endpoint, don't get the notifications twice.
+    let mut endpoints: Vec<&String> = Vec::with_capacity(subscriptions.len());
+    for (topic, endpoint) in subscriptions {
+        if !endpoints.contains(&endpoint) {
+            socket.connect(endpoint)?;
+            endpoints.push(endpoint);
+        }
+        socket.set_subscribe(topic)?;
+        log::info!(
+            "Listening to bitcoind's '{}' ZMQ notifications at '{}'",
+            String::from_utf8_lossy(topic),
+            endpoint
+        );
+    }
+
+    Ok(Some(thread::spawn(move || {
+        notifications_loop(socket, notif_tx, shutdown)
+    })))
+}
+
+/// We were built without ZMQ support, the poller will only poll.
+#[cfg(not(feature = "zmq"))]
+pub fn start_notifications_listener(
+    bitcoind_config: &BitcoindConfig,
+    _notif_tx: SyncSender<Notification>,
+    _shutdown: Arc<AtomicBool>,
+) -> Result<Option<thread::JoinHandle<Result<(), BitcoindError>>>, BitcoindError> {
+    if bitcoind_config.zmq_hashblock.is_some() || bitcoind_config.zmq_rawtx.is_some() {
+        log::warn!(
+            "Ignoring the ZMQ endpoints in the configuration: revaultd was built without the \
+             'zmq' feature."
+        );
+    }
+
+    Ok(None)
+}
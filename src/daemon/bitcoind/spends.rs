@@ -0,0 +1,670 @@
+//! Find the transactions spending our vaults. Rather than asking bitcoind about each spent
+//! outpoint, we go through the new transactions of the watchonly wallet since the last block we
+//! processed (they are in there as they spend our coins), match their inputs against the
+//! deposit and Unvault outputs of our vaults, and tell what they are from their outputs.
+//! The spends are kept in memory, and rebuilt at startup from the transactions we stored for our
+//! vaults. Only the last block we went through is persisted.
+
+use crate::{
+    bitcoind::{actions::decode_wallet_tx, interface::BitcoinD, BitcoindError},
+    database::{
+        interface::{db_onchain_transactions_by_type, db_spends_last_block, db_vaults},
+        schema::OnchainTransactionType,
+    },
+    revaultd::RevaultD,
+};
+use revault_tx::bitcoin::{
+    hashes::{sha256, Hash},
+    util::bip32::ChildNumber,
+    BlockHash, OutPoint, Script, Transaction, Txid,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// What spent an output of one of our vaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpendKind {
+    /// The deposit was spent by the Unvault transaction
+    Unvault,
+    /// The deposit was spent by the Emergency transaction
+    Emergency,
+    /// The deposit was spent by anything else. Without the Emergency address hash, an
+    /// Emergency transaction looks like a bypass.
+    Bypass,
+    /// The Unvault output was spent by the Cancel transaction
+    Cancel,
+    /// The Unvault output was spent by the Unvault Emergency transaction
+    UnvaultEmergency,
+    /// The Unvault output was spent by anything else, which must be a Spend transaction
+    Spend,
+}
+
+impl fmt::Display for SpendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unvault => write!(f, "unvault"),
+            Self::Emergency => write!(f, "emergency"),
+            Self::Bypass => write!(f, "bypass"),
+            Self::Cancel => write!(f, "cancel"),
+            Self::UnvaultEmergency => write!(f, "unvault_emergency"),
+            Self::Spend => write!(f, "spend"),
+        }
+    }
+}
+
+/// A transaction spending an output of one of our vaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VaultSpend {
+    /// The deposit outpoint of the vault
+    pub deposit_outpoint: OutPoint,
+    pub txid: Txid,
+    pub kind: SpendKind,
+}
+
+/// The spends of our vaults we found so far in the watchonly wallet
+#[derive(Debug, Default)]
+pub struct VaultSpends {
+    // Where we stopped last time, None if we never went through the wallet transactions
+    last_block: Option<BlockHash>,
+    // The transactions we already went through
+    seen: HashSet<Txid>,
+    // The transactions spending our vaults that were still in the mempool last time we checked
+    unconfirmed: HashSet<Txid>,
+    // The Unvault outputs we found, along with the deposit outpoint and derivation index of
+    // their vault
+    unvault_outputs: HashMap<OutPoint, (OutPoint, ChildNumber)>,
+    // The spends we found, by spent outpoint
+    spends: HashMap<OutPoint, VaultSpend>,
+    // The spends we found since they were last taken
+    new_spends: Vec<VaultSpend>,
+}
+
+// Whether this script is the Emergency address' one
+fn pays_emergency(revaultd: &RevaultD, script_pubkey: &Script) -> bool {
+    revaultd.emergency_address_hash == Some(sha256::Hash::hash(script_pubkey.as_bytes()))
+}
+
+impl VaultSpends {
+    pub fn new() -> VaultSpends {
+        VaultSpends::default()
+    }
+
+    /// Rebuild the spends from the transactions we stored for our vaults, and resume going
+    /// through the wallet transactions from the last block we processed.
+    pub fn from_db(revaultd: &RevaultD) -> Result<VaultSpends, BitcoindError> {
+        let mut vault_spends = VaultSpends {
+            last_block: db_spends_last_block(&revaultd.db)?,
+            ..VaultSpends::default()
+        };
+
+        // A transaction spending many vaults is stored once per vault
+        let mut txs: HashMap<Txid, (Transaction, bool)> = HashMap::new();
+        for tx_type in [
+            OnchainTransactionType::Unvault,
+            OnchainTransactionType::Emergency,
+            OnchainTransactionType::Cancel,
+            OnchainTransactionType::UnvaultEmergency,
+            OnchainTransactionType::Spend,
+        ]
+        .iter()
+        {
+            for db_tx in db_onchain_transactions_by_type(&revaultd.db, *tx_type)? {
+                txs.insert(db_tx.tx.txid(), (db_tx.tx, db_tx.blockheight.is_some()));
+            }
+        }
+
+        let txs: Vec<Transaction> = txs
+            .into_iter()
+            .map(|(_, (tx, confirmed))| {
+                if !confirmed {
+                    vault_spends.unconfirmed.insert(tx.txid());
+                }
+                tx
+            })
+            .collect();
+        vault_spends.record_spends(revaultd, &txs)?;
+        // We already applied them
+        vault_spends.new_spends.clear();
+
+        Ok(vault_spends)
+    }
+
+    /// What spent the deposit at this outpoint, as of our last update
+    pub fn deposit_spend(&self, deposit_outpoint: &OutPoint) -> Option<&VaultSpend> {
+        self.spends.get(deposit_outpoint)
+    }
+
+    /// What spent the Unvault output of the vault at this deposit outpoint, as of our last update
+    pub fn unvault_spend(&self, deposit_outpoint: &OutPoint) -> Option<&VaultSpend> {
+        self.spends.values().find(|spend| {
+            spend.deposit_outpoint == *deposit_outpoint
+                && matches!(
+                    spend.kind,
+                    SpendKind::Cancel | SpendKind::UnvaultEmergency | SpendKind::Spend
+                )
+        })
+    }
+
+    /// The spends we found since the last call, in the order we found them
+    pub fn take_new_spends(&mut self) -> Vec<VaultSpend> {
+        std::mem::take(&mut self.new_spends)
+    }
+
+    /// Go through all the wallet transactions again on the next update, for instance after a
+    /// rescan added older ones. The spends we already know about aren't reported again.
+    pub fn rewind(&mut self) {
+        self.last_block = None;
+    }
+
+    /// The last block we went through the wallet transactions up to
+    pub fn last_block(&self) -> Option<&BlockHash> {
+        self.last_block.as_ref()
+    }
+
+    // This transaction was reorged out, evicted from the mempool, or replaced. We'll see it again
+    // if it gets mined.
+    fn forget(&mut self, txid: &Txid) {
+        self.seen.remove(txid);
+        self.unconfirmed.remove(txid);
+        self.spends.retain(|_, spend| spend.txid != *txid);
+        self.new_spends.retain(|spend| spend.txid != *txid);
+        self.unvault_outputs
+            .retain(|unvault_outpoint, _| unvault_outpoint.txid != *txid);
+    }
+
+    fn deposit_spent(
+        &mut self,
+        revaultd: &RevaultD,
+        tx: &Transaction,
+        deposit_outpoint: OutPoint,
+        derivation_index: ChildNumber,
+    ) -> VaultSpend {
+        let txid = tx.txid();
+        let unvault_script = revaultd.unvault_address(derivation_index).script_pubkey();
+        let kind = match tx
+            .output
+            .iter()
+            .position(|txo| txo.script_pubkey == unvault_script)
+        {
+            Some(vout) => {
+                self.unvault_outputs.insert(
+                    OutPoint::new(txid, vout as u32),
+                    (deposit_outpoint, derivation_index),
+                );
+                SpendKind::Unvault
+            }
+            None if tx
+                .output
+                .iter()
+                .any(|txo| pays_emergency(revaultd, &txo.script_pubkey)) =>
+            {
+                SpendKind::Emergency
+            }
+            None => SpendKind::Bypass,
+        };
+
+        let spend = VaultSpend {
+            deposit_outpoint,
+            txid,
+            kind,
+        };
+        self.spends.insert(deposit_outpoint, spend);
+        spend
+    }
+
+    fn unvault_spent(
+        &mut self,
+        revaultd: &RevaultD,
+        tx: &Transaction,
+        unvault_outpoint: OutPoint,
+        deposit_outpoint: OutPoint,
+        derivation_index: ChildNumber,
+    ) -> VaultSpend {
+        // The Cancel transaction pays back to the deposit descriptor, at the same index.
+        let deposit_script = revaultd.vault_address(derivation_index).script_pubkey();
+        let kind = if tx
+            .output
+            .iter()
+            .any(|txo| txo.script_pubkey == deposit_script)
+        {
+            SpendKind::Cancel
+        } else if tx
+            .output
+            .iter()
+            .any(|txo| pays_emergency(revaultd, &txo.script_pubkey))
+        {
+            SpendKind::UnvaultEmergency
+        } else {
+            SpendKind::Spend
+        };
+
+        let spend = VaultSpend {
+            deposit_outpoint,
+            txid: tx.txid(),
+            kind,
+        };
+        self.spends.insert(unvault_outpoint, spend);
+        spend
+    }
+
+    // Record the spends of our vaults by these transactions
+    fn record_spends(
+        &mut self,
+        revaultd: &RevaultD,
+        txs: &[Transaction],
+    ) -> Result<(), BitcoindError> {
+        // The deposits first, as their Unvault may be spent by another of these transactions.
+        let deposits: HashMap<OutPoint, ChildNumber> = db_vaults(&revaultd.db)?
+            .into_iter()
+            .map(|db_vault| (db_vault.deposit_outpoint, db_vault.derivation_index))
+            .collect();
+        for tx in txs.iter() {
+            for txin in tx.input.iter() {
+                if let Some(derivation_index) = deposits.get(&txin.previous_output) {
+                    let spend =
+                        self.deposit_spent(revaultd, tx, txin.previous_output, *derivation_index);
+                    self.new_spends.push(spend);
+                }
+            }
+        }
+        for tx in txs.iter() {
+            for txin in tx.input.iter() {
+                if let Some((deposit_outpoint, derivation_index)) =
+                    self.unvault_outputs.get(&txin.previous_output).copied()
+                {
+                    let spend = self.unvault_spent(
+                        revaultd,
+                        tx,
+                        txin.previous_output,
+                        deposit_outpoint,
+                        derivation_index,
+                    );
+                    self.new_spends.push(spend);
+                }
+            }
+        }
+
+        self.seen.extend(txs.iter().map(|tx| tx.txid()));
+        Ok(())
+    }
+
+    /// Go through the wallet transactions since our last update, and record in memory the ones
+    /// spending the vaults. Forget about the spends whose transaction was reorged out, or left
+    /// the mempool without being mined. The new spends are queued until taken.
+    pub fn update(
+        &mut self,
+        revaultd: &RevaultD,
+        bitcoind: &BitcoinD,
+    ) -> Result<(), BitcoindError> {
+        let changes = bitcoind.list_since_block(self.last_block.as_ref())?;
+        for txid in changes.removed.iter() {
+            self.forget(txid);
+        }
+
+        // An unconfirmed spend may have been evicted from the mempool, or replaced.
+        for txid in self.unconfirmed.clone().iter() {
+            if bitcoind.is_in_mempool(txid)? {
+                continue;
+            }
+            if bitcoind.get_wallet_transaction(txid)?.blockheight.is_some() {
+                self.unconfirmed.remove(txid);
+            } else {
+                log::debug!("Spending transaction '{}' left the mempool", txid);
+                self.forget(txid);
+            }
+        }
+
+        let mut txs = Vec::with_capacity(changes.txids.len());
+        let mut unconfirmed = HashSet::new();
+        for txid in changes.txids {
+            if self.seen.contains(&txid) {
+                continue;
+            }
+            let wallet_tx = bitcoind.get_wallet_transaction(&txid)?;
+            if wallet_tx.blockheight.is_none() {
+                // The wallet keeps the transactions that were evicted or replaced. Don't mark
+                // them as seen, so we record them if they ever get mined.
+                if !bitcoind.is_in_mempool(&txid)? {
+                    continue;
+                }
+                unconfirmed.insert(txid);
+            }
+            txs.push(decode_wallet_tx(&txid, &wallet_tx)?);
+        }
+
+        self.record_spends(revaultd, &txs)?;
+        let spends = &self.spends;
+        self.unconfirmed.extend(
+            unconfirmed
+                .into_iter()
+                .filter(|txid| spends.values().any(|spend| spend.txid == *txid)),
+        );
+        self.last_block = Some(changes.last_block);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            actions::{
+                db_insert_new_unconfirmed_vault, db_store_onchain_tx, db_update_spends_last_block,
+                setup_db,
+            },
+            interface::{db_vault_by_deposit, DbConnection},
+        },
+        revaultd::VaultStatus,
+    };
+    use common::config::Config;
+    use revault_tx::bitcoin::{Address, Amount, TxIn, TxOut};
+
+    use std::{fs, path::PathBuf, str::FromStr};
+
+    // A RevaultD with its own database, so that the tests don't erase each other's
+    fn dummy_revaultd(test_name: &str) -> RevaultD {
+        let mut config_path = PathBuf::from(file!()).parent().unwrap().to_path_buf();
+        config_path.push("../../../test_data/datadir/config.toml");
+        let mut datadir_path = std::env::temp_dir();
+        datadir_path.push(format!("revaultd-{}-{}", test_name, std::process::id()));
+        fs::remove_dir_all(&datadir_path).unwrap_or_else(|_| {});
+        fs::create_dir_all(&datadir_path).unwrap();
+
+        let config = Config::from_file(Some(config_path)).expect("Parsing valid config file");
+        let mut revaultd = RevaultD::from_config(config).expect("Creating state from config");
+        revaultd.data_dir = datadir_path;
+        revaultd.db = DbConnection::new(revaultd.db_file());
+        setup_db(&mut revaultd).unwrap();
+
+        revaultd
+    }
+
+    // Insert a vault at this derivation index, and return its deposit outpoint
+    fn dummy_vault(revaultd: &RevaultD, index: u32) -> OutPoint {
+        let deposit_outpoint = OutPoint::new(Txid::hash(&index.to_be_bytes()), 0);
+        db_insert_new_unconfirmed_vault(
+            &revaultd.db,
+            revaultd.wallet_id.unwrap(),
+            &VaultStatus::Unconfirmed,
+            &deposit_outpoint,
+            &Amount::from_sat(1_000_000),
+            ChildNumber::from(index),
+        )
+        .unwrap();
+        deposit_outpoint
+    }
+
+    fn dummy_tx(inputs: &[OutPoint], outputs: Vec<Script>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xff_ff_ff_ff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 900_000,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    fn emergency_script() -> Script {
+        Address::from_str("bc1qwqdg6squsna38e46795at95yu9atm8azzmyvckulcc7kytlcckxswvvzej")
+            .unwrap()
+            .script_pubkey()
+    }
+
+    fn unvault_tx(revaultd: &RevaultD, deposit_outpoint: OutPoint, index: u32) -> Transaction {
+        let index = ChildNumber::from(index);
+        dummy_tx(
+            &[deposit_outpoint],
+            vec![
+                revaultd.unvault_address(index).script_pubkey(),
+                revaultd.cpfp_address(index).script_pubkey(),
+            ],
+        )
+    }
+
+    fn clear_datadir(revaultd: &RevaultD) {
+        fs::remove_dir_all(&revaultd.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_spends_classification() {
+        let revaultd = dummy_revaultd("spends-classification");
+        let outpoints: Vec<OutPoint> = (0..6).map(|i| dummy_vault(&revaultd, i)).collect();
+
+        // The deposits spends
+        let unvault_txs: Vec<Transaction> = (3..6)
+            .map(|i| unvault_tx(&revaultd, outpoints[i], i as u32))
+            .collect();
+        let emer_tx = dummy_tx(&[outpoints[1]], vec![emergency_script()]);
+        let bypass_tx = dummy_tx(&[outpoints[2]], vec![Script::new()]);
+        let mut txs = vec![emer_tx.clone(), bypass_tx.clone()];
+        txs.extend(unvault_txs.iter().cloned());
+        let mut spends = VaultSpends::new();
+        spends.record_spends(&revaultd, &txs).unwrap();
+
+        assert!(spends.deposit_spend(&outpoints[0]).is_none());
+        assert_eq!(
+            spends.deposit_spend(&outpoints[1]),
+            Some(&VaultSpend {
+                deposit_outpoint: outpoints[1],
+                txid: emer_tx.txid(),
+                kind: SpendKind::Emergency,
+            })
+        );
+        assert_eq!(
+            spends.deposit_spend(&outpoints[2]).unwrap().kind,
+            SpendKind::Bypass
+        );
+        for (i, tx) in (3..6).zip(unvault_txs.iter()) {
+            let spend = spends.deposit_spend(&outpoints[i]).unwrap();
+            assert_eq!(spend.kind, SpendKind::Unvault);
+            assert_eq!(spend.txid, tx.txid());
+        }
+        assert_eq!(spends.take_new_spends().len(), 5);
+        assert!(spends.take_new_spends().is_empty());
+        assert!(spends.unvault_spend(&outpoints[3]).is_none());
+
+        // The Unvault outputs spends
+        let unvault_outpoints: Vec<OutPoint> = unvault_txs
+            .iter()
+            .map(|tx| OutPoint::new(tx.txid(), 0))
+            .collect();
+        let cancel_tx = dummy_tx(
+            &[unvault_outpoints[0]],
+            vec![revaultd.vault_address(ChildNumber::from(3)).script_pubkey()],
+        );
+        let unemer_tx = dummy_tx(&[unvault_outpoints[1]], vec![emergency_script()]);
+        let spend_tx = dummy_tx(&[unvault_outpoints[2]], vec![Script::new()]);
+        spends
+            .record_spends(
+                &revaultd,
+                &[cancel_tx.clone(), unemer_tx.clone(), spend_tx.clone()],
+            )
+            .unwrap();
+        assert_eq!(
+            spends.unvault_spend(&outpoints[3]).unwrap().txid,
+            cancel_tx.txid()
+        );
+        let new_spends = spends.take_new_spends();
+        assert_eq!(
+            new_spends,
+            vec![
+                VaultSpend {
+                    deposit_outpoint: outpoints[3],
+                    txid: cancel_tx.txid(),
+                    kind: SpendKind::Cancel,
+                },
+                VaultSpend {
+                    deposit_outpoint: outpoints[4],
+                    txid: unemer_tx.txid(),
+                    kind: SpendKind::UnvaultEmergency,
+                },
+                VaultSpend {
+                    deposit_outpoint: outpoints[5],
+                    txid: spend_tx.txid(),
+                    kind: SpendKind::Spend,
+                },
+            ]
+        );
+
+        // An Unvault and the spend of its output in the same batch, the latter first
+        let index = 0;
+        let unvault = unvault_tx(&revaultd, outpoints[index], index as u32);
+        let cancel = dummy_tx(
+            &[OutPoint::new(unvault.txid(), 0)],
+            vec![revaultd
+                .vault_address(ChildNumber::from(index as u32))
+                .script_pubkey()],
+        );
+        spends
+            .record_spends(&revaultd, &[cancel.clone(), unvault.clone()])
+            .unwrap();
+        let kinds: Vec<SpendKind> = spends
+            .take_new_spends()
+            .into_iter()
+            .map(|spend| spend.kind)
+            .collect();
+        assert_eq!(kinds, vec![SpendKind::Unvault, SpendKind::Cancel]);
+
+        clear_datadir(&revaultd);
+    }
+
+    #[test]
+    fn test_spends_forget() {
+        let revaultd = dummy_revaultd("spends-forget");
+        let deposit_outpoint = dummy_vault(&revaultd, 0);
+        let unvault = unvault_tx(&revaultd, deposit_outpoint, 0);
+        let unvault_outpoint = OutPoint::new(unvault.txid(), 0);
+        let cancel = dummy_tx(
+            &[unvault_outpoint],
+            vec![revaultd.vault_address(ChildNumber::from(0)).script_pubkey()],
+        );
+
+        let mut spends = VaultSpends::new();
+        spends
+            .record_spends(&revaultd, &[unvault.clone(), cancel.clone()])
+            .unwrap();
+        spends.unconfirmed.insert(cancel.txid());
+        assert_eq!(spends.take_new_spends().len(), 2);
+
+        // The Cancel was evicted from the mempool: the vault is back to its Unvault
+        spends.forget(&cancel.txid());
+        assert!(!spends.seen.contains(&cancel.txid()));
+        assert!(spends.unconfirmed.is_empty());
+        assert!(spends.spends.get(&unvault_outpoint).is_none());
+        assert_eq!(
+            spends.deposit_spend(&deposit_outpoint).unwrap().kind,
+            SpendKind::Unvault
+        );
+
+        // It is reported again if it gets mined
+        spends.record_spends(&revaultd, &[cancel.clone()]).unwrap();
+        assert_eq!(
+            spends.take_new_spends(),
+            vec![VaultSpend {
+                deposit_outpoint,
+                txid: cancel.txid(),
+                kind: SpendKind::Cancel,
+            }]
+        );
+
+        // The Unvault was reorged out before we took the spends: we forget about both it and its
+        // output.
+        spends.record_spends(&revaultd, &[unvault.clone()]).unwrap();
+        spends.forget(&unvault.txid());
+        assert!(spends.take_new_spends().is_empty());
+        assert!(spends.deposit_spend(&deposit_outpoint).is_none());
+        assert!(spends.unvault_outputs.is_empty());
+        spends.record_spends(&revaultd, &[cancel]).unwrap();
+        assert!(spends.take_new_spends().is_empty());
+
+        clear_datadir(&revaultd);
+    }
+
+    #[test]
+    fn test_spends_from_db() {
+        let revaultd = dummy_revaultd("spends-from-db");
+
+        // Nothing to resume from
+        let spends = VaultSpends::from_db(&revaultd).unwrap();
+        assert!(spends.last_block().is_none());
+        assert!(spends.spends.is_empty());
+
+        // A confirmed Unvault, whose output is being spent by an unconfirmed Cancel
+        let deposit_outpoint = dummy_vault(&revaultd, 0);
+        let vault_id = db_vault_by_deposit(&revaultd.db, &deposit_outpoint)
+            .unwrap()
+            .unwrap()
+            .id;
+        let unvault = unvault_tx(&revaultd, deposit_outpoint, 0);
+        let unvault_outpoint = OutPoint::new(unvault.txid(), 0);
+        let cancel = dummy_tx(
+            &[unvault_outpoint],
+            vec![revaultd.vault_address(ChildNumber::from(0)).script_pubkey()],
+        );
+        db_store_onchain_tx(
+            &revaultd.db,
+            vault_id,
+            OnchainTransactionType::Unvault,
+            &unvault,
+            Some(101),
+            Some(1_600_000_000),
+            1_600_000_000,
+        )
+        .unwrap();
+        db_store_onchain_tx(
+            &revaultd.db,
+            vault_id,
+            OnchainTransactionType::Cancel,
+            &cancel,
+            None,
+            None,
+            1_600_000_100,
+        )
+        .unwrap();
+        let last_block = BlockHash::hash(&[1]);
+        db_update_spends_last_block(&revaultd.db, &last_block).unwrap();
+
+        let mut spends = VaultSpends::from_db(&revaultd).unwrap();
+        assert_eq!(spends.last_block(), Some(&last_block));
+        assert_eq!(
+            spends.deposit_spend(&deposit_outpoint).unwrap().txid,
+            unvault.txid()
+        );
+        assert_eq!(
+            spends.spends.get(&unvault_outpoint).unwrap().kind,
+            SpendKind::Cancel
+        );
+        assert!(spends.seen.contains(&unvault.txid()) && spends.seen.contains(&cancel.txid()));
+        assert_eq!(
+            spends.unconfirmed.iter().collect::<Vec<&Txid>>(),
+            vec![&cancel.txid()]
+        );
+        // They were applied already
+        assert!(spends.take_new_spends().is_empty());
+
+        // Rewinding doesn't touch the persisted block
+        spends.rewind();
+        assert!(spends.last_block().is_none());
+        let spends = VaultSpends::from_db(&revaultd).unwrap();
+        assert_eq!(spends.last_block(), Some(&last_block));
+
+        clear_datadir(&revaultd);
+    }
+}
@@ -7,12 +7,19 @@
 //! command sent to the RPC server. This control handling is what happens here.
 
 use crate::{
+    audit::{audit_vault, AuditError},
+    backup::create_backup,
     bitcoind::BitcoindError,
     database::{
-        actions::db_update_presigned_tx,
+        actions::{db_set_vault_label, db_store_onchain_tx, db_update_presigned_tx},
         interface::{
-            db_cancel_transaction, db_emer_transaction, db_tip, db_unvault_emer_transaction,
-            db_unvault_transaction, db_vault_by_deposit, db_vaults,
+            db_balance, db_cancel_transaction, db_emer_transaction, db_list_vaults,
+            db_onchain_transaction, db_presigned_transactions, db_tip, db_unvault_emer_transaction,
+            db_unvault_transaction, db_vault_by_deposit, db_vault_events, db_vault_label,
+            db_vault_onchain_transactions, db_vaults, DbConnection, VaultsQuery,
+        },
+        schema::{
+            DbOnchainTransaction, DbVault, OnchainTransactionType, RevaultTx, TransactionType,
         },
         DatabaseError,
     },
@@ -20,13 +27,19 @@ use crate::{
     sigfetcher::presigned_tx_sighash,
     threadmessages::*,
 };
-use common::{assume_ok, assume_some};
+use common::{
+    assume_ok, assume_some,
+    emergency_kit::{EmergencyKit, KitVault, KIT_VERSION},
+};
 
 use revault_net::{message::server::Sig, transport::KKTransport};
 use revault_tx::{
     bitcoin::{
+        consensus::encode,
+        hashes::hex::FromHex,
         secp256k1::{self, Signature},
-        Network, OutPoint, PublicKey as BitcoinPubKey, SigHashType, Txid,
+        Amount, Network, OutPoint, PublicKey as BitcoinPubKey, SigHashType,
+        Transaction as BitcoinTransaction, Txid,
     },
     transactions::{
         transaction_chain, CancelTransaction, EmergencyTransaction, RevaultTransaction,
@@ -37,15 +50,14 @@ use revault_tx::{
 };
 
 use std::{
-    collections::BTreeMap,
-    fmt,
-    path::PathBuf,
-    process,
+    collections::{BTreeMap, HashMap},
+    fmt, process,
     sync::{
         mpsc::{self, Receiver, RecvError, SendError, Sender},
         Arc, RwLock,
     },
     thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 /// Any error that could arise during the process of executing the user's will.
@@ -107,6 +119,12 @@ impl From<revault_tx::error::TransactionCreationError> for ControlError {
     }
 }
 
+impl From<AuditError> for ControlError {
+    fn from(e: AuditError) -> Self {
+        Self::TransactionManagement(e.to_string())
+    }
+}
+
 // Ask bitcoind for a wallet transaction
 fn bitcoind_wallet_tx(
     bitcoind_tx: &Sender<BitcoindMessageOut>,
@@ -119,38 +137,119 @@ fn bitcoind_wallet_tx(
     bitrep_rx.recv().map_err(|e| e.into())
 }
 
-// List the vaults from DB, and filter out the info the RPC wants
-// FIXME: we could make this more efficient with smarter SQL queries
+fn wallet_tx_from_db(db_tx: DbOnchainTransaction) -> WalletTransaction {
+    WalletTransaction {
+        hex: encode::serialize_hex(&db_tx.tx),
+        blockheight: db_tx.blockheight,
+        blocktime: db_tx.blocktime,
+        received_time: db_tx.received_at,
+    }
+}
+
+// Whether a vault with this status may have had a transaction of this type broadcast
+fn may_be_broadcast(status: VaultStatus, tx_type: OnchainTransactionType) -> bool {
+    match tx_type {
+        OnchainTransactionType::Deposit => true,
+        OnchainTransactionType::Unvault => matches!(
+            status,
+            VaultStatus::Unvaulting
+                | VaultStatus::Unvaulted
+                | VaultStatus::Canceling
+                | VaultStatus::Canceled
+                | VaultStatus::UnvaultEmergencyVaulting
+                | VaultStatus::UnvaultEmergencyVaulted
+                | VaultStatus::Spendable
+                | VaultStatus::Spending
+                | VaultStatus::Spent
+        ),
+        OnchainTransactionType::Cancel => {
+            matches!(status, VaultStatus::Canceling | VaultStatus::Canceled)
+        }
+        OnchainTransactionType::Emergency => matches!(
+            status,
+            VaultStatus::EmergencyVaulting | VaultStatus::EmergencyVaulted
+        ),
+        OnchainTransactionType::UnvaultEmergency => matches!(
+            status,
+            VaultStatus::UnvaultEmergencyVaulting | VaultStatus::UnvaultEmergencyVaulted
+        ),
+        OnchainTransactionType::Spend => {
+            matches!(status, VaultStatus::Spending | VaultStatus::Spent)
+        }
+    }
+}
+
+// Get a transaction of this vault from our database. If we don't have it, or it wasn't confirmed
+// yet, ask bitcoind's wallet and store what it tells us for next time. We don't bother bitcoind
+// about the transactions the status of the vault says were never broadcast.
+fn vault_onchain_tx(
+    db: &DbConnection,
+    bitcoind_tx: &Sender<BitcoindMessageOut>,
+    db_vault: &DbVault,
+    tx_type: OnchainTransactionType,
+    txid: Txid,
+) -> Result<Option<WalletTransaction>, ControlError> {
+    let stored = db_onchain_transaction(db, db_vault.id, &txid)?;
+    match stored {
+        Some(ref db_tx) if db_tx.blockheight.is_some() => return Ok(stored.map(wallet_tx_from_db)),
+        None if !may_be_broadcast(db_vault.status, tx_type) => return Ok(None),
+        _ => {}
+    }
+
+    match bitcoind_wallet_tx(bitcoind_tx, txid)? {
+        Some(wallet_tx) => store_wallet_tx(db, db_vault.id, tx_type, txid, wallet_tx).map(Some),
+        // If bitcoind doesn't know about it (anymore), what we have is better than nothing.
+        None => Ok(stored.map(wallet_tx_from_db)),
+    }
+}
+
+// Keep a copy of what bitcoind's wallet told us about this transaction of the vault
+fn store_wallet_tx(
+    db: &DbConnection,
+    vault_id: u32,
+    tx_type: OnchainTransactionType,
+    txid: Txid,
+    wallet_tx: WalletTransaction,
+) -> Result<WalletTransaction, ControlError> {
+    let tx: BitcoinTransaction = Vec::<u8>::from_hex(&wallet_tx.hex)
+        .ok()
+        .and_then(|bytes| encode::deserialize(&bytes).ok())
+        .ok_or_else(|| {
+            ControlError::Bitcoind(format!("Invalid wallet transaction for '{}'", txid))
+        })?;
+    db_store_onchain_tx(
+        db,
+        vault_id,
+        tx_type,
+        &tx,
+        wallet_tx.blockheight,
+        wallet_tx.blocktime,
+        wallet_tx.received_time,
+    )?;
+
+    Ok(wallet_tx)
+}
+
+// List the vaults from DB matching this query, and add the info the RPC wants
 fn listvaults_from_db(
     revaultd: &RevaultD,
-    statuses: Option<Vec<VaultStatus>>,
-    outpoints: Option<Vec<OutPoint>>,
+    query: VaultsQuery,
 ) -> Result<Vec<ListVaultsEntry>, DatabaseError> {
-    db_vaults(&revaultd.db_file()).map(|db_vaults| {
+    db_list_vaults(&revaultd.db, &query).map(|db_vaults| {
         db_vaults
             .into_iter()
-            .filter_map(|db_vault| {
-                if let Some(ref statuses) = statuses {
-                    if !statuses.contains(&db_vault.status) {
-                        return None;
-                    }
-                }
-
-                if let Some(ref outpoints) = &outpoints {
-                    if !outpoints.contains(&db_vault.deposit_outpoint) {
-                        return None;
-                    }
-                }
-
+            .map(|(db_vault, label)| {
                 let address = revaultd.vault_address(db_vault.derivation_index);
-                Some(ListVaultsEntry {
+                ListVaultsEntry {
                     amount: db_vault.amount,
                     status: db_vault.status,
                     deposit_outpoint: db_vault.deposit_outpoint,
                     derivation_index: db_vault.derivation_index,
+                    blockheight: db_vault.blockheight,
                     updated_at: db_vault.updated_at,
                     address,
-                })
+                    label,
+                }
             })
             .collect()
     })
@@ -161,14 +260,14 @@ fn presigned_txs_list_from_outpoints(
     revaultd: &RevaultD,
     outpoints: Option<Vec<OutPoint>>,
 ) -> Result<Result<Vec<VaultPresignedTransactions>, RpcControlError>, ControlError> {
-    let db_path = &revaultd.db_file();
+    let db = &revaultd.db;
 
     // If they didn't provide us with a list of outpoints, catch'em all!
     let db_vaults = if let Some(outpoints) = outpoints {
         // FIXME: we can probably make this more efficient with some SQL magic
         let mut vaults = Vec::with_capacity(outpoints.len());
         for outpoint in outpoints.iter() {
-            if let Some(vault) = db_vault_by_deposit(db_path, &outpoint)? {
+            if let Some(vault) = db_vault_by_deposit(db, &outpoint)? {
                 // If it's unconfirmed, the presigned transactions are not in db!
                 match vault.status {
                     VaultStatus::Unconfirmed => {
@@ -185,20 +284,20 @@ fn presigned_txs_list_from_outpoints(
         }
         vaults
     } else {
-        db_vaults(db_path)?
+        db_vaults(db)?
     };
 
     let mut tx_list = Vec::with_capacity(db_vaults.len());
     for db_vault in db_vaults {
         let outpoint = db_vault.deposit_outpoint;
 
-        let (_, unvault) = db_unvault_transaction(db_path, db_vault.id)?;
-        let (_, cancel) = db_cancel_transaction(db_path, db_vault.id)?;
+        let (_, unvault) = db_unvault_transaction(db, db_vault.id)?;
+        let (_, cancel) = db_cancel_transaction(db, db_vault.id)?;
         let mut emergency = None;
         let mut unvault_emergency = None;
         if revaultd.is_stakeholder() {
-            emergency = Some(db_emer_transaction(db_path, db_vault.id)?.1);
-            unvault_emergency = Some(db_unvault_emer_transaction(db_path, db_vault.id)?.1);
+            emergency = Some(db_emer_transaction(db, db_vault.id)?.1);
+            unvault_emergency = Some(db_unvault_emer_transaction(db, db_vault.id)?.1);
         }
 
         tx_list.push(VaultPresignedTransactions {
@@ -213,20 +312,96 @@ fn presigned_txs_list_from_outpoints(
     Ok(Ok(tx_list))
 }
 
+// Compare the stored presigned transactions of these vaults (all the confirmed ones if None) to
+// the ones we derive from them.
+fn audit_presigned(
+    revaultd: &RevaultD,
+    outpoints: Option<Vec<OutPoint>>,
+) -> Result<Result<Vec<VaultAudit>, RpcControlError>, ControlError> {
+    let db = &revaultd.db;
+
+    let db_vaults = if let Some(outpoints) = outpoints {
+        let mut vaults = Vec::with_capacity(outpoints.len());
+        for outpoint in outpoints.iter() {
+            match db_vault_by_deposit(db, &outpoint)? {
+                Some(vault) if vault.status == VaultStatus::Unconfirmed => {
+                    return Ok(Err(RpcControlError::InvalidStatus((
+                        vault.status,
+                        VaultStatus::Funded,
+                    ))))
+                }
+                Some(vault) => vaults.push(vault),
+                None => return Ok(Err(RpcControlError::UnknownOutpoint(*outpoint))),
+            }
+        }
+        vaults
+    } else {
+        db_vaults(db)?
+            .into_iter()
+            .filter(|vault| vault.status != VaultStatus::Unconfirmed)
+            .collect()
+    };
+
+    let mut audits = Vec::with_capacity(db_vaults.len());
+    for db_vault in db_vaults {
+        let mismatches = audit_vault(revaultd, &db_vault)?;
+        for mismatch in mismatches.iter() {
+            log::error!(
+                "Presigned transactions of vault '{}' were tampered with: {}",
+                db_vault.deposit_outpoint,
+                mismatch
+            );
+        }
+        audits.push(VaultAudit {
+            outpoint: db_vault.deposit_outpoint,
+            mismatches,
+        });
+    }
+
+    Ok(Ok(audits))
+}
+
+// Make sure the stored presigned transactions of this vault are the ones we derive from it
+// before signing or broadcasting any of them.
+fn check_presigned(
+    revaultd: &RevaultD,
+    db_vault: &DbVault,
+) -> Result<Result<(), RpcControlError>, ControlError> {
+    let mismatches = audit_vault(revaultd, db_vault)?;
+    if mismatches.is_empty() {
+        return Ok(Ok(()));
+    }
+
+    let reason = format!(
+        "vault '{}': {}",
+        db_vault.deposit_outpoint,
+        mismatches
+            .iter()
+            .map(|mismatch| mismatch.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    log::error!(
+        "Refusing to use the stored presigned transactions of {}",
+        reason
+    );
+    Ok(Err(RpcControlError::TamperedPresigned(reason)))
+}
+
 // List all the onchain transactions from these vaults.
 fn onchain_txs_list_from_outpoints(
     revaultd: &RevaultD,
     bitcoind_tx: &Sender<BitcoindMessageOut>,
     outpoints: Option<Vec<OutPoint>>,
 ) -> Result<Result<Vec<VaultOnchainTransactions>, RpcControlError>, ControlError> {
-    let db_path = &revaultd.db_file();
+    let db = &revaultd.db;
 
     // If they didn't provide us with a list of outpoints, catch'em all!
     let db_vaults = if let Some(outpoints) = outpoints {
         // FIXME: we can probably make this more efficient with some SQL magic
         let mut vaults = Vec::with_capacity(outpoints.len());
         for outpoint in outpoints.iter() {
-            if let Some(vault) = db_vault_by_deposit(db_path, &outpoint)? {
+            if let Some(vault) = db_vault_by_deposit(db, &outpoint)? {
                 // Note that we accept any status
                 vaults.push(vault);
             } else {
@@ -235,7 +410,7 @@ fn onchain_txs_list_from_outpoints(
         }
         vaults
     } else {
-        db_vaults(db_path)?
+        db_vaults(db)?
     };
 
     let mut tx_list = Vec::with_capacity(db_vaults.len());
@@ -244,37 +419,64 @@ fn onchain_txs_list_from_outpoints(
 
         // If the vault exist, there must always be a deposit transaction available.
         let deposit = assume_some!(
-            bitcoind_wallet_tx(bitcoind_tx, db_vault.deposit_outpoint.txid)?,
+            vault_onchain_tx(
+                db,
+                bitcoind_tx,
+                &db_vault,
+                OnchainTransactionType::Deposit,
+                db_vault.deposit_outpoint.txid
+            )?,
             "Vault exists but not deposit tx?"
         );
 
         // For the other transactions, it depends on the status of the vault. For the sake of
-        // simplicity bitcoind will tell us (but we could have some optimisation eventually here,
+        // simplicity we look them all up (but we could have some optimisation eventually here,
         // eg returning None early on Funded vaults).
         let (unvault, cancel, emergency, unvault_emergency, spend) = match db_vault.status {
             // We allow the unconfirmed status, for which we don't have any presigned tx in db!
             VaultStatus::Unconfirmed => (None, None, None, None, None),
             _ => {
-                let (_, unvault) = db_unvault_transaction(db_path, db_vault.id)?;
-                let unvault =
-                    bitcoind_wallet_tx(bitcoind_tx, unvault.into_psbt().extract_tx().txid())?;
-                let (_, cancel) = db_cancel_transaction(db_path, db_vault.id)?;
-                let cancel =
-                    bitcoind_wallet_tx(bitcoind_tx, cancel.into_psbt().extract_tx().txid())?;
+                let onchain_tx =
+                    |tx_type, txid| vault_onchain_tx(db, bitcoind_tx, &db_vault, tx_type, txid);
+
+                let (_, unvault) = db_unvault_transaction(db, db_vault.id)?;
+                let unvault = onchain_tx(
+                    OnchainTransactionType::Unvault,
+                    unvault.into_psbt().extract_tx().txid(),
+                )?;
+                let (_, cancel) = db_cancel_transaction(db, db_vault.id)?;
+                let cancel = onchain_tx(
+                    OnchainTransactionType::Cancel,
+                    cancel.into_psbt().extract_tx().txid(),
+                )?;
 
                 // Emergencies are only for stakeholders!
                 let mut emergency = None;
                 let mut unvault_emergency = None;
                 if revaultd.is_stakeholder() {
-                    let emer = db_emer_transaction(db_path, db_vault.id)?.1;
-                    emergency =
-                        bitcoind_wallet_tx(bitcoind_tx, emer.into_psbt().extract_tx().txid())?;
+                    let emer = db_emer_transaction(db, db_vault.id)?.1;
+                    emergency = onchain_tx(
+                        OnchainTransactionType::Emergency,
+                        emer.into_psbt().extract_tx().txid(),
+                    )?;
 
-                    let unemer = db_unvault_emer_transaction(db_path, db_vault.id)?.1;
-                    unvault_emergency =
-                        bitcoind_wallet_tx(bitcoind_tx, unemer.into_psbt().extract_tx().txid())?;
+                    let unemer = db_unvault_emer_transaction(db, db_vault.id)?.1;
+                    unvault_emergency = onchain_tx(
+                        OnchainTransactionType::UnvaultEmergency,
+                        unemer.into_psbt().extract_tx().txid(),
+                    )?;
                 }
-                let spend = None; // TODO!
+
+                // The Spend isn't presigned, we only know about it if we saw it onchain
+                let spend_txid = db_vault_onchain_transactions(db, db_vault.id)?
+                    .into_iter()
+                    .filter(|db_tx| db_tx.tx_type == OnchainTransactionType::Spend)
+                    .max_by_key(|db_tx| (db_tx.blockheight.is_some(), db_tx.received_at))
+                    .map(|db_tx| db_tx.tx.txid());
+                let spend = match spend_txid {
+                    Some(txid) => onchain_tx(OnchainTransactionType::Spend, txid)?,
+                    None => None,
+                };
 
                 (unvault, cancel, emergency, unvault_emergency, spend)
             }
@@ -294,6 +496,433 @@ fn onchain_txs_list_from_outpoints(
     Ok(Ok(tx_list))
 }
 
+// All the changes of status of this vault, oldest first
+fn vault_events(db: &DbConnection, vault_id: u32) -> Result<Vec<VaultEvent>, DatabaseError> {
+    Ok(db_vault_events(db, vault_id)?
+        .into_iter()
+        .map(|event| VaultEvent {
+            old_status: event.old_status,
+            new_status: event.new_status,
+            timestamp: event.timestamp,
+            tip_height: event.tip_height,
+            txid: event.txid,
+        })
+        .collect())
+}
+
+fn presigned_tx_details(
+    tx_type: TransactionType,
+    tx: &impl RevaultTransaction,
+    fully_signed: bool,
+) -> PresignedTransactionDetails {
+    PresignedTransactionDetails {
+        tx_type,
+        txid: tx.inner_tx().global.unsigned_tx.txid(),
+        psbt: tx.as_psbt_string(),
+        // We only store them without feebump input
+        signatures: tx.inner_tx().inputs[0].partial_sigs.len(),
+        fully_signed,
+    }
+}
+
+// Everything we know about this vault.
+fn vault_details(
+    revaultd: &RevaultD,
+    bitcoind_tx: &Sender<BitcoindMessageOut>,
+    outpoint: OutPoint,
+) -> Result<Result<VaultDetails, RpcControlError>, ControlError> {
+    let db = &revaultd.db;
+    let db_vault = match db_vault_by_deposit(db, &outpoint)? {
+        Some(vault) => vault,
+        None => return Ok(Err(RpcControlError::UnknownOutpoint(outpoint))),
+    };
+    let tip_height = db_tip(db)?.height;
+
+    // The presigned transactions are only stored once the deposit is confirmed
+    let presigned_transactions = db_presigned_transactions(db, db_vault.id)?
+        .into_iter()
+        .map(|db_tx| match db_tx.psbt {
+            RevaultTx::Unvault(ref tx) => {
+                presigned_tx_details(db_tx.tx_type, tx, db_tx.is_fully_signed)
+            }
+            RevaultTx::Cancel(ref tx) => {
+                presigned_tx_details(db_tx.tx_type, tx, db_tx.is_fully_signed)
+            }
+            RevaultTx::Emergency(ref tx) => {
+                presigned_tx_details(db_tx.tx_type, tx, db_tx.is_fully_signed)
+            }
+            RevaultTx::UnvaultEmergency(ref tx) => {
+                presigned_tx_details(db_tx.tx_type, tx, db_tx.is_fully_signed)
+            }
+        })
+        .collect();
+
+    let onchain_transactions =
+        match onchain_txs_list_from_outpoints(revaultd, bitcoind_tx, Some(vec![outpoint]))? {
+            Ok(mut txs) => assume_some!(txs.pop(), "We just checked the vault exists"),
+            Err(e) => return Ok(Err(e)),
+        };
+
+    // The Unvault output can only be spent by the managers once the CSV expired. If it was
+    // not confirmed yet, the countdown didn't start.
+    let remaining_csv = match db_vault.status {
+        VaultStatus::Unvaulting | VaultStatus::Unvaulted | VaultStatus::Spendable => {
+            match onchain_transactions
+                .unvault
+                .as_ref()
+                .and_then(|unvault| unvault.blockheight)
+            {
+                Some(height) => {
+                    Some((height + revaultd.unvault_csv).saturating_sub(tip_height + 1))
+                }
+                None => Some(revaultd.unvault_csv),
+            }
+        }
+        _ => None,
+    };
+
+    Ok(Ok(VaultDetails {
+        vault: ListVaultsEntry {
+            amount: db_vault.amount,
+            status: db_vault.status,
+            deposit_outpoint: db_vault.deposit_outpoint,
+            derivation_index: db_vault.derivation_index,
+            address: revaultd.vault_address(db_vault.derivation_index),
+            blockheight: db_vault.blockheight,
+            updated_at: db_vault.updated_at,
+            label: db_vault_label(db, &outpoint)?,
+        },
+        unvault_address: revaultd.unvault_address(db_vault.derivation_index),
+        cpfp_address: revaultd.cpfp_address(db_vault.derivation_index),
+        remaining_csv,
+        presigned_transactions,
+        onchain_transactions,
+        tip_height,
+        events: vault_events(db, db_vault.id)?,
+    }))
+}
+
+// The fees paid by this presigned transaction, from the witness utxos of its PSBT.
+fn presigned_tx_fee(tx: &impl RevaultTransaction) -> Option<Amount> {
+    let psbt = tx.inner_tx();
+    let mut input_value: u64 = 0;
+    for input in psbt.inputs.iter() {
+        input_value = input_value.checked_add(input.witness_utxo.as_ref()?.value)?;
+    }
+    let output_value: u64 = psbt
+        .global
+        .unsigned_tx
+        .output
+        .iter()
+        .map(|txo| txo.value)
+        .sum();
+
+    input_value.checked_sub(output_value).map(Amount::from_sat)
+}
+
+// A transaction of a vault we stored, as bitcoind's wallet last told us about it
+struct StoredVaultTx {
+    tx_type: OnchainTransactionType,
+    txid: Txid,
+    wallet_tx: WalletTransaction,
+}
+
+impl StoredVaultTx {
+    fn confirmed_at(&self, height: u32) -> Option<u32> {
+        self.wallet_tx.blockheight.filter(|h| *h <= height)
+    }
+}
+
+// All the transactions of this vault we stored. The unconfirmed ones are refreshed, as the poller
+// doesn't follow all of them until they confirm.
+fn stored_vault_txs(
+    db: &DbConnection,
+    bitcoind_tx: &Sender<BitcoindMessageOut>,
+    db_vault: &DbVault,
+) -> Result<Vec<StoredVaultTx>, ControlError> {
+    let mut txs = Vec::new();
+    for db_tx in db_vault_onchain_transactions(db, db_vault.id)? {
+        let (tx_type, txid) = (db_tx.tx_type, db_tx.tx.txid());
+        let wallet_tx = if db_tx.blockheight.is_some() {
+            wallet_tx_from_db(db_tx)
+        } else {
+            vault_onchain_tx(db, bitcoind_tx, db_vault, tx_type, txid)?
+                .unwrap_or_else(|| wallet_tx_from_db(db_tx))
+        };
+        txs.push(StoredVaultTx {
+            tx_type,
+            txid,
+            wallet_tx,
+        });
+    }
+
+    Ok(txs)
+}
+
+fn exported_presigned_tx(
+    tx: &impl RevaultTransaction,
+    stored_txs: &[StoredVaultTx],
+    height: u32,
+) -> ExportedTransaction {
+    let txid = tx.inner_tx().global.unsigned_tx.txid();
+    let blockheight = stored_txs
+        .iter()
+        .find(|stored| stored.txid == txid)
+        .and_then(|stored| stored.confirmed_at(height));
+
+    ExportedTransaction {
+        txid,
+        fee: presigned_tx_fee(tx),
+        blockheight,
+    }
+}
+
+// The fees paid by a Spend, if all its inputs are Unvault outputs we know the value of.
+fn spend_tx_fee(spend_hex: &str, unvault_outputs: &HashMap<OutPoint, u64>) -> Option<Amount> {
+    let bytes = Vec::<u8>::from_hex(spend_hex).ok()?;
+    let spend_tx: BitcoinTransaction = encode::deserialize(&bytes).ok()?;
+
+    let mut input_value: u64 = 0;
+    for txin in spend_tx.input.iter() {
+        input_value = input_value.checked_add(*unvault_outputs.get(&txin.previous_output)?)?;
+    }
+    let output_value: u64 = spend_tx.output.iter().map(|txo| txo.value).sum();
+
+    input_value.checked_sub(output_value).map(Amount::from_sat)
+}
+
+// All the vaults we knew about at this block height, as they were at this height. What happened
+// onchain is read from the confirmation heights of the transactions we stored, and the
+// transactions confirmed after it (or not at all) are ignored, so that the export doesn't change
+// as long as there is no reorg deeper than this height. Only the signatures exchanges, which
+// don't happen onchain, are read from the history of the vault.
+fn export_vaults(
+    revaultd: &RevaultD,
+    bitcoind_tx: &Sender<BitcoindMessageOut>,
+    height: Option<u32>,
+) -> Result<Result<(u32, Vec<ExportedVault>), RpcControlError>, ControlError> {
+    let db = &revaultd.db;
+    let tip_height = db_tip(db)?.height;
+    let height = match height {
+        Some(height) if height > tip_height => {
+            return Ok(Err(RpcControlError::InvalidBlockheight((
+                height, tip_height,
+            ))))
+        }
+        Some(height) => height,
+        None => tip_height,
+    };
+
+    let mut db_vaults = db_vaults(db)?;
+    db_vaults.sort_by_key(|db_vault| db_vault.id);
+
+    // We need the value of all the Unvault outputs to compute the fees of the Spends, which may
+    // spend many vaults at once.
+    let mut unvault_txs = HashMap::with_capacity(db_vaults.len());
+    for db_vault in db_vaults.iter() {
+        if db_vault.status != VaultStatus::Unconfirmed {
+            unvault_txs.insert(db_vault.id, db_unvault_transaction(db, db_vault.id)?.1);
+        }
+    }
+    let unvault_outputs: HashMap<OutPoint, u64> = unvault_txs
+        .values()
+        .flat_map(|unvault_tx| {
+            let tx = &unvault_tx.inner_tx().global.unsigned_tx;
+            let txid = tx.txid();
+            tx.output
+                .iter()
+                .enumerate()
+                .map(move |(vout, txo)| (OutPoint::new(txid, vout as u32), txo.value))
+        })
+        .collect();
+
+    let mut exported = Vec::with_capacity(db_vaults.len());
+    for db_vault in db_vaults {
+        let stored_txs = stored_vault_txs(db, bitcoind_tx, &db_vault)?;
+        let confirmed = |tx_type: OnchainTransactionType| {
+            stored_txs
+                .iter()
+                .find(|stored| stored.tx_type == tx_type && stored.confirmed_at(height).is_some())
+        };
+        let events = vault_events(db, db_vault.id)?;
+
+        // We know about an unconfirmed deposit from the moment we saw it.
+        let deposit = assume_some!(
+            stored_txs
+                .iter()
+                .find(|stored| stored.tx_type == OnchainTransactionType::Deposit),
+            "Vault exists but not deposit tx?"
+        );
+        let deposit_height = deposit.confirmed_at(height);
+        if deposit_height.is_none() && !matches!(events.first(), Some(e) if e.tip_height <= height)
+        {
+            continue;
+        }
+
+        // The timestamp at which the vault first reached each status, in order.
+        let mut status_timestamps =
+            vec![(VaultStatus::Unconfirmed, deposit.wallet_tx.received_time)];
+        if deposit_height.is_some() {
+            status_timestamps.push((
+                VaultStatus::Funded,
+                deposit
+                    .wallet_tx
+                    .blocktime
+                    .unwrap_or(deposit.wallet_tx.received_time),
+            ));
+            for status in [VaultStatus::Secured, VaultStatus::Active].iter() {
+                if let Some(event) = events
+                    .iter()
+                    .find(|event| event.new_status == *status && event.tip_height <= height)
+                {
+                    status_timestamps.push((*status, event.timestamp));
+                }
+            }
+            for (tx_type, status) in [
+                (
+                    OnchainTransactionType::Emergency,
+                    VaultStatus::EmergencyVaulted,
+                ),
+                (OnchainTransactionType::Unvault, VaultStatus::Unvaulted),
+                (OnchainTransactionType::Cancel, VaultStatus::Canceled),
+                (
+                    OnchainTransactionType::UnvaultEmergency,
+                    VaultStatus::UnvaultEmergencyVaulted,
+                ),
+                (OnchainTransactionType::Spend, VaultStatus::Spent),
+            ]
+            .iter()
+            {
+                if let Some(stored) = confirmed(*tx_type) {
+                    let blocktime = stored.wallet_tx.blocktime;
+                    status_timestamps
+                        .push((*status, blocktime.unwrap_or(stored.wallet_tx.received_time)));
+                }
+            }
+        }
+        // The current status is the last one reached
+        let (status, updated_at) = status_timestamps[status_timestamps.len() - 1];
+
+        let (mut unvault, mut cancel, mut emergency, mut unvault_emergency) =
+            (None, None, None, None);
+        // The presigned transactions only exist once the deposit is confirmed
+        let unvault_tx = deposit_height.and_then(|_| unvault_txs.get(&db_vault.id));
+        if let Some(unvault_tx) = unvault_tx {
+            let vault_id = db_vault.id;
+            unvault = Some(exported_presigned_tx(unvault_tx, &stored_txs, height));
+            let cancel_tx = db_cancel_transaction(db, vault_id)?.1;
+            cancel = Some(exported_presigned_tx(&cancel_tx, &stored_txs, height));
+
+            // Emergencies are only for stakeholders!
+            if revaultd.is_stakeholder() {
+                let emer_tx = db_emer_transaction(db, vault_id)?.1;
+                emergency = Some(exported_presigned_tx(&emer_tx, &stored_txs, height));
+                let unemer_tx = db_unvault_emer_transaction(db, vault_id)?.1;
+                unvault_emergency = Some(exported_presigned_tx(&unemer_tx, &stored_txs, height));
+            }
+        }
+
+        // The Spend isn't presigned, we only know about it if we saw it onchain
+        let spend = confirmed(OnchainTransactionType::Spend).map(|stored| ExportedTransaction {
+            txid: stored.txid,
+            fee: spend_tx_fee(&stored.wallet_tx.hex, &unvault_outputs),
+            blockheight: stored.confirmed_at(height),
+        });
+
+        exported.push(ExportedVault {
+            vault: ListVaultsEntry {
+                amount: db_vault.amount,
+                status,
+                deposit_outpoint: db_vault.deposit_outpoint,
+                derivation_index: db_vault.derivation_index,
+                address: revaultd.vault_address(db_vault.derivation_index),
+                blockheight: deposit_height.unwrap_or(0),
+                updated_at,
+                label: db_vault_label(db, &db_vault.deposit_outpoint)?,
+            },
+            status_timestamps,
+            unvault,
+            cancel,
+            emergency,
+            unvault_emergency,
+            spend,
+        });
+    }
+
+    Ok(Ok((height, exported)))
+}
+
+// The finalized transaction, as hex
+fn finalized_tx_hex(tx: impl RevaultTransaction) -> String {
+    encode::serialize_hex(&tx.into_psbt().extract_tx())
+}
+
+// All the fully signed Emergency and Unvault-Emergency transactions of the vaults they could still
+// be broadcast for.
+fn emergency_kit(
+    revaultd: &RevaultD,
+) -> Result<Result<EmergencyKit, RpcControlError>, ControlError> {
+    let db = &revaultd.db;
+    let mut db_vaults = db_vaults(db)?;
+    db_vaults.sort_by_key(|db_vault| db_vault.id);
+
+    let mut vaults = Vec::with_capacity(db_vaults.len());
+    for db_vault in db_vaults {
+        // The coins are not there yet, or already gone.
+        if matches!(
+            db_vault.status,
+            VaultStatus::Unconfirmed
+                | VaultStatus::Canceled
+                | VaultStatus::EmergencyVaulted
+                | VaultStatus::UnvaultEmergencyVaulted
+                | VaultStatus::Spent
+        ) {
+            continue;
+        }
+        if let Err(e) = check_presigned(revaultd, &db_vault)? {
+            return Ok(Err(e));
+        }
+
+        let (mut emergency_tx, mut unvault_emergency_tx) = (None, None);
+        for db_tx in db_presigned_transactions(db, db_vault.id)? {
+            if !db_tx.is_fully_signed {
+                continue;
+            }
+            match db_tx.psbt {
+                RevaultTx::Emergency(tx) => emergency_tx = Some(finalized_tx_hex(tx)),
+                RevaultTx::UnvaultEmergency(tx) => {
+                    unvault_emergency_tx = Some(finalized_tx_hex(tx))
+                }
+                RevaultTx::Unvault(_) | RevaultTx::Cancel(_) => {}
+            }
+        }
+        if emergency_tx.is_none() && unvault_emergency_tx.is_none() {
+            continue;
+        }
+
+        vaults.push(KitVault {
+            deposit_outpoint: db_vault.deposit_outpoint.to_string(),
+            amount: db_vault.amount.as_sat(),
+            derivation_index: db_vault.derivation_index.into(),
+            status: db_vault.status.to_string(),
+            emergency_tx,
+            unvault_emergency_tx,
+        });
+    }
+
+    let created_at = assume_ok!(
+        SystemTime::now().duration_since(UNIX_EPOCH),
+        "Computing time since epoch"
+    )
+    .as_secs();
+    Ok(Ok(EmergencyKit {
+        version: KIT_VERSION,
+        network: revaultd.bitcoind_config.network.to_string(),
+        created_at,
+        vaults,
+    }))
+}
+
 /// An error thrown when the verification of a signature fails
 #[derive(Debug)]
 enum SigError {
@@ -457,7 +1086,7 @@ fn share_unvault_signatures(
 /// Handle events incoming from the JSONRPC interface.
 pub fn handle_rpc_messages(
     revaultd: Arc<RwLock<RevaultD>>,
-    db_path: PathBuf,
+    db: DbConnection,
     network: Network,
     rpc_rx: Receiver<RpcMessageIn>,
     jsonrpc_thread: JoinHandle<()>,
@@ -485,22 +1114,31 @@ pub fn handle_rpc_messages(
                 let (bitrep_tx, bitrep_rx) = mpsc::sync_channel(0);
                 bitcoind_tx.send(BitcoindMessageOut::SyncProgress(bitrep_tx))?;
                 let progress = bitrep_rx.recv()?;
+                let (bitrep_tx, bitrep_rx) = mpsc::sync_channel(0);
+                bitcoind_tx.send(BitcoindMessageOut::RescanProgress(bitrep_tx))?;
+                let rescan_progress = bitrep_rx.recv()?;
 
                 // This means blockheight == 0 for IBD.
                 let BlockchainTip {
                     height: blockheight,
                     ..
-                } = db_tip(&db_path)?;
+                } = db_tip(&db)?;
 
-                response_tx.send((network.to_string(), blockheight, progress))?;
+                let revaultd = revaultd.read().unwrap();
+                response_tx.send((
+                    network.to_string(),
+                    blockheight,
+                    progress,
+                    revaultd.deployment_fingerprint(),
+                    revaultd.bitcoind_poll_interval.as_secs(),
+                    revaultd.min_conf,
+                    revaultd.gap_limit,
+                    rescan_progress,
+                ))?;
             }
-            RpcMessageIn::ListVaults((statuses, outpoints), response_tx) => {
+            RpcMessageIn::ListVaults(query, response_tx) => {
                 log::trace!("Got listvaults from RPC thread");
-                response_tx.send(listvaults_from_db(
-                    &revaultd.read().unwrap(),
-                    statuses,
-                    outpoints,
-                )?)?;
+                response_tx.send(listvaults_from_db(&revaultd.read().unwrap(), query)?)?;
             }
             RpcMessageIn::DepositAddr(response_tx) => {
                 log::trace!("Got 'depositaddr' request from RPC thread");
@@ -510,10 +1148,10 @@ pub fn handle_rpc_messages(
                 log::trace!("Got 'getrevocationtxs' request from RPC thread");
                 let revaultd = revaultd.read().unwrap();
                 let xpub_ctx = revaultd.xpub_ctx();
-                let db_file = &revaultd.db_file();
+                let db = &revaultd.db;
 
                 // First, make sure the vault exists and is confirmed.
-                let vault = match db_vault_by_deposit(db_file, &outpoint)? {
+                let vault = match db_vault_by_deposit(db, &outpoint)? {
                     None => None,
                     Some(vault) => match vault.status {
                         VaultStatus::Unconfirmed => None,
@@ -567,7 +1205,7 @@ pub fn handle_rpc_messages(
 
                 // They may only send revocation transactions for confirmed and not-yet-presigned
                 // vaults.
-                let db_vault = match db_vault_by_deposit(&revaultd.db_file(), &outpoint)? {
+                let db_vault = match db_vault_by_deposit(&revaultd.db, &outpoint)? {
                     Some(v) => match v.status {
                         VaultStatus::Funded => v,
                         status => {
@@ -587,9 +1225,15 @@ pub fn handle_rpc_messages(
                     }
                 };
 
+                // Make sure we are not about to sign a tampered version of them
+                if let Err(e) = check_presigned(&revaultd, &db_vault)? {
+                    response_tx.send(Some(e.to_string()))?;
+                    continue;
+                }
+
                 // Sanity check they didn't send us garbaged PSBTs
                 let (cancel_db_id, db_cancel_tx) =
-                    db_cancel_transaction(&revaultd.db_file(), db_vault.id)?;
+                    db_cancel_transaction(&revaultd.db, db_vault.id)?;
                 let rpc_txid = cancel_tx.inner_tx().global.unsigned_tx.wtxid();
                 let db_txid = db_cancel_tx.inner_tx().global.unsigned_tx.wtxid();
                 if rpc_txid != db_txid {
@@ -599,8 +1243,7 @@ pub fn handle_rpc_messages(
                     )))?;
                     continue;
                 }
-                let (emer_db_id, db_emer_tx) =
-                    db_emer_transaction(&revaultd.db_file(), db_vault.id)?;
+                let (emer_db_id, db_emer_tx) = db_emer_transaction(&revaultd.db, db_vault.id)?;
                 let rpc_txid = emer_tx.inner_tx().global.unsigned_tx.wtxid();
                 let db_txid = db_emer_tx.inner_tx().global.unsigned_tx.wtxid();
                 if rpc_txid != db_txid {
@@ -611,7 +1254,7 @@ pub fn handle_rpc_messages(
                     continue;
                 }
                 let (unvault_emer_db_id, db_unemer_tx) =
-                    db_unvault_emer_transaction(&revaultd.db_file(), db_vault.id)?;
+                    db_unvault_emer_transaction(&revaultd.db, db_vault.id)?;
                 let rpc_txid = unvault_emer_tx.inner_tx().global.unsigned_tx.wtxid();
                 let db_txid = db_unemer_tx.inner_tx().global.unsigned_tx.wtxid();
                 if rpc_txid != db_txid {
@@ -704,21 +1347,21 @@ pub fn handle_rpc_messages(
                 // FIXME: edgy edge case: don't crash here, rather return an error if
                 // deposit tx was reorged out in between now and the above status check.
                 db_update_presigned_tx(
-                    &revaultd.db_file(),
+                    &revaultd.db,
                     db_vault.id,
                     cancel_db_id,
                     cancel_sigs.clone(),
                     secp_ctx,
                 )?;
                 db_update_presigned_tx(
-                    &revaultd.db_file(),
+                    &revaultd.db,
                     db_vault.id,
                     emer_db_id,
                     emer_sigs.clone(),
                     secp_ctx,
                 )?;
                 db_update_presigned_tx(
-                    &revaultd.db_file(),
+                    &revaultd.db,
                     db_vault.id,
                     unvault_emer_db_id,
                     unvault_emer_sigs.clone(),
@@ -743,11 +1386,11 @@ pub fn handle_rpc_messages(
                 log::trace!("Got 'getunvaulttx' request from RPC thread");
                 let revaultd = revaultd.read().unwrap();
                 let xpub_ctx = revaultd.xpub_ctx();
-                let db_file = &revaultd.db_file();
+                let db = &revaultd.db;
 
                 // We allow the call for Funded 'only' as unvaulttx would later fail if it's
                 // not 'secured'.
-                let vault = match db_vault_by_deposit(db_file, &outpoint)? {
+                let vault = match db_vault_by_deposit(db, &outpoint)? {
                     None => {
                         response_tx.send(Err(RpcControlError::UnknownOutpoint(outpoint)))?;
                         continue;
@@ -791,7 +1434,7 @@ pub fn handle_rpc_messages(
                 // better not send our unvault sig!
                 // If the vault is already active (or more) there is no point in spamming the
                 // coordinator.
-                let db_vault = match db_vault_by_deposit(&revaultd.db_file(), &outpoint)? {
+                let db_vault = match db_vault_by_deposit(&revaultd.db, &outpoint)? {
                     None => {
                         response_tx.send(Err(RpcControlError::UnknownOutpoint(outpoint)))?;
                         continue;
@@ -808,9 +1451,15 @@ pub fn handle_rpc_messages(
                     },
                 };
 
+                // Make sure we are not about to sign a tampered version of it
+                if let Err(e) = check_presigned(&revaultd, &db_vault)? {
+                    response_tx.send(Err(e))?;
+                    continue;
+                }
+
                 // Sanity check they didn't send us a garbaged PSBT
                 let (unvault_db_id, db_unvault_tx) =
-                    db_unvault_transaction(&revaultd.db_file(), db_vault.id)?;
+                    db_unvault_transaction(&revaultd.db, db_vault.id)?;
                 let rpc_txid = unvault_tx.inner_tx().global.unsigned_tx.wtxid();
                 let db_txid = db_unvault_tx.inner_tx().global.unsigned_tx.wtxid();
                 if rpc_txid != db_txid {
@@ -855,7 +1504,7 @@ pub fn handle_rpc_messages(
                 // FIXME: edgy edge case: don't crash here, rather return an error if
                 // deposit tx was reorged out in between now and the above status check.
                 db_update_presigned_tx(
-                    &revaultd.db_file(),
+                    &revaultd.db,
                     db_vault.id,
                     unvault_db_id,
                     sigs.clone(),
@@ -886,6 +1535,99 @@ pub fn handle_rpc_messages(
                     outpoints,
                 )?)?;
             }
+            RpcMessageIn::GetVaultHistory(outpoint, response_tx) => {
+                log::trace!("Got 'getvaulthistory' request from RPC thread");
+
+                let history = match db_vault_by_deposit(&db, &outpoint)? {
+                    None => Err(RpcControlError::UnknownOutpoint(outpoint)),
+                    Some(vault) => Ok(vault_events(&db, vault.id)?),
+                };
+                response_tx.send(history)?;
+            }
+            RpcMessageIn::GetVault(outpoint, response_tx) => {
+                log::trace!("Got 'getvault' request from RPC thread");
+                response_tx.send(vault_details(
+                    &revaultd.read().unwrap(),
+                    &bitcoind_tx,
+                    outpoint,
+                )?)?;
+            }
+            RpcMessageIn::GetBalance(response_tx) => {
+                log::trace!("Got 'getbalance' request from RPC thread");
+                let balance = db_balance(&db)?
+                    .into_iter()
+                    .map(|entry| StatusBalance {
+                        status: entry.status,
+                        amount: entry.amount,
+                        count: entry.count,
+                    })
+                    .collect();
+                response_tx.send(balance)?;
+            }
+            RpcMessageIn::SetVaultLabel((outpoint, label), response_tx) => {
+                log::trace!("Got 'setvaultlabel' request from RPC thread");
+                db_set_vault_label(&db, &outpoint, label.as_deref())?;
+                response_tx.send(())?;
+            }
+            RpcMessageIn::GetVaultLabel(outpoint, response_tx) => {
+                log::trace!("Got 'getvaultlabel' request from RPC thread");
+                response_tx.send(db_vault_label(&db, &outpoint)?)?;
+            }
+            RpcMessageIn::ExportVaults(height, response_tx) => {
+                log::trace!("Got 'exportvaults' request from RPC thread");
+                response_tx.send(export_vaults(
+                    &revaultd.read().unwrap(),
+                    &bitcoind_tx,
+                    height,
+                )?)?;
+            }
+            RpcMessageIn::EmergencyKit(path, response_tx) => {
+                log::trace!("Got 'emergencykit' request from RPC thread");
+                let kit = match emergency_kit(&revaultd.read().unwrap())? {
+                    Ok(kit) => kit,
+                    Err(e) => {
+                        response_tx.send(Err(e))?;
+                        continue;
+                    }
+                };
+                response_tx.send(kit.write_to(&path).map(|_| kit).map_err(|e| {
+                    RpcControlError::EmergencyKit(format!(
+                        "Writing '{}': {}",
+                        path.to_string_lossy(),
+                        e
+                    ))
+                }))?;
+            }
+            RpcMessageIn::Backup((path, passphrase), response_tx) => {
+                log::trace!("Got 'backup' request from RPC thread");
+                response_tx.send(
+                    create_backup(&revaultd.read().unwrap(), &path, &passphrase)
+                        .map_err(|e| RpcControlError::Backup(e.to_string())),
+                )?;
+            }
+            RpcMessageIn::CheckConsistency(repair, response_tx) => {
+                log::trace!("Got 'checkconsistency' request from RPC thread");
+                // It needs bitcoind, the bitcoind thread does it.
+                let (bitrep_tx, bitrep_rx) = mpsc::sync_channel(0);
+                bitcoind_tx.send(BitcoindMessageOut::CheckConsistency(repair, bitrep_tx))?;
+                response_tx.send(bitrep_rx.recv()?.map_err(RpcControlError::ConsistencyCheck))?;
+            }
+            RpcMessageIn::AuditPresigned(outpoints, response_tx) => {
+                log::trace!("Got 'auditpresigned' request from RPC thread");
+                response_tx.send(audit_presigned(&revaultd.read().unwrap(), outpoints)?)?;
+            }
+            RpcMessageIn::RescanIndexes(range, response_tx) => {
+                log::trace!("Got 'rescanindexes' request from RPC thread");
+                let (bitrep_tx, bitrep_rx) = mpsc::sync_channel(0);
+                bitcoind_tx.send(BitcoindMessageOut::RescanIndexes(range, bitrep_tx))?;
+                response_tx.send(bitrep_rx.recv()?.map_err(RpcControlError::Rescan))?;
+            }
+            RpcMessageIn::Rescan(start, response_tx) => {
+                log::trace!("Got 'rescan' request from RPC thread");
+                let (bitrep_tx, bitrep_rx) = mpsc::sync_channel(0);
+                bitcoind_tx.send(BitcoindMessageOut::Rescan(start, bitrep_tx))?;
+                response_tx.send(bitrep_rx.recv()?.map_err(RpcControlError::Rescan))?;
+            }
         }
     }
 
@@ -1,13 +1,20 @@
 use crate::{
     database::{
         interface::*,
-        schema::{DbTransaction, RevaultTx, TransactionType, SCHEMA},
+        schema::{
+            DbAddress, DbTransaction, OnchainTransactionType, RevaultTx, TransactionType,
+            MIGRATIONS, SCHEMA,
+        },
         DatabaseError, DB_VERSION,
     },
-    revaultd::{BlockchainTip, RevaultD, VaultStatus},
+    revaultd::{canonical_descriptor, BlockchainTip, RevaultD, VaultStatus},
+    statemachine::check_transition,
 };
 use revault_tx::{
-    bitcoin::{secp256k1, util::bip32::ChildNumber, Amount, OutPoint, PublicKey as BitcoinPubKey},
+    bitcoin::{
+        consensus::encode, secp256k1, util::bip32::ChildNumber, Amount, BlockHash, OutPoint,
+        PublicKey as BitcoinPubKey, Transaction as BitcoinTransaction, Txid,
+    },
     miniscript::Descriptor,
     scripts::{DepositDescriptor, UnvaultDescriptor},
     transactions::{
@@ -18,13 +25,15 @@ use revault_tx::{
 
 use std::{
     collections::BTreeMap,
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     fs,
+    ops::Range,
     path::PathBuf,
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use revault_net::sodiumoxide::randombytes::randombytes_uniform;
 use rusqlite::params;
 
 // Sqlite supports up to i64, thus rusqlite prevents us from inserting u64's.
@@ -84,7 +93,7 @@ fn create_db(revaultd: &RevaultD) -> Result<(), DatabaseError> {
     create_db_file(&db_path)
         .map_err(|e| DatabaseError(format!("Creating db file: {}", e.to_string())))?;
 
-    db_exec(&db_path, |tx| {
+    db_exec(&revaultd.db, |tx| {
         tx.execute_batch(&SCHEMA)
             .map_err(|e| DatabaseError(format!("Creating database: {}", e.to_string())))?;
         tx.execute(
@@ -120,21 +129,68 @@ fn create_db(revaultd: &RevaultD) -> Result<(), DatabaseError> {
     })
 }
 
+// Where we copy the database file before upgrading it from this version
+fn db_backup_path(db_path: &PathBuf, version: u32) -> PathBuf {
+    let mut backup_path = db_path.clone().into_os_string();
+    backup_path.push(format!(".v{}.bak", version));
+    PathBuf::from(backup_path)
+}
+
+// Upgrade a database from this version to the current one. We first make a backup copy of the
+// database file, then apply all the migrations in a single transaction.
+fn upgrade_db(db: &DbConnection, version: u32) -> Result<(), DatabaseError> {
+    let backup_path = db_backup_path(db.path(), version);
+    // Make sure the file is up to date before copying it
+    db.checkpoint()?;
+    fs::copy(db.path(), &backup_path).map_err(|e| {
+        DatabaseError(format!(
+            "Copying database to '{:?}' before upgrading it: {}",
+            backup_path,
+            e.to_string()
+        ))
+    })?;
+    log::info!(
+        "Upgrading database from version '{}' to '{}'. Copy of the previous database at '{:?}'.",
+        version,
+        DB_VERSION,
+        backup_path
+    );
+
+    db_exec(db, |tx| {
+        for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            tx.execute_batch(migration).map_err(|e| {
+                DatabaseError(format!(
+                    "Migrating database from version '{}' to '{}': {}",
+                    from_version,
+                    from_version + 1,
+                    e.to_string()
+                ))
+            })?;
+        }
+        tx.execute("UPDATE version SET version = (?1)", params![DB_VERSION])
+            .map_err(|e| DatabaseError(format!("Updating version: {}", e.to_string())))?;
+
+        Ok(())
+    })
+}
+
 // Called on startup to check database integrity
 fn check_db(revaultd: &RevaultD) -> Result<(), DatabaseError> {
-    let db_path = revaultd.db_file();
+    let db = &revaultd.db;
 
-    // Check if their database is not from the future.
-    // We'll eventually do migration here if version < VERSION, but be strict until then.
-    let version = db_version(&db_path)?;
-    if version != DB_VERSION {
+    // Check if their database is not from the future, and upgrade it if it's from the past.
+    let version = db_version(db)?;
+    if version > DB_VERSION {
         return Err(DatabaseError(format!(
             "Unexpected database version: got '{}', expected '{}'",
             version, DB_VERSION
         )));
     }
+    if version < DB_VERSION {
+        upgrade_db(db, version)?;
+    }
 
-    let db_net = db_network(&db_path)?;
+    let db_net = db_network(db)?;
     if db_net != revaultd.bitcoind_config.network {
         return Err(DatabaseError(format!(
             "Invalid network. Database is on '{}' but config says '{}'.",
@@ -142,15 +198,32 @@ fn check_db(revaultd: &RevaultD) -> Result<(), DatabaseError> {
         )));
     }
 
+    // The Unvault descriptor commits to all the keys and to the managers threshold: if the
+    // configuration changed it we'd be tracking different coins. The keys' origins don't change
+    // the scripts, and are only there if the descriptors were configured directly.
+    let db_unvault_descriptor = db_wallet(db)?.unvault_descriptor;
+    let config_unvault_descriptor = revaultd.unvault_descriptor.0.to_string();
+    if canonical_descriptor(&db_unvault_descriptor)
+        != canonical_descriptor(&config_unvault_descriptor)
+    {
+        return Err(DatabaseError(format!(
+            "Unvault descriptor mismatch. Database has '{}' but config gives '{}'. Did the \
+             managers threshold change?",
+            db_unvault_descriptor, config_unvault_descriptor
+        )));
+    }
+
     Ok(())
 }
 
+// How many of the cached scripts we check against our descriptors at startup
+const ADDRESSES_SPOT_CHECKS: usize = 16;
+
 // Called on startup to populate our cache from the database
 fn state_from_db(revaultd: &mut RevaultD) -> Result<(), DatabaseError> {
-    let db_path = revaultd.db_file();
-    let wallet = db_wallet(&db_path)?;
+    let wallet = db_wallet(&revaultd.db)?;
 
-    revaultd.tip = Some(db_tip(&db_path)?);
+    revaultd.tip = Some(db_tip(&revaultd.db)?);
 
     //FIXME: Use the Abstract Miniscript policy to check the policies described in the
     // config files are equivalent to the miniscript in the db.
@@ -177,20 +250,39 @@ fn state_from_db(revaultd: &mut RevaultD) -> Result<(), DatabaseError> {
     // Of course, it's no good... Miniscript on bitcoind soon :tm:
     // FIXME: in the meantime, reversed gap limit?
     let raw_index: u32 = revaultd.current_unused_index.into();
-    (0..raw_index + revaultd.gap_limit()).for_each(|i| {
-        // FIXME: this should fail instead of creating a hardened index
-        let index = ChildNumber::from(i);
-        revaultd.derivation_index_map.insert(
-            revaultd
-                .deposit_descriptor
-                .derive(index)
-                .0
-                .address(revaultd.bitcoind_config.network, revaultd.xpub_ctx())
-                .expect("deposit_descriptor is a wsh")
-                .script_pubkey(),
-            index,
-        );
-    });
+    let watched_end = revaultd.watched_indexes_end(raw_index).ok_or_else(|| {
+        DatabaseError(format!(
+            "Can't watch {} addresses past deposit derivation index {} without deriving hardened \
+             indexes",
+            revaultd.gap_limit, raw_index
+        ))
+    })?;
+    let addresses = db_addresses(&revaultd.db)?;
+    // We don't trust the database more than the configuration: the cached scripts must be the
+    // ones our descriptors derive. Deriving them all again would defeat the point of the cache,
+    // so we check the ones at the index we hand out, and a random sample of the others.
+    let mut spot_checked = vec![revaultd.current_unused_index];
+    if !addresses.is_empty() {
+        for _ in 0..ADDRESSES_SPOT_CHECKS {
+            let i = randombytes_uniform(addresses.len() as u32) as usize;
+            spot_checked.push(addresses[i].derivation_index);
+        }
+    }
+    for address in addresses {
+        revaultd.cache_scripts(address.derivation_index, address.scripts);
+    }
+    for index in spot_checked {
+        if let Some(scripts) = revaultd.derived_scripts.get(&index) {
+            if *scripts != revaultd.derive_scripts(index) {
+                return Err(DatabaseError(format!(
+                    "The cached scripts at derivation index '{}' aren't the ones our descriptors \
+                     derive",
+                    index
+                )));
+            }
+        }
+    }
+    derive_addresses(revaultd, 0..watched_end)?;
     revaultd.wallet_id = Some(wallet.id);
 
     // TODO: update vaults-that-are-not-in-deposit-state cache from the database
@@ -229,16 +321,66 @@ pub fn db_update_tip_dbtx(
 }
 
 /// Set the current best block hash and height
-pub fn db_update_tip(db_path: &PathBuf, tip: &BlockchainTip) -> Result<(), DatabaseError> {
-    db_exec(db_path, |db_tx| db_update_tip_dbtx(db_tx, tip))
+pub fn db_update_tip(db: &DbConnection, tip: &BlockchainTip) -> Result<(), DatabaseError> {
+    db_exec(db, |db_tx| db_update_tip_dbtx(db_tx, tip))
+}
+
+pub fn db_insert_addresses(
+    db: &DbConnection,
+    addresses: &[DbAddress],
+) -> Result<(), DatabaseError> {
+    db_exec(db, |tx| {
+        for address in addresses {
+            tx.execute(
+                "INSERT INTO addresses (derivation_index, deposit_script, unvault_script, \
+                 cpfp_script) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    u32::from(address.derivation_index),
+                    address.scripts.deposit.as_bytes(),
+                    address.scripts.unvault.as_bytes(),
+                    address.scripts.cpfp.as_bytes()
+                ],
+            )
+            .map_err(|e| DatabaseError(format!("Inserting address: {}", e.to_string())))?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Make sure we have the scripts for this range of derivation indexes, deriving and storing
+/// only the ones we never derived before.
+pub fn derive_addresses(revaultd: &mut RevaultD, indexes: Range<u32>) -> Result<(), DatabaseError> {
+    let new_addresses: Vec<DbAddress> = indexes
+        .map(ChildNumber::from)
+        .filter(|index| !revaultd.derived_scripts.contains_key(index))
+        .map(|index| DbAddress {
+            derivation_index: index,
+            scripts: revaultd.derive_scripts(index),
+        })
+        .collect();
+    if new_addresses.is_empty() {
+        return Ok(());
+    }
+
+    db_insert_addresses(&revaultd.db, &new_addresses)?;
+    log::debug!(
+        "Derived the addresses at {} new derivation indexes",
+        new_addresses.len()
+    );
+    for address in new_addresses {
+        revaultd.cache_scripts(address.derivation_index, address.scripts);
+    }
+
+    Ok(())
 }
 
 pub fn db_update_deposit_index(
-    db_path: &PathBuf,
+    db: &DbConnection,
     new_index: ChildNumber,
 ) -> Result<(), DatabaseError> {
     let new_index: u32 = new_index.into();
-    db_exec(db_path, |tx| {
+    db_exec(db, |tx| {
         tx.execute(
             "UPDATE wallets SET deposit_derivation_index = (?1)",
             params![new_index],
@@ -249,17 +391,119 @@ pub fn db_update_deposit_index(
     })
 }
 
+/// Set the last block the vault spends tracker went through the wallet transactions up to
+pub fn db_update_spends_last_block(
+    db: &DbConnection,
+    last_block: &BlockHash,
+) -> Result<(), DatabaseError> {
+    db_exec(db, |tx| {
+        tx.execute(
+            "UPDATE wallets SET spends_last_block = (?1)",
+            params![last_block.to_vec()],
+        )
+        .map_err(|e| DatabaseError(format!("Inserting spends last block: {}", e.to_string())))?;
+
+        Ok(())
+    })
+}
+
+// Record a change of status of a vault in the events log, with our current tip height.
+// `old_status` is None for a newly inserted vault.
+fn db_log_vault_event_dbtx(
+    db_tx: &rusqlite::Transaction,
+    vault_id: u32,
+    old_status: Option<u32>,
+    new_status: VaultStatus,
+    txid: Option<&Txid>,
+) -> Result<(), DatabaseError> {
+    db_tx
+        .execute(
+            "INSERT INTO vault_events (vault_id, old_status, new_status, timestamp, tip_height, \
+             txid) VALUES (?1, ?2, ?3, strftime('%s','now'), (SELECT blockheight FROM tip), ?4)",
+            params![
+                vault_id,
+                old_status,
+                new_status as u32,
+                txid.map(|txid| txid.to_vec())
+            ],
+        )
+        .map_err(|e| DatabaseError(format!("Inserting vault event: {}", e.to_string())))
+        .map(|_| ())
+}
+
+// Update the status of a vault, logging the change in the events log. `txid` is the
+// transaction that triggered the change, if any.
+// The transition is checked against the vault state machine, and refused if illegal.
+fn db_update_vault_status_dbtx(
+    db_tx: &rusqlite::Transaction,
+    vault_id: u32,
+    new_status: VaultStatus,
+    txid: Option<&Txid>,
+) -> Result<(), DatabaseError> {
+    let old_status = db_vault_status_dbtx(db_tx, vault_id)?;
+
+    if let Err(e) = check_transition(old_status, new_status) {
+        log::error!("Refusing to update vault '{}': {}", vault_id, e);
+        return Err(DatabaseError(format!(
+            "Updating vault '{}': {}",
+            vault_id, e
+        )));
+    }
+
+    db_tx
+        .execute(
+            "UPDATE vaults SET status = (?1), updated_at = strftime('%s','now') WHERE id = (?2)",
+            params![new_status as u32, vault_id],
+        )
+        .map_err(|e| {
+            DatabaseError(format!(
+                "Updating vault to '{}': {}",
+                new_status,
+                e.to_string()
+            ))
+        })?;
+
+    if old_status != new_status {
+        db_log_vault_event_dbtx(db_tx, vault_id, Some(old_status as u32), new_status, txid)?;
+    }
+
+    Ok(())
+}
+
+// The current status of a vault, as seen from within this database transaction
+fn db_vault_status_dbtx(
+    db_tx: &rusqlite::Transaction,
+    vault_id: u32,
+) -> Result<VaultStatus, DatabaseError> {
+    let status: u32 = db_tx
+        .query_row(
+            "SELECT status FROM vaults WHERE id = (?1)",
+            params![vault_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| {
+            DatabaseError(format!(
+                "Getting status of vault '{}': {}",
+                vault_id,
+                e.to_string()
+            ))
+        })?;
+
+    VaultStatus::try_from(status)
+        .map_err(|_| DatabaseError(format!("Unknown vault status '{}'", status)))
+}
+
 /// Insert a new deposit in the database
 #[allow(clippy::too_many_arguments)]
 pub fn db_insert_new_unconfirmed_vault(
-    db_path: &PathBuf,
+    db: &DbConnection,
     wallet_id: u32,
     status: &VaultStatus,
     deposit_outpoint: &OutPoint,
     amount: &Amount,
     derivation_index: ChildNumber,
 ) -> Result<(), DatabaseError> {
-    db_exec(db_path, |tx| {
+    db_exec(db, |tx| {
         let derivation_index: u32 = derivation_index.into();
         tx.execute(
             "INSERT INTO vaults (wallet_id, status, blockheight, deposit_txid, \
@@ -276,6 +520,8 @@ pub fn db_insert_new_unconfirmed_vault(
             ],
         )
         .map_err(|e| DatabaseError(format!("Inserting vault: {}", e.to_string())))?;
+        let vault_id = tx.last_insert_rowid() as u32;
+        db_log_vault_event_dbtx(tx, vault_id, None, *status, Some(&deposit_outpoint.txid))?;
 
         Ok(())
     })
@@ -307,7 +553,7 @@ macro_rules! db_store_unsigned_transactions {
 /// unsigned "presigned-transactions".
 /// The `emer_tx` and `unemer_tx` may only be passed for stakeholders.
 pub fn db_confirm_deposit(
-    db_path: &PathBuf,
+    db: &DbConnection,
     outpoint: &OutPoint,
     blockheight: u32,
     unvault_tx: &UnvaultTransaction,
@@ -315,7 +561,7 @@ pub fn db_confirm_deposit(
     emer_tx: Option<&EmergencyTransaction>,
     unemer_tx: Option<&UnvaultEmergencyTransaction>,
 ) -> Result<(), DatabaseError> {
-    let vault_id = db_vault_by_deposit(db_path, outpoint)?
+    let vault_id = db_vault_by_deposit(db, outpoint)?
         .ok_or_else(|| {
             DatabaseError(format!(
                 "Confirming '{}' but it does not exist in db?",
@@ -324,13 +570,14 @@ pub fn db_confirm_deposit(
         })?
         .id;
 
-    db_exec(db_path, |db_tx| {
+    db_exec(db, |db_tx| {
         db_tx
             .execute(
-                "UPDATE vaults SET status = (?1), blockheight = (?2), updated_at = strftime('%s','now') WHERE id = (?3)",
-                params![VaultStatus::Funded as u32, blockheight, vault_id,],
+                "UPDATE vaults SET blockheight = (?1) WHERE id = (?2)",
+                params![blockheight, vault_id],
             )
-            .map_err(|e| DatabaseError(format!("Updating vault to 'funded': {}", e.to_string())))?;
+            .map_err(|e| DatabaseError(format!("Updating vault blockheight: {}", e.to_string())))?;
+        db_update_vault_status_dbtx(db_tx, vault_id, VaultStatus::Funded, Some(&outpoint.txid))?;
 
         match (emer_tx, unemer_tx) {
             (Some(emer_tx), Some(unemer_tx)) => {
@@ -355,32 +602,140 @@ pub fn db_confirm_deposit(
 pub fn db_unconfirm_deposit_dbtx(
     db_tx: &rusqlite::Transaction,
     vault_id: u32,
+    deposit_txid: &Txid,
 ) -> Result<(), DatabaseError> {
     db_tx.execute(
         "DELETE FROM presigned_transactions WHERE vault_id = (?1)",
         params![vault_id],
     )?;
     db_tx.execute(
-        "UPDATE vaults SET status = (?1), blockheight = (?2), updated_at = strftime('%s','now') \
-         WHERE id = (?3)",
-        params![VaultStatus::Unconfirmed as u32, 0, vault_id],
+        "UPDATE vaults SET blockheight = (?1) WHERE id = (?2)",
+        params![0, vault_id],
+    )?;
+    // Its descendants can't be confirmed either
+    db_tx.execute(
+        "UPDATE onchain_transactions SET blockheight = NULL, blocktime = NULL \
+         WHERE vault_id = (?1)",
+        params![vault_id],
+    )?;
+    db_update_vault_status_dbtx(
+        db_tx,
+        vault_id,
+        VaultStatus::Unconfirmed,
+        Some(deposit_txid),
     )?;
 
     Ok(())
 }
 
-/// Mark an active vault as being in 'unvaulting' state
-pub fn db_unvault_deposit(db_path: &PathBuf, outpoint: &OutPoint) -> Result<(), DatabaseError> {
-    db_exec(db_path, |tx| {
+/// Set the height of the block the deposit of this vault was confirmed in, if it changed without
+/// us noticing (eg after a reorg).
+pub fn db_update_vault_blockheight(
+    db: &DbConnection,
+    vault_id: u32,
+    blockheight: u32,
+) -> Result<(), DatabaseError> {
+    db_exec(db, |tx| {
         tx.execute(
-            "UPDATE vaults SET status = (?1), updated_at = strftime('%s','now') WHERE deposit_txid = (?2) AND deposit_vout = (?3) ",
-            params![
-                VaultStatus::Unvaulting as u32,
-                outpoint.txid.to_vec(),
-                outpoint.vout
-            ],
+            "UPDATE vaults SET blockheight = (?1) WHERE id = (?2)",
+            params![blockheight, vault_id],
         )
-        .map_err(|e| DatabaseError(format!("Updating vault to 'unvaulting': {}", e.to_string())))?;
+        .map_err(|e| DatabaseError(format!("Updating vault blockheight: {}", e.to_string())))?;
+
+        Ok(())
+    })
+}
+
+/// Move the vault at this deposit outpoint to this status, as this transaction spending it was
+/// seen onchain
+pub fn db_update_vault_status(
+    db: &DbConnection,
+    outpoint: &OutPoint,
+    new_status: VaultStatus,
+    txid: &Txid,
+) -> Result<(), DatabaseError> {
+    db_exec(db, |tx| {
+        let vault_id: u32 = tx
+            .query_row(
+                "SELECT id FROM vaults WHERE deposit_txid = (?1) AND deposit_vout = (?2)",
+                params![outpoint.txid.to_vec(), outpoint.vout],
+                |row| row.get(0),
+            )
+            .map_err(|e| {
+                DatabaseError(format!(
+                    "Getting vault at '{}': {}",
+                    outpoint,
+                    e.to_string()
+                ))
+            })?;
+        db_update_vault_status_dbtx(tx, vault_id, new_status, Some(txid))?;
+
+        Ok(())
+    })
+}
+
+/// Mark an active vault as being in 'unvaulting' state
+pub fn db_unvault_deposit(
+    db: &DbConnection,
+    outpoint: &OutPoint,
+    unvault_txid: &Txid,
+) -> Result<(), DatabaseError> {
+    db_update_vault_status(db, outpoint, VaultStatus::Unvaulting, unvault_txid)
+}
+
+/// Store a transaction of this vault we saw onchain, or update its confirmation status if we
+/// already had it.
+pub fn db_store_onchain_tx(
+    db: &DbConnection,
+    vault_id: u32,
+    tx_type: OnchainTransactionType,
+    tx: &BitcoinTransaction,
+    blockheight: Option<u32>,
+    blocktime: Option<u32>,
+    received_at: u32,
+) -> Result<(), DatabaseError> {
+    db_exec(db, |db_tx| {
+        db_tx
+            .execute(
+                "INSERT INTO onchain_transactions (vault_id, type, txid, raw_tx, blockheight, \
+                 blocktime, received_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) \
+                 ON CONFLICT (vault_id, txid) DO UPDATE SET blockheight = excluded.blockheight, \
+                 blocktime = excluded.blocktime",
+                params![
+                    vault_id,
+                    tx_type as u32,
+                    tx.txid().to_vec(),
+                    encode::serialize(tx),
+                    blockheight,
+                    blocktime,
+                    received_at
+                ],
+            )
+            .map_err(|e| DatabaseError(format!("Storing onchain tx: {}", e.to_string())))?;
+
+        Ok(())
+    })
+}
+
+/// Attach this label to the deposit at this outpoint, replacing the previous one if any. `None`
+/// removes it.
+pub fn db_set_vault_label(
+    db: &DbConnection,
+    deposit: &OutPoint,
+    label: Option<&str>,
+) -> Result<(), DatabaseError> {
+    db_exec(db, |tx| {
+        match label {
+            Some(label) => tx.execute(
+                "INSERT OR REPLACE INTO vault_labels (deposit_txid, deposit_vout, label) \
+                 VALUES (?1, ?2, ?3)",
+                params![deposit.txid.to_vec(), deposit.vout, label],
+            )?,
+            None => tx.execute(
+                "DELETE FROM vault_labels WHERE deposit_txid = (?1) AND deposit_vout = (?2)",
+                params![deposit.txid.to_vec(), deposit.vout],
+            )?,
+        };
 
         Ok(())
     })
@@ -390,26 +745,26 @@ fn revault_tx_merge_sigs(
     tx: &mut impl RevaultTransaction,
     sigs: BTreeMap<BitcoinPubKey, Vec<u8>>,
     secp_ctx: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
-) -> Result<(bool, Vec<u8>), DatabaseError> {
+) -> Result<(bool, Vec<u8>, Txid), DatabaseError> {
     tx.inner_tx_mut().inputs[0].partial_sigs.extend(sigs);
     let fully_signed = tx.finalize(secp_ctx).is_ok();
     let raw_psbt = tx.as_psbt_serialized();
-    Ok((fully_signed, raw_psbt))
+    let txid = tx.inner_tx().global.unsigned_tx.txid();
+    Ok((fully_signed, raw_psbt, txid))
 }
 
 /// Update the presigned transaction in-db. If the transaction is valid and no more revocation
 /// transactions are remaining unsigned for this vault, it will update the vault status as well in
-/// the same database transaction.
+/// the same database transaction: a 'funded' vault becomes 'secured' once all its revocation
+/// transactions are signed, and a 'secured' vault becomes 'active' once its Unvault is signed too.
 pub fn db_update_presigned_tx(
-    db_path: &PathBuf,
+    db: &DbConnection,
     vault_id: u32,
     tx_db_id: u32,
     sigs: BTreeMap<BitcoinPubKey, Vec<u8>>,
     secp_ctx: &secp256k1::Secp256k1<secp256k1::VerifyOnly>,
 ) -> Result<(), DatabaseError> {
-    db_exec(db_path, move |db_tx| {
-        let mut is_unvault = false;
-
+    db_exec(db, move |db_tx| {
         // Fetch the PSBT in the transaction, to avoid someone else to modify it under our feet..
         let presigned_tx: DbTransaction = db_tx
             .prepare("SELECT * FROM presigned_transactions WHERE id = (?1)")?
@@ -423,15 +778,12 @@ pub fn db_update_presigned_tx(
             })?
             .try_into()?;
         // Now we are safe merging the signatures on what is the latest version of the PSBT
-        let (fully_signed, raw_psbt) = match presigned_tx.psbt {
+        let (fully_signed, raw_psbt, txid) = match presigned_tx.psbt {
             RevaultTx::Cancel(mut tx) => revault_tx_merge_sigs(&mut tx, sigs, secp_ctx)?,
             RevaultTx::Emergency(mut tx) => revault_tx_merge_sigs(&mut tx, sigs, secp_ctx)?,
 
             RevaultTx::UnvaultEmergency(mut tx) => revault_tx_merge_sigs(&mut tx, sigs, secp_ctx)?,
-            RevaultTx::Unvault(mut tx) => {
-                is_unvault = true;
-                revault_tx_merge_sigs(&mut tx, sigs, secp_ctx)?
-            }
+            RevaultTx::Unvault(mut tx) => revault_tx_merge_sigs(&mut tx, sigs, secp_ctx)?,
         };
 
         db_tx.execute(
@@ -440,37 +792,38 @@ pub fn db_update_presigned_tx(
         )?;
 
         if fully_signed {
-            // Are there some remaining unsigned revocation txs?
-            if db_tx
-                .prepare(
-                    "SELECT * FROM presigned_transactions WHERE fullysigned = 0 AND type != (?1)",
-                )?
-                // All presigned transactions but the Unvault are revocation txs
-                .query(params![TransactionType::Unvault as u32])?
-                .next()?
-                .is_none()
+            let mut status = db_vault_status_dbtx(db_tx, vault_id)?;
+
+            // Are there some remaining unsigned revocation txs for this vault?
+            // All presigned transactions but the Unvault are revocation txs.
+            if status == VaultStatus::Funded
+                && db_tx
+                    .prepare(
+                        "SELECT * FROM presigned_transactions WHERE vault_id = (?1) \
+                         AND fullysigned = 0 AND type != (?2)",
+                    )?
+                    .query(params![vault_id, TransactionType::Unvault as u32])?
+                    .next()?
+                    .is_none()
             {
                 // Nope. Mark the vault as 'secured'
-                db_tx
-                    .execute(
-                        "UPDATE vaults SET status = (?1), updated_at = strftime('%s','now') WHERE id = (?2) ",
-                        params![VaultStatus::Secured as u32, vault_id],
-                    )
-                    .map_err(|e| {
-                        DatabaseError(format!("Updating vault to 'secured': {}", e.to_string()))
-                    })?;
+                db_update_vault_status_dbtx(db_tx, vault_id, VaultStatus::Secured, Some(&txid))?;
+                status = VaultStatus::Secured;
             }
 
-            // Was it the unvault that was fully signed ? If so, mark the vault as active.
-            if is_unvault {
-                db_tx
-                    .execute(
-                        "UPDATE vaults SET status = (?1), updated_at = strftime('%s','now') WHERE id = (?2) ",
-                        params![VaultStatus::Active as u32, vault_id],
-                    )
-                    .map_err(|e| {
-                        DatabaseError(format!("Updating vault to 'active': {}", e.to_string()))
-                    })?;
+            // Is the Unvault fully signed, be it the one we just updated or not? If so, mark
+            // the vault as active.
+            if status == VaultStatus::Secured
+                && db_tx
+                    .prepare(
+                        "SELECT * FROM presigned_transactions WHERE vault_id = (?1) \
+                         AND fullysigned = 1 AND type = (?2)",
+                    )?
+                    .query(params![vault_id, TransactionType::Unvault as u32])?
+                    .next()?
+                    .is_some()
+            {
+                db_update_vault_status_dbtx(db_tx, vault_id, VaultStatus::Active, Some(&txid))?;
             }
         }
 
@@ -484,7 +837,7 @@ mod test {
     use crate::revaultd::RevaultD;
     use common::config::Config;
     use revault_tx::{
-        bitcoin::{Network, OutPoint, PublicKey},
+        bitcoin::{Network, OutPoint, PublicKey, Script, TxIn, TxOut},
         transactions::{CancelTransaction, EmergencyTransaction, UnvaultEmergencyTransaction},
     };
 
@@ -502,20 +855,32 @@ mod test {
         let mut revaultd = RevaultD::from_config(config).expect("Creating state from config");
         // Tweak the datadir, or it'll create it at ~/.revault/
         revaultd.data_dir = datadir_path.clone();
+        revaultd.db = DbConnection::new(revaultd.db_file());
 
         // Just in case there is a leftover from a previous run
         fs::remove_file(db_path).unwrap_or_else(|_| {
             eprintln!("No leftover");
         });
+        remove_wal_files(&datadir_path);
 
         revaultd
     }
 
+    // The WAL files may or may not be there, depending on whether the connections were closed
+    fn remove_wal_files(datadir_path: &PathBuf) {
+        for file_name in &["revaultd.sqlite3-wal", "revaultd.sqlite3-shm"] {
+            let mut path = datadir_path.clone();
+            path.push(file_name);
+            fs::remove_file(path).unwrap_or_else(|_| {});
+        }
+    }
+
     // Delete everything but the config (just our main db for now)
     fn clear_datadir(datadir_path: &PathBuf) {
         let mut db_path = datadir_path.clone();
         db_path.push("revaultd.sqlite3");
         fs::remove_file(db_path).expect("Removing db path");
+        remove_wal_files(datadir_path);
     }
 
     fn revault_tx_add_dummy_sig(tx: &mut impl RevaultTransaction, input_index: usize) {
@@ -540,7 +905,17 @@ mod test {
         create_db(&mut revaultd).unwrap();
         // There must be a wallet entry now, and there is only one so its id must
         // be 0.
-        assert_eq!(db_wallet(&revaultd.db_file()).unwrap().id, 1);
+        assert_eq!(db_wallet(&revaultd.db).unwrap().id, 1);
+        // The spends tracker never went through the wallet transactions yet
+        assert!(db_spends_last_block(&revaultd.db).unwrap().is_none());
+        let last_block =
+            BlockHash::from_str("000000000000000000068f5ac6a91bb9cc3a07d8e6eea4bd3faf79cd3fb8bd84")
+                .unwrap();
+        db_update_spends_last_block(&revaultd.db, &last_block).unwrap();
+        assert_eq!(
+            db_spends_last_block(&revaultd.db).unwrap(),
+            Some(last_block)
+        );
         // We can't create it twice
         create_db(&mut revaultd).unwrap_err();
         // The version is right
@@ -550,7 +925,7 @@ mod test {
         check_db(&mut revaultd).unwrap_err();
         revaultd.bitcoind_config.network = Network::Bitcoin;
         // Neither would it accept to open a database from the future!
-        db_exec(&revaultd.db_file(), |tx| {
+        db_exec(&revaultd.db, |tx| {
             tx.execute("UPDATE version SET version = (?1)", params![DB_VERSION + 1])
                 .unwrap();
             Ok(())
@@ -561,9 +936,58 @@ mod test {
         clear_datadir(&revaultd.data_dir);
     }
 
+    fn test_db_addresses() {
+        let mut revaultd = dummy_revaultd();
+        setup_db(&mut revaultd).unwrap();
+
+        // The watched range was derived and stored at startup
+        let gap_limit = revaultd.gap_limit;
+        let addresses = db_addresses(&revaultd.db).unwrap();
+        assert_eq!(addresses.len(), gap_limit as usize);
+        for address in addresses.iter() {
+            assert_eq!(
+                address.scripts,
+                revaultd.derive_scripts(address.derivation_index)
+            );
+        }
+
+        // Only the new ones are derived and stored
+        derive_addresses(&mut revaultd, 0..gap_limit + 2).unwrap();
+        let addresses = db_addresses(&revaultd.db).unwrap();
+        assert_eq!(addresses.len(), gap_limit as usize + 2);
+        assert_eq!(revaultd.derived_scripts.len(), gap_limit as usize + 2);
+
+        // At the next startup, they are not derived again but loaded from the database
+        revaultd.derived_scripts.clear();
+        revaultd.derivation_index_map.clear();
+        setup_db(&mut revaultd).unwrap();
+        assert_eq!(revaultd.derived_scripts.len(), gap_limit as usize + 2);
+        assert_eq!(revaultd.derivation_index_map.len(), gap_limit as usize + 2);
+
+        // But we refuse to hand out a deposit address the database made up
+        let index = revaultd.current_unused_index;
+        let other_script = revaultd
+            .derive_scripts(ChildNumber::from(gap_limit))
+            .deposit;
+        db_exec(&revaultd.db, |tx| {
+            tx.execute(
+                "UPDATE addresses SET deposit_script = (?1) WHERE derivation_index = (?2)",
+                params![other_script.as_bytes(), u32::from(index)],
+            )
+            .unwrap();
+            Ok(())
+        })
+        .unwrap();
+        revaultd.derived_scripts.clear();
+        revaultd.derivation_index_map.clear();
+        setup_db(&mut revaultd).unwrap_err();
+
+        clear_datadir(&revaultd.data_dir);
+    }
+
     fn test_db_fetch_deposits() {
         let mut revaultd = dummy_revaultd();
-        let db_path = revaultd.db_file();
+        let db = revaultd.db.clone();
 
         setup_db(&mut revaultd).unwrap();
 
@@ -578,7 +1002,7 @@ mod test {
         let amount = Amount::from_sat(123456);
         let derivation_index = ChildNumber::from(3);
         db_insert_new_unconfirmed_vault(
-            &db_path,
+            &db,
             wallet_id,
             &status,
             &first_deposit_outpoint,
@@ -596,7 +1020,7 @@ mod test {
         let amount = Amount::from_sat(456789);
         let derivation_index = ChildNumber::from(12);
         db_insert_new_unconfirmed_vault(
-            &db_path,
+            &db,
             wallet_id,
             &status,
             &second_deposit_outpoint,
@@ -614,7 +1038,7 @@ mod test {
         let amount = Amount::from_sat(428000);
         let derivation_index = ChildNumber::from(15);
         db_insert_new_unconfirmed_vault(
-            &db_path,
+            &db,
             wallet_id,
             &status,
             &third_deposit_outpoint,
@@ -626,7 +1050,7 @@ mod test {
         // By the way, trying to insert for an inexistant wallet will fail the
         // db constraint
         db_insert_new_unconfirmed_vault(
-            &db_path,
+            &db,
             wallet_id + 1,
             &status,
             &third_deposit_outpoint,
@@ -637,7 +1061,7 @@ mod test {
 
         // Now retrieve the deposits; there must be the first ones but not the
         // unvaulting one.
-        let deposit_outpoints: Vec<OutPoint> = db_deposits(&db_path)
+        let deposit_outpoints: Vec<OutPoint> = db_deposits(&db)
             .unwrap()
             .into_iter()
             .map(|db_vault| db_vault.deposit_outpoint)
@@ -648,8 +1072,11 @@ mod test {
         assert!(!deposit_outpoints.contains(&third_deposit_outpoint));
 
         // Now if we mark the first as being unvaulted we'll onlu fetch one
-        db_unvault_deposit(&db_path, &first_deposit_outpoint).unwrap();
-        let deposit_outpoints: Vec<OutPoint> = db_deposits(&db_path)
+        let unvault_txid =
+            Txid::from_str("0b4bbd1e4e3bb2fc4a9d2c2dd0fcc7f1a9b6baf7fa5e0da3d4dfa5e0b47d7e02")
+                .unwrap();
+        db_unvault_deposit(&db, &first_deposit_outpoint, &unvault_txid).unwrap();
+        let deposit_outpoints: Vec<OutPoint> = db_deposits(&db)
             .unwrap()
             .into_iter()
             .map(|db_vault| db_vault.deposit_outpoint)
@@ -659,12 +1086,208 @@ mod test {
         assert!(deposit_outpoints.contains(&second_deposit_outpoint));
         assert!(!deposit_outpoints.contains(&third_deposit_outpoint));
 
+        // And we recorded it, along with the transaction that triggered it
+        let first_vault = db_vault_by_deposit(&db, &first_deposit_outpoint)
+            .unwrap()
+            .unwrap();
+        let events = db_vault_events(&db, first_vault.id).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].old_status, None);
+        assert_eq!(events[0].new_status, VaultStatus::Funded);
+        assert_eq!(events[0].txid, Some(first_deposit_outpoint.txid));
+        assert_eq!(events[1].old_status, Some(VaultStatus::Funded));
+        assert_eq!(events[1].new_status, VaultStatus::Unvaulting);
+        assert_eq!(events[1].txid, Some(unvault_txid));
+
+        // An illegal transition is refused, and not recorded
+        db_exec(&db, |db_tx| {
+            db_update_vault_status_dbtx(db_tx, first_vault.id, VaultStatus::Spent, None)
+                .unwrap_err();
+            db_update_vault_status_dbtx(db_tx, first_vault.id, VaultStatus::Active, None)
+                .unwrap_err();
+            Ok(())
+        })
+        .unwrap();
+        let first_vault = db_vault_by_deposit(&db, &first_deposit_outpoint)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first_vault.status, VaultStatus::Unvaulting);
+        assert_eq!(db_vault_events(&db, first_vault.id).unwrap().len(), 2);
+
+        // Then its Unvault output gets spent by the Cancel transaction
+        let cancel_txid =
+            Txid::from_str("6d3b5e2c8f1a0b9c7e4d2f1a3b5c7d9e0f2a4b6c8d0e1f3a5b7c9d0e2f4a6b8c")
+                .unwrap();
+        db_update_vault_status(
+            &db,
+            &first_deposit_outpoint,
+            VaultStatus::Canceling,
+            &cancel_txid,
+        )
+        .unwrap();
+        let events = db_vault_events(&db, first_vault.id).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].old_status, Some(VaultStatus::Unvaulting));
+        assert_eq!(events[2].new_status, VaultStatus::Canceling);
+        assert_eq!(events[2].txid, Some(cancel_txid));
+        // It can't be unvaulted again
+        db_unvault_deposit(&db, &first_deposit_outpoint, &unvault_txid).unwrap_err();
+
+        clear_datadir(&revaultd.data_dir);
+    }
+
+    fn test_db_onchain_transactions() {
+        let mut revaultd = dummy_revaultd();
+        let db = revaultd.db.clone();
+
+        setup_db(&mut revaultd).unwrap();
+
+        let outpoints: Vec<OutPoint> = [
+            "4d799e993665149109682555ba482b386aea03c5dbd62c059b48eb8f40f2f040:0",
+            "e56808d17a866de5a1d0874894c84a759a7cabc8763694966cc6423f4c597a7f:0",
+        ]
+        .iter()
+        .map(|s| OutPoint::from_str(s).unwrap())
+        .collect();
+        for (i, outpoint) in outpoints.iter().enumerate() {
+            db_insert_new_unconfirmed_vault(
+                &db,
+                1,
+                &VaultStatus::Unconfirmed,
+                outpoint,
+                &Amount::from_sat(123456),
+                ChildNumber::from(i as u32),
+            )
+            .unwrap();
+        }
+        let vault_ids: Vec<u32> = outpoints
+            .iter()
+            .map(|outpoint| db_vault_by_deposit(&db, outpoint).unwrap().unwrap().id)
+            .collect();
+
+        // Some transaction spending both vaults
+        let tx = BitcoinTransaction {
+            version: 2,
+            lock_time: 0,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: 0xff_ff_ff_ff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 240_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let txid = tx.txid();
+        assert!(db_onchain_transaction(&db, vault_ids[0], &txid)
+            .unwrap()
+            .is_none());
+
+        // We first see it unconfirmed
+        db_store_onchain_tx(
+            &db,
+            vault_ids[0],
+            OnchainTransactionType::Spend,
+            &tx,
+            None,
+            None,
+            1_600_000_000,
+        )
+        .unwrap();
+        let db_tx = db_onchain_transaction(&db, vault_ids[0], &txid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(db_tx.tx_type, OnchainTransactionType::Spend);
+        assert_eq!(db_tx.tx, tx);
+        assert_eq!(db_tx.blockheight, None);
+        assert_eq!(db_tx.blocktime, None);
+        assert_eq!(db_tx.received_at, 1_600_000_000);
+        // It's per-vault
+        assert!(db_onchain_transaction(&db, vault_ids[1], &txid)
+            .unwrap()
+            .is_none());
+
+        // Then confirmed: we only update the confirmation
+        db_store_onchain_tx(
+            &db,
+            vault_ids[0],
+            OnchainTransactionType::Spend,
+            &tx,
+            Some(100),
+            Some(1_600_000_600),
+            1_600_000_500,
+        )
+        .unwrap();
+        let db_tx = db_onchain_transaction(&db, vault_ids[0], &txid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(db_tx.blockheight, Some(100));
+        assert_eq!(db_tx.blocktime, Some(1_600_000_600));
+        assert_eq!(db_tx.received_at, 1_600_000_000);
+
+        // The same transaction can be stored for the other vault it spends
+        db_store_onchain_tx(
+            &db,
+            vault_ids[1],
+            OnchainTransactionType::Spend,
+            &tx,
+            Some(100),
+            Some(1_600_000_600),
+            1_600_000_000,
+        )
+        .unwrap();
+        assert_eq!(
+            db_onchain_transaction(&db, vault_ids[1], &txid)
+                .unwrap()
+                .unwrap()
+                .blockheight,
+            Some(100)
+        );
+        assert_eq!(
+            db_onchain_transactions_by_type(&db, OnchainTransactionType::Spend)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(
+            db_onchain_transactions_by_type(&db, OnchainTransactionType::Unvault)
+                .unwrap()
+                .is_empty()
+        );
+        let vault_txs = db_vault_onchain_transactions(&db, vault_ids[1]).unwrap();
+        assert_eq!(vault_txs.len(), 1);
+        assert_eq!(vault_txs[0].vault_id, vault_ids[1]);
+        assert_eq!(vault_txs[0].tx_type, OnchainTransactionType::Spend);
+
+        // If the deposit gets unconfirmed, so are its descendants
+        db_exec(&db, |db_tx| {
+            db_unconfirm_deposit_dbtx(db_tx, vault_ids[0], &outpoints[0].txid)
+        })
+        .unwrap();
+        let db_tx = db_onchain_transaction(&db, vault_ids[0], &txid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(db_tx.blockheight, None);
+        assert_eq!(db_tx.blocktime, None);
+        assert_eq!(
+            db_onchain_transaction(&db, vault_ids[1], &txid)
+                .unwrap()
+                .unwrap()
+                .blockheight,
+            Some(100)
+        );
+
         clear_datadir(&revaultd.data_dir);
     }
 
     fn test_db_store_presigned_txs() {
         let mut revaultd = dummy_revaultd();
-        let db_path = revaultd.db_file();
+        let db = revaultd.db.clone();
 
         setup_db(&mut revaultd).unwrap();
 
@@ -678,7 +1301,7 @@ mod test {
         let amount = Amount::from_sat(123456);
         let derivation_index = ChildNumber::from(33334);
         db_insert_new_unconfirmed_vault(
-            &db_path,
+            &db,
             wallet_id,
             &status,
             &outpoint,
@@ -686,7 +1309,7 @@ mod test {
             derivation_index,
         )
         .unwrap();
-        let db_vault = db_vault_by_deposit(&db_path, &outpoint).unwrap().unwrap();
+        let db_vault = db_vault_by_deposit(&db, &outpoint).unwrap().unwrap();
 
         // We can store unsigned transactions
         let fresh_emer_tx = EmergencyTransaction::from_psbt_str("cHNidP8BAF4CAAAAAVqQwvZ+XLjEW+P90WnqdbVWkC1riPNhF8j9Ca4dM0RiAAAAAAD9////AfhgAwAAAAAAIgAgB6abzQJ4vo5CO9XW3r3JnNumTwlpQbZm9FVICsLHPYQAAAAAAAEBK4iUAwAAAAAAIgAgB6abzQJ4vo5CO9XW3r3JnNumTwlpQbZm9FVICsLHPYQBAwSBAAAAAQVHUiED35umh5GhiToV6GS7lTokWfq/Rvy+rRI9XMQuf+foOoEhA9GtXpHhUvxcj9DJWbaRvz59CNsMwH2NEvmRa8gc2WRkUq4AAA==").unwrap();
@@ -696,7 +1319,7 @@ mod test {
 
         let blockheight = 700000;
         db_confirm_deposit(
-            &db_path,
+            &db,
             &outpoint,
             blockheight,
             &fresh_unvault_tx,
@@ -707,101 +1330,95 @@ mod test {
         .unwrap();
 
         // Sanity check we can add sigs to them now
-        let (tx_db_id, stored_cancel_tx) = db_cancel_transaction(&db_path, db_vault.id).unwrap();
+        let (tx_db_id, stored_cancel_tx) = db_cancel_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_cancel_tx.inner_tx().inputs[0].partial_sigs.len(), 0);
         let mut cancel_tx = fresh_cancel_tx.clone();
         revault_tx_add_dummy_sig(&mut cancel_tx, 0);
         db_update_presigned_tx(
-            &db_path,
+            &db,
             db_vault.id,
             tx_db_id,
             cancel_tx.inner_tx().inputs[0].partial_sigs.clone(),
             &revaultd.secp_ctx,
         )
         .unwrap();
-        let (_, stored_cancel_tx) = db_cancel_transaction(&db_path, db_vault.id).unwrap();
+        let (_, stored_cancel_tx) = db_cancel_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_cancel_tx.inner_tx().inputs[0].partial_sigs.len(), 1);
 
-        let (tx_db_id, stored_emer_tx) = db_emer_transaction(&db_path, db_vault.id).unwrap();
+        let (tx_db_id, stored_emer_tx) = db_emer_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_emer_tx.inner_tx().inputs[0].partial_sigs.len(), 0);
         let mut emer_tx = fresh_emer_tx.clone();
         revault_tx_add_dummy_sig(&mut emer_tx, 0);
         db_update_presigned_tx(
-            &db_path,
+            &db,
             db_vault.id,
             tx_db_id,
             emer_tx.inner_tx().inputs[0].partial_sigs.clone(),
             &revaultd.secp_ctx,
         )
         .unwrap();
-        let (_, stored_emer_tx) = db_emer_transaction(&db_path, db_vault.id).unwrap();
+        let (_, stored_emer_tx) = db_emer_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_emer_tx.inner_tx().inputs[0].partial_sigs.len(), 1);
 
-        let (tx_db_id, stored_unemer_tx) =
-            db_unvault_emer_transaction(&db_path, db_vault.id).unwrap();
+        let (tx_db_id, stored_unemer_tx) = db_unvault_emer_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_unemer_tx.inner_tx().inputs[0].partial_sigs.len(), 0);
         let mut unemer_tx = fresh_unemer_tx.clone();
         revault_tx_add_dummy_sig(&mut unemer_tx, 0);
         db_update_presigned_tx(
-            &db_path,
+            &db,
             db_vault.id,
             tx_db_id,
             unemer_tx.inner_tx().inputs[0].partial_sigs.clone(),
             &revaultd.secp_ctx,
         )
         .unwrap();
-        let (_, stored_unemer_tx) = db_unvault_emer_transaction(&db_path, db_vault.id).unwrap();
+        let (_, stored_unemer_tx) = db_unvault_emer_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_unemer_tx.inner_tx().inputs[0].partial_sigs.len(), 1);
 
-        let (tx_db_id, stored_unvault_tx) = db_unvault_transaction(&db_path, db_vault.id).unwrap();
+        let (tx_db_id, stored_unvault_tx) = db_unvault_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_unvault_tx.inner_tx().inputs[0].partial_sigs.len(), 0);
         let mut unvault_tx = fresh_unvault_tx.clone();
         revault_tx_add_dummy_sig(&mut unvault_tx, 0);
         db_update_presigned_tx(
-            &db_path,
+            &db,
             db_vault.id,
             tx_db_id,
             unvault_tx.inner_tx().inputs[0].partial_sigs.clone(),
             &revaultd.secp_ctx,
         )
         .unwrap();
-        let (_, stored_unvault_tx) = db_unvault_transaction(&db_path, db_vault.id).unwrap();
+        let (_, stored_unvault_tx) = db_unvault_transaction(&db, db_vault.id).unwrap();
         assert_eq!(stored_unvault_tx.inner_tx().inputs[0].partial_sigs.len(), 1);
 
         // They can also be queried
-        assert_eq!(
-            emer_tx,
-            db_emer_transaction(&db_path, db_vault.id).unwrap().1
-        );
+        assert_eq!(emer_tx, db_emer_transaction(&db, db_vault.id).unwrap().1);
         assert_eq!(
             cancel_tx,
-            db_cancel_transaction(&db_path, db_vault.id).unwrap().1
+            db_cancel_transaction(&db, db_vault.id).unwrap().1
         );
         assert_eq!(
             unemer_tx,
-            db_unvault_emer_transaction(&db_path, db_vault.id)
-                .unwrap()
-                .1
+            db_unvault_emer_transaction(&db, db_vault.id).unwrap().1
         );
         assert_eq!(
             unvault_tx,
-            db_unvault_transaction(&db_path, db_vault.id).unwrap().1
+            db_unvault_transaction(&db, db_vault.id).unwrap().1
         );
 
         // And removed, if there is eg a reorg.
-        db_exec(&db_path, |db_tx| {
-            db_unconfirm_deposit_dbtx(&db_tx, db_vault.id).unwrap();
+        db_exec(&db, |db_tx| {
+            db_unconfirm_deposit_dbtx(&db_tx, db_vault.id, &outpoint.txid).unwrap();
             Ok(())
         })
         .unwrap();
-        db_emer_transaction(&db_path, db_vault.id).unwrap_err();
-        db_cancel_transaction(&db_path, db_vault.id).unwrap_err();
-        db_unvault_emer_transaction(&db_path, db_vault.id).unwrap_err();
-        db_unvault_transaction(&db_path, db_vault.id).unwrap_err();
+        db_emer_transaction(&db, db_vault.id).unwrap_err();
+        db_cancel_transaction(&db, db_vault.id).unwrap_err();
+        db_unvault_emer_transaction(&db, db_vault.id).unwrap_err();
+        db_unvault_transaction(&db, db_vault.id).unwrap_err();
 
         // And re-added of course
         db_confirm_deposit(
-            &db_path,
+            &db,
             &outpoint,
             blockheight,
             &fresh_unvault_tx,
@@ -812,7 +1429,7 @@ mod test {
         .unwrap();
         // But not twice! (UNIQUE on the psbt field)
         db_confirm_deposit(
-            &db_path,
+            &db,
             &outpoint,
             blockheight,
             &fresh_unvault_tx,
@@ -822,15 +1439,284 @@ mod test {
         )
         .unwrap_err();
 
+        // The reorg and the confirmation were logged, but not the failed attempt
+        let events: Vec<(Option<VaultStatus>, VaultStatus)> = db_vault_events(&db, db_vault.id)
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.old_status, event.new_status))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (None, VaultStatus::Funded),
+                (Some(VaultStatus::Funded), VaultStatus::Unconfirmed),
+                (Some(VaultStatus::Unconfirmed), VaultStatus::Funded),
+            ]
+        );
+
         clear_datadir(&revaultd.data_dir);
     }
 
+    fn test_db_list_vaults() {
+        let mut revaultd = dummy_revaultd();
+        let db = revaultd.db.clone();
+
+        setup_db(&mut revaultd).unwrap();
+
+        // Insert some vaults, with various amounts, heights and update times
+        let vaults = [
+            (
+                "4d799e993665149109682555ba482b386aea03c5dbd62c059b48eb8f40f2f040:0",
+                100_000,
+                10,
+                1_000,
+            ),
+            (
+                "e56808d17a866de5a1d0874894c84a759a7cabc8763694966cc6423f4c597a7f:0",
+                300_000,
+                30,
+                2_000,
+            ),
+            (
+                "616efc37747c8cafc2f99692177a5400bad81b671d8d35ffa347d84b246e9a83:1",
+                200_000,
+                20,
+                3_000,
+            ),
+            (
+                "0b4bbd1e4e3bb2fc4a9d2c2dd0fcc7f1a9b6baf7fa5e0da3d4dfa5e0b47d7e02:2",
+                400_000,
+                40,
+                4_000,
+            ),
+        ];
+        let outpoints: Vec<OutPoint> = vaults
+            .iter()
+            .map(|(outpoint, _, _, _)| OutPoint::from_str(outpoint).unwrap())
+            .collect();
+        for (i, (_, amount, blockheight, updated_at)) in vaults.iter().enumerate() {
+            db_insert_new_unconfirmed_vault(
+                &db,
+                1,
+                &VaultStatus::Funded,
+                &outpoints[i],
+                &Amount::from_sat(*amount),
+                ChildNumber::from(i as u32),
+            )
+            .unwrap();
+            db_exec(&db, |db_tx| {
+                db_tx
+                    .execute(
+                        "UPDATE vaults SET blockheight = (?1), updated_at = (?2) \
+                         WHERE deposit_txid = (?3)",
+                        params![blockheight, updated_at, outpoints[i].txid.to_vec()],
+                    )
+                    .unwrap();
+                Ok(())
+            })
+            .unwrap();
+        }
+        let vault_outpoints = |query: &VaultsQuery| -> Vec<OutPoint> {
+            db_list_vaults(&db, query)
+                .unwrap()
+                .into_iter()
+                .map(|(v, _)| v.deposit_outpoint)
+                .collect()
+        };
+
+        // By default, all of them by most recent update first
+        let query = VaultsQuery::default();
+        assert_eq!(
+            vault_outpoints(&query),
+            vec![outpoints[3], outpoints[2], outpoints[1], outpoints[0]]
+        );
+
+        // Sorted by amount or blockheight, in both orders
+        let mut query = VaultsQuery {
+            order_by: VaultsOrderBy::Amount,
+            descending: false,
+            ..VaultsQuery::default()
+        };
+        assert_eq!(
+            vault_outpoints(&query),
+            vec![outpoints[0], outpoints[2], outpoints[1], outpoints[3]]
+        );
+        query.order_by = VaultsOrderBy::Blockheight;
+        query.descending = true;
+        assert_eq!(
+            vault_outpoints(&query),
+            vec![outpoints[3], outpoints[1], outpoints[2], outpoints[0]]
+        );
+
+        // Paginated
+        query.limit = Some(3);
+        assert_eq!(
+            vault_outpoints(&query),
+            vec![outpoints[3], outpoints[1], outpoints[2]]
+        );
+        query.offset = 3;
+        assert_eq!(vault_outpoints(&query), vec![outpoints[0]]);
+        query.offset = 4;
+        assert!(vault_outpoints(&query).is_empty());
+
+        // Filtered by amount and time ranges (inclusive)
+        let mut query = VaultsQuery {
+            min_amount: Some(Amount::from_sat(200_000)),
+            max_amount: Some(Amount::from_sat(300_000)),
+            ..VaultsQuery::default()
+        };
+        assert_eq!(vault_outpoints(&query), vec![outpoints[2], outpoints[1]]);
+        query.updated_before = Some(2_999);
+        assert_eq!(vault_outpoints(&query), vec![outpoints[1]]);
+        query.updated_before = None;
+        query.updated_after = Some(2_001);
+        assert_eq!(vault_outpoints(&query), vec![outpoints[2]]);
+
+        // By status and outpoints
+        db_unvault_deposit(&db, &outpoints[1], &outpoints[3].txid).unwrap();
+        let mut query = VaultsQuery {
+            statuses: Some(vec![VaultStatus::Unvaulting, VaultStatus::Secured]),
+            ..VaultsQuery::default()
+        };
+        assert_eq!(vault_outpoints(&query), vec![outpoints[1]]);
+        query.statuses = Some(vec![VaultStatus::Funded]);
+        query.outpoints = Some(vec![outpoints[0], outpoints[1], outpoints[3]]);
+        query.order_by = VaultsOrderBy::Amount;
+        assert_eq!(vault_outpoints(&query), vec![outpoints[3], outpoints[0]]);
+        query.outpoints = Some(vec![]);
+        assert!(vault_outpoints(&query).is_empty());
+
+        // By label, which are keyed by outpoint
+        db_set_vault_label(&db, &outpoints[0], Some("Q3 cold reserve")).unwrap();
+        db_set_vault_label(&db, &outpoints[2], Some("client X")).unwrap();
+        db_set_vault_label(&db, &outpoints[2], Some("client X, Q3")).unwrap();
+        assert_eq!(
+            db_vault_label(&db, &outpoints[2]).unwrap(),
+            Some("client X, Q3".to_string())
+        );
+        assert_eq!(db_vault_label(&db, &outpoints[1]).unwrap(), None);
+        let mut query = VaultsQuery {
+            label: Some("Q3".to_string()),
+            ..VaultsQuery::default()
+        };
+        assert_eq!(vault_outpoints(&query), vec![outpoints[2], outpoints[0]]);
+        query.label = Some("client".to_string());
+        let listed = db_list_vaults(&db, &query).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0.deposit_outpoint, outpoints[2]);
+        assert_eq!(listed[0].1, Some("client X, Q3".to_string()));
+        db_set_vault_label(&db, &outpoints[2], None).unwrap();
+        assert!(vault_outpoints(&query).is_empty());
+        assert_eq!(db_vault_label(&db, &outpoints[2]).unwrap(), None);
+
+        // The balance is aggregated by status
+        assert_eq!(
+            db_balance(&db).unwrap(),
+            vec![
+                DbStatusBalance {
+                    status: VaultStatus::Funded,
+                    amount: Amount::from_sat(700_000),
+                    count: 3,
+                },
+                DbStatusBalance {
+                    status: VaultStatus::Unvaulting,
+                    amount: Amount::from_sat(300_000),
+                    count: 1,
+                },
+            ]
+        );
+
+        clear_datadir(&revaultd.data_dir);
+    }
+
+    // The name and type of the columns of each table of this database
+    fn db_columns(db_path: &PathBuf) -> BTreeMap<String, Vec<(String, String)>> {
+        let conn = rusqlite::Connection::open(db_path).unwrap();
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .unwrap()
+            .query_map(rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+
+        tables
+            .into_iter()
+            .map(|table| {
+                let columns = conn
+                    .prepare(&format!("PRAGMA table_info({})", table))
+                    .unwrap()
+                    .query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(1)?, row.get(2)?)))
+                    .unwrap()
+                    .collect::<rusqlite::Result<_>>()
+                    .unwrap();
+                (table, columns)
+            })
+            .collect()
+    }
+
+    fn test_db_upgrade() {
+        let revaultd = dummy_revaultd();
+        let db_path = revaultd.db_file();
+
+        // What a freshly created database looks like
+        let mut fresh_db_path = revaultd.data_dir.clone();
+        fresh_db_path.push("fresh.sqlite3");
+        rusqlite::Connection::open(&fresh_db_path)
+            .unwrap()
+            .execute_batch(&SCHEMA)
+            .unwrap();
+        let fresh_columns = db_columns(&fresh_db_path);
+        fs::remove_file(&fresh_db_path).unwrap();
+
+        for version in 0..DB_VERSION {
+            let mut fixture_path = revaultd.data_dir.clone();
+            fixture_path.push(format!("../db_fixtures/v{}.sql", version));
+            let fixture = fs::read_to_string(&fixture_path).unwrap();
+            rusqlite::Connection::open(&db_path)
+                .unwrap()
+                .execute_batch(&fixture)
+                .unwrap();
+            let db = DbConnection::new(db_path.clone());
+            assert_eq!(db_version(&db).unwrap(), version);
+            let vaults_before = db_vaults(&db).unwrap();
+
+            upgrade_db(&db, version).unwrap();
+
+            // We made a copy of the old database first
+            let backup_path = db_backup_path(&db_path, version);
+            assert_eq!(
+                db_version(&DbConnection::new(backup_path.clone())).unwrap(),
+                version
+            );
+            fs::remove_file(&backup_path).unwrap();
+
+            // It's now just like a fresh one, without losing any vault
+            assert_eq!(db_version(&db).unwrap(), DB_VERSION);
+            assert_eq!(db_columns(&db_path), fresh_columns);
+            let vaults_after = db_vaults(&db).unwrap();
+            assert_eq!(vaults_before.len(), vaults_after.len());
+            for (before, after) in vaults_before.iter().zip(vaults_after.iter()) {
+                assert_eq!(before.deposit_outpoint, after.deposit_outpoint);
+                assert_eq!(before.status, after.status);
+                assert_eq!(before.amount, after.amount);
+            }
+
+            drop(db);
+            clear_datadir(&revaultd.data_dir);
+        }
+    }
+
     // We disabled #[test] for the above, as they may erase the db concurrently.
     // Instead, run them sequentially.
     #[test]
     fn db_sequential_test_runner() {
         test_db_creation();
+        test_db_addresses();
         test_db_fetch_deposits();
+        test_db_onchain_transactions();
         test_db_store_presigned_txs();
+        test_db_list_vaults();
+        test_db_upgrade();
     }
 }
@@ -1,16 +1,19 @@
 use crate::{
     assert_tx_type,
     database::{
-        schema::{DbTransaction, DbVault, DbWallet, RevaultTx, TransactionType},
+        schema::{
+            DbAddress, DbOnchainTransaction, DbTransaction, DbVault, DbVaultEvent, DbWallet,
+            OnchainTransactionType, RevaultTx, TransactionType,
+        },
         DatabaseError,
     },
-    revaultd::{BlockchainTip, VaultStatus},
+    revaultd::{BlockchainTip, DerivedScripts, VaultStatus},
 };
 use revault_tx::{
     bitcoin::{
         consensus::encode,
         util::bip32::{ChildNumber, ExtendedPubKey},
-        Amount, BlockHash, Network, OutPoint, Txid,
+        Amount, BlockHash, Network, OutPoint, Script, Txid,
     },
     transactions::{
         CancelTransaction, EmergencyTransaction, RevaultTransaction, UnvaultEmergencyTransaction,
@@ -21,25 +24,116 @@ use revault_tx::{
 use std::{
     boxed::Box,
     convert::{TryFrom, TryInto},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rusqlite::{
+    params, types::FromSqlError, Connection, DatabaseName, Row, ToSql, Transaction, NO_PARAMS,
 };
 
-use rusqlite::{params, types::FromSqlError, Connection, Row, ToSql, Transaction, NO_PARAMS};
+// How long to wait for another connection to release its lock before failing a query.
+const DB_BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+// How many idle connections we keep around for reuse.
+const DB_MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// A handle to our database, cheap to clone and to share between threads.
+///
+/// As the bundled sqlite is compiled with SQLITE_THREADSAFE, quoting sqlite.org:
+/// > Multi-thread. In this mode, SQLite can be safely used by multiple threads provided that
+/// > no single database connection is used simultaneously in two or more threads.
+///
+/// Therefore each query takes a connection for itself out of a small pool, opening a new one only
+/// if all of them are in use, and gives it back afterwards. Connections are opened in WAL mode so
+/// that readers don't block the writer (and conversely), and wait for a lock to be released
+/// instead of failing right away with "database is locked".
+#[derive(Debug, Clone)]
+pub struct DbConnection {
+    path: PathBuf,
+    pool: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl DbConnection {
+    /// A handle to the database at this path. It is only opened (and created) on first use.
+    pub fn new(path: PathBuf) -> DbConnection {
+        DbConnection {
+            path,
+            pool: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The path to the database file
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn open(&self) -> Result<Connection, DatabaseError> {
+        let conn = Connection::open(&self.path)
+            .map_err(|e| DatabaseError(format!("Opening database: {}", e.to_string())))?;
+        conn.busy_timeout(DB_BUSY_TIMEOUT)
+            .map_err(|e| DatabaseError(format!("Setting busy timeout: {}", e.to_string())))?;
+        let journal_mode: String = conn
+            .query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| row.get(0))
+            .map_err(|e| DatabaseError(format!("Enabling WAL mode: {}", e.to_string())))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(DatabaseError(format!(
+                "Could not enable WAL mode, journal mode is '{}'",
+                journal_mode
+            )));
+        }
+
+        Ok(conn)
+    }
+
+    // Get a connection, either an idle one or a fresh one.
+    fn get(&self) -> Result<Connection, DatabaseError> {
+        let idle_conn = self.pool.lock().expect("Database pool lock poisoned").pop();
+        match idle_conn {
+            Some(conn) => Ok(conn),
+            None => self.open(),
+        }
+    }
+
+    // Give back a connection we got through `get()`, so it can be reused.
+    fn release(&self, conn: Connection) {
+        let mut pool = self.pool.lock().expect("Database pool lock poisoned");
+        if pool.len() < DB_MAX_IDLE_CONNECTIONS {
+            pool.push(conn);
+        }
+    }
 
-// Note that we don't share a global struct that would contain the connection here.
-// As the bundled sqlite is compiled with SQLITE_THREADSAFE, quoting sqlite.org:
-// > Multi-thread. In this mode, SQLite can be safely used by multiple threads provided that
-// > no single database connection is used simultaneously in two or more threads.
-// Therefore the below routines create a new connection and can be used from any thread.
+    /// Write a consistent copy of the database to this path, using SQLite's online backup. It's
+    /// fine to write to the database in the meantime.
+    pub fn backup(&self, dest: &Path) -> Result<(), DatabaseError> {
+        let conn = self.get()?;
+        conn.backup(DatabaseName::Main, dest, None)
+            .map_err(|e| DatabaseError(format!("Backing up database: {}", e.to_string())))?;
+        self.release(conn);
+
+        Ok(())
+    }
+
+    /// Write all the content of the WAL into the database file, for instance before copying it.
+    pub fn checkpoint(&self) -> Result<(), DatabaseError> {
+        let conn = self.get()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |_| Ok(()))
+            .map_err(|e| DatabaseError(format!("Checkpointing WAL: {}", e.to_string())))?;
+        self.release(conn);
+
+        Ok(())
+    }
+}
 
 /// Perform a set of modifications to the database inside a single transaction
-pub fn db_exec<F>(path: &PathBuf, modifications: F) -> Result<(), DatabaseError>
+pub fn db_exec<F>(db: &DbConnection, modifications: F) -> Result<(), DatabaseError>
 where
     F: FnOnce(&Transaction) -> Result<(), DatabaseError>,
 {
-    let mut conn = Connection::open(path)
-        .map_err(|e| DatabaseError(format!("Opening database: {}", e.to_string())))?;
+    let mut conn = db.get()?;
+    // If anything fails, the transaction is rolled back as it's dropped. Then the connection is
+    // dropped too, we'll just open a fresh one next time.
     let tx = conn
         .transaction()
         .map_err(|e| DatabaseError(format!("Creating transaction: {}", e.to_string())))?;
@@ -47,13 +141,14 @@ where
     modifications(&tx)?;
     tx.commit()
         .map_err(|e| DatabaseError(format!("Comitting transaction: {}", e.to_string())))?;
+    db.release(conn);
 
     Ok(())
 }
 
 // Internal helper for queries boilerplate
 fn db_query<'a, P, F, T>(
-    path: &PathBuf,
+    db: &DbConnection,
     stmt_str: &'a str,
     params: P,
     f: F,
@@ -63,8 +158,7 @@ where
     P::Item: ToSql,
     F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
 {
-    let conn = Connection::open(path)
-        .map_err(|e| DatabaseError(format!("Opening database for query: {}", e.to_string())))?;
+    let conn = db.get()?;
 
     // rustc says 'borrowed value does not live long enough'
     let x = conn
@@ -74,13 +168,14 @@ where
         .map_err(|e| DatabaseError(format!("Mapping query: '{}'", e.to_string())))?
         .collect::<rusqlite::Result<Vec<T>>>()
         .map_err(|e| DatabaseError(format!("Executing query: '{}'", e.to_string())));
+    db.release(conn);
 
     x
 }
 
 /// Get the database version
-pub fn db_version(db_path: &PathBuf) -> Result<u32, DatabaseError> {
-    let mut rows = db_query(db_path, "SELECT version FROM version", NO_PARAMS, |row| {
+pub fn db_version(db: &DbConnection) -> Result<u32, DatabaseError> {
+    let mut rows = db_query(db, "SELECT version FROM version", NO_PARAMS, |row| {
         row.get::<_, u32>(0)
     })?;
 
@@ -89,9 +184,9 @@ pub fn db_version(db_path: &PathBuf) -> Result<u32, DatabaseError> {
 }
 
 /// Get our tip from the database
-pub fn db_tip(db_path: &PathBuf) -> Result<BlockchainTip, DatabaseError> {
+pub fn db_tip(db: &DbConnection) -> Result<BlockchainTip, DatabaseError> {
     let mut rows = db_query(
-        db_path,
+        db,
         "SELECT blockheight, blockhash FROM tip",
         NO_PARAMS,
         |row| {
@@ -107,9 +202,28 @@ pub fn db_tip(db_path: &PathBuf) -> Result<BlockchainTip, DatabaseError> {
         .ok_or_else(|| DatabaseError("No row in tip table?".to_string()))
 }
 
+/// Get the last block the vault spends tracker went through the wallet transactions up to, if
+/// it ever did
+pub fn db_spends_last_block(db: &DbConnection) -> Result<Option<BlockHash>, DatabaseError> {
+    let mut rows = db_query(
+        db,
+        "SELECT spends_last_block FROM wallets",
+        NO_PARAMS,
+        |row| match row.get::<_, Option<Vec<u8>>>(0)? {
+            Some(hash) => Ok(Some(
+                encode::deserialize(&hash).map_err(|e| FromSqlError::Other(Box::new(e)))?,
+            )),
+            None => Ok(None),
+        },
+    )?;
+
+    rows.pop()
+        .ok_or_else(|| DatabaseError("No row in wallet table?".to_string()))
+}
+
 /// Get the network this DB was created on
-pub fn db_network(db_path: &PathBuf) -> Result<Network, DatabaseError> {
-    let mut rows = db_query(db_path, "SELECT network FROM tip", NO_PARAMS, |row| {
+pub fn db_network(db: &DbConnection) -> Result<Network, DatabaseError> {
+    let mut rows = db_query(db, "SELECT network FROM tip", NO_PARAMS, |row| {
         Ok(Network::from_str(&row.get::<_, String>(0)?)
             .expect("We only evert insert from to_string"))
     })?;
@@ -119,8 +233,8 @@ pub fn db_network(db_path: &PathBuf) -> Result<Network, DatabaseError> {
 }
 
 /// Get the database wallet. We only support single wallet, so this always return the first row.
-pub fn db_wallet(db_path: &PathBuf) -> Result<DbWallet, DatabaseError> {
-    let mut rows = db_query(db_path, "SELECT * FROM wallets", NO_PARAMS, |row| {
+pub fn db_wallet(db: &DbConnection) -> Result<DbWallet, DatabaseError> {
+    let mut rows = db_query(db, "SELECT * FROM wallets", NO_PARAMS, |row| {
         let our_man_xpub_str = row.get::<_, Option<String>>(4)?;
         let our_man_xpub = if let Some(ref xpub_str) = our_man_xpub_str {
             Some(
@@ -196,10 +310,62 @@ impl TryFrom<&Row<'_>> for DbVault {
     }
 }
 
+// Status are stored as integers
+fn status_from_row(row: &Row, index: usize) -> rusqlite::Result<VaultStatus> {
+    let status = row.get::<_, u32>(index)?;
+    status.try_into().map_err(|_| {
+        FromSqlError::Other(Box::new(DatabaseError(format!(
+            "Unknown vault status '{}'",
+            status
+        ))))
+        .into()
+    })
+}
+
+impl TryFrom<&Row<'_>> for DbVaultEvent {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let old_status = match row.get::<_, Option<u32>>(2)? {
+            Some(_) => Some(status_from_row(row, 2)?),
+            None => None,
+        };
+        let txid = match row.get::<_, Option<Vec<u8>>>(6)? {
+            Some(txid) => {
+                Some(encode::deserialize(&txid).map_err(|e| FromSqlError::Other(Box::new(e)))?)
+            }
+            None => None,
+        };
+
+        Ok(DbVaultEvent {
+            id: row.get(0)?,
+            vault_id: row.get(1)?,
+            old_status,
+            new_status: status_from_row(row, 3)?,
+            timestamp: row.get(4)?,
+            tip_height: row.get(5)?,
+            txid,
+        })
+    }
+}
+
+/// Get all the changes of status of this vault, oldest first
+pub fn db_vault_events(
+    db: &DbConnection,
+    vault_id: u32,
+) -> Result<Vec<DbVaultEvent>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT * FROM vault_events WHERE vault_id = (?1) ORDER BY id",
+        params![vault_id],
+        |row| row.try_into(),
+    )
+}
+
 /// Get all the vaults we know about from the db, sorted by last update
-pub fn db_vaults(db_path: &PathBuf) -> Result<Vec<DbVault>, DatabaseError> {
+pub fn db_vaults(db: &DbConnection) -> Result<Vec<DbVault>, DatabaseError> {
     db_query::<_, _, DbVault>(
-        db_path,
+        db,
         "SELECT * FROM vaults ORDER BY updated_at DESC",
         NO_PARAMS,
         |row| row.try_into(),
@@ -216,9 +382,9 @@ pub fn db_vaults_dbtx(db_tx: &Transaction) -> Result<Vec<DbVault>, DatabaseError
 }
 
 /// Get the vaults that didn't move onchain yet from the DB.
-pub fn db_deposits(db_path: &PathBuf) -> Result<Vec<DbVault>, DatabaseError> {
+pub fn db_deposits(db: &DbConnection) -> Result<Vec<DbVault>, DatabaseError> {
     db_query(
-        db_path,
+        db,
         "SELECT * FROM vaults WHERE status <= (?1) ORDER BY updated_at DESC",
         &[VaultStatus::Active as u32],
         |row| row.try_into(),
@@ -227,11 +393,11 @@ pub fn db_deposits(db_path: &PathBuf) -> Result<Vec<DbVault>, DatabaseError> {
 
 /// Get a vault from a deposit outpoint. Returns None if we never heard of such a vault.
 pub fn db_vault_by_deposit(
-    db_path: &PathBuf,
+    db: &DbConnection,
     deposit: &OutPoint,
 ) -> Result<Option<DbVault>, DatabaseError> {
     db_query(
-        db_path,
+        db,
         "SELECT * FROM vaults WHERE deposit_txid = (?1) AND deposit_vout = (?2)",
         params![deposit.txid.to_vec(), deposit.vout],
         |row| row.try_into(),
@@ -239,6 +405,286 @@ pub fn db_vault_by_deposit(
     .map(|mut vault_list| vault_list.pop())
 }
 
+/// The total amount and number of vaults in a given status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DbStatusBalance {
+    pub status: VaultStatus,
+    pub amount: Amount,
+    pub count: u32,
+}
+
+/// Get the total amount and number of vaults for each status we have at least one vault in
+pub fn db_balance(db: &DbConnection) -> Result<Vec<DbStatusBalance>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT status, SUM(amount), COUNT(*) FROM vaults GROUP BY status ORDER BY status",
+        NO_PARAMS,
+        |row| {
+            Ok(DbStatusBalance {
+                status: status_from_row(row, 0)?,
+                amount: Amount::from_sat(row.get::<_, i64>(1)? as u64),
+                count: row.get(2)?,
+            })
+        },
+    )
+}
+
+/// The column to sort the vaults by in [db_list_vaults]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VaultsOrderBy {
+    Amount,
+    UpdatedAt,
+    Blockheight,
+}
+
+impl VaultsOrderBy {
+    fn column(&self) -> &'static str {
+        match self {
+            VaultsOrderBy::Amount => "amount",
+            VaultsOrderBy::UpdatedAt => "updated_at",
+            VaultsOrderBy::Blockheight => "blockheight",
+        }
+    }
+}
+
+/// Which vaults to get from [db_list_vaults], and in which order. All the filters are optional,
+/// and a vault must match all of them to be returned.
+#[derive(Debug, Clone)]
+pub struct VaultsQuery {
+    pub statuses: Option<Vec<VaultStatus>>,
+    pub outpoints: Option<Vec<OutPoint>>,
+    /// Inclusive amount range
+    pub min_amount: Option<Amount>,
+    pub max_amount: Option<Amount>,
+    /// Inclusive range of the time of the last status change
+    pub updated_after: Option<u32>,
+    pub updated_before: Option<u32>,
+    /// Only the vaults whose label contains this string
+    pub label: Option<String>,
+    pub order_by: VaultsOrderBy,
+    pub descending: bool,
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+impl Default for VaultsQuery {
+    // All the vaults, most recently updated first
+    fn default() -> Self {
+        VaultsQuery {
+            statuses: None,
+            outpoints: None,
+            min_amount: None,
+            max_amount: None,
+            updated_after: None,
+            updated_before: None,
+            label: None,
+            order_by: VaultsOrderBy::UpdatedAt,
+            descending: true,
+            limit: None,
+            offset: 0,
+        }
+    }
+}
+
+/// Get the vaults matching this query, sorted and paginated as requested, along with their label.
+pub fn db_list_vaults(
+    db: &DbConnection,
+    query: &VaultsQuery,
+) -> Result<Vec<(DbVault, Option<String>)>, DatabaseError> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+
+    if let Some(ref statuses) = query.statuses {
+        let placeholders: Vec<String> = statuses
+            .iter()
+            .map(|status| {
+                params.push(Box::new(*status as u32));
+                format!("?{}", params.len())
+            })
+            .collect();
+        conditions.push(format!("status IN ({})", placeholders.join(", ")));
+    }
+
+    if let Some(ref outpoints) = query.outpoints {
+        let outpoints_conditions: Vec<String> = outpoints
+            .iter()
+            .map(|outpoint| {
+                params.push(Box::new(outpoint.txid.to_vec()));
+                params.push(Box::new(outpoint.vout));
+                format!(
+                    "(vaults.deposit_txid = ?{} AND vaults.deposit_vout = ?{})",
+                    params.len() - 1,
+                    params.len()
+                )
+            })
+            .collect();
+        if outpoints_conditions.is_empty() {
+            conditions.push("0".to_string());
+        } else {
+            conditions.push(format!("({})", outpoints_conditions.join(" OR ")));
+        }
+    }
+
+    if let Some(min_amount) = query.min_amount {
+        params.push(Box::new(min_amount.as_sat() as i64));
+        conditions.push(format!("amount >= ?{}", params.len()));
+    }
+    if let Some(max_amount) = query.max_amount {
+        params.push(Box::new(max_amount.as_sat() as i64));
+        conditions.push(format!("amount <= ?{}", params.len()));
+    }
+    if let Some(updated_after) = query.updated_after {
+        params.push(Box::new(updated_after));
+        conditions.push(format!("updated_at >= ?{}", params.len()));
+    }
+    if let Some(updated_before) = query.updated_before {
+        params.push(Box::new(updated_before));
+        conditions.push(format!("updated_at <= ?{}", params.len()));
+    }
+    if let Some(ref label) = query.label {
+        params.push(Box::new(label.clone()));
+        conditions.push(format!("instr(vault_labels.label, ?{}) > 0", params.len()));
+    }
+
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    let direction = if query.descending { "DESC" } else { "ASC" };
+    // SQLite needs a LIMIT to accept an OFFSET, a negative one means no limit.
+    params.push(Box::new(query.limit.map(i64::from).unwrap_or(-1)));
+    params.push(Box::new(query.offset));
+    // The id breaks ties, so that pages don't overlap.
+    let stmt_str = format!(
+        "SELECT vaults.*, vault_labels.label AS label FROM vaults \
+         LEFT JOIN vault_labels ON vault_labels.deposit_txid = vaults.deposit_txid \
+         AND vault_labels.deposit_vout = vaults.deposit_vout \
+         {} ORDER BY {} {}, vaults.id {} LIMIT ?{} OFFSET ?{}",
+        where_clause,
+        query.order_by.column(),
+        direction,
+        direction,
+        params.len() - 1,
+        params.len()
+    );
+
+    db_query(db, &stmt_str, params, |row| {
+        Ok((DbVault::try_from(row)?, row.get("label")?))
+    })
+}
+
+/// Get the label the user attached to this deposit, if any
+pub fn db_vault_label(
+    db: &DbConnection,
+    deposit: &OutPoint,
+) -> Result<Option<String>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT label FROM vault_labels WHERE deposit_txid = (?1) AND deposit_vout = (?2)",
+        params![deposit.txid.to_vec(), deposit.vout],
+        |row| row.get(0),
+    )
+    .map(|mut labels| labels.pop())
+}
+
+impl TryFrom<&Row<'_>> for DbAddress {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let derivation_index = ChildNumber::from(row.get::<_, u32>(0)?);
+        let deposit_script: Vec<u8> = row.get(1)?;
+        let unvault_script: Vec<u8> = row.get(2)?;
+        let cpfp_script: Vec<u8> = row.get(3)?;
+
+        Ok(DbAddress {
+            derivation_index,
+            scripts: DerivedScripts {
+                deposit: Script::from(deposit_script),
+                unvault: Script::from(unvault_script),
+                cpfp: Script::from(cpfp_script),
+            },
+        })
+    }
+}
+
+/// Get all the scripts we derived so far
+pub fn db_addresses(db: &DbConnection) -> Result<Vec<DbAddress>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT derivation_index, deposit_script, unvault_script, cpfp_script FROM addresses",
+        NO_PARAMS,
+        |row| row.try_into(),
+    )
+}
+
+impl TryFrom<&Row<'_>> for DbOnchainTransaction {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let db_tx_type: u32 = row.get(2)?;
+        let tx_type: OnchainTransactionType = db_tx_type.try_into().map_err(|_| {
+            FromSqlError::Other(Box::new(DatabaseError(format!(
+                "Unsane db: got an invalid onchain tx type: '{}'",
+                db_tx_type
+            ))))
+        })?;
+        let raw_tx: Vec<u8> = row.get(4)?;
+        let tx = encode::deserialize(&raw_tx).map_err(|e| FromSqlError::Other(Box::new(e)))?;
+
+        Ok(DbOnchainTransaction {
+            id: row.get(0)?,
+            vault_id: row.get(1)?,
+            tx_type,
+            tx,
+            blockheight: row.get(5)?,
+            blocktime: row.get(6)?,
+            received_at: row.get(7)?,
+        })
+    }
+}
+
+/// Get this transaction of this vault, if we stored it
+pub fn db_onchain_transaction(
+    db: &DbConnection,
+    vault_id: u32,
+    txid: &Txid,
+) -> Result<Option<DbOnchainTransaction>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT * FROM onchain_transactions WHERE vault_id = (?1) AND txid = (?2)",
+        params![vault_id, txid.to_vec()],
+        |row| row.try_into(),
+    )
+    .map(|mut txs| txs.pop())
+}
+
+/// Get all the transactions of this vault we stored
+pub fn db_vault_onchain_transactions(
+    db: &DbConnection,
+    vault_id: u32,
+) -> Result<Vec<DbOnchainTransaction>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT * FROM onchain_transactions WHERE vault_id = (?1)",
+        params![vault_id],
+        |row| row.try_into(),
+    )
+}
+
+/// Get all the transactions of this type we stored, for all vaults
+pub fn db_onchain_transactions_by_type(
+    db: &DbConnection,
+    tx_type: OnchainTransactionType,
+) -> Result<Vec<DbOnchainTransaction>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT * FROM onchain_transactions WHERE type = (?1)",
+        params![tx_type as u32],
+        |row| row.try_into(),
+    )
+}
+
 impl TryFrom<&Row<'_>> for DbTransaction {
     type Error = rusqlite::Error;
 
@@ -289,11 +735,11 @@ impl TryFrom<&Row<'_>> for DbTransaction {
 
 /// Get the Unvault transaction for this vault
 pub fn db_unvault_transaction(
-    db_path: &PathBuf,
+    db: &DbConnection,
     vault_id: u32,
 ) -> Result<(u32, UnvaultTransaction), DatabaseError> {
     let mut rows: Vec<DbTransaction> = db_query(
-        db_path,
+        db,
         "SELECT * FROM presigned_transactions WHERE vault_id = (?1) AND type = (?2)",
         params![vault_id, TransactionType::Unvault as u32],
         |row| row.try_into(),
@@ -310,11 +756,11 @@ pub fn db_unvault_transaction(
 
 /// Get the Cancel transaction corresponding to this vault
 pub fn db_cancel_transaction(
-    db_path: &PathBuf,
+    db: &DbConnection,
     vault_id: u32,
 ) -> Result<(u32, CancelTransaction), DatabaseError> {
     let mut rows: Vec<DbTransaction> = db_query(
-        db_path,
+        db,
         "SELECT * FROM presigned_transactions WHERE vault_id = (?1) AND type = (?2)",
         params![vault_id, TransactionType::Cancel as u32],
         |row| row.try_into(),
@@ -332,11 +778,11 @@ pub fn db_cancel_transaction(
 /// Get the Emergency transaction corresponding to this vault.
 /// Will error if there are none, ie if called by a non-stakeholder!
 pub fn db_emer_transaction(
-    db_path: &PathBuf,
+    db: &DbConnection,
     vault_id: u32,
 ) -> Result<(u32, EmergencyTransaction), DatabaseError> {
     let mut rows: Vec<DbTransaction> = db_query(
-        db_path,
+        db,
         "SELECT * FROM presigned_transactions WHERE vault_id = (?1) AND type = (?2)",
         params![vault_id, TransactionType::Emergency as u32],
         |row| row.try_into(),
@@ -354,11 +800,11 @@ pub fn db_emer_transaction(
 /// Get the Unvault Emergency transaction corresponding to this vault
 /// Will error if there are none, ie if called by a non-stakeholder!
 pub fn db_unvault_emer_transaction(
-    db_path: &PathBuf,
+    db: &DbConnection,
     vault_id: u32,
 ) -> Result<(u32, UnvaultEmergencyTransaction), DatabaseError> {
     let mut rows: Vec<DbTransaction> = db_query(
-        db_path,
+        db,
         "SELECT * FROM presigned_transactions WHERE vault_id = (?1) AND type = (?2)",
         params![vault_id, TransactionType::UnvaultEmergency as u32],
         |row| row.try_into(),
@@ -379,11 +825,25 @@ pub fn db_unvault_emer_transaction(
 /// Get all the presigned transactions for which we don't have all the sigs yet.
 /// Note that it will return the emergency transactions (if unsigned) only if we
 /// are a stakeholder.
-pub fn db_transactions_sig_missing(db_path: &PathBuf) -> Result<Vec<DbTransaction>, DatabaseError> {
+pub fn db_transactions_sig_missing(db: &DbConnection) -> Result<Vec<DbTransaction>, DatabaseError> {
     db_query(
-        db_path,
+        db,
         "SELECT * FROM presigned_transactions WHERE fullysigned = 0",
         params![],
         |row| row.try_into(),
     )
 }
+
+/// Get all the presigned transactions of this vault. There are none if the deposit isn't
+/// confirmed yet.
+pub fn db_presigned_transactions(
+    db: &DbConnection,
+    vault_id: u32,
+) -> Result<Vec<DbTransaction>, DatabaseError> {
+    db_query(
+        db,
+        "SELECT * FROM presigned_transactions WHERE vault_id = (?1) ORDER BY type",
+        params![vault_id],
+        |row| row.try_into(),
+    )
+}
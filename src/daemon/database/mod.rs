@@ -33,4 +33,5 @@ impl From<PsbtError> for DatabaseError {
     }
 }
 
-pub const DB_VERSION: u32 = 0;
+/// The current version of the database schema, bumped by each migration
+pub const DB_VERSION: u32 = schema::MIGRATIONS.len() as u32;
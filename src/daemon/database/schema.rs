@@ -1,15 +1,15 @@
-use crate::revaultd::VaultStatus;
+use crate::revaultd::{DerivedScripts, VaultStatus};
 use revault_tx::{
     bitcoin::{
         util::bip32::{ChildNumber, ExtendedPubKey},
-        Amount, OutPoint,
+        Amount, OutPoint, Transaction, Txid,
     },
     transactions::{
         CancelTransaction, EmergencyTransaction, UnvaultEmergencyTransaction, UnvaultTransaction,
     },
 };
 
-use std::convert::TryFrom;
+use std::{convert::TryFrom, fmt};
 
 pub const SCHEMA: &str = "\
 CREATE TABLE version (
@@ -33,7 +33,8 @@ CREATE TABLE wallets (
     unvault_descriptor TEXT NOT NULL,
     our_manager_xpub TEXT,
     our_stakeholder_xpub TEXT,
-    deposit_derivation_index INTEGER NOT NULL
+    deposit_derivation_index INTEGER NOT NULL,
+    spends_last_block BLOB
 );
 
 /* This stores the vaults we heard about. The deposit may be unconfirmed,
@@ -73,10 +74,157 @@ CREATE TABLE presigned_transactions (
         ON DELETE RESTRICT
 );
 
+/* This stores all the changes of status of the vaults, as an audit log. The
+ * old status is NULL when the vault was first inserted. The tip height is
+ * our tip at the time of the change (not the height of the transaction that
+ * triggered it), and the txid the transaction that triggered the change, if
+ * any.
+ */
+CREATE TABLE vault_events (
+    id INTEGER PRIMARY KEY NOT NULL,
+    vault_id INTEGER NOT NULL,
+    old_status INTEGER,
+    new_status INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    tip_height INTEGER NOT NULL,
+    txid BLOB,
+    FOREIGN KEY (vault_id) REFERENCES vaults (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+
+/* This stores the labels the user attached to their vaults. They are local
+ * only and never shared with the other participants. They are keyed by deposit
+ * outpoint rather than by vault id, as the user may label a deposit they
+ * expect.
+ */
+CREATE TABLE vault_labels (
+    id INTEGER PRIMARY KEY NOT NULL,
+    deposit_txid BLOB NOT NULL,
+    deposit_vout INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    UNIQUE (deposit_txid, deposit_vout)
+);
+
+/* This stores the transactions of our vaults we saw onchain (or in the
+ * mempool), so that we don't depend on bitcoind's wallet to serve them. The
+ * blockheight and blocktime are NULL while it's unconfirmed. A Spend may spend
+ * many vaults, hence it's unique per vault and not globally.
+ */
+CREATE TABLE onchain_transactions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    vault_id INTEGER NOT NULL,
+    type INTEGER NOT NULL,
+    txid BLOB NOT NULL,
+    raw_tx BLOB NOT NULL,
+    blockheight INTEGER,
+    blocktime INTEGER,
+    received_at INTEGER NOT NULL,
+    UNIQUE (vault_id, txid),
+    FOREIGN KEY (vault_id) REFERENCES vaults (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+
+/* This caches the scripts we derived from our descriptors, as derivation is
+ * slow with many participants. Only the indexes we never derived before are
+ * derived at startup.
+ */
+CREATE TABLE addresses (
+    id INTEGER PRIMARY KEY NOT NULL,
+    derivation_index INTEGER UNIQUE NOT NULL,
+    deposit_script BLOB UNIQUE NOT NULL,
+    unvault_script BLOB NOT NULL,
+    cpfp_script BLOB NOT NULL
+);
+
 CREATE INDEX vault_status ON vaults (status);
+CREATE INDEX vault_amount ON vaults (amount);
+CREATE INDEX vault_updated_at ON vaults (updated_at);
+CREATE INDEX vault_blockheight ON vaults (blockheight);
 CREATE INDEX vault_transactions ON presigned_transactions (vault_id);
+CREATE INDEX vault_events_vault ON vault_events (vault_id);
+CREATE INDEX onchain_transactions_txid ON onchain_transactions (txid);
 ";
 
+/// The migrations to apply, in order, to a database created with a previous version of the
+/// schema: `MIGRATIONS[n]` upgrades a database from version `n` to version `n + 1`.
+/// They are forward-only. Never modify or remove one, rather append a new one (along with the
+/// corresponding changes to the [SCHEMA] and a fixture database for the previous version).
+pub const MIGRATIONS: &[&str] = &[
+    // Version 1: the vault events log. We start the history with the current status of the
+    // existing vaults.
+    "\
+CREATE TABLE vault_events (
+    id INTEGER PRIMARY KEY NOT NULL,
+    vault_id INTEGER NOT NULL,
+    old_status INTEGER,
+    new_status INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    blockheight INTEGER NOT NULL,
+    txid BLOB,
+    FOREIGN KEY (vault_id) REFERENCES vaults (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+CREATE INDEX vault_events_vault ON vault_events (vault_id);
+INSERT INTO vault_events (vault_id, old_status, new_status, timestamp, blockheight, txid)
+    SELECT id, NULL, status, updated_at, (SELECT blockheight FROM tip), NULL FROM vaults;
+",
+    // Version 2: indices for sorting and filtering the vaults list.
+    "\
+CREATE INDEX vault_amount ON vaults (amount);
+CREATE INDEX vault_updated_at ON vaults (updated_at);
+CREATE INDEX vault_blockheight ON vaults (blockheight);
+",
+    // Version 3: the user's labels.
+    "\
+CREATE TABLE vault_labels (
+    id INTEGER PRIMARY KEY NOT NULL,
+    deposit_txid BLOB NOT NULL,
+    deposit_vout INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    UNIQUE (deposit_txid, deposit_vout)
+);
+",
+    // Version 4: the onchain transactions. They'll be filled as we (re)fetch them from bitcoind.
+    "\
+CREATE TABLE onchain_transactions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    vault_id INTEGER NOT NULL,
+    type INTEGER NOT NULL,
+    txid BLOB NOT NULL,
+    raw_tx BLOB NOT NULL,
+    blockheight INTEGER,
+    blocktime INTEGER,
+    received_at INTEGER NOT NULL,
+    UNIQUE (vault_id, txid),
+    FOREIGN KEY (vault_id) REFERENCES vaults (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+CREATE INDEX onchain_transactions_txid ON onchain_transactions (txid);
+",
+    // Version 5: the derived scripts cache. It'll be filled at startup.
+    "\
+CREATE TABLE addresses (
+    id INTEGER PRIMARY KEY NOT NULL,
+    derivation_index INTEGER UNIQUE NOT NULL,
+    deposit_script BLOB UNIQUE NOT NULL,
+    unvault_script BLOB NOT NULL,
+    cpfp_script BLOB NOT NULL
+);
+",
+    // Version 6: where the vault spends tracker stopped going through the wallet transactions.
+    "\
+ALTER TABLE wallets ADD COLUMN spends_last_block BLOB;
+",
+    // Version 7: the events' height is our tip's, not the one of the transaction.
+    "\
+ALTER TABLE vault_events RENAME COLUMN blockheight TO tip_height;
+",
+];
+
 /// A row in the "wallets" table
 #[derive(Clone)]
 pub struct DbWallet {
@@ -102,6 +250,70 @@ pub struct DbVault {
     pub updated_at: u32,
 }
 
+/// A row of the "addresses" table
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbAddress {
+    pub derivation_index: ChildNumber,
+    pub scripts: DerivedScripts,
+}
+
+/// A row of the "vault_events" table
+#[derive(Debug, Clone)]
+pub struct DbVaultEvent {
+    pub id: u32,
+    pub vault_id: u32,
+    /// None if the vault was just inserted
+    pub old_status: Option<VaultStatus>,
+    pub new_status: VaultStatus,
+    pub timestamp: u32,
+    /// Our tip at the time of the change, not the height of the transaction that triggered it
+    pub tip_height: u32,
+    /// The transaction that triggered the change, if any
+    pub txid: Option<Txid>,
+}
+
+/// A row of the "onchain_transactions" table
+#[derive(Debug, Clone)]
+pub struct DbOnchainTransaction {
+    pub id: u32,
+    pub vault_id: u32,
+    pub tx_type: OnchainTransactionType,
+    pub tx: Transaction,
+    /// None if unconfirmed
+    pub blockheight: Option<u32>,
+    /// None if unconfirmed
+    pub blocktime: Option<u32>,
+    /// When bitcoind first saw it
+    pub received_at: u32,
+}
+
+/// The type of the transaction, as stored in the "onchain_transactions" table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnchainTransactionType {
+    Deposit,
+    Unvault,
+    Cancel,
+    Emergency,
+    UnvaultEmergency,
+    Spend,
+}
+
+impl TryFrom<u32> for OnchainTransactionType {
+    type Error = ();
+
+    fn try_from(n: u32) -> Result<Self, Self::Error> {
+        match n {
+            0 => Ok(Self::Deposit),
+            1 => Ok(Self::Unvault),
+            2 => Ok(Self::Cancel),
+            3 => Ok(Self::Emergency),
+            4 => Ok(Self::UnvaultEmergency),
+            5 => Ok(Self::Spend),
+            _ => Err(()),
+        }
+    }
+}
+
 /// The type of the transaction, as stored in the "presigned_transactions" table
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TransactionType {
@@ -125,6 +337,17 @@ impl TryFrom<u32> for TransactionType {
     }
 }
 
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Unvault => write!(f, "unvault"),
+            Self::Cancel => write!(f, "cancel"),
+            Self::Emergency => write!(f, "emergency"),
+            Self::UnvaultEmergency => write!(f, "unvault_emergency"),
+        }
+    }
+}
+
 macro_rules! tx_type_from_tx {
     ($tx:ident, $tx_type:ident) => {
         impl From<&$tx> for TransactionType {
@@ -0,0 +1,348 @@
+//! The `revaultd init` subcommand. It creates a configuration file out of the parameters of the
+//! deployment, our data directory and our Noise static key.
+//!
+//! Each parameter can be passed as a `--flag value` command line argument. The missing ones are
+//! asked for interactively.
+
+use crate::revaultd::RevaultD;
+use common::config::{config_file_path, Config};
+use revault_tx::bitcoin::hashes::hex::ToHex;
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use toml::value::{Table, Value};
+
+// The flags we understand, along with the question to ask if it wasn't given.
+const PARAMETERS: &[(&str, &str)] = &[
+    (
+        "conf",
+        "Path of the configuration file to create (empty for the default)",
+    ),
+    (
+        "data-dir",
+        "Path of the data directory (empty for the default)",
+    ),
+    ("network", "Bitcoin network (bitcoin, testnet or regtest)"),
+    ("bitcoind-cookie", "Path to bitcoind's cookie file"),
+    ("bitcoind-addr", "bitcoind's RPC address (ip:port)"),
+    ("coordinator-host", "The Coordinator's address (ip:port)"),
+    (
+        "coordinator-noise-key",
+        "The Coordinator's Noise static public key (hex)",
+    ),
+    (
+        "stakeholders-xpubs",
+        "The stakeholders' xpubs (comma-separated)",
+    ),
+    (
+        "cosigners-keys",
+        "The cosigning servers' public keys (comma-separated)",
+    ),
+    ("managers-xpubs", "The managers' xpubs (comma-separated)"),
+    (
+        "managers-threshold",
+        "How many managers must sign a Spend (empty for all of them)",
+    ),
+    ("unvault-csv", "The Unvault relative timelock, in blocks"),
+    (
+        "stakeholder-xpub",
+        "Our xpub as a stakeholder (empty if we are not a stakeholder)",
+    ),
+    ("emergency-address", "The Emergency address"),
+    (
+        "watchtowers",
+        "Our watchtowers as 'noise_key@host' (comma-separated, may be empty)",
+    ),
+    (
+        "manager-xpub",
+        "Our xpub as a manager (empty if we are not a manager)",
+    ),
+    (
+        "cosigning-servers",
+        "The cosigning servers as 'noise_key@host' (comma-separated, may be empty)",
+    ),
+    (
+        "emergency-address-hash",
+        "The sha256 of the Emergency address scriptPubKey (empty if unknown)",
+    ),
+];
+
+#[derive(Debug)]
+pub struct InitError(String);
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Initialization error: {}", self.0)
+    }
+}
+
+impl std::error::Error for InitError {}
+
+// The parameters given on the command line, by flag name
+struct InitArgs(HashMap<String, String>);
+
+impl InitArgs {
+    fn from_args(args: &[String]) -> Result<InitArgs, InitError> {
+        let mut values = HashMap::with_capacity(args.len() / 2);
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let flag = arg
+                .strip_prefix("--")
+                .filter(|f| PARAMETERS.iter().any(|(name, _)| name == f))
+                .ok_or_else(|| InitError(format!("Unknown argument '{}'", arg)))?;
+            let value = args
+                .next()
+                .ok_or_else(|| InitError(format!("No value for '{}'", arg)))?;
+            values.insert(flag.to_string(), value.trim().to_string());
+        }
+
+        Ok(InitArgs(values))
+    }
+
+    // Get this parameter from the command line, or ask for it. Empty means None.
+    fn get(&self, flag: &str) -> Result<Option<String>, InitError> {
+        let value = match self.0.get(flag) {
+            Some(value) => value.clone(),
+            None => {
+                let (_, question) = PARAMETERS
+                    .iter()
+                    .find(|(name, _)| *name == flag)
+                    .expect("We only ask for known parameters");
+                print!("{}: ", question);
+                io::stdout()
+                    .flush()
+                    .map_err(|e| InitError(format!("Writing to stdout: {}", e)))?;
+
+                let mut line = String::new();
+                let read = io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(|e| InitError(format!("Reading from stdin: {}", e)))?;
+                if read == 0 {
+                    return Err(InitError(format!("No value given for '--{}'", flag)));
+                }
+                line.trim().to_string()
+            }
+        };
+
+        Ok(Some(value).filter(|v| !v.is_empty()))
+    }
+
+    fn get_required(&self, flag: &str) -> Result<String, InitError> {
+        self.get(flag)?
+            .ok_or_else(|| InitError(format!("'--{}' is required", flag)))
+    }
+
+    fn get_list(&self, flag: &str) -> Result<Vec<String>, InitError> {
+        Ok(self
+            .get(flag)?
+            .map(|list| {
+                list.split(',')
+                    .map(|elem| elem.trim().to_string())
+                    .filter(|elem| !elem.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn get_integer(&self, flag: &str) -> Result<Option<Value>, InitError> {
+        self.get(flag)?
+            .map(|value| {
+                value
+                    .parse::<u32>()
+                    .map(|n| Value::Integer(n.into()))
+                    .map_err(|e| InitError(format!("Invalid '--{}': {}", flag, e)))
+            })
+            .transpose()
+    }
+
+    // A list of 'noise_key@host' as an array of { host, noise_key } tables
+    fn get_servers(&self, flag: &str) -> Result<Value, InitError> {
+        let servers = self
+            .get_list(flag)?
+            .into_iter()
+            .map(|server| {
+                let mut parts = server.splitn(2, '@');
+                match (parts.next(), parts.next()) {
+                    (Some(noise_key), Some(host)) => {
+                        let mut table = Table::new();
+                        table.insert("host".to_string(), Value::String(host.to_string()));
+                        table.insert(
+                            "noise_key".to_string(),
+                            Value::String(noise_key.to_string()),
+                        );
+                        Ok(Value::Table(table))
+                    }
+                    _ => Err(InitError(format!(
+                        "Invalid '--{}' entry '{}': expected 'noise_key@host'",
+                        flag, server
+                    ))),
+                }
+            })
+            .collect::<Result<Vec<Value>, InitError>>()?;
+
+        Ok(Value::Array(servers))
+    }
+}
+
+fn string_array(values: Vec<String>) -> Value {
+    Value::Array(values.into_iter().map(Value::String).collect())
+}
+
+// Create the content of the configuration file out of the parameters
+fn config_table(args: &InitArgs) -> Result<Table, InitError> {
+    let mut config = Table::new();
+
+    if let Some(data_dir) = args.get("data-dir")? {
+        config.insert("data_dir".to_string(), Value::String(data_dir));
+    }
+
+    let mut bitcoind_config = Table::new();
+    bitcoind_config.insert(
+        "network".to_string(),
+        Value::String(args.get_required("network")?),
+    );
+    bitcoind_config.insert(
+        "cookie_path".to_string(),
+        Value::String(args.get_required("bitcoind-cookie")?),
+    );
+    bitcoind_config.insert(
+        "addr".to_string(),
+        Value::String(args.get_required("bitcoind-addr")?),
+    );
+    config.insert("bitcoind_config".to_string(), Value::Table(bitcoind_config));
+
+    config.insert(
+        "coordinator_host".to_string(),
+        Value::String(args.get_required("coordinator-host")?),
+    );
+    config.insert(
+        "coordinator_noise_key".to_string(),
+        Value::String(args.get_required("coordinator-noise-key")?),
+    );
+
+    config.insert(
+        "stakeholders_xpubs".to_string(),
+        string_array(args.get_list("stakeholders-xpubs")?),
+    );
+    config.insert(
+        "cosigners_keys".to_string(),
+        string_array(args.get_list("cosigners-keys")?),
+    );
+    config.insert(
+        "managers_xpubs".to_string(),
+        string_array(args.get_list("managers-xpubs")?),
+    );
+    if let Some(threshold) = args.get_integer("managers-threshold")? {
+        config.insert("managers_threshold".to_string(), threshold);
+    }
+    config.insert(
+        "unvault_csv".to_string(),
+        args.get_integer("unvault-csv")?
+            .ok_or_else(|| InitError("'--unvault-csv' is required".to_string()))?,
+    );
+
+    let is_stakeholder = if let Some(xpub) = args.get("stakeholder-xpub")? {
+        let mut stk_config = Table::new();
+        stk_config.insert("xpub".to_string(), Value::String(xpub));
+        stk_config.insert(
+            "emergency_address".to_string(),
+            Value::String(args.get_required("emergency-address")?),
+        );
+        stk_config.insert("watchtowers".to_string(), args.get_servers("watchtowers")?);
+        config.insert("stakeholder_config".to_string(), Value::Table(stk_config));
+        true
+    } else {
+        false
+    };
+
+    if let Some(xpub) = args.get("manager-xpub")? {
+        let mut man_config = Table::new();
+        man_config.insert("xpub".to_string(), Value::String(xpub));
+        man_config.insert(
+            "cosigners".to_string(),
+            args.get_servers("cosigning-servers")?,
+        );
+        // As a stakeholder, we know the Emergency address itself.
+        if !is_stakeholder {
+            if let Some(emer_hash) = args.get("emergency-address-hash")? {
+                man_config.insert(
+                    "emergency_address_hash".to_string(),
+                    Value::String(emer_hash),
+                );
+            }
+        }
+        config.insert("manager_config".to_string(), Value::Table(man_config));
+    }
+
+    Ok(config)
+}
+
+// Create the configuration file, refusing to overwrite an existing one.
+fn write_config_file(path: &PathBuf, content: &str) -> Result<(), io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options = options.write(true).create_new(true).clone();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options = options.mode(0o600).clone();
+    }
+
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Create the configuration file, the data directory and our Noise key out of the command line
+/// arguments following `init` (and what we were interactively given).
+pub fn init(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = InitArgs::from_args(args)?;
+
+    let config_path = match args.get("conf")? {
+        Some(path) => PathBuf::from(path),
+        None => config_file_path()?,
+    };
+    if config_path.exists() {
+        return Err(Box::from(InitError(format!(
+            "Configuration file '{}' already exists",
+            config_path.to_string_lossy()
+        ))));
+    }
+
+    let config_content = toml::to_string(&Value::Table(config_table(&args)?))?;
+    // Same checks as when starting up, and before writing anything.
+    let config = Config::from_toml(config_content.as_bytes())?;
+    write_config_file(&config_path, &config_content).map_err(|e| {
+        InitError(format!(
+            "Writing configuration file '{}': {}",
+            config_path.to_string_lossy(),
+            e
+        ))
+    })?;
+
+    // This creates the data directory and our Noise key
+    let revaultd = RevaultD::from_config(config)?;
+
+    println!(
+        "Wrote configuration file at '{}'.",
+        config_path.to_string_lossy()
+    );
+    println!(
+        "Created data directory at '{}'.",
+        revaultd.data_dir.to_string_lossy()
+    );
+    println!(
+        "Our Noise static public key, to share with the other participants: '{}'",
+        revaultd.noise_pubkey().0.to_hex()
+    );
+
+    Ok(())
+}
@@ -2,11 +2,16 @@
 //! *valid* JSONRPC2 commands here. All the communication and parsing is done in the
 //! `server` mod.
 
-use crate::{jsonrpc::UserRole, revaultd::VaultStatus, threadmessages::*};
+use crate::{
+    database::interface::{VaultsOrderBy, VaultsQuery},
+    jsonrpc::UserRole,
+    revaultd::{VaultStatus, HARDENED_INDEX},
+    threadmessages::*,
+};
 use common::{assume_ok, VERSION};
 
 use revault_tx::{
-    bitcoin::OutPoint,
+    bitcoin::{Amount, OutPoint},
     transactions::{
         CancelTransaction, EmergencyTransaction, RevaultTransaction, UnvaultEmergencyTransaction,
         UnvaultTransaction,
@@ -14,6 +19,8 @@ use revault_tx::{
 };
 
 use std::{
+    convert::TryFrom,
+    path::PathBuf,
     process,
     str::FromStr,
     sync::{
@@ -25,8 +32,27 @@ use std::{
 
 use jsonrpc_core::Error as JsonRpcError;
 use jsonrpc_derive::rpc;
+use serde::Deserialize;
 use serde_json::json;
 
+/// The optional parameters of `listvaults` to sort, paginate and filter the vaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListVaultsOptions {
+    limit: Option<u32>,
+    offset: Option<u32>,
+    sort_by: Option<String>,
+    order: Option<String>,
+    min_amount: Option<u64>,
+    max_amount: Option<u64>,
+    updated_after: Option<u32>,
+    updated_before: Option<u32>,
+    label: Option<String>,
+}
+
+/// The maximum length of a vault label, in bytes
+const MAX_LABEL_LEN: usize = 1024;
+
 #[derive(Clone)]
 pub struct JsonRpcMetaData {
     pub tx: Sender<RpcMessageIn>,
@@ -66,13 +92,15 @@ pub trait RpcApi {
     #[rpc(meta, name = "getinfo")]
     fn getinfo(&self, meta: Self::Metadata) -> jsonrpc_core::Result<serde_json::Value>;
 
-    /// Get a list of current vaults, which can be sorted by txids or status
+    /// Get a list of current vaults, which can be filtered by txids or status, and sorted,
+    /// paginated and filtered further by amount and time of last update
     #[rpc(meta, name = "listvaults")]
     fn listvaults(
         &self,
         meta: Self::Metadata,
         statuses: Option<Vec<String>>,
         outpoints: Option<Vec<String>>,
+        options: Option<ListVaultsOptions>,
     ) -> jsonrpc_core::Result<serde_json::Value>;
 
     /// Get an address to receive funds to the stakeholders' descriptor
@@ -134,6 +162,98 @@ pub trait RpcApi {
         meta: Self::Metadata,
         outpoints: Option<Vec<String>>,
     ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Get all the changes of status of a vault identified by its deposit outpoint
+    #[rpc(meta, name = "getvaulthistory")]
+    fn getvaulthistory(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Get everything we know about a vault identified by its deposit outpoint
+    #[rpc(meta, name = "getvault")]
+    fn getvault(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Get the total amount and number of vaults by status
+    #[rpc(meta, name = "getbalance")]
+    fn getbalance(&self, meta: Self::Metadata) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Attach a label to the vault identified by this deposit outpoint. An empty label removes it.
+    #[rpc(meta, name = "setvaultlabel")]
+    fn setvaultlabel(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+        label: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Get the label of the vault identified by this deposit outpoint
+    #[rpc(meta, name = "getvaultlabel")]
+    fn getvaultlabel(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Export all the vaults as they were at a given block height, as CSV or JSON
+    #[rpc(meta, name = "exportvaults")]
+    fn exportvaults(
+        &self,
+        meta: Self::Metadata,
+        format: Option<String>,
+        blockheight: Option<u32>,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Write the fully signed Emergency transactions of our vaults to a kit file at this path
+    #[rpc(meta, name = "emergencykit")]
+    fn emergencykit(
+        &self,
+        meta: Self::Metadata,
+        path: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Write an encrypted backup of our database, configuration and Noise key to this path
+    #[rpc(meta, name = "backup")]
+    fn backup(
+        &self,
+        meta: Self::Metadata,
+        path: String,
+        passphrase: String,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Check our database against bitcoind's watchonly wallet, and repair what can be if asked to
+    #[rpc(meta, name = "checkconsistency")]
+    fn checkconsistency(
+        &self,
+        meta: Self::Metadata,
+        repair: Option<bool>,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Check the stored presigned transactions of a list of vaults against the ones we derive
+    #[rpc(meta, name = "auditpresigned")]
+    fn auditpresigned(
+        &self,
+        meta: Self::Metadata,
+        outpoints: Option<Vec<String>>,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Rescan the chain for deposits to the addresses at this range of derivation indexes
+    #[rpc(meta, name = "rescanindexes")]
+    fn rescanindexes(
+        &self,
+        meta: Self::Metadata,
+        start_index: u32,
+        end_index: u32,
+    ) -> jsonrpc_core::Result<serde_json::Value>;
+
+    /// Rescan the chain from this height, or this timestamp, for the transactions of our wallet
+    #[rpc(meta, name = "rescan")]
+    fn rescan(&self, meta: Self::Metadata, start: u32) -> jsonrpc_core::Result<serde_json::Value>;
 }
 
 // TODO: we should probably make this a proc macro and apply it above?
@@ -187,6 +307,21 @@ macro_rules! parse_outpoints {
     };
 }
 
+// We may be running as a daemon, relative to what?
+macro_rules! parse_absolute_path {
+    ($path:expr) => {{
+        let path = PathBuf::from(&$path);
+        if path.is_absolute() {
+            Ok(path)
+        } else {
+            Err(JsonRpcError::invalid_params(format!(
+                "'{}' is not an absolute path",
+                &$path
+            )))
+        }
+    }};
+}
+
 macro_rules! parse_vault_status {
     ($status:expr) => {
         VaultStatus::from_str(&$status).map_err(|_| {
@@ -195,6 +330,144 @@ macro_rules! parse_vault_status {
     };
 }
 
+// Quote a CSV field if needed, as per RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// The names of the exported transactions, in the order of the CSV columns
+const EXPORTED_TXS: &[&str] = &[
+    "unvault",
+    "cancel",
+    "emergency",
+    "unvault_emergency",
+    "spend",
+];
+
+fn exported_vaults_csv(vaults: &[ExportedVault]) -> String {
+    let mut statuses = Vec::new();
+    while let Ok(status) = VaultStatus::try_from(statuses.len() as u32) {
+        statuses.push(status);
+    }
+
+    let mut header: Vec<String> = [
+        "deposit_txid",
+        "deposit_vout",
+        "amount",
+        "derivation_index",
+        "deposit_blockheight",
+        "status",
+        "updated_at",
+        "label",
+    ]
+    .iter()
+    .map(|column| column.to_string())
+    .collect();
+    header.extend(statuses.iter().map(|status| format!("{}_at", status)));
+    for tx in EXPORTED_TXS {
+        header.push(format!("{}_txid", tx));
+        header.push(format!("{}_fee", tx));
+        header.push(format!("{}_blockheight", tx));
+    }
+
+    let mut lines = vec![header.join(",")];
+    for exported in vaults {
+        let vault = &exported.vault;
+        let derivation_index: u32 = vault.derivation_index.into();
+        let mut line = vec![
+            vault.deposit_outpoint.txid.to_string(),
+            vault.deposit_outpoint.vout.to_string(),
+            vault.amount.as_sat().to_string(),
+            derivation_index.to_string(),
+            vault.blockheight.to_string(),
+            vault.status.to_string(),
+            vault.updated_at.to_string(),
+            csv_field(vault.label.as_deref().unwrap_or("")),
+        ];
+        line.extend(statuses.iter().map(|status| {
+            exported
+                .status_timestamps
+                .iter()
+                .find(|(s, _)| s == status)
+                .map(|(_, timestamp)| timestamp.to_string())
+                .unwrap_or_default()
+        }));
+        for tx in [
+            &exported.unvault,
+            &exported.cancel,
+            &exported.emergency,
+            &exported.unvault_emergency,
+            &exported.spend,
+        ]
+        .iter()
+        {
+            match tx {
+                Some(tx) => {
+                    line.push(tx.txid.to_string());
+                    line.push(
+                        tx.fee
+                            .map(|fee| fee.as_sat().to_string())
+                            .unwrap_or_default(),
+                    );
+                    line.push(
+                        tx.blockheight
+                            .map(|height| height.to_string())
+                            .unwrap_or_default(),
+                    );
+                }
+                None => line.extend(vec![String::new(); 3]),
+            }
+        }
+        lines.push(line.join(","));
+    }
+
+    // Always end with a newline
+    lines.push(String::new());
+    lines.join("\r\n")
+}
+
+fn exported_vault_json(exported: ExportedVault) -> serde_json::Value {
+    let tx_json = |tx: Option<ExportedTransaction>| {
+        tx.map(|tx| {
+            json!({
+                "txid": tx.txid.to_string(),
+                "fee": tx.fee.map(|fee| fee.as_sat()),
+                "blockheight": tx.blockheight,
+            })
+        })
+    };
+    let status_timestamps: serde_json::Map<String, serde_json::Value> = exported
+        .status_timestamps
+        .into_iter()
+        .map(|(status, timestamp)| (status.to_string(), json!(timestamp)))
+        .collect();
+    let vault = exported.vault;
+    let derivation_index: u32 = vault.derivation_index.into();
+
+    json!({
+        "amount": vault.amount.as_sat(),
+        "status": vault.status.to_string(),
+        "txid": vault.deposit_outpoint.txid.to_string(),
+        "vout": vault.deposit_outpoint.vout,
+        "derivation_index": derivation_index,
+        "blockheight": vault.blockheight,
+        "updated_at": vault.updated_at,
+        "label": vault.label,
+        "status_timestamps": status_timestamps,
+        "transactions": {
+            "unvault": tx_json(exported.unvault),
+            "cancel": tx_json(exported.cancel),
+            "emergency": tx_json(exported.emergency),
+            "unvault_emergency": tx_json(exported.unvault_emergency),
+            "spend": tx_json(exported.spend),
+        },
+    })
+}
+
 pub struct RpcImpl;
 impl RpcApi for RpcImpl {
     type Metadata = JsonRpcMetaData;
@@ -211,7 +484,16 @@ impl RpcApi for RpcImpl {
             meta.tx.send(RpcMessageIn::GetInfo(response_tx)),
             "Sending 'getinfo' to main thread"
         );
-        let (net, height, progress) = assume_ok!(
+        let (
+            net,
+            height,
+            progress,
+            fingerprint,
+            poll_interval,
+            min_conf,
+            gap_limit,
+            rescan_progress,
+        ) = assume_ok!(
             response_rx.recv(),
             "Receiving 'getinfo' result from main thread"
         );
@@ -221,6 +503,11 @@ impl RpcApi for RpcImpl {
             "network": net,
             "blockheight": height,
             "sync": progress,
+            "deployment_fingerprint": fingerprint.map(|f| f.to_string()),
+            "bitcoind_poll_interval": poll_interval,
+            "min_conf": min_conf,
+            "gap_limit": gap_limit,
+            "rescan": rescan_progress,
         }))
     }
 
@@ -229,6 +516,7 @@ impl RpcApi for RpcImpl {
         meta: Self::Metadata,
         statuses: Option<Vec<String>>,
         outpoints: Option<Vec<String>>,
+        options: Option<ListVaultsOptions>,
     ) -> jsonrpc_core::Result<serde_json::Value> {
         let statuses = if let Some(statuses) = statuses {
             // If they give an empty array, it's not that they don't want any result, but rather
@@ -248,10 +536,60 @@ impl RpcApi for RpcImpl {
         };
         let outpoints = parse_outpoints!(outpoints);
 
+        let options = options.unwrap_or_default();
+        let order_by = match options.sort_by.as_deref() {
+            None | Some("updated_at") => VaultsOrderBy::UpdatedAt,
+            Some("amount") => VaultsOrderBy::Amount,
+            Some("blockheight") => VaultsOrderBy::Blockheight,
+            Some(sort_by) => {
+                return Err(JsonRpcError::invalid_params(format!(
+                    "'{}' is not a valid sorting field (must be 'updated_at', 'amount' or \
+                     'blockheight')",
+                    sort_by
+                )))
+            }
+        };
+        let descending = match options.order.as_deref() {
+            None | Some("desc") => true,
+            Some("asc") => false,
+            Some(order) => {
+                return Err(JsonRpcError::invalid_params(format!(
+                    "'{}' is not a valid order (must be 'asc' or 'desc')",
+                    order
+                )))
+            }
+        };
+        if let (Some(min), Some(max)) = (options.min_amount, options.max_amount) {
+            if min > max {
+                return Err(JsonRpcError::invalid_params(
+                    "'min_amount' is larger than 'max_amount'".to_string(),
+                ));
+            }
+        }
+        if let (Some(after), Some(before)) = (options.updated_after, options.updated_before) {
+            if after > before {
+                return Err(JsonRpcError::invalid_params(
+                    "'updated_after' is later than 'updated_before'".to_string(),
+                ));
+            }
+        }
+        let query = VaultsQuery {
+            statuses,
+            outpoints,
+            min_amount: options.min_amount.map(Amount::from_sat),
+            max_amount: options.max_amount.map(Amount::from_sat),
+            updated_after: options.updated_after,
+            updated_before: options.updated_before,
+            label: options.label,
+            order_by,
+            descending,
+            limit: options.limit,
+            offset: options.offset.unwrap_or(0),
+        };
+
         let (response_tx, response_rx) = mpsc::sync_channel(0);
         assume_ok!(
-            meta.tx
-                .send(RpcMessageIn::ListVaults((statuses, outpoints), response_tx)),
+            meta.tx.send(RpcMessageIn::ListVaults(query, response_tx)),
             "Sending 'listvaults' to main thread"
         );
         let vaults = assume_ok!(
@@ -269,7 +607,9 @@ impl RpcApi for RpcImpl {
                     "vout": entry.deposit_outpoint.vout,
                     "derivation_index": derivation_index,
                     "address": entry.address.to_string(),
+                    "blockheight": entry.blockheight,
                     "updated_at": entry.updated_at,
+                    "label": entry.label,
                 })
             })
             .collect();
@@ -434,6 +774,7 @@ impl RpcApi for RpcImpl {
         fn wallet_tx_to_json(tx: WalletTransaction) -> serde_json::Value {
             json!({
                 "blockheight": tx.blockheight.map(serde_json::Number::from),
+                "blocktime": tx.blocktime.map(serde_json::Number::from),
                 "received_at": serde_json::Number::from(tx.received_time),
                 "hex": serde_json::Value::String(tx.hex),
             })
@@ -507,4 +848,450 @@ impl RpcApi for RpcImpl {
 
         Ok(json!({}))
     }
+
+    fn getvaulthistory(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let outpoint = parse_outpoint!(outpoint)?;
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::GetVaultHistory(outpoint, response_tx)),
+            "Sending 'getvaulthistory' to main thread"
+        );
+        let events: Vec<serde_json::Value> = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'getvaulthistory' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?
+        .into_iter()
+        .map(|event| {
+            json!({
+                "old_status": event.old_status.map(|s| s.to_string()),
+                "new_status": event.new_status.to_string(),
+                "timestamp": event.timestamp,
+                "tip_height": event.tip_height,
+                "txid": event.txid.map(|txid| txid.to_string()),
+            })
+        })
+        .collect();
+
+        Ok(json!({ "events": events }))
+    }
+
+    fn getvault(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let outpoint = parse_outpoint!(outpoint)?;
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::GetVault(outpoint, response_tx)),
+            "Sending 'getvault' to main thread"
+        );
+        let details = assume_ok!(response_rx.recv(), "Receiving 'getvault' from main thread")
+            .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        let tip_height = details.tip_height;
+        let wallet_tx_to_json = |tx: WalletTransaction| {
+            // Unconfirmed transactions have 0 confirmations
+            let confirmations = tx
+                .blockheight
+                .map(|height| (tip_height + 1).saturating_sub(height))
+                .unwrap_or(0);
+            json!({
+                "blockheight": tx.blockheight,
+                "blocktime": tx.blocktime,
+                "received_at": tx.received_time,
+                "confirmations": confirmations,
+                "hex": tx.hex,
+            })
+        };
+        let onchain = details.onchain_transactions;
+        let presigned: Vec<serde_json::Value> = details
+            .presigned_transactions
+            .into_iter()
+            .map(|tx| {
+                json!({
+                    "type": tx.tx_type.to_string(),
+                    "txid": tx.txid.to_string(),
+                    "psbt": tx.psbt,
+                    "signatures": tx.signatures,
+                    "fully_signed": tx.fully_signed,
+                })
+            })
+            .collect();
+        let events: Vec<serde_json::Value> = details
+            .events
+            .into_iter()
+            .map(|event| {
+                json!({
+                    "old_status": event.old_status.map(|s| s.to_string()),
+                    "new_status": event.new_status.to_string(),
+                    "timestamp": event.timestamp,
+                    "tip_height": event.tip_height,
+                    "txid": event.txid.map(|txid| txid.to_string()),
+                })
+            })
+            .collect();
+        let vault = details.vault;
+        let derivation_index: u32 = vault.derivation_index.into();
+
+        Ok(json!({
+            "amount": vault.amount.as_sat(),
+            "status": vault.status.to_string(),
+            "txid": vault.deposit_outpoint.txid.to_string(),
+            "vout": vault.deposit_outpoint.vout,
+            "derivation_index": derivation_index,
+            "blockheight": vault.blockheight,
+            "updated_at": vault.updated_at,
+            "label": vault.label,
+            "deposit_address": vault.address.to_string(),
+            "unvault_address": details.unvault_address.to_string(),
+            "cpfp_address": details.cpfp_address.to_string(),
+            "remaining_csv": details.remaining_csv,
+            "presigned_transactions": presigned,
+            "onchain_transactions": {
+                "deposit": wallet_tx_to_json(onchain.deposit),
+                "unvault": onchain.unvault.map(wallet_tx_to_json),
+                "cancel": onchain.cancel.map(wallet_tx_to_json),
+                "emergency": onchain.emergency.map(wallet_tx_to_json),
+                "unvault_emergency": onchain.unvault_emergency.map(wallet_tx_to_json),
+                "spend": onchain.spend.map(wallet_tx_to_json),
+            },
+            "events": events,
+        }))
+    }
+
+    fn getbalance(&self, meta: Self::Metadata) -> jsonrpc_core::Result<serde_json::Value> {
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::GetBalance(response_tx)),
+            "Sending 'getbalance' to main thread"
+        );
+        let balance = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'getbalance' from main thread"
+        );
+
+        // Every status is present in the output, even if we don't have any vault in it.
+        let mut statuses = serde_json::Map::new();
+        let mut n = 0;
+        while let Ok(status) = VaultStatus::try_from(n) {
+            statuses.insert(status.to_string(), json!({ "amount": 0, "count": 0 }));
+            n += 1;
+        }
+        let (mut confirmed, mut moving, mut unconfirmed) = ((0, 0), (0, 0), (0, 0));
+        for entry in balance {
+            let amount = entry.amount.as_sat();
+            statuses.insert(
+                entry.status.to_string(),
+                json!({ "amount": amount, "count": entry.count }),
+            );
+
+            // The funds of vaults in a final state already moved out of them
+            let total = match entry.status {
+                VaultStatus::Unconfirmed => &mut unconfirmed,
+                VaultStatus::Funded | VaultStatus::Secured | VaultStatus::Active => &mut confirmed,
+                VaultStatus::Unvaulting
+                | VaultStatus::Unvaulted
+                | VaultStatus::Canceling
+                | VaultStatus::EmergencyVaulting
+                | VaultStatus::UnvaultEmergencyVaulting
+                | VaultStatus::Spendable
+                | VaultStatus::Spending => &mut moving,
+                VaultStatus::Canceled
+                | VaultStatus::EmergencyVaulted
+                | VaultStatus::UnvaultEmergencyVaulted
+                | VaultStatus::Spent => continue,
+            };
+            total.0 += amount;
+            total.1 += entry.count;
+        }
+
+        Ok(json!({
+            "statuses": statuses,
+            "confirmed": { "amount": confirmed.0, "count": confirmed.1 },
+            "moving": { "amount": moving.0, "count": moving.1 },
+            "unconfirmed": { "amount": unconfirmed.0, "count": unconfirmed.1 },
+        }))
+    }
+
+    fn setvaultlabel(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+        label: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let outpoint = parse_outpoint!(outpoint)?;
+        if label.len() > MAX_LABEL_LEN {
+            return Err(JsonRpcError::invalid_params(format!(
+                "Label is too long ({} bytes, maximum is {})",
+                label.len(),
+                MAX_LABEL_LEN
+            )));
+        }
+        let label = Some(label).filter(|l| !l.is_empty());
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::SetVaultLabel((outpoint, label), response_tx)),
+            "Sending 'setvaultlabel' to main thread"
+        );
+        assume_ok!(
+            response_rx.recv(),
+            "Receiving 'setvaultlabel' from main thread"
+        );
+
+        Ok(json!({}))
+    }
+
+    fn getvaultlabel(
+        &self,
+        meta: Self::Metadata,
+        outpoint: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let outpoint = parse_outpoint!(outpoint)?;
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::GetVaultLabel(outpoint, response_tx)),
+            "Sending 'getvaultlabel' to main thread"
+        );
+        let label = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'getvaultlabel' from main thread"
+        );
+
+        Ok(json!({ "label": label }))
+    }
+
+    fn exportvaults(
+        &self,
+        meta: Self::Metadata,
+        format: Option<String>,
+        blockheight: Option<u32>,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let as_csv = match format.as_deref() {
+            None | Some("json") => false,
+            Some("csv") => true,
+            Some(format) => {
+                return Err(JsonRpcError::invalid_params(format!(
+                    "'{}' is not a valid export format (must be 'json' or 'csv')",
+                    format
+                )))
+            }
+        };
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::ExportVaults(blockheight, response_tx)),
+            "Sending 'exportvaults' to main thread"
+        );
+        let (blockheight, vaults) = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'exportvaults' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        if as_csv {
+            Ok(json!({
+                "blockheight": blockheight,
+                "csv": exported_vaults_csv(&vaults),
+            }))
+        } else {
+            let vaults: Vec<serde_json::Value> =
+                vaults.into_iter().map(exported_vault_json).collect();
+            Ok(json!({
+                "blockheight": blockheight,
+                "vaults": vaults,
+            }))
+        }
+    }
+
+    fn emergencykit(
+        &self,
+        meta: Self::Metadata,
+        path: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        stakeholder_only!(meta);
+        let path = parse_absolute_path!(path)?;
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::EmergencyKit(path, response_tx)),
+            "Sending 'emergencykit' to main thread"
+        );
+        let kit = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'emergencykit' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        Ok(json!({
+            "vaults": kit.vaults.len(),
+            "emergency": kit.vaults.iter().filter(|v| v.emergency_tx.is_some()).count(),
+            "unvault_emergency": kit
+                .vaults
+                .iter()
+                .filter(|v| v.unvault_emergency_tx.is_some())
+                .count(),
+        }))
+    }
+
+    fn backup(
+        &self,
+        meta: Self::Metadata,
+        path: String,
+        passphrase: String,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let path = parse_absolute_path!(path)?;
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::Backup((path, passphrase), response_tx)),
+            "Sending 'backup' to main thread"
+        );
+        assume_ok!(response_rx.recv(), "Receiving 'backup' from main thread")
+            .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        Ok(json!({}))
+    }
+
+    fn checkconsistency(
+        &self,
+        meta: Self::Metadata,
+        repair: Option<bool>,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::CheckConsistency(
+                repair.unwrap_or(false),
+                response_tx
+            )),
+            "Sending 'checkconsistency' to main thread"
+        );
+        let inconsistencies = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'checkconsistency' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        let inconsistencies: Vec<serde_json::Value> = inconsistencies
+            .into_iter()
+            .map(|inconsistency| {
+                json!({
+                    "txid": inconsistency.deposit_outpoint.txid.to_string(),
+                    "vout": inconsistency.deposit_outpoint.vout,
+                    "kind": inconsistency.kind.to_string(),
+                    "description": inconsistency.description,
+                    "repaired": inconsistency.repaired,
+                })
+            })
+            .collect();
+
+        Ok(json!({
+            "inconsistencies": inconsistencies,
+        }))
+    }
+
+    fn auditpresigned(
+        &self,
+        meta: Self::Metadata,
+        outpoints: Option<Vec<String>>,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        let outpoints = parse_outpoints!(outpoints);
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx
+                .send(RpcMessageIn::AuditPresigned(outpoints, response_tx)),
+            "Sending 'auditpresigned' to main thread"
+        );
+        let audits = assume_ok!(
+            response_rx.recv(),
+            "Receiving 'auditpresigned' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        let vaults: Vec<serde_json::Value> = audits
+            .into_iter()
+            .map(|audit| {
+                let mismatches: Vec<serde_json::Value> = audit
+                    .mismatches
+                    .into_iter()
+                    .map(|mismatch| {
+                        json!({
+                            "transaction_type": mismatch.tx_type.to_string(),
+                            "expected_txid": mismatch.expected_txid.map(|txid| txid.to_string()),
+                            "stored_txid": mismatch.stored_txid.map(|txid| txid.to_string()),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "vault_outpoint": audit.outpoint.to_string(),
+                    "valid": mismatches.is_empty(),
+                    "mismatches": mismatches,
+                })
+            })
+            .collect();
+
+        Ok(json!({ "vaults": vaults }))
+    }
+    fn rescanindexes(
+        &self,
+        meta: Self::Metadata,
+        start_index: u32,
+        end_index: u32,
+    ) -> jsonrpc_core::Result<serde_json::Value> {
+        if start_index > end_index {
+            return Err(JsonRpcError::invalid_params(format!(
+                "Start index '{}' is above end index '{}'",
+                start_index, end_index
+            )));
+        }
+        if end_index >= HARDENED_INDEX {
+            return Err(JsonRpcError::invalid_params(format!(
+                "End index '{}' is a hardened derivation index",
+                end_index
+            )));
+        }
+
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::RescanIndexes(
+                start_index..end_index + 1,
+                response_tx
+            )),
+            "Sending 'rescanindexes' to main thread"
+        );
+        assume_ok!(
+            response_rx.recv(),
+            "Receiving 'rescanindexes' from main thread"
+        )
+        .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        Ok(json!({}))
+    }
+
+    fn rescan(&self, meta: Self::Metadata, start: u32) -> jsonrpc_core::Result<serde_json::Value> {
+        let (response_tx, response_rx) = mpsc::sync_channel(0);
+        assume_ok!(
+            meta.tx.send(RpcMessageIn::Rescan(start, response_tx)),
+            "Sending 'rescan' to main thread"
+        );
+        assume_ok!(response_rx.recv(), "Receiving 'rescan' from main thread")
+            .map_err(|e| JsonRpcError::invalid_params(e.to_string()))?;
+
+        Ok(json!({}))
+    }
 }
@@ -1,9 +1,13 @@
+mod audit;
+mod backup;
 mod bitcoind;
 mod control;
 mod database;
+mod init;
 mod jsonrpc;
 mod revaultd;
 mod sigfetcher;
+mod statemachine;
 mod threadmessages;
 
 use crate::{
@@ -17,7 +21,10 @@ use crate::{
     revaultd::RevaultD,
     sigfetcher::signature_fetcher_loop,
 };
-use common::{assume_ok, config::Config};
+use common::{
+    assume_ok,
+    config::{config_file_path, Config},
+};
 use revault_net::sodiumoxide;
 use revault_tx::bitcoin::hashes::hex::ToHex;
 
@@ -32,22 +39,89 @@ use std::{
 
 use daemonize_simple::Daemonize;
 
-fn parse_args(args: Vec<String>) -> Option<PathBuf> {
-    if args.len() == 1 {
-        return None;
+// The command line arguments of the daemon itself
+struct Args {
+    conf_file: Option<PathBuf>,
+    recover: bool,
+    recovery_gap: Option<u32>,
+    recovery_start: Option<u32>,
+    restore: Option<PathBuf>,
+}
+
+fn args_error(message: String) -> ! {
+    eprintln!("{}", message);
+    eprintln!(
+        "Only '--conf <configuration file path>', '--recover [--recovery-gap <gap>] \
+         [--recovery-start <height or timestamp>]', '--restore <backup file path>' and \
+         'init [--<parameter> <value>]...' are supported."
+    );
+    process::exit(1);
+}
+
+fn parse_args(args: Vec<String>) -> Args {
+    let mut parsed = Args {
+        conf_file: None,
+        recover: false,
+        recovery_gap: None,
+        recovery_start: None,
+        restore: None,
+    };
+    let mut args = args.into_iter().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--conf" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| args_error("No value for '--conf'.".to_string()));
+                parsed.conf_file = Some(PathBuf::from(path));
+            }
+            "--recover" => parsed.recover = true,
+            "--restore" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| args_error("No value for '--restore'.".to_string()));
+                parsed.restore = Some(PathBuf::from(path));
+            }
+            "--recovery-gap" => {
+                let gap = args
+                    .next()
+                    .unwrap_or_else(|| args_error("No value for '--recovery-gap'.".to_string()));
+                let gap = gap
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|gap| *gap > 0)
+                    .unwrap_or_else(|| args_error(format!("Invalid recovery gap '{}'.", gap)));
+                parsed.recovery_gap = Some(gap);
+            }
+            "--recovery-start" => {
+                let start = args
+                    .next()
+                    .unwrap_or_else(|| args_error("No value for '--recovery-start'.".to_string()));
+                let start = start
+                    .parse::<u32>()
+                    .unwrap_or_else(|_| args_error(format!("Invalid recovery start '{}'.", start)));
+                parsed.recovery_start = Some(start);
+            }
+            _ => args_error(format!("Unknown argument '{}'.", arg)),
+        }
     }
 
-    if args.len() != 3 {
-        eprintln!("Unknown arguments '{:?}'.", args);
-        eprintln!("Only '--conf <configuration file path>' is supported.");
-        process::exit(1);
+    if parsed.recovery_gap.is_some() && !parsed.recover {
+        args_error("'--recovery-gap' is only meaningful with '--recover'.".to_string());
+    }
+    if parsed.recovery_start.is_some() && !parsed.recover {
+        args_error("'--recovery-start' is only meaningful with '--recover'.".to_string());
+    }
+    if parsed.recover && parsed.restore.is_some() {
+        args_error("'--recover' and '--restore' are mutually exclusive.".to_string());
     }
 
-    Some(PathBuf::from(args[2].to_owned()))
+    parsed
 }
 
 fn daemon_main(mut revaultd: RevaultD) {
-    let (db_path, network) = (revaultd.db_file(), revaultd.bitcoind_config.network);
+    let (db, network) = (revaultd.db.clone(), revaultd.bitcoind_config.network);
     let user_role = match (revaultd.is_stakeholder(), revaultd.is_manager()) {
         (true, false) => UserRole::Stakeholder,
         (false, true) => UserRole::Manager,
@@ -59,6 +133,19 @@ fn daemon_main(mut revaultd: RevaultD) {
     log::info!("Setting up database");
     assume_ok!(setup_db(&mut revaultd), "Error setting up database");
 
+    // Participants configured with different parameters would not be able to cooperate.
+    // Until the Coordinator can check it for us, they need to compare it out of band.
+    match revaultd.deployment_fingerprint() {
+        Some(fingerprint) => log::info!(
+            "Deployment fingerprint: '{}'. It must be the same for all participants.",
+            fingerprint
+        ),
+        None => log::warn!(
+            "Can't compute the deployment fingerprint without the Emergency address hash. \
+             Set 'emergency_address_hash' in the 'manager_config' section."
+        ),
+    }
+
     log::info!("Setting up bitcoind connection");
     let bitcoind = assume_ok!(start_bitcoind(&mut revaultd), "Error setting up bitcoind");
 
@@ -114,7 +201,7 @@ fn daemon_main(mut revaultd: RevaultD) {
     assume_ok!(
         handle_rpc_messages(
             revaultd,
-            db_path,
+            db,
             network,
             rpc_rx,
             rpc_thread,
@@ -155,8 +242,7 @@ fn setup_logger(
 }
 
 fn main() {
-    let args = env::args().collect();
-    let conf_file = parse_args(args);
+    let args: Vec<String> = env::args().collect();
 
     // We use libsodium for Noise keys and Noise channels (through revault_net)
     sodiumoxide::init().unwrap_or_else(|_| {
@@ -164,7 +250,30 @@ fn main() {
         process::exit(1);
     });
 
-    let config = Config::from_file(conf_file).unwrap_or_else(|e| {
+    if args.get(1).map(|arg| arg.as_str()) == Some("init") {
+        init::init(&args[2..]).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        process::exit(0);
+    }
+    let args = parse_args(args);
+
+    if let Some(backup_file) = args.restore {
+        backup::restore(&backup_file, args.conf_file).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        process::exit(0);
+    }
+
+    let config_file = args.conf_file.unwrap_or_else(|| {
+        config_file_path().unwrap_or_else(|e| {
+            eprintln!("Error getting config file path: {}", e);
+            process::exit(1);
+        })
+    });
+    let config = Config::from_file(Some(config_file.clone())).unwrap_or_else(|e| {
         eprintln!("Error parsing config: {}", e);
         process::exit(1);
     });
@@ -177,10 +286,23 @@ fn main() {
         log::LevelFilter::Info
     };
     // FIXME: should probably be from_db(), would allow us to not use Option members
-    let revaultd = RevaultD::from_config(config).unwrap_or_else(|e| {
+    let mut revaultd = RevaultD::from_config(config).unwrap_or_else(|e| {
         eprintln!("Error creating global state: {}", e);
         process::exit(1);
     });
+    revaultd.config_file = Some(config_file);
+    if args.recover {
+        // We'd mix the recovered vaults with the ones we know of. Refuse to touch them.
+        if revaultd.db_file().exists() {
+            eprintln!(
+                "Refusing to recover: a database already exists at '{}'.",
+                revaultd.db_file().to_string_lossy()
+            );
+            process::exit(1);
+        }
+        revaultd.recovery_gap = Some(args.recovery_gap.unwrap_or_else(|| revaultd.gap_limit));
+        revaultd.recovery_start = args.recovery_start;
+    }
 
     let log_file = revaultd.log_file();
     let log_output = if revaultd.daemon {
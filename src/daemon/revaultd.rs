@@ -1,4 +1,8 @@
-use common::config::{config_folder_path, BitcoindConfig, Config, ConfigError};
+use crate::database::interface::DbConnection;
+use common::config::{
+    config_folder_path, emergency_address_hash, BitcoindConfig, Config, ConfigError,
+    RevaultDescriptors,
+};
 
 use std::{
     collections::HashMap,
@@ -18,23 +22,30 @@ use revault_net::{
 };
 use revault_tx::{
     bitcoin::{
+        hashes::{sha256, Hash, HashEngine},
         secp256k1,
-        util::bip32::{ChildNumber, DerivationPath, ExtendedPubKey},
-        Address, BlockHash, Script, TxOut,
-    },
-    miniscript::descriptor::{
-        DescriptorPublicKey, DescriptorPublicKeyCtx, DescriptorSinglePub, DescriptorXKey,
-    },
-    scripts::{
-        cpfp_descriptor, deposit_descriptor, unvault_descriptor, CpfpDescriptor, DepositDescriptor,
-        EmergencyAddress, UnvaultDescriptor,
+        util::bip32::{ChildNumber, ExtendedPubKey},
+        Address, BlockHash, Network, Script, TxOut,
     },
+    miniscript::descriptor::{DescriptorPublicKey, DescriptorPublicKeyCtx},
+    scripts::{CpfpDescriptor, DepositDescriptor, EmergencyAddress, UnvaultDescriptor},
     transactions::{
         CancelTransaction, DepositTransaction, EmergencyTransaction, UnvaultEmergencyTransaction,
         UnvaultTransaction,
     },
 };
 
+/// The scripts of our deposit, Unvault and CPFP descriptors derived at a given index
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedScripts {
+    pub deposit: Script,
+    pub unvault: Script,
+    pub cpfp: Script,
+}
+
+/// The first hardened derivation index. We derive our descriptors at normal indexes only.
+pub const HARDENED_INDEX: u32 = 1 << 31;
+
 /// The status of a [Vault], depends both on the block chain and the set of pre-signed
 /// transactions
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -259,6 +270,8 @@ pub struct RevaultD {
     pub lock_time: u32,
     /// The CSV in the unvault_descriptor. Unfortunately segregated from the descriptor..
     pub unvault_csv: u32,
+    /// The hash of the Emergency address scriptPubKey, if we know it
+    pub emergency_address_hash: Option<sha256::Hash>,
 
     // Network stuff
     /// The static private key we use to establish connections to servers. We reuse it, but Trevor
@@ -269,24 +282,64 @@ pub struct RevaultD {
     /// The static public key to enact the Noise channel with the Coordinator
     pub coordinator_noisekey: NoisePubKey,
     pub coordinator_poll_interval: time::Duration,
+    /// How often we poll bitcoind
+    pub bitcoind_poll_interval: time::Duration,
 
     // 'Wallet' stuff
     /// A map from a scriptPubKey to a derivation index. Used to retrieve the actual public
     /// keys used to generate a script from bitcoind until we can pass it xpub-expressed
     /// Miniscript descriptors.
     pub derivation_index_map: HashMap<Script, ChildNumber>,
+    /// The scripts we derived from our descriptors, by derivation index. Derivation is slow
+    /// with many participants, so they are cached in database too.
+    pub derived_scripts: HashMap<ChildNumber, DerivedScripts>,
+    /// How many deposit addresses we watch past the last used one
+    pub gap_limit: u32,
+    /// How many confirmations a deposit needs before we consider it
+    pub min_conf: u32,
     /// The id of the wallet used in the db
     pub wallet_id: Option<u32>,
+    /// The handle to our database, shared by all threads
+    pub db: DbConnection,
 
     // Misc stuff
     /// We store all our data in one place, that's here.
     pub data_dir: PathBuf,
     /// Should we run as a daemon? (Default: yes)
     pub daemon: bool,
+    /// The configuration file we were started with, if we know it. It's part of our backups.
+    pub config_file: Option<PathBuf>,
+    /// If set, we were started with `--recover` and rebuild the database from the chain once
+    /// bitcoind is synced, until this many consecutive derivation indexes are unused.
+    pub recovery_gap: Option<u32>,
+    /// The block height or timestamp to rescan the chain from when recovering. The genesis block
+    /// if not set.
+    pub recovery_start: Option<u32>,
     // TODO: servers connection stuff
 }
 
-fn create_datadir(datadir_path: &PathBuf) -> Result<(), std::io::Error> {
+/// A descriptor without its keys' origins nor its checksum, to compare or hash it. They don't
+/// change the scripts, and are only present if the descriptors were configured directly.
+pub fn canonical_descriptor(descriptor: &str) -> String {
+    let descriptor = descriptor.split('#').next().unwrap_or("");
+    let mut in_origin = false;
+    descriptor
+        .chars()
+        .filter(|c| match c {
+            '[' => {
+                in_origin = true;
+                false
+            }
+            ']' => {
+                in_origin = false;
+                false
+            }
+            _ => !in_origin,
+        })
+        .collect()
+}
+
+pub fn create_datadir(datadir_path: &PathBuf) -> Result<(), std::io::Error> {
     #[cfg(unix)]
     return {
         use fs::DirBuilder;
@@ -303,46 +356,28 @@ fn create_datadir(datadir_path: &PathBuf) -> Result<(), std::io::Error> {
     };
 }
 
-fn descriptorxpub_from_xpub(xpubs: Vec<ExtendedPubKey>) -> Vec<DescriptorPublicKey> {
-    xpubs
-        .into_iter()
-        .map(|xpub| {
-            DescriptorPublicKey::XPub(DescriptorXKey {
-                origin: None,
-                xkey: xpub,
-                derivation_path: DerivationPath::from(vec![]),
-                is_wildcard: true,
-            })
-        })
-        .collect()
-}
-
 impl RevaultD {
     /// Creates our global state by consuming the static configuration
     pub fn from_config(config: Config) -> Result<RevaultD, Box<dyn std::error::Error>> {
-        let our_man_xpub = config.manager_config.map(|x| x.xpub);
+        let our_man_xpub = config.manager_config.as_ref().map(|x| x.xpub);
         let our_stk_xpub = config.stakeholder_config.as_ref().map(|x| x.xpub);
         // Config should have checked that!
         assert!(our_man_xpub.is_some() || our_stk_xpub.is_some());
 
-        let managers_pubkeys = descriptorxpub_from_xpub(config.managers_xpubs);
-        let stakeholders_pubkeys = descriptorxpub_from_xpub(config.stakeholders_xpubs);
-        let cosigners_pubkeys = config
-            .cosigners_keys
-            .into_iter()
-            .map(|key| DescriptorPublicKey::SinglePub(DescriptorSinglePub { origin: None, key }))
-            .collect();
-
-        let deposit_descriptor = deposit_descriptor(stakeholders_pubkeys.clone())?;
-        let unvault_descriptor = unvault_descriptor(
-            stakeholders_pubkeys,
-            managers_pubkeys.clone(),
-            managers_pubkeys.len(),
-            cosigners_pubkeys,
-            config.unvault_csv,
-        )?;
-        let cpfp_descriptor = cpfp_descriptor(managers_pubkeys)?;
+        let RevaultDescriptors {
+            deposit_descriptor,
+            unvault_descriptor,
+            cpfp_descriptor,
+            unvault_csv,
+            ..
+        } = config.scripts_config.revault_descriptors()?;
         let emergency_address = config.stakeholder_config.map(|x| x.emergency_address);
+        // Managers don't know the Emergency address, but may be given its hash
+        let man_emer_hash = config.manager_config.and_then(|x| x.emergency_address_hash);
+        let emergency_address_hash = emergency_address
+            .as_ref()
+            .map(emergency_address_hash)
+            .or(man_emer_hash);
 
         let mut data_dir = config.data_dir.unwrap_or(config_folder_path()?);
         data_dir.push(config.bitcoind_config.network.to_string());
@@ -362,6 +397,7 @@ impl RevaultD {
             .expect("Impossible: the datadir path is valid unicode");
         let noise_secret_file = [data_dir_str, "noise_secret"].iter().collect();
         let noise_secret = read_or_create_noise_key(noise_secret_file)?;
+        let db = DbConnection::new([data_dir_str, "revaultd.sqlite3"].iter().collect());
 
         // TODO: support hidden services
         let coordinator_host = SocketAddr::from_str(&config.coordinator_host)?;
@@ -369,6 +405,18 @@ impl RevaultD {
         let coordinator_poll_interval =
             time::Duration::from_secs(config.coordinator_poll_seconds.unwrap_or(60));
 
+        // When bitcoind is synced, we poll each 30s by default. On regtest we speed it up for
+        // testing.
+        let bitcoind_poll_interval =
+            time::Duration::from_secs(config.bitcoind_config.poll_seconds.unwrap_or(
+                match config.bitcoind_config.network {
+                    Network::Regtest => 3,
+                    _ => 30,
+                },
+            ));
+        let gap_limit = config.gap_limit.unwrap_or(100);
+        let min_conf = config.min_conf.unwrap_or(6);
+
         let daemon = !matches!(config.daemon, Some(false));
 
         let secp_ctx = secp256k1::Secp256k1::verification_only();
@@ -382,21 +430,30 @@ impl RevaultD {
             secp_ctx,
             data_dir,
             daemon,
+            config_file: None,
+            recovery_gap: None,
+            recovery_start: None,
             emergency_address,
             noise_secret,
             coordinator_host,
             coordinator_noisekey,
             coordinator_poll_interval,
+            bitcoind_poll_interval,
             lock_time: 0,
-            unvault_csv: config.unvault_csv,
+            unvault_csv,
+            emergency_address_hash,
             bitcoind_config: config.bitcoind_config,
             tip: None,
             // Will be updated by the database
             current_unused_index: ChildNumber::from(0),
             // FIXME: we don't need SipHash for those, use a faster alternative
             derivation_index_map: HashMap::new(),
+            derived_scripts: HashMap::new(),
+            gap_limit,
+            min_conf,
             // Will be updated soon (:tm:)
             wallet_id: None,
+            db,
         })
     }
 
@@ -421,7 +478,7 @@ impl RevaultD {
         NoisePubKey(curve25519::scalarmult_base(&scalar).0)
     }
 
-    pub fn vault_address(&self, child_number: ChildNumber) -> Address {
+    fn derived_deposit_address(&self, child_number: ChildNumber) -> Address {
         self.deposit_descriptor
             .derive(child_number)
             .0
@@ -429,7 +486,7 @@ impl RevaultD {
             .expect("deposit_descriptor is a wsh")
     }
 
-    pub fn unvault_address(&self, child_number: ChildNumber) -> Address {
+    fn derived_unvault_address(&self, child_number: ChildNumber) -> Address {
         self.unvault_descriptor
             .derive(child_number)
             .0
@@ -437,8 +494,78 @@ impl RevaultD {
             .expect("unvault_descriptor is a wsh")
     }
 
-    pub fn gap_limit(&self) -> u32 {
-        100
+    fn derived_cpfp_address(&self, child_number: ChildNumber) -> Address {
+        self.cpfp_descriptor
+            .derive(child_number)
+            .0
+            .address(self.bitcoind_config.network, self.xpub_ctx())
+            .expect("cpfp_descriptor is a wsh")
+    }
+
+    /// Derive our descriptors at this index. This is slow, see [RevaultD::cache_scripts].
+    pub fn derive_scripts(&self, child_number: ChildNumber) -> DerivedScripts {
+        DerivedScripts {
+            deposit: self.derived_deposit_address(child_number).script_pubkey(),
+            unvault: self.derived_unvault_address(child_number).script_pubkey(),
+            cpfp: self.derived_cpfp_address(child_number).script_pubkey(),
+        }
+    }
+
+    /// Remember the scripts we derived at this index, the addresses at this index won't be
+    /// derived again.
+    pub fn cache_scripts(&mut self, child_number: ChildNumber, scripts: DerivedScripts) {
+        self.derivation_index_map
+            .insert(scripts.deposit.clone(), child_number);
+        self.derived_scripts.insert(child_number, scripts);
+    }
+
+    fn script_address(&self, script: &Script) -> Address {
+        Address::from_script(script, self.bitcoind_config.network).expect("Derived from a wsh")
+    }
+
+    pub fn vault_address(&self, child_number: ChildNumber) -> Address {
+        match self.derived_scripts.get(&child_number) {
+            Some(scripts) => self.script_address(&scripts.deposit),
+            None => self.derived_deposit_address(child_number),
+        }
+    }
+
+    pub fn unvault_address(&self, child_number: ChildNumber) -> Address {
+        match self.derived_scripts.get(&child_number) {
+            Some(scripts) => self.script_address(&scripts.unvault),
+            None => self.derived_unvault_address(child_number),
+        }
+    }
+
+    pub fn cpfp_address(&self, child_number: ChildNumber) -> Address {
+        match self.derived_scripts.get(&child_number) {
+            Some(scripts) => self.script_address(&scripts.cpfp),
+            None => self.derived_cpfp_address(child_number),
+        }
+    }
+
+    /// A fingerprint of the parameters all the participants must share: the deposit, unvault
+    /// and CPFP descriptors, the Unvault CSV and the Emergency address hash. Participants
+    /// configured with a mistyped key or CSV would end up with a different fingerprint. The keys'
+    /// origins aren't part of it, so that it doesn't depend on the form of the configuration.
+    /// None if we don't know the Emergency address hash.
+    pub fn deployment_fingerprint(&self) -> Option<sha256::Hash> {
+        let emergency_address_hash = self.emergency_address_hash?;
+
+        let mut engine = sha256::Hash::engine();
+        for descriptor in &[
+            canonical_descriptor(&self.deposit_descriptor.0.to_string()),
+            canonical_descriptor(&self.unvault_descriptor.0.to_string()),
+            canonical_descriptor(&self.cpfp_descriptor.0.to_string()),
+        ] {
+            // Length-prefix them to not be ambiguous
+            engine.input(&(descriptor.len() as u32).to_be_bytes());
+            engine.input(descriptor.as_bytes());
+        }
+        engine.input(&self.unvault_csv.to_be_bytes());
+        engine.input(&emergency_address_hash[..]);
+
+        Some(sha256::Hash::from_engine(engine))
     }
 
     pub fn watchonly_wallet_name(&self) -> Option<String> {
@@ -479,23 +606,33 @@ impl RevaultD {
         self.our_man_xpub.is_some()
     }
 
+    /// The address to hand out for a new deposit. It is always derived from our descriptor, as
+    /// the cached scripts come from the database.
     pub fn deposit_address(&self) -> Address {
-        self.vault_address(self.current_unused_index)
-    }
+        let index = self.current_unused_index;
+        let address = self.derived_deposit_address(index);
+        let cached = self.derived_scripts.get(&index).map(|s| &s.deposit);
+        if cached.map(|script| script != &address.script_pubkey()) == Some(true) {
+            log::error!(
+                "The cached deposit script at derivation index '{}' isn't the one our descriptor \
+                 derives. Was the database tampered with?",
+                index
+            );
+        }
 
-    pub fn last_deposit_address(&self) -> Address {
-        let raw_index: u32 = self.current_unused_index.into();
-        // FIXME: this should fail instead of creating a hardened index
-        self.vault_address(ChildNumber::from(raw_index + self.gap_limit()))
+        address
     }
 
-    pub fn last_unvault_address(&self) -> Address {
-        let raw_index: u32 = self.current_unused_index.into();
-        // FIXME: this should fail instead of creating a hardened index
-        self.unvault_address(ChildNumber::from(raw_index + self.gap_limit()))
+    /// One past the last derivation index we watch if `first_unused` is the first unused one: we
+    /// always keep `gap_limit` unused addresses past it. None if we'd need to derive at a
+    /// hardened index for that.
+    pub fn watched_indexes_end(&self, first_unused: u32) -> Option<u32> {
+        first_unused
+            .checked_add(self.gap_limit)
+            .filter(|end| *end <= HARDENED_INDEX)
     }
 
-    /// All deposit addresses as strings up to the gap limit (100)
+    /// All deposit addresses as strings up to the gap limit
     pub fn all_deposit_addresses(&mut self) -> Vec<String> {
         self.derivation_index_map
             .keys()
@@ -507,22 +644,19 @@ impl RevaultD {
             .collect()
     }
 
-    /// All unvault addresses as strings up to the gap limit (100)
+    /// All unvault addresses as strings up to the gap limit
     pub fn all_unvault_addresses(&mut self) -> Vec<String> {
-        let raw_index: u32 = self.current_unused_index.into();
-        (0..raw_index + self.gap_limit())
-            .map(|raw_index| {
-                // FIXME: this should fail instead of creating a hardened index
-                self.unvault_address(ChildNumber::from(raw_index))
-                    .to_string()
-            })
+        // Their scripts were cached along with the deposit ones.
+        self.derivation_index_map
+            .values()
+            .map(|index| self.unvault_address(*index).to_string())
             .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::RevaultD;
+    use super::{canonical_descriptor, RevaultD, HARDENED_INDEX};
     use common::config::Config;
 
     use std::path::PathBuf;
@@ -537,7 +671,37 @@ mod tests {
         path.pop();
         path.push("valid_config.toml");
         let config = Config::from_file(Some(path)).expect("Parsing valid config file");
-        RevaultD::from_config(config).expect("Creating state from config");
+        let revaultd = RevaultD::from_config(config).expect("Creating state from config");
         // TODO: test actual fields..
+
+        // We never watch hardened derivation indexes
+        let gap_limit = revaultd.gap_limit;
+        assert_eq!(revaultd.watched_indexes_end(0), Some(gap_limit));
+        assert_eq!(
+            revaultd.watched_indexes_end(HARDENED_INDEX - gap_limit),
+            Some(HARDENED_INDEX)
+        );
+        assert_eq!(
+            revaultd.watched_indexes_end(HARDENED_INDEX - gap_limit + 1),
+            None
+        );
+        assert_eq!(revaultd.watched_indexes_end(u32::MAX), None);
+    }
+
+    #[test]
+    fn test_canonical_descriptor() {
+        let xpub_a = "xpub6AHA9hZDN11k2ijHMeS5QqHx2KP9aMBRhTDqANMnwVtdyw2TDYRmF8PjpvwUFcL1Et8Hj59S3gTSMcUQ5gAqTz3Wd8EsMTmF3DChhqPQBnU";
+        let xpub_b = "xpub6AaffFGfH6WXfm6pwWzmUMuECQnoLeB3agMKaLyEBZ5ZVfwtnS5VJKqXBt8o5ooCWVy2H87GsZshp7DeKE25eWLyd1ENaQ4qoVmRyDJHsCJ";
+        let bare = format!("wsh(multi(2,{}/*,{}/*))", xpub_a, xpub_b);
+        let with_origins = format!(
+            "wsh(multi(2,[aabbccdd/48'/1'/0'/2']{}/*,[00112233]{}/*))#qwertyui",
+            xpub_a, xpub_b
+        );
+        assert_eq!(canonical_descriptor(&bare), bare);
+        assert_eq!(canonical_descriptor(&with_origins), bare);
+        assert_ne!(
+            canonical_descriptor(&bare),
+            canonical_descriptor(&format!("wsh(multi(1,{}/*,{}/*))", xpub_a, xpub_b))
+        );
     }
 }
@@ -109,12 +109,8 @@ pub fn check_signature(
 // transaction (https://github.com/re-vault/practical-revault/blob/master/messages.md#get_sigs).
 // If the Coordinator hands us some new signatures, update the transaction in DB.
 // If this made the transaction valid, maybe update the vault state.
-// NOTE: the vault state update assumes that we will never have all unvault signatures before
-// having all revocation transaction signatures (in which case the vault would get back from
-// 'active' to 'secured'). This assumptions holds as we are never accepting the user to provide
-// their own signature until we gathered all revocation signatures (therefore even if all our peers
-// are sending their unvault transaction to the coordinator and we fetch them, we would never have
-// a fully-valid Unvault transaction until all other signatures have been stored in db).
+// The vault status transitions are enforced by the state machine (see `statemachine.rs`): a vault
+// is only marked as 'active' once 'secured', whatever the order in which we get the signatures.
 fn get_sigs(
     revaultd: &RevaultD,
     tx_db_id: u32,
@@ -122,7 +118,7 @@ fn get_sigs(
     mut tx: impl RevaultTransaction,
     tx_type: TransactionType,
 ) -> Result<(), SignatureFetcherError> {
-    let db_path = &revaultd.db_file();
+    let db = &revaultd.db;
     let secp_ctx = &revaultd.secp_ctx;
     let id = tx.inner_tx().global.unsigned_tx.txid();
     let getsigs_msg = GetSigs { id };
@@ -183,7 +179,7 @@ fn get_sigs(
         // NOTE: In theory, the deposit could have been reorged out and the presigned
         // transactions wiped from the database. Would be a quite edgy case though.
         if let Err(e) = db_update_presigned_tx(
-            db_path,
+            db,
             vault_id,
             tx_db_id,
             tx.inner_tx().inputs[0].partial_sigs.clone(),
@@ -252,7 +248,7 @@ pub fn signature_fetcher_loop(
         }
 
         // This will ignore emergency transactions if we are manager-only
-        let txs = db_transactions_sig_missing(&revaultd.read().unwrap().db_file())?;
+        let txs = db_transactions_sig_missing(&revaultd.read().unwrap().db)?;
         log::trace!("Fetching transactions for {:#?}", txs);
         fetch_all_signatures(&revaultd.read().unwrap(), txs).unwrap_or_else(|e| {
             log::warn!("Error while fetching signatures: '{}'", e);
@@ -0,0 +1,199 @@
+//! The vault state machine. Every change of a vault's [VaultStatus] in the database must be
+//! checked against it.
+//!
+//! Most transitions are driven by the chain: a deposit gets confirmed, then one of its presigned
+//! transactions (or a bypass..) spends it. Some are driven by us: a vault gets 'secured' and then
+//! 'active' as we gather signatures. Any transaction may be reorged out (or evicted from the
+//! mempool) which makes the vault go back to its previous status, and a reorg of the deposit
+//! itself makes it go back to 'unconfirmed' whatever happened after it.
+
+use crate::revaultd::VaultStatus;
+
+use std::fmt;
+
+/// An attempt to move a vault to a status it can't reach from its current one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransitionError {
+    pub from: VaultStatus,
+    pub to: VaultStatus,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Illegal vault status transition from '{}' to '{}'",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+/// Can a vault go from `from` to `to`? Staying in the same status is always allowed.
+pub fn is_allowed_transition(from: VaultStatus, to: VaultStatus) -> bool {
+    use VaultStatus::*;
+
+    if from == to {
+        return true;
+    }
+
+    // The deposit got reorged out.
+    if to == Unconfirmed {
+        return true;
+    }
+
+    matches!(
+        (from, to),
+        // The deposit got confirmed
+        (Unconfirmed, Funded)
+        // We gathered all the revocation transactions signatures, then the Unvault ones.
+        | (Funded, Secured)
+        | (Secured, Active)
+        // The deposit was spent. We may not have all the signatures yet, but the chain wins.
+        | (Funded, Unvaulting)
+        | (Secured, Unvaulting)
+        | (Active, Unvaulting)
+        | (Funded, EmergencyVaulting)
+        | (Secured, EmergencyVaulting)
+        | (Active, EmergencyVaulting)
+        // The Unvault got confirmed, then its CSV expired.
+        | (Unvaulting, Unvaulted)
+        | (Unvaulted, Spendable)
+        // The Unvault output was spent by a revocation transaction, or by a Spend.
+        | (Unvaulting, Canceling)
+        | (Unvaulted, Canceling)
+        | (Spendable, Canceling)
+        | (Spending, Canceling)
+        | (Unvaulting, UnvaultEmergencyVaulting)
+        | (Unvaulted, UnvaultEmergencyVaulting)
+        | (Spendable, UnvaultEmergencyVaulting)
+        | (Spending, UnvaultEmergencyVaulting)
+        | (Spendable, Spending)
+        // The spending transaction got confirmed
+        | (Canceling, Canceled)
+        | (EmergencyVaulting, EmergencyVaulted)
+        | (UnvaultEmergencyVaulting, UnvaultEmergencyVaulted)
+        | (Spending, Spent)
+        // The spending transaction got reorged out or evicted
+        | (Unvaulting, Funded)
+        | (Unvaulting, Secured)
+        | (Unvaulting, Active)
+        | (Unvaulted, Unvaulting)
+        | (EmergencyVaulting, Funded)
+        | (EmergencyVaulting, Secured)
+        | (EmergencyVaulting, Active)
+        | (Canceling, Unvaulted)
+        | (Canceling, Spendable)
+        | (UnvaultEmergencyVaulting, Unvaulted)
+        | (UnvaultEmergencyVaulting, Spendable)
+        | (Spending, Spendable)
+        // The confirmation of the spending transaction got reorged out
+        | (Canceled, Canceling)
+        | (EmergencyVaulted, EmergencyVaulting)
+        | (UnvaultEmergencyVaulted, UnvaultEmergencyVaulting)
+        | (Spent, Spending)
+    )
+}
+
+/// Check that a vault can go from `from` to `to`.
+pub fn check_transition(from: VaultStatus, to: VaultStatus) -> Result<(), TransitionError> {
+    if is_allowed_transition(from, to) {
+        Ok(())
+    } else {
+        Err(TransitionError { from, to })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_transition, is_allowed_transition, TransitionError};
+    use crate::revaultd::VaultStatus;
+
+    use std::convert::TryFrom;
+
+    fn all_statuses() -> Vec<VaultStatus> {
+        (0..15)
+            .map(|n| VaultStatus::try_from(n).expect("There are 15 statuses"))
+            .collect()
+    }
+
+    // The statuses reachable from each status, besides itself and 'unconfirmed'.
+    fn reachable(from: VaultStatus) -> Vec<VaultStatus> {
+        use VaultStatus::*;
+
+        match from {
+            Unconfirmed => vec![Funded],
+            Funded => vec![Secured, Unvaulting, EmergencyVaulting],
+            Secured => vec![Active, Unvaulting, EmergencyVaulting],
+            Active => vec![Unvaulting, EmergencyVaulting],
+            Unvaulting => vec![
+                Funded,
+                Secured,
+                Active,
+                Unvaulted,
+                Canceling,
+                UnvaultEmergencyVaulting,
+            ],
+            Unvaulted => vec![Unvaulting, Spendable, Canceling, UnvaultEmergencyVaulting],
+            Canceling => vec![Unvaulted, Spendable, Canceled],
+            Canceled => vec![Canceling],
+            EmergencyVaulting => vec![Funded, Secured, Active, EmergencyVaulted],
+            EmergencyVaulted => vec![EmergencyVaulting],
+            UnvaultEmergencyVaulting => vec![Unvaulted, Spendable, UnvaultEmergencyVaulted],
+            UnvaultEmergencyVaulted => vec![UnvaultEmergencyVaulting],
+            Spendable => vec![Spending, Canceling, UnvaultEmergencyVaulting],
+            Spending => vec![Spendable, Spent, Canceling, UnvaultEmergencyVaulting],
+            Spent => vec![Spending],
+        }
+    }
+
+    #[test]
+    fn vault_status_transitions() {
+        let statuses = all_statuses();
+        assert_eq!(statuses.len(), 15);
+        VaultStatus::try_from(15).unwrap_err();
+
+        for from in statuses.iter().copied() {
+            let reachable = reachable(from);
+            for to in statuses.iter().copied() {
+                let expected =
+                    to == from || to == VaultStatus::Unconfirmed || reachable.contains(&to);
+                assert_eq!(
+                    is_allowed_transition(from, to),
+                    expected,
+                    "Transition from '{}' to '{}'",
+                    from,
+                    to
+                );
+
+                if expected {
+                    check_transition(from, to).unwrap();
+                } else {
+                    assert_eq!(
+                        check_transition(from, to),
+                        Err(TransitionError { from, to })
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_shortcut_to_active() {
+        use VaultStatus::*;
+
+        // We can't sign an Unvault before having signed all the revocation transactions
+        check_transition(Unconfirmed, Active).unwrap_err();
+        check_transition(Funded, Active).unwrap_err();
+        // And we never go back to 'secured' once active, unless the Unvault got reorged out
+        check_transition(Active, Secured).unwrap_err();
+        check_transition(Active, Funded).unwrap_err();
+        check_transition(Secured, Funded).unwrap_err();
+        // Final states are only left through a reorg
+        for status in &[Canceled, EmergencyVaulted, UnvaultEmergencyVaulted, Spent] {
+            check_transition(*status, Active).unwrap_err();
+            check_transition(*status, Spendable).unwrap_err();
+        }
+    }
+}
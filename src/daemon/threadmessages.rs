@@ -1,23 +1,36 @@
-use crate::revaultd::VaultStatus;
+use crate::{
+    database::{interface::VaultsQuery, schema::TransactionType},
+    revaultd::VaultStatus,
+};
+use common::emergency_kit::EmergencyKit;
 use revault_tx::{
-    bitcoin::{util::bip32::ChildNumber, Address, Amount, OutPoint, Txid},
+    bitcoin::{hashes::sha256, util::bip32::ChildNumber, Address, Amount, OutPoint, Txid},
     transactions::{
         CancelTransaction, EmergencyTransaction, UnvaultEmergencyTransaction, UnvaultTransaction,
     },
 };
 
-use std::sync::mpsc::SyncSender;
+use std::{ops::Range, path::PathBuf, sync::mpsc::SyncSender};
 
 /// Incoming from RPC server thread
 #[derive(Debug)]
 pub enum RpcMessageIn {
     Shutdown,
-    // Network, blockheight, sync progress
-    GetInfo(SyncSender<(String, u32, f64)>),
-    ListVaults(
-        (Option<Vec<VaultStatus>>, Option<Vec<OutPoint>>),
-        SyncSender<Vec<ListVaultsEntry>>,
+    // Network, blockheight, sync progress, deployment fingerprint, the bitcoind poll
+    // interval in seconds, min confirmations, gap limit and the progress of the ongoing rescan
+    GetInfo(
+        SyncSender<(
+            String,
+            u32,
+            f64,
+            Option<sha256::Hash>,
+            u64,
+            u32,
+            u32,
+            Option<f64>,
+        )>,
     ),
+    ListVaults(VaultsQuery, SyncSender<Vec<ListVaultsEntry>>),
     DepositAddr(SyncSender<Address>),
     GetRevocationTxs(
         OutPoint,
@@ -58,6 +71,37 @@ pub enum RpcMessageIn {
         Option<Vec<OutPoint>>,
         SyncSender<Result<Vec<VaultOnchainTransactions>, RpcControlError>>,
     ),
+    GetVaultHistory(
+        OutPoint,
+        SyncSender<Result<Vec<VaultEvent>, RpcControlError>>,
+    ),
+    GetVault(OutPoint, SyncSender<Result<VaultDetails, RpcControlError>>),
+    GetBalance(SyncSender<Vec<StatusBalance>>),
+    // None removes the label
+    SetVaultLabel((OutPoint, Option<String>), SyncSender<()>),
+    GetVaultLabel(OutPoint, SyncSender<Option<String>>),
+    // The vaults as of this block height (our tip if None), along with the height used
+    ExportVaults(
+        Option<u32>,
+        SyncSender<Result<(u32, Vec<ExportedVault>), RpcControlError>>,
+    ),
+    // Path of the kit file to create
+    EmergencyKit(PathBuf, SyncSender<Result<EmergencyKit, RpcControlError>>),
+    // Path of the backup file, passphrase
+    Backup((PathBuf, String), SyncSender<Result<(), RpcControlError>>),
+    // Whether to repair what can be
+    CheckConsistency(
+        bool,
+        SyncSender<Result<Vec<Inconsistency>, RpcControlError>>,
+    ),
+    AuditPresigned(
+        Option<Vec<OutPoint>>,
+        SyncSender<Result<Vec<VaultAudit>, RpcControlError>>,
+    ),
+    // The range of derivation indexes to rescan the chain for
+    RescanIndexes(Range<u32>, SyncSender<Result<(), RpcControlError>>),
+    // The height or timestamp to rescan the chain from. Returns once the rescan started.
+    Rescan(u32, SyncSender<Result<(), RpcControlError>>),
 }
 
 /// Outgoing to the bitcoind poller thread
@@ -65,7 +109,17 @@ pub enum RpcMessageIn {
 pub enum BitcoindMessageOut {
     Shutdown,
     SyncProgress(SyncSender<f64>),
+    // None if the watchonly wallet isn't rescanning the chain
+    RescanProgress(SyncSender<Option<f64>>),
     WalletTransaction(Txid, SyncSender<Option<WalletTransaction>>),
+    // Whether to repair what can be. Errors if bitcoind isn't synced yet.
+    CheckConsistency(bool, SyncSender<Result<Vec<Inconsistency>, String>>),
+    // The range of derivation indexes to rescan the chain for. Errors if bitcoind isn't synced
+    // yet.
+    RescanIndexes(Range<u32>, SyncSender<Result<(), String>>),
+    // The height or timestamp to rescan the chain from. Errors if bitcoind isn't synced yet,
+    // answers as soon as the rescan started otherwise.
+    Rescan(u32, SyncSender<Result<(), String>>),
 }
 
 /// Outgoing to the signature fetcher thread
@@ -79,6 +133,8 @@ pub struct WalletTransaction {
     pub hex: String,
     // None if unconfirmed
     pub blockheight: Option<u32>,
+    // None if unconfirmed
+    pub blocktime: Option<u32>,
     pub received_time: u32,
 }
 
@@ -111,7 +167,153 @@ pub struct ListVaultsEntry {
     pub deposit_outpoint: OutPoint,
     pub derivation_index: ChildNumber,
     pub address: Address,
+    pub blockheight: u32,
     pub updated_at: u32,
+    pub label: Option<String>,
+}
+
+/// The total amount and number of vaults in a given status
+#[derive(Debug)]
+pub struct StatusBalance {
+    pub status: VaultStatus,
+    pub amount: Amount,
+    pub count: u32,
+}
+
+#[derive(Debug)]
+pub struct VaultEvent {
+    // None if the vault was just inserted
+    pub old_status: Option<VaultStatus>,
+    pub new_status: VaultStatus,
+    pub timestamp: u32,
+    // Our tip at the time of the change
+    pub tip_height: u32,
+    pub txid: Option<Txid>,
+}
+
+#[derive(Debug)]
+pub struct PresignedTransactionDetails {
+    pub tx_type: TransactionType,
+    pub txid: Txid,
+    // Base64-encoded
+    pub psbt: String,
+    // How many signatures we have for now
+    pub signatures: usize,
+    pub fully_signed: bool,
+}
+
+#[derive(Debug)]
+pub struct VaultDetails {
+    pub vault: ListVaultsEntry,
+    pub unvault_address: Address,
+    pub cpfp_address: Address,
+    // How many blocks until the Unvault output can be spent. None if not unvaulted
+    pub remaining_csv: Option<u32>,
+    // Empty if unconfirmed
+    pub presigned_transactions: Vec<PresignedTransactionDetails>,
+    pub onchain_transactions: VaultOnchainTransactions,
+    // To compute the confirmations of the onchain transactions
+    pub tip_height: u32,
+    pub events: Vec<VaultEvent>,
+}
+
+/// A transaction of a vault, as exported for bookkeeping
+#[derive(Debug)]
+pub struct ExportedTransaction {
+    pub txid: Txid,
+    // None if we can't tell (eg a Spend with a fee-bumping input)
+    pub fee: Option<Amount>,
+    // None if not confirmed at the export height
+    pub blockheight: Option<u32>,
+}
+
+/// A vault as it was at a given block height, as exported for bookkeeping
+#[derive(Debug)]
+pub struct ExportedVault {
+    // The status and last update as of the export height
+    pub vault: ListVaultsEntry,
+    // The first time the vault reached each of its statuses
+    pub status_timestamps: Vec<(VaultStatus, u32)>,
+    // None if unconfirmed, as the presigned transactions don't exist yet
+    pub unvault: Option<ExportedTransaction>,
+    pub cancel: Option<ExportedTransaction>,
+    // Always None if not stakeholder
+    pub emergency: Option<ExportedTransaction>,
+    pub unvault_emergency: Option<ExportedTransaction>,
+    // None if not spent at the export height
+    pub spend: Option<ExportedTransaction>,
+}
+
+/// What our database and bitcoind's watchonly wallet disagree about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InconsistencyKind {
+    /// The deposit is not in the wallet, or not as we stored it
+    Deposit,
+    /// The deposit is not confirmed as we stored it
+    Confirmation,
+    /// The deposit is not spent as the vault status says
+    Spend,
+    /// The presigned transactions are not the ones we'd derive from the deposit
+    PresignedTransactions,
+}
+
+impl std::fmt::Display for InconsistencyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Deposit => write!(f, "deposit"),
+            Self::Confirmation => write!(f, "confirmation"),
+            Self::Spend => write!(f, "spend"),
+            Self::PresignedTransactions => write!(f, "presigned_transactions"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Inconsistency {
+    pub deposit_outpoint: OutPoint,
+    pub kind: InconsistencyKind,
+    pub description: String,
+    // Only in repair mode
+    pub repaired: bool,
+}
+
+/// A presigned transaction we stored that is not the one we derive from the vault
+#[derive(Debug, Clone)]
+pub struct PresignedMismatch {
+    pub tx_type: TransactionType,
+    // None if we should not have stored this transaction at all
+    pub expected_txid: Option<Txid>,
+    // None if it's missing from the database
+    pub stored_txid: Option<Txid>,
+}
+
+impl std::fmt::Display for PresignedMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.expected_txid, self.stored_txid) {
+            (Some(expected), Some(stored)) => write!(
+                f,
+                "{} transaction: expected '{}' but stored '{}'",
+                self.tx_type, expected, stored
+            ),
+            (Some(expected), None) => write!(
+                f,
+                "{} transaction: expected '{}' but none is stored",
+                self.tx_type, expected
+            ),
+            (None, Some(stored)) => write!(
+                f,
+                "{} transaction: unexpected '{}' stored",
+                self.tx_type, stored
+            ),
+            (None, None) => write!(f, "{} transaction", self.tx_type),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VaultAudit {
+    pub outpoint: OutPoint,
+    pub mismatches: Vec<PresignedMismatch>,
 }
 
 /// An error that occured during RPC message handling
@@ -122,6 +324,13 @@ pub enum RpcControlError {
     InvalidStatus((VaultStatus, VaultStatus)),
     InvalidPsbt(String),
     Communication(String),
+    // .0 is the requested height, .1 our tip
+    InvalidBlockheight((u32, u32)),
+    EmergencyKit(String),
+    Backup(String),
+    ConsistencyCheck(String),
+    TamperedPresigned(String),
+    Rescan(String),
 }
 
 impl std::fmt::Display for RpcControlError {
@@ -135,6 +344,20 @@ impl std::fmt::Display for RpcControlError {
             ),
             Self::InvalidPsbt(reason) => write!(f, "Invalid PSBT: '{}'", reason),
             Self::Communication(reason) => write!(f, "Communication error: '{}'", reason),
+            Self::InvalidBlockheight((height, tip)) => {
+                write!(f, "Block height '{}' is above our tip ('{}')", height, tip)
+            }
+            Self::EmergencyKit(reason) => write!(f, "Emergency kit error: '{}'", reason),
+            Self::Backup(reason) => write!(f, "{}", reason),
+            Self::ConsistencyCheck(reason) => write!(f, "Consistency check error: '{}'", reason),
+            Self::TamperedPresigned(reason) => {
+                write!(
+                    f,
+                    "Stored presigned transactions were tampered with: '{}'",
+                    reason
+                )
+            }
+            Self::Rescan(reason) => write!(f, "Rescan error: '{}'", reason),
         }
     }
 }
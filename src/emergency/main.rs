@@ -0,0 +1,251 @@
+//! Broadcast the transactions of an emergency kit (as created by the `emergencykit` command of
+//! revaultd) through a bitcoind. It only needs the kit file and a bitcoind, so that the vaults
+//! can be emergency-vaulted even if revaultd or its data are gone.
+
+use common::emergency_kit::{EmergencyKit, KitVault};
+use revault_tx::bitcoin::{consensus::encode, hashes::hex::FromHex, Network, Transaction};
+
+use std::{env, fs, path::PathBuf, process, str::FromStr, time::Duration};
+
+use jsonrpc::{arg, client::Client, error::Error, simple_http::SimpleHttpTransport};
+use serde_json::Value as Json;
+
+// bitcoind's RPC_VERIFY_ALREADY_IN_CHAIN
+const ALREADY_IN_CHAIN: i32 = -27;
+
+// Exits with error
+fn show_usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!(" revault-emergency <kit file> --check");
+    eprintln!(
+        " revault-emergency <kit file> --bitcoind-addr <ip:port> \
+         (--bitcoind-cookie <path> | --bitcoind-auth <user:password>)"
+    );
+    process::exit(1);
+}
+
+enum BitcoindAuth {
+    Cookie(PathBuf),
+    UserPass(String, String),
+}
+
+struct Args {
+    kit_file: PathBuf,
+    // None if we only check the kit
+    bitcoind: Option<(String, BitcoindAuth)>,
+}
+
+fn parse_args(args: Vec<String>) -> Args {
+    let mut args = args.into_iter().skip(1);
+    let kit_file = PathBuf::from(args.next().unwrap_or_else(|| show_usage()));
+
+    let (mut check, mut addr, mut auth) = (false, None, None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--bitcoind-addr" => addr = Some(args.next().unwrap_or_else(|| show_usage())),
+            "--bitcoind-cookie" => {
+                auth = Some(BitcoindAuth::Cookie(PathBuf::from(
+                    args.next().unwrap_or_else(|| show_usage()),
+                )))
+            }
+            "--bitcoind-auth" => {
+                let user_pass = args.next().unwrap_or_else(|| show_usage());
+                let mut parts = user_pass.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(user), Some(pass)) => {
+                        auth = Some(BitcoindAuth::UserPass(user.to_string(), pass.to_string()))
+                    }
+                    _ => {
+                        eprintln!("'--bitcoind-auth' must be 'user:password'.");
+                        show_usage();
+                    }
+                }
+            }
+            _ => {
+                eprintln!("Unknown argument '{}'.", arg);
+                show_usage();
+            }
+        }
+    }
+
+    let bitcoind = match (check, addr, auth) {
+        (true, None, None) => None,
+        (false, Some(addr), Some(auth)) => Some((addr, auth)),
+        _ => show_usage(),
+    };
+    Args { kit_file, bitcoind }
+}
+
+fn bitcoind_client(addr: &str, auth: BitcoindAuth) -> Result<Client, String> {
+    let builder = SimpleHttpTransport::builder()
+        .url(addr)
+        .map_err(|e| format!("Invalid bitcoind address '{}': {}", addr, e))?
+        .timeout(Duration::from_secs(30));
+    let builder = match auth {
+        BitcoindAuth::Cookie(path) => {
+            let cookie = fs::read_to_string(&path)
+                .map_err(|e| format!("Reading cookie file {:?}: {}", path, e))?;
+            builder.cookie_auth(cookie)
+        }
+        BitcoindAuth::UserPass(user, pass) => builder.auth(user, Some(pass)),
+    };
+
+    Ok(Client::with_transport(builder.build()))
+}
+
+fn request(
+    client: &Client,
+    method: &str,
+    params: &[Box<serde_json::value::RawValue>],
+) -> Result<Json, Error> {
+    client
+        .send_request(client.build_request(method, params))?
+        .result()
+}
+
+// Make sure we won't broadcast on the wrong chain
+fn check_network(client: &Client, kit_network: Network) -> Result<(), String> {
+    let chaininfo = request(client, "getblockchaininfo", &[])
+        .map_err(|e| format!("Calling 'getblockchaininfo': {}", e))?;
+    let chain = chaininfo
+        .get("chain")
+        .and_then(|c| c.as_str())
+        .ok_or_else(|| "No valid 'chain' in getblockchaininfo response?".to_string())?;
+    let bip70_net = match kit_network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Regtest => "regtest",
+    };
+
+    if chain != bip70_net {
+        return Err(format!(
+            "Wrong network, bitcoind is on '{}' but the kit is for '{}'",
+            chain, kit_network
+        ));
+    }
+    Ok(())
+}
+
+fn decode_tx(tx_hex: &str) -> Result<Transaction, String> {
+    let bytes = Vec::<u8>::from_hex(tx_hex).map_err(|e| format!("Invalid hex: {}", e))?;
+    encode::deserialize(&bytes).map_err(|e| format!("Invalid transaction: {}", e))
+}
+
+// The transactions of this vault we could broadcast, in the order we'd try them
+fn vault_transactions(vault: &KitVault) -> Vec<(&'static str, &String)> {
+    vec![
+        ("Emergency", vault.emergency_tx.as_ref()),
+        ("Unvault-Emergency", vault.unvault_emergency_tx.as_ref()),
+    ]
+    .into_iter()
+    .filter_map(|(name, tx_hex)| tx_hex.map(|tx_hex| (name, tx_hex)))
+    .collect()
+}
+
+// Try the Emergency transaction, and the Unvault-Emergency one if the deposit was already
+// unvaulted. Returns whether one of them got to the network.
+fn broadcast_vault(client: &Client, vault: &KitVault) -> bool {
+    for (name, tx_hex) in vault_transactions(vault) {
+        let txid = decode_tx(tx_hex)
+            .map(|tx| tx.txid().to_string())
+            .unwrap_or_default();
+        // It's an emergency: don't let bitcoind refuse it for its high feerate.
+        match request(client, "sendrawtransaction", &[arg(tx_hex), arg(0)]) {
+            Ok(_) => {
+                println!(
+                    "Broadcast {} transaction '{}' for vault '{}'.",
+                    name, txid, vault.deposit_outpoint
+                );
+                return true;
+            }
+            Err(Error::Rpc(ref e)) if e.code == ALREADY_IN_CHAIN => {
+                println!(
+                    "{} transaction '{}' for vault '{}' is already confirmed.",
+                    name, txid, vault.deposit_outpoint
+                );
+                return true;
+            }
+            Err(e) => eprintln!(
+                "Could not broadcast {} transaction '{}' for vault '{}': {}",
+                name, txid, vault.deposit_outpoint, e
+            ),
+        }
+    }
+
+    false
+}
+
+fn main() {
+    let args = parse_args(env::args().collect());
+
+    let content = fs::read(&args.kit_file).unwrap_or_else(|e| {
+        eprintln!("Reading kit file {:?}: {}", args.kit_file, e);
+        process::exit(1);
+    });
+    let kit = EmergencyKit::from_file_content(&content).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let network = Network::from_str(&kit.network).unwrap_or_else(|e| {
+        eprintln!("Invalid network '{}' in kit: {}", kit.network, e);
+        process::exit(1);
+    });
+
+    println!(
+        "Emergency kit for {} vault(s) on '{}', created at {}.",
+        kit.vaults.len(),
+        network,
+        kit.created_at
+    );
+    let mut valid = true;
+    for vault in kit.vaults.iter() {
+        for (name, tx_hex) in vault_transactions(vault) {
+            match decode_tx(tx_hex) {
+                Ok(tx) => println!(
+                    "  Vault '{}' ({} sats): {} transaction '{}'",
+                    vault.deposit_outpoint,
+                    vault.amount,
+                    name,
+                    tx.txid()
+                ),
+                Err(e) => {
+                    eprintln!(
+                        "  Vault '{}': invalid {} transaction: {}",
+                        vault.deposit_outpoint, name, e
+                    );
+                    valid = false;
+                }
+            }
+        }
+    }
+    if !valid {
+        process::exit(1);
+    }
+
+    let (addr, auth) = match args.bitcoind {
+        Some(bitcoind) => bitcoind,
+        None => process::exit(0),
+    };
+    let client = bitcoind_client(&addr, auth).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    check_network(&client, network).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let failed = kit
+        .vaults
+        .iter()
+        .filter(|vault| !broadcast_vault(&client, vault))
+        .count();
+    if failed > 0 {
+        eprintln!(
+            "Could not broadcast any transaction for {} vault(s).",
+            failed
+        );
+        process::exit(1);
+    }
+}
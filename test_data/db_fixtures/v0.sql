@@ -0,0 +1,83 @@
+/* A revaultd database at version 0, used to test the migrations. Never modify it. */
+CREATE TABLE version (
+    version INTEGER NOT NULL
+);
+
+CREATE TABLE tip (
+    network TEXT NOT NULL,
+    blockheight INTEGER NOT NULL,
+    blockhash BLOB NOT NULL
+);
+
+/* This stores metadata about our wallet. We only support single wallet for
+ * now (and the foreseeable future). This MUST be in sync with bitcoind's
+ * wallet.
+ */
+CREATE TABLE wallets (
+    id INTEGER PRIMARY KEY NOT NULL,
+    timestamp INTEGER NOT NULL,
+    deposit_descriptor TEXT NOT NULL,
+    unvault_descriptor TEXT NOT NULL,
+    our_manager_xpub TEXT,
+    our_stakeholder_xpub TEXT,
+    deposit_derivation_index INTEGER NOT NULL
+);
+
+/* This stores the vaults we heard about. The deposit may be unconfirmed,
+ * in which case the blockheight will be 0 (FIXME: should be NULL instead?).
+ * For any vault entry a deposit transaction MUST be present in bitcoind's
+ * wallet.
+ */
+CREATE TABLE vaults (
+    id INTEGER PRIMARY KEY NOT NULL,
+    wallet_id INTEGER NOT NULL,
+    status INTEGER NOT NULL,
+    blockheight INTEGER NOT NULL,
+    deposit_txid BLOB UNIQUE NOT NULL,
+    deposit_vout INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    derivation_index INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    FOREIGN KEY (wallet_id) REFERENCES wallets (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+
+/* This stores transactions we presign:
+ * - Emergency (only for stakeholders)
+ * - Unvault
+ * - Cancel
+ * - Unvault Emergency (only for stakeholders)
+ */
+CREATE TABLE presigned_transactions (
+    id INTEGER PRIMARY KEY NOT NULL,
+    vault_id INTEGER NOT NULL,
+    type INTEGER NOT NULL,
+    psbt BLOB UNIQUE NOT NULL,
+    fullysigned BOOLEAN NOT NULL CHECK (fullysigned IN (0,1)),
+    FOREIGN KEY (vault_id) REFERENCES vaults (id)
+        ON UPDATE RESTRICT
+        ON DELETE RESTRICT
+);
+
+CREATE INDEX vault_status ON vaults (status);
+CREATE INDEX vault_transactions ON presigned_transactions (vault_id);
+
+INSERT INTO version (version) VALUES (0);
+INSERT INTO tip (network, blockheight, blockhash) VALUES ('bitcoin', 682000, X'0000000000000000000a8d3e9b1a8e6f4b5d1c2e0f1a2b3c4d5e6f708192a3b4');
+INSERT INTO wallets (timestamp, deposit_descriptor, unvault_descriptor, our_manager_xpub, our_stakeholder_xpub, deposit_derivation_index)
+    VALUES (1614000000, 'wsh(multi(2,xpubA/*,xpubB/*))', 'wsh(andor(multi(1,xpubC/*),older(6),thresh(2,pkh(xpubA/*),a:pkh(xpubB/*))))', NULL, 'xpubA', 3);
+INSERT INTO vaults (wallet_id, status, blockheight, deposit_txid, deposit_vout, amount, derivation_index, updated_at)
+    VALUES (1, 0, 0, X'0101010101010101010101010101010101010101010101010101010101010101', 0, 120000000, 0, 1614000100);
+INSERT INTO vaults (wallet_id, status, blockheight, deposit_txid, deposit_vout, amount, derivation_index, updated_at)
+    VALUES (1, 2, 681900, X'0202020202020202020202020202020202020202020202020202020202020202', 1, 50000000, 1, 1614000200);
+INSERT INTO vaults (wallet_id, status, blockheight, deposit_txid, deposit_vout, amount, derivation_index, updated_at)
+    VALUES (1, 3, 681950, X'0303030303030303030303030303030303030303030303030303030303030303', 0, 75000000, 2, 1614000300);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (2, 1, X'70736274ff0201', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (2, 2, X'70736274ff0202', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (2, 3, X'70736274ff0203', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (2, 0, X'70736274ff0200', 0);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (3, 1, X'70736274ff0301', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (3, 2, X'70736274ff0302', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (3, 3, X'70736274ff0303', 1);
+INSERT INTO presigned_transactions (vault_id, type, psbt, fullysigned) VALUES (3, 0, X'70736274ff0300', 1);
@@ -1,11 +1,15 @@
 import copy
 import logging
+import os
 import pytest
 import serializations
+import shutil
+import sqlite3
+import subprocess
 
 from bitcoin.core import COIN
 from fixtures import *
-from utils import POSTGRES_IS_SETUP, TIMEOUT, RpcError, wait_for
+from utils import POSTGRES_IS_SETUP, TIMEOUT, RpcError, get_participants, wait_for
 
 
 def test_revaultd_stakeholder_starts(revaultd_stakeholder):
@@ -32,11 +36,79 @@ def test_revaultd_manager_starts(revaultd_manager):
     revaultd_manager.proc.wait(TIMEOUT)
 
 
+def test_revaultd_init(directory, bitcoind):
+    (stks, cosigs, mans) = get_participants(2, 3)
+    datadir = os.path.join(directory, "revaultd-init")
+    conf_file = os.path.join(datadir, "revault.toml")
+    bin = os.path.join(os.path.dirname(__file__), "..", "target/debug/revaultd")
+    init_cmd = [
+        bin,
+        "init",
+        "--conf",
+        conf_file,
+        "--data-dir",
+        datadir,
+        "--network",
+        "regtest",
+        "--bitcoind-cookie",
+        os.path.join(bitcoind.bitcoin_dir, "regtest", ".cookie"),
+        "--bitcoind-addr",
+        f"127.0.0.1:{bitcoind.rpcport}",
+        "--coordinator-host",
+        "127.0.0.1:8383",
+        "--coordinator-noise-key",
+        "d91563973102454a7830137e92d0548bc83b4ea2799f1df04622ca1307381402",
+        "--stakeholders-xpubs",
+        ",".join(stk.get_xpub() for stk in stks),
+        "--cosigners-keys",
+        ",".join(cosig.get_static_key().hex() for cosig in cosigs),
+        "--managers-xpubs",
+        ",".join(man.get_xpub() for man in mans),
+        "--managers-threshold",
+        "2",
+        "--unvault-csv",
+        "12",
+        "--stakeholder-xpub",
+        "",
+        "--manager-xpub",
+        mans[0].get_xpub(),
+        "--cosigning-servers",
+        "087629614d227ff2b9ed5f2ce2eb7cd527d2d18f866b24009647251fce58de38@127.0.0.1:1",
+        "--emergency-address-hash",
+        "",
+    ]
+
+    # Not one of the managers
+    bad_cmd = copy.copy(init_cmd)
+    bad_cmd[bad_cmd.index("--manager-xpub") + 1] = stks[0].get_xpub()
+    res = subprocess.run(bad_cmd, capture_output=True, stdin=subprocess.DEVNULL)
+    assert res.returncode == 1
+    assert b"not part of the given managers' xpubs" in res.stderr
+    assert not os.path.exists(conf_file)
+
+    res = subprocess.run(init_cmd, capture_output=True, stdin=subprocess.DEVNULL)
+    assert res.returncode == 0, res.stderr
+    assert b"Our Noise static public key" in res.stdout
+    assert os.path.isfile(conf_file)
+    assert os.path.isfile(os.path.join(datadir, "regtest", "noise_secret"))
+    assert os.stat(os.path.join(datadir, "regtest")).st_mode & 0o777 == 0o700
+
+    # It won't overwrite an existing configuration
+    res = subprocess.run(init_cmd, capture_output=True, stdin=subprocess.DEVNULL)
+    assert res.returncode == 1
+    assert b"already exists" in res.stderr
+
+
 def test_getinfo(revaultd_manager, bitcoind):
     res = revaultd_manager.rpc.call("getinfo")
     assert res["network"] == "regtest"
     assert res["sync"] == 1.0
     assert res["version"] == "0.0.2"
+    assert len(res["deployment_fingerprint"]) == 64
+    assert res["bitcoind_poll_interval"] == 3
+    assert res["min_conf"] == 6
+    assert res["gap_limit"] == 100
+    assert res["rescan"] is None
 
     wait_for(lambda: revaultd_manager.rpc.call("getinfo")["blockheight"] > 0)
     height = revaultd_manager.rpc.call("getinfo")["blockheight"]
@@ -44,6 +116,67 @@ def test_getinfo(revaultd_manager, bitcoind):
     wait_for(lambda: revaultd_manager.rpc.call("getinfo")["blockheight"] == height + 1)
 
 
+def test_zmq_notifications(revaultd_manager, bitcoind):
+    revaultd_manager.wait_for_logs(
+        [
+            "Listening to bitcoind's 'hashblock' ZMQ notifications",
+            "Listening to bitcoind's 'rawtx' ZMQ notifications",
+        ]
+    )
+
+    # A new deposit wakes up the poller
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.5)
+    revaultd_manager.wait_for_logs(
+        [
+            "Polling bitcoind right away after a ZMQ notification",
+            f"Got a new unconfirmed deposit at {txid}",
+        ]
+    )
+
+    # So does a new block
+    height = revaultd_manager.rpc.call("getinfo")["blockheight"]
+    bitcoind.generate_block(1)
+    revaultd_manager.wait_for_log("Polling bitcoind right away after a ZMQ notification")
+    wait_for(lambda: revaultd_manager.rpc.call("getinfo")["blockheight"] == height + 1)
+
+
+def test_operational_parameters(revaultd_manager, bitcoind):
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+
+    # Top-level entries must come before the tables
+    with open(revaultd_manager.conf_file, "r") as f:
+        conf = f.read()
+    with open(revaultd_manager.conf_file, "w") as f:
+        f.write("min_conf = 2\ngap_limit = 150\n" + conf)
+    revaultd_manager.start()
+
+    # The wallet was created with a smaller gap limit
+    revaultd_manager.wait_for_log(
+        r"Importing the addresses up to the new gap limit \(150\)"
+    )
+    res = revaultd_manager.rpc.call("getinfo")
+    assert res["min_conf"] == 2
+    assert res["gap_limit"] == 150
+
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.2)
+    revaultd_manager.wait_for_log(f"Got a new unconfirmed deposit at {txid}")
+    bitcoind.generate_block(2)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+
+    # It's only done once
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    log_start = len(revaultd_manager.logs)
+    revaultd_manager.start()
+    revaultd_manager.wait_for_log("bitcoind now synced")
+    assert not revaultd_manager.is_in_log(
+        "Importing the addresses up to the new gap limit", start=log_start
+    )
+
+
 def test_listvaults(revaultd_manager, bitcoind):
     res = revaultd_manager.rpc.call("listvaults")
     assert res["vaults"] == []
@@ -110,6 +243,669 @@ def test_listvaults(revaultd_manager, bitcoind):
     assert len(vault_list) == 0
 
 
+def test_listvaults_sort_and_paginate(revaultd_manager, bitcoind):
+    amounts = [0.5, 2, 1]
+    txids = []
+    for amount in amounts:
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, amount))
+    wait_for(lambda: len(revaultd_manager.rpc.call("listvaults")["vaults"]) == 3)
+
+    # Sorted by amount
+    vault_list = revaultd_manager.rpc.call(
+        "listvaults", [[], [], {"sort_by": "amount", "order": "asc"}]
+    )["vaults"]
+    assert [v["txid"] for v in vault_list] == [txids[0], txids[2], txids[1]]
+    vault_list = revaultd_manager.rpc.call(
+        "listvaults", [[], [], {"sort_by": "amount"}]
+    )["vaults"]
+    assert [v["txid"] for v in vault_list] == [txids[1], txids[2], txids[0]]
+
+    # Paginated
+    options = {"sort_by": "amount", "limit": 2}
+    vault_list = revaultd_manager.rpc.call("listvaults", [[], [], options])["vaults"]
+    assert [v["txid"] for v in vault_list] == [txids[1], txids[2]]
+    options["offset"] = 2
+    vault_list = revaultd_manager.rpc.call("listvaults", [[], [], options])["vaults"]
+    assert [v["txid"] for v in vault_list] == [txids[0]]
+
+    # Filtered by amount
+    options = {"min_amount": 75000000, "max_amount": 150000000}
+    vault_list = revaultd_manager.rpc.call("listvaults", [[], [], options])["vaults"]
+    assert [v["txid"] for v in vault_list] == [txids[2]]
+
+    # Along with the status filter
+    options = {"min_amount": 75000000}
+    vault_list = revaultd_manager.rpc.call(
+        "listvaults", [["funded"], [], options]
+    )["vaults"]
+    assert vault_list == []
+
+    # Invalid options are refused
+    for options in [
+        {"sort_by": "txid"},
+        {"order": "random"},
+        {"min_amount": 2, "max_amount": 1},
+        {"unknown": 1},
+    ]:
+        with pytest.raises(RpcError):
+            revaultd_manager.rpc.call("listvaults", [[], [], options])
+
+
+def test_getvaulthistory(revaultd_manager, bitcoind):
+    amount_sent = 0.42
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, amount_sent)
+    revaultd_manager.wait_for_log("Got a new unconfirmed deposit")
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    outpoint = f"{txid}:{vault['vout']}"
+
+    events = revaultd_manager.rpc.call("getvaulthistory", [outpoint])["events"]
+    assert len(events) == 1
+    assert events[0]["old_status"] is None
+    assert events[0]["new_status"] == "unconfirmed"
+    assert events[0]["txid"] == txid
+
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    events = revaultd_manager.rpc.call("getvaulthistory", [outpoint])["events"]
+    assert len(events) == 2
+    assert events[1]["old_status"] == "unconfirmed"
+    assert events[1]["new_status"] == "funded"
+    assert events[1]["txid"] == txid
+    assert events[1]["tip_height"] >= events[0]["tip_height"]
+    assert events[1]["timestamp"] >= events[0]["timestamp"]
+
+    with pytest.raises(RpcError, match="No vault at"):
+        revaultd_manager.rpc.call("getvaulthistory", [f"{txid}:100"])
+
+
+def test_getvault(revaultd_manager, bitcoind):
+    amount_sent = 0.42
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, amount_sent)
+    revaultd_manager.wait_for_log("Got a new unconfirmed deposit")
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    outpoint = f"{txid}:{vault['vout']}"
+
+    details = revaultd_manager.rpc.call("getvault", [outpoint])
+    assert details["status"] == "unconfirmed"
+    assert details["amount"] == amount_sent * 10 ** 8
+    assert details["txid"] == txid
+    assert details["vout"] == vault["vout"]
+    assert details["deposit_address"] == addr
+    assert details["blockheight"] == 0
+    assert details["remaining_csv"] is None
+    assert details["presigned_transactions"] == []
+    deposit = details["onchain_transactions"]["deposit"]
+    assert deposit["blockheight"] is None
+    assert deposit["confirmations"] == 0
+    assert details["onchain_transactions"]["unvault"] is None
+    assert len(details["events"]) == 1
+
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    details = revaultd_manager.rpc.call("getvault", [outpoint])
+    assert details["status"] == "funded"
+    assert details["blockheight"] > 0
+    deposit = details["onchain_transactions"]["deposit"]
+    assert deposit["blockheight"] == details["blockheight"]
+    assert deposit["confirmations"] >= 6
+    assert bitcoind.rpc.decoderawtransaction(deposit["hex"])["txid"] == txid
+    # As a manager we don't have the Emergency transactions
+    presigned = {tx["type"]: tx for tx in details["presigned_transactions"]}
+    assert set(presigned.keys()) == {"unvault", "cancel"}
+    for tx in presigned.values():
+        assert tx["signatures"] == 0
+        assert not tx["fully_signed"]
+    assert details["unvault_address"] != details["deposit_address"]
+    assert len(details["events"]) == 2
+    assert details["events"] == revaultd_manager.rpc.call(
+        "getvaulthistory", [outpoint]
+    )["events"]
+
+    with pytest.raises(RpcError, match="No vault at"):
+        revaultd_manager.rpc.call("getvault", [f"{txid}:100"])
+
+
+def test_getbalance(revaultd_manager, bitcoind):
+    balance = revaultd_manager.rpc.call("getbalance")
+    assert len(balance["statuses"]) == 15
+    assert all(b == {"amount": 0, "count": 0} for b in balance["statuses"].values())
+    assert balance["confirmed"] == {"amount": 0, "count": 0}
+    assert balance["moving"] == {"amount": 0, "count": 0}
+    assert balance["unconfirmed"] == {"amount": 0, "count": 0}
+
+    txids = []
+    for amount in [0.1, 0.2, 0.3]:
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, amount))
+        revaultd_manager.wait_for_log("Got a new unconfirmed deposit")
+    balance = revaultd_manager.rpc.call("getbalance")
+    assert balance["unconfirmed"] == {"amount": 60_000_000, "count": 3}
+    assert balance["statuses"]["unconfirmed"] == balance["unconfirmed"]
+    assert balance["confirmed"] == {"amount": 0, "count": 0}
+
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_logs(
+        [f"Vault at .*{txid}.* is now confirmed" for txid in txids]
+    )
+    balance = revaultd_manager.rpc.call("getbalance")
+    assert balance["unconfirmed"] == {"amount": 0, "count": 0}
+    assert balance["confirmed"] == {"amount": 60_000_000, "count": 3}
+    assert balance["statuses"]["funded"] == balance["confirmed"]
+    assert balance["moving"] == {"amount": 0, "count": 0}
+
+
+def test_vault_labels(revaultd_manager, bitcoind):
+    outpoints = []
+    for amount in [0.1, 0.2]:
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txid = bitcoind.rpc.sendtoaddress(addr, amount)
+        revaultd_manager.wait_for_log("Got a new unconfirmed deposit")
+        vault = revaultd_manager.rpc.call("listvaults", [[], [f"{txid}:0", f"{txid}:1"]])[
+            "vaults"
+        ][0]
+        outpoints.append(f"{txid}:{vault['vout']}")
+
+    assert revaultd_manager.rpc.call("getvaultlabel", [outpoints[0]])["label"] is None
+    vaults = revaultd_manager.rpc.call("listvaults")["vaults"]
+    assert all(v["label"] is None for v in vaults)
+
+    revaultd_manager.rpc.call("setvaultlabel", [outpoints[0], "Q3 cold reserve"])
+    revaultd_manager.rpc.call("setvaultlabel", [outpoints[1], "client X"])
+    assert (
+        revaultd_manager.rpc.call("getvaultlabel", [outpoints[0]])["label"]
+        == "Q3 cold reserve"
+    )
+    assert (
+        revaultd_manager.rpc.call("getvault", [outpoints[1]])["label"] == "client X"
+    )
+    vaults = revaultd_manager.rpc.call("listvaults", [[], [], {"label": "cold"}])[
+        "vaults"
+    ]
+    assert len(vaults) == 1
+    assert f"{vaults[0]['txid']}:{vaults[0]['vout']}" == outpoints[0]
+    assert vaults[0]["label"] == "Q3 cold reserve"
+
+    # An empty label removes it
+    revaultd_manager.rpc.call("setvaultlabel", [outpoints[0], ""])
+    assert revaultd_manager.rpc.call("getvaultlabel", [outpoints[0]])["label"] is None
+    vaults = revaultd_manager.rpc.call("listvaults", [[], [], {"label": "cold"}])[
+        "vaults"
+    ]
+    assert len(vaults) == 0
+
+    with pytest.raises(RpcError, match="Label is too long"):
+        revaultd_manager.rpc.call("setvaultlabel", [outpoints[1], "a" * 1025])
+
+
+def test_exportvaults(revaultd_manager, bitcoind):
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.5)
+    revaultd_manager.wait_for_log("Got a new unconfirmed deposit")
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    outpoint = f"{txid}:{vault['vout']}"
+    revaultd_manager.rpc.call("setvaultlabel", [outpoint, "client X, Q3"])
+    height_before = revaultd_manager.rpc.call("getinfo")["blockheight"]
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+
+    export = revaultd_manager.rpc.call("exportvaults")
+    assert export["blockheight"] == revaultd_manager.rpc.call("getinfo")["blockheight"]
+    assert len(export["vaults"]) == 1
+    exported = export["vaults"][0]
+    assert exported["txid"] == txid
+    assert exported["amount"] == 50_000_000
+    assert exported["status"] == "funded"
+    assert exported["label"] == "client X, Q3"
+    assert set(exported["status_timestamps"].keys()) == {"unconfirmed", "funded"}
+    # Timestamped by the block confirming the deposit, not by when we processed it
+    deposit_block = bitcoind.rpc.getblock(
+        bitcoind.rpc.getblockhash(exported["blockheight"])
+    )
+    assert txid in deposit_block["tx"]
+    assert exported["status_timestamps"]["funded"] == deposit_block["time"]
+    unvault = exported["transactions"]["unvault"]
+    assert unvault["blockheight"] is None
+    assert unvault["fee"] > 0
+    assert exported["transactions"]["cancel"]["fee"] > 0
+    assert exported["transactions"]["spend"] is None
+    # Reproducible
+    assert revaultd_manager.rpc.call("exportvaults", ["json"]) == export
+
+    # At the time it was unconfirmed
+    exported = revaultd_manager.rpc.call("exportvaults", ["json", height_before])[
+        "vaults"
+    ][0]
+    assert exported["status"] == "unconfirmed"
+    assert exported["blockheight"] == 0
+    assert exported["transactions"]["unvault"] is None
+
+    csv = revaultd_manager.rpc.call("exportvaults", ["csv"])["csv"]
+    lines = csv.split("\r\n")
+    assert lines[0].startswith("deposit_txid,deposit_vout,amount,")
+    assert lines[1].startswith(f"{txid},{vault['vout']},50000000,")
+    assert '"client X, Q3"' in lines[1]
+    assert lines[2] == ""
+
+    with pytest.raises(RpcError, match="is above our tip"):
+        revaultd_manager.rpc.call("exportvaults", ["json", export["blockheight"] + 1])
+    with pytest.raises(RpcError, match="not a valid export format"):
+        revaultd_manager.rpc.call("exportvaults", ["xml"])
+
+
+@pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
+def test_onchain_transactions_stored(revaultd_manager, bitcoind):
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.3)
+    revaultd_manager.wait_for_log(f"Got a new unconfirmed deposit at {txid}")
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    outpoint = f"{txid}:{vault['vout']}"
+
+    # We stored the deposit as soon as we saw it
+    db_path = os.path.join(
+        os.path.dirname(revaultd_manager.conf_file), "regtest", "revaultd.sqlite3"
+    )
+
+    def stored_txs():
+        conn = sqlite3.connect(db_path)
+        rows = conn.execute(
+            "SELECT type, txid, raw_tx, blockheight, blocktime FROM onchain_transactions"
+        ).fetchall()
+        conn.close()
+        return rows
+
+    rows = stored_txs()
+    assert len(rows) == 1
+    (tx_type, db_txid, raw_tx, blockheight, blocktime) = rows[0]
+    assert tx_type == 0
+    assert db_txid[::-1].hex() == txid
+    assert bitcoind.rpc.decoderawtransaction(raw_tx.hex())["txid"] == txid
+    assert blockheight is None and blocktime is None
+
+    # And updated it once confirmed
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    rows = stored_txs()
+    assert len(rows) == 1
+    block = bitcoind.rpc.getblock(bitcoind.rpc.getblockhash(rows[0][3]))
+    assert txid in block["tx"]
+    assert rows[0][4] == block["time"]
+
+    # Which is what we serve
+    txs = revaultd_manager.rpc.call("listonchaintransactions", [[outpoint]])[
+        "onchain_transactions"
+    ][0]
+    assert txs["deposit"]["blockheight"] == rows[0][3]
+    assert txs["deposit"]["blocktime"] == block["time"]
+    assert txs["deposit"]["hex"] == rows[0][2].hex()
+    assert txs["unvault"] is None
+
+
+def test_checkconsistency(revaultd_manager, bitcoind):
+    revaultd_manager.wait_for_log(
+        "Database is consistent with bitcoind's watchonly wallet"
+    )
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.4)
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    assert revaultd_manager.rpc.call("checkconsistency")["inconsistencies"] == []
+    blockheight = revaultd_manager.rpc.call("listvaults")["vaults"][0]["blockheight"]
+
+    # Mess with the height we recorded for the deposit
+    db_path = os.path.join(
+        os.path.dirname(revaultd_manager.conf_file), "regtest", "revaultd.sqlite3"
+    )
+    conn = sqlite3.connect(db_path)
+    conn.execute("UPDATE vaults SET blockheight = 1")
+    conn.commit()
+    conn.close()
+    inconsistencies = revaultd_manager.rpc.call("checkconsistency")["inconsistencies"]
+    assert len(inconsistencies) == 1
+    assert inconsistencies[0]["txid"] == txid
+    assert inconsistencies[0]["kind"] == "confirmation"
+    assert not inconsistencies[0]["repaired"]
+
+    # It's reported at startup too
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    revaultd_manager.start()
+    revaultd_manager.wait_for_log("Found 1 inconsistent vault")
+
+    inconsistencies = revaultd_manager.rpc.call("checkconsistency", [True])[
+        "inconsistencies"
+    ]
+    assert len(inconsistencies) == 1
+    assert inconsistencies[0]["repaired"]
+    assert revaultd_manager.rpc.call("checkconsistency")["inconsistencies"] == []
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    assert vault["blockheight"] == blockheight
+    assert vault["status"] == "funded"
+
+
+def test_auditpresigned(revaultd_manager, bitcoind):
+    txids = []
+    for amount in [0.3, 0.4]:
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, amount))
+        revaultd_manager.wait_for_log(f"Got a new unconfirmed deposit at {txids[-1]}")
+    bitcoind.generate_block(6)
+    for txid in txids:
+        revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+
+    vaults = revaultd_manager.rpc.call("auditpresigned")["vaults"]
+    assert len(vaults) == 2
+    assert all(v["valid"] and v["mismatches"] == [] for v in vaults)
+
+    # Swap the stored Cancel transactions of the two vaults
+    db_path = os.path.join(
+        os.path.dirname(revaultd_manager.conf_file), "regtest", "revaultd.sqlite3"
+    )
+    conn = sqlite3.connect(db_path)
+    (id_a, psbt_a), (id_b, psbt_b) = conn.execute(
+        "SELECT id, psbt FROM presigned_transactions WHERE type = 1"
+    ).fetchall()
+    # The PSBTs are unique, so go through a placeholder
+    query = "UPDATE presigned_transactions SET psbt = ? WHERE id = ?"
+    conn.execute(query, (b"\x00", id_a))
+    conn.execute(query, (psbt_a, id_b))
+    conn.execute(query, (psbt_b, id_a))
+    conn.commit()
+    conn.close()
+
+    vaults = revaultd_manager.rpc.call("auditpresigned")["vaults"]
+    assert len(vaults) == 2
+    for audit in vaults:
+        assert not audit["valid"]
+        assert len(audit["mismatches"]) == 1
+        mismatch = audit["mismatches"][0]
+        assert mismatch["transaction_type"] == "cancel"
+        assert mismatch["stored_txid"] is not None
+        assert mismatch["expected_txid"] != mismatch["stored_txid"]
+    revaultd_manager.wait_for_log(
+        "Presigned transactions of vault .* were tampered with"
+    )
+
+    # It can be restricted to some vaults
+    outpoint = vaults[0]["vault_outpoint"]
+    audits = revaultd_manager.rpc.call("auditpresigned", [[outpoint]])["vaults"]
+    assert [a["vault_outpoint"] for a in audits] == [outpoint]
+    txid, vout = outpoint.split(":")
+    with pytest.raises(RpcError, match="No vault at"):
+        revaultd_manager.rpc.call("auditpresigned", [[f"{txid}:{int(vout) + 1}"]])
+
+
+def test_recover(revaultd_manager, bitcoind):
+    # It refuses to override an existing database
+    res = subprocess.run(
+        revaultd_manager.cmd_line + ["--recover"], capture_output=True, text=True
+    )
+    assert res.returncode == 1
+    assert "Refusing to recover" in res.stderr
+
+    txids = []
+    for amount in [0.1, 0.2]:
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, amount))
+        revaultd_manager.wait_for_log(f"Got a new unconfirmed deposit at {txids[-1]}")
+    bitcoind.generate_block(6)
+    for txid in txids:
+        revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    vaults = sorted(
+        revaultd_manager.rpc.call("listvaults")["vaults"], key=lambda v: v["txid"]
+    )
+    next_addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    first_height = min(
+        bitcoind.rpc.gettransaction(txid)["blockheight"] for txid in txids
+    )
+
+    # Lose everything but the configuration
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    datadir = os.path.join(os.path.dirname(revaultd_manager.conf_file), "regtest")
+    wallet_path = os.path.join(datadir, "revaultd-watchonly-wallet-1")
+    bitcoind.rpc.unloadwallet(wallet_path)
+    shutil.rmtree(wallet_path)
+    os.remove(os.path.join(datadir, "revaultd.sqlite3"))
+
+    revaultd_manager.cmd_line += [
+        "--recover",
+        "--recovery-gap",
+        "20",
+        "--recovery-start",
+        str(first_height),
+    ]
+    revaultd_manager.start()
+    revaultd_manager.wait_for_log(
+        f"Recovering our vaults from the chain since block {first_height}"
+    )
+    revaultd_manager.wait_for_log("Recovery done: found 2 vault")
+    recovered = sorted(
+        revaultd_manager.rpc.call("listvaults")["vaults"], key=lambda v: v["txid"]
+    )
+    assert [(v["txid"], v["vout"]) for v in recovered] == [
+        (v["txid"], v["vout"]) for v in vaults
+    ]
+    for (vault, original) in zip(recovered, vaults):
+        assert vault["status"] == "funded"
+        assert vault["amount"] == original["amount"]
+        assert vault["address"] == original["address"]
+        presigned = revaultd_manager.rpc.call(
+            "getvault", [f"{vault['txid']}:{vault['vout']}"]
+        )["presigned_transactions"]
+        assert {tx["type"] for tx in presigned} == {"unvault", "cancel"}
+    # We won't hand out a used address
+    assert revaultd_manager.rpc.call("getdepositaddress")["address"] == next_addr
+
+
+def test_rescan(revaultd_manager, bitcoind):
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.1)
+    revaultd_manager.wait_for_log(f"Got a new unconfirmed deposit at {txid}")
+    bitcoind.generate_block(6, wait_for_mempool=txid)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    deposit_height = bitcoind.rpc.gettransaction(txid)["blockheight"]
+
+    # Start over with a new watchonly wallet, created after the deposit
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    datadir = os.path.join(os.path.dirname(revaultd_manager.conf_file), "regtest")
+    wallet_path = os.path.join(datadir, "revaultd-watchonly-wallet-1")
+    bitcoind.rpc.unloadwallet(wallet_path)
+    shutil.rmtree(wallet_path)
+    os.remove(os.path.join(datadir, "revaultd.sqlite3"))
+    revaultd_manager.start()
+    bitcoind.generate_block(1)
+    wait_for(
+        lambda: revaultd_manager.rpc.call("getinfo")["blockheight"]
+        == bitcoind.rpc.getblockcount()
+    )
+    assert len(revaultd_manager.rpc.call("listvaults")["vaults"]) == 0
+
+    tip = bitcoind.rpc.getblockcount()
+    with pytest.raises(RpcError, match=f"Start height '{tip + 1}' is above our tip"):
+        revaultd_manager.rpc.call("rescan", [tip + 1])
+    with pytest.raises(RpcError, match="No block after timestamp '4000000000' yet"):
+        revaultd_manager.rpc.call("rescan", [4_000_000_000])
+
+    # The rescan is done in the background, and the deposit picked up afterwards
+    revaultd_manager.rpc.call("rescan", [deposit_height])
+    revaultd_manager.wait_for_logs(
+        [
+            f"Rescan since block {deposit_height} done",
+            f"Got a new unconfirmed deposit at {txid}",
+            f"Vault at .*{txid}.* is now confirmed",
+        ]
+    )
+    assert revaultd_manager.rpc.call("getinfo")["rescan"] is None
+    vaults = revaultd_manager.rpc.call("listvaults")["vaults"]
+    assert [(v["txid"], v["status"]) for v in vaults] == [(txid, "funded")]
+
+    # It works with a timestamp too
+    block_time = bitcoind.rpc.getblockheader(
+        bitcoind.rpc.getblockhash(deposit_height)
+    )["mediantime"]
+    revaultd_manager.rpc.call("rescan", [block_time])
+    revaultd_manager.wait_for_log("Rescan since block .* done")
+
+
+def test_deposit_during_rescan(revaultd_manager, bitcoind):
+    # Only watch 2 addresses past the first unused one, so that each deposit imports a new one
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    with open(revaultd_manager.conf_file, "r") as f:
+        conf = f.read()
+    with open(revaultd_manager.conf_file, "w") as f:
+        f.write("gap_limit = 2\n" + conf)
+    revaultd_manager.start()
+    # Give the rescan something to chew on
+    bitcoind.generate_block(200)
+
+    # bitcoind refuses imports while rescanning, the deposit at index 0 must not take the
+    # poller down
+    revaultd_manager.rpc.call("rescan", [0])
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.1)
+    revaultd_manager.wait_for_logs(
+        [
+            f"Got a new unconfirmed deposit at {txid}",
+            "Incremented deposit derivation index from 0 to 1",
+        ]
+    )
+    wait_for(lambda: revaultd_manager.rpc.call("getinfo")["rescan"] is None)
+
+    # The address at index 2 was imported, be it right away or once the rescan was over
+    txids = []
+    for i in range(1, 3):
+        addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, 0.1))
+        revaultd_manager.wait_for_log(
+            f"Incremented deposit derivation index from {i} to {i + 1}"
+        )
+    bitcoind.generate_block(6, wait_for_mempool=txids)
+    for txid in txids:
+        revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    assert len(revaultd_manager.rpc.call("listvaults")["vaults"]) == 3
+
+
+def test_backup_restore(revaultd_manager, bitcoind):
+    addr = revaultd_manager.rpc.call("getdepositaddress")["address"]
+    txid = bitcoind.rpc.sendtoaddress(addr, 0.5)
+    bitcoind.generate_block(6)
+    revaultd_manager.wait_for_log(f"Vault at .*{txid}.* is now confirmed")
+    vault = revaultd_manager.rpc.call("listvaults")["vaults"][0]
+    outpoint = f"{txid}:{vault['vout']}"
+    revaultd_manager.rpc.call("setvaultlabel", [outpoint, "backed up"])
+
+    datadir = os.path.dirname(revaultd_manager.conf_file)
+    backup_path = os.path.join(datadir, "revaultd.backup")
+    with pytest.raises(RpcError, match="not an absolute path"):
+        revaultd_manager.rpc.call("backup", ["revaultd.backup", "passphrase"])
+    with pytest.raises(RpcError, match="passphrase must not be empty"):
+        revaultd_manager.rpc.call("backup", [backup_path, ""])
+    revaultd_manager.rpc.call("backup", [backup_path, "correct horse"])
+    with pytest.raises(RpcError, match="already exists"):
+        revaultd_manager.rpc.call("backup", [backup_path, "correct horse"])
+
+    revaultd_manager.rpc.call("stop")
+    revaultd_manager.proc.wait(TIMEOUT)
+    restore_cmd = revaultd_manager.cmd_line + ["--restore", backup_path]
+
+    def restore(passphrase):
+        return subprocess.run(
+            restore_cmd, input=f"{passphrase}\n", capture_output=True, text=True
+        )
+
+    res = restore("wrong horse")
+    assert res.returncode == 1
+    assert "wrong passphrase" in res.stderr
+    res = restore("correct horse")
+    assert res.returncode == 1
+    assert "Refusing to overwrite the database" in res.stderr
+
+    db_path = os.path.join(datadir, "regtest", "revaultd.sqlite3")
+    for path in [db_path, f"{db_path}-wal", f"{db_path}-shm"]:
+        if os.path.exists(path):
+            os.remove(path)
+    res = restore("correct horse")
+    assert res.returncode == 0, res.stderr
+    assert "Restored database" in res.stdout
+
+    revaultd_manager.start()
+    restored = revaultd_manager.rpc.call("listvaults")["vaults"]
+    assert len(restored) == 1
+    assert restored[0]["txid"] == txid
+    assert restored[0]["status"] == "funded"
+    assert restored[0]["label"] == "backed up"
+
+
+def test_deployment_fingerprint(revault_network):
+    (stks, mans) = revault_network.deploy(3, 2)
+    fingerprint = stks[0].rpc.call("getinfo")["deployment_fingerprint"]
+    assert fingerprint is not None
+    for n in stks + mans:
+        assert n.rpc.call("getinfo")["deployment_fingerprint"] == fingerprint
+
+
+@pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
+def test_emergency_kit(revault_network, bitcoind):
+    (stks, mans) = revault_network.deploy(2, 1)
+    vault = revault_network.fund(1)
+    revault_network.secure_vault(vault)
+    deposit = f"{vault['txid']}:{vault['vout']}"
+
+    kit_path = os.path.join(os.path.dirname(stks[0].conf_file), "emergency_kit.json")
+    with pytest.raises(RpcError, match="This is a stakeholder command"):
+        mans[0].rpc.call("emergencykit", [kit_path])
+    with pytest.raises(RpcError, match="not an absolute path"):
+        stks[0].rpc.call("emergencykit", ["emergency_kit.json"])
+    res = stks[0].rpc.call("emergencykit", [kit_path])
+    assert res == {"vaults": 1, "emergency": 1, "unvault_emergency": 1}
+    with pytest.raises(RpcError, match="Emergency kit error"):
+        stks[0].rpc.call("emergencykit", [kit_path])
+
+    # It works without revaultd
+    revault_network.stop_wallets()
+    bin = os.path.join(
+        os.path.dirname(__file__), "..", "target/debug/revault-emergency"
+    )
+    res = subprocess.run([bin, kit_path, "--check"], capture_output=True, text=True)
+    assert res.returncode == 0, res.stderr
+    assert deposit in res.stdout
+
+    # A corrupted kit is detected
+    with open(kit_path) as f:
+        content = f.read()
+    tampered_path = f"{kit_path}.tampered"
+    with open(tampered_path, "w") as f:
+        f.write(content.replace(vault["txid"], "00" * 32))
+    res = subprocess.run(
+        [bin, tampered_path, "--check"], capture_output=True, text=True
+    )
+    assert res.returncode == 1
+    assert "Checksum mismatch" in res.stderr
+
+    cookie_path = os.path.join(bitcoind.bitcoin_dir, "regtest", ".cookie")
+    res = subprocess.run(
+        [
+            bin,
+            kit_path,
+            "--bitcoind-addr",
+            f"127.0.0.1:{bitcoind.rpcport}",
+            "--bitcoind-cookie",
+            cookie_path,
+        ],
+        capture_output=True,
+        text=True,
+    )
+    assert res.returncode == 0, res.stderr
+    assert "Broadcast Emergency transaction" in res.stdout
+    bitcoind.generate_block(1, wait_for_mempool=1)
+    assert bitcoind.rpc.gettxout(vault["txid"], vault["vout"]) is None
+
+
 @pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
 def test_getdepositaddress(revault_network, bitcoind):
     (stks, mans) = revault_network.deploy(4, 2)
@@ -133,6 +929,61 @@ def test_getdepositaddress(revault_network, bitcoind):
         assert addr2 == n.rpc.call("getdepositaddress")["address"]
 
 
+@pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
+def test_gap_limit_extension(revault_network, bitcoind):
+    (stks, mans) = revault_network.deploy(2, 1)
+    stk, man = stks[0], mans[0]
+
+    # The manager only watches 2 addresses past the first unused one
+    man.rpc.call("stop")
+    man.proc.wait(TIMEOUT)
+    with open(man.conf_file, "r") as f:
+        conf = f.read()
+    with open(man.conf_file, "w") as f:
+        f.write("gap_limit = 2\n" + conf)
+
+    # Deposits at indexes 0 to 3 are made while it's offline
+    txids = []
+    for i in range(4):
+        addr = stk.rpc.call("getdepositaddress")["address"]
+        txids.append(bitcoind.rpc.sendtoaddress(addr, 0.1))
+        stk.wait_for_log(f"Incremented deposit derivation index from {i} to {i + 1}")
+    bitcoind.generate_block(1, wait_for_mempool=txids)
+
+    # It only sees the ones within its window, and imports the next addresses
+    man.start()
+    man.wait_for_logs(
+        [
+            f"Got a new unconfirmed deposit at {txids[0]}",
+            f"Got a new unconfirmed deposit at {txids[1]}",
+        ]
+    )
+    man.wait_for_log("Incremented deposit derivation index from .* to 2")
+    assert len(man.rpc.listvaults()["vaults"]) == 2
+
+    # But the deposits at indexes 2 and 3 were made before it imported them
+    with pytest.raises(RpcError, match="Start index '3' is above end index '2'"):
+        man.rpc.rescanindexes(3, 2)
+    with pytest.raises(RpcError, match="is a hardened derivation index"):
+        man.rpc.rescanindexes(0, 2 ** 31)
+    man.rpc.rescanindexes(3, 3)
+    man.wait_for_logs(
+        [
+            "Rescan since block .* done",
+            f"Got a new unconfirmed deposit at {txids[3]}",
+            "Got a deposit at derivation index 3 while the first unused one was 2",
+            "Incremented deposit derivation index from 2 to 4",
+        ]
+    )
+    man.rpc.rescanindexes(2, 2)
+    man.wait_for_log(f"Got a new unconfirmed deposit at {txids[2]}")
+    assert len(man.rpc.listvaults()["vaults"]) == 4
+    assert (
+        man.rpc.call("getdepositaddress")["address"]
+        == stk.rpc.call("getdepositaddress")["address"]
+    )
+
+
 @pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
 def test_huge_deposit(revault_network, bitcoind):
     revault_network.deploy(2, 1)
@@ -754,3 +1605,137 @@ def test_reorged_deposit_status(revault_network, bitcoind):
     revault_network.start_wallets()
     for w in revault_network.stk_wallets + revault_network.man_wallets:
         w.wait_for_active_vaults([deposit])
+
+
+@pytest.mark.skipif(not POSTGRES_IS_SETUP, reason="Needs Postgres for servers db")
+def test_unvault_output_spends(revault_network, bitcoind):
+    revault_network.deploy(2, 1)
+    stk = revault_network.stk_wallets[0]
+    vaults = [revault_network.fund(amount) for amount in [0.5, 0.6]]
+    deposits = [f"{v['txid']}:{v['vout']}" for v in vaults]
+    for vault in vaults:
+        revault_network.secure_vault(vault)
+        revault_network.activate_vault(vault)
+
+    # Once all signed, the presigned transactions are finalized
+    presigned = {
+        txs["vault_outpoint"]: txs
+        for txs in stk.rpc.listpresignedtransactions(deposits)[
+            "presigned_transactions"
+        ]
+    }
+
+    def broadcast(psbt):
+        tx_hex = bitcoind.rpc.finalizepsbt(psbt)["hex"]
+        return bitcoind.rpc.sendrawtransaction(tx_hex)
+
+    def status(w, deposit):
+        return w.rpc.listvaults([], [deposit])["vaults"][0]["status"]
+
+    for deposit in deposits:
+        broadcast(presigned[deposit]["unvault"])
+    for w in revault_network.stk_wallets + revault_network.man_wallets:
+        for deposit in deposits:
+            wait_for(lambda: status(w, deposit) == "unvaulting")
+    bitcoind.generate_block(1, wait_for_mempool=2)
+
+    # The first one is canceled, the second one emergency-vaulted
+    cancel_txid = broadcast(presigned[deposits[0]]["cancel"])
+    unemer_txid = broadcast(presigned[deposits[1]]["unvault_emergency"])
+    for w in revault_network.stk_wallets + revault_network.man_wallets:
+        wait_for(lambda: status(w, deposits[0]) == "canceling")
+        wait_for(lambda: status(w, deposits[1]) == "unvaultermergencyvaulting")
+    txs = stk.rpc.listonchaintransactions(deposits)["onchain_transactions"]
+    txs = {t["vault_outpoint"]: t for t in txs}
+    assert txs[deposits[0]]["cancel"]["hex"] == bitcoind.rpc.getrawtransaction(
+        cancel_txid
+    )
+    assert txs[deposits[1]]["unvault_emergency"]["hex"] == (
+        bitcoind.rpc.getrawtransaction(unemer_txid)
+    )
+
+    # They stay there across restarts, and don't get updated again
+    bitcoind.generate_block(1, wait_for_mempool=[cancel_txid, unemer_txid])
+    stk.stop()
+    stk.start()
+    assert status(stk, deposits[0]) == "canceling"
+    assert status(stk, deposits[1]) == "unvaultermergencyvaulting"
+    updated_at = [
+        stk.rpc.listvaults([], [d])["vaults"][0]["updated_at"] for d in deposits
+    ]
+    height = bitcoind.rpc.getblockcount()
+    bitcoind.generate_block(2)
+    wait_for(lambda: stk.rpc.getinfo()["blockheight"] == height + 2)
+    assert [
+        stk.rpc.listvaults([], [d])["vaults"][0]["updated_at"] for d in deposits
+    ] == updated_at
+
+
+def test_recover_spent_vaults(revault_network, bitcoind):
+    revault_network.deploy(2, 1)
+    stk = revault_network.stk_wallets[0]
+    vaults = [revault_network.fund(amount) for amount in [0.5, 0.6, 0.7]]
+    deposits = [f"{v['txid']}:{v['vout']}" for v in vaults]
+    for vault in vaults:
+        revault_network.secure_vault(vault)
+        revault_network.activate_vault(vault)
+    presigned = {
+        txs["vault_outpoint"]: txs
+        for txs in stk.rpc.listpresignedtransactions(deposits)[
+            "presigned_transactions"
+        ]
+    }
+
+    def broadcast(psbt):
+        tx_hex = bitcoind.rpc.finalizepsbt(psbt)["hex"]
+        return bitcoind.rpc.sendrawtransaction(tx_hex)
+
+    def status(deposit):
+        return stk.rpc.listvaults([], [deposit])["vaults"][0]["status"]
+
+    # The first one is canceled, the second one emergency-vaulted after being
+    # unvaulted, and the third one emergency-vaulted
+    unvault_txids = [broadcast(presigned[d]["unvault"]) for d in deposits[:2]]
+    emer_txid = broadcast(presigned[deposits[2]]["emergency"])
+    bitcoind.generate_block(1, wait_for_mempool=unvault_txids + [emer_txid])
+    cancel_txid = broadcast(presigned[deposits[0]]["cancel"])
+    unemer_txid = broadcast(presigned[deposits[1]]["unvault_emergency"])
+    bitcoind.generate_block(1, wait_for_mempool=[cancel_txid, unemer_txid])
+    wait_for(lambda: status(deposits[0]) == "canceled")
+    wait_for(lambda: status(deposits[1]) == "unvaultermergencyvaulted")
+    wait_for(lambda: status(deposits[2]) == "emergencyvaulted")
+    first_height = min(
+        bitcoind.rpc.gettransaction(v["txid"])["blockheight"] for v in vaults
+    )
+
+    # Lose everything but the configuration
+    stk.stop()
+    datadir = os.path.join(os.path.dirname(stk.conf_file), "regtest")
+    wallet_path = os.path.join(datadir, "revaultd-watchonly-wallet-1")
+    bitcoind.rpc.unloadwallet(wallet_path)
+    shutil.rmtree(wallet_path)
+    os.remove(os.path.join(datadir, "revaultd.sqlite3"))
+
+    stk.cmd_line += [
+        "--recover",
+        "--recovery-gap",
+        "20",
+        "--recovery-start",
+        str(first_height),
+    ]
+    stk.start()
+    stk.wait_for_log("Recovery done: found 3 vault")
+    assert status(deposits[0]) == "canceled"
+    assert status(deposits[1]) == "unvaultermergencyvaulted"
+    assert status(deposits[2]) == "emergencyvaulted"
+    txs = stk.rpc.listonchaintransactions(deposits)["onchain_transactions"]
+    txs = {t["vault_outpoint"]: t for t in txs}
+    assert txs[deposits[0]]["cancel"]["hex"] == bitcoind.rpc.getrawtransaction(
+        cancel_txid
+    )
+    assert txs[deposits[1]]["unvault_emergency"]["hex"] == (
+        bitcoind.rpc.getrawtransaction(unemer_txid)
+    )
+    assert txs[deposits[2]]["emergency"]["hex"] == bitcoind.rpc.getrawtransaction(
+        emer_txid
+    )
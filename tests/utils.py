@@ -488,6 +488,7 @@ class BitcoinD(TailableProc):
         self.bitcoin_dir = bitcoin_dir
         self.rpcport = rpcport
         self.p2pport = reserve()
+        self.zmqport = reserve()
         self.prefix = "bitcoind"
 
         regtestdir = os.path.join(bitcoin_dir, "regtest")
@@ -507,6 +508,8 @@ class BitcoinD(TailableProc):
             "rpcport": rpcport,
             "debug": 1,
             "fallbackfee": Decimal(1000) / bitcoin.core.COIN,
+            "zmqpubhashblock": f"tcp://127.0.0.1:{self.zmqport}",
+            "zmqpubrawtx": f"tcp://127.0.0.1:{self.zmqport}",
         }
         self.conf_file = os.path.join(bitcoin_dir, "bitcoin.conf")
         with open(self.conf_file, "w") as f:
@@ -703,6 +706,8 @@ class Revaultd(TailableProc):
             f.write(f'network = "regtest"\n')
             f.write(f"cookie_path = '{bitcoind_cookie}'\n")
             f.write(f"addr = '127.0.0.1:{bitcoind.rpcport}'\n")
+            f.write(f"zmq_hashblock = 'tcp://127.0.0.1:{bitcoind.zmqport}'\n")
+            f.write(f"zmq_rawtx = 'tcp://127.0.0.1:{bitcoind.zmqport}'\n")
 
             if stk_config is not None:
                 f.write("[stakeholder_config]\n")
@@ -732,6 +737,11 @@ class Revaultd(TailableProc):
                         f"\"{wt['noise_key']}\" }}, "
                     )
                 f.write("]\n")
+                # The sha256 of the above emergency address' scriptPubKey
+                f.write(
+                    "emergency_address_hash = "
+                    '"4d82e36c668f9f4cbc14b7ba797c7343e5e0021b519d1b6bac9fc831ec41a426"\n'
+                )
 
     def wait_for_deposits(self, outpoints):
         """